@@ -0,0 +1,80 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use elrpc::{Client, MethodRegistry, Server};
+use lexpr::Value;
+use tokio::runtime::Runtime;
+
+fn bench_registry_dispatch(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let registry = MethodRegistry::new();
+    rt.block_on(async {
+        registry
+            .register_value_method("echo", Ok, None::<&str>, None::<&str>)
+            .await
+            .unwrap();
+        registry
+            .register_closure(
+                "add",
+                |(a, b): (i64, i64)| Ok(a + b),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+    });
+
+    let mut group = c.benchmark_group("registry");
+    group.bench_function("value_method", |b| {
+        b.iter(|| {
+            rt.block_on(registry.call_method("echo", black_box(Value::from("hello"))))
+                .unwrap()
+        })
+    });
+    group.bench_function("typed_method", |b| {
+        let args = Value::list(vec![Value::from(1), Value::from(2)]);
+        b.iter(|| {
+            rt.block_on(registry.call_method("add", black_box(args.clone())))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn bench_loopback_roundtrip(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (mut server, client) = rt.block_on(async {
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_value_method("echo", Ok, None::<&str>, None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let client = Client::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        (server, client)
+    });
+
+    let mut group = c.benchmark_group("loopback");
+    group.throughput(Throughput::Elements(1));
+    for size in [8usize, 4096] {
+        let payload = "x".repeat(size);
+        group.bench_function(format!("echo/{}", size), |b| {
+            b.iter(|| {
+                rt.block_on(client.call_sync::<_, String>("echo", black_box(&payload)))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    rt.block_on(async {
+        client.close().await.unwrap();
+        server.shutdown().await.unwrap();
+    });
+}
+
+criterion_group!(benches, bench_registry_dispatch, bench_loopback_roundtrip);
+criterion_main!(benches);
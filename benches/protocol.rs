@@ -0,0 +1,83 @@
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use elrpc::{Framer, Message, ValueArena};
+use lexpr::Value;
+
+fn call_with_args(n: usize) -> String {
+    let args = Value::list((0..n as i64).map(Value::from).collect::<Vec<_>>());
+    Message::new_call(42, "bench", args).to_sexp().unwrap()
+}
+
+fn bench_parse_call(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_sexp/call");
+    for n in [0usize, 2, 4, 64] {
+        let sexp = call_with_args(n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &sexp, |b, sexp| {
+            b.iter(|| Message::from_sexp(black_box(sexp)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_parse_return(c: &mut Criterion) {
+    let sexp = Message::new_return(7, Value::string("ok"))
+        .to_sexp()
+        .unwrap();
+    c.bench_function("from_sexp/return", |b| {
+        b.iter(|| Message::from_sexp(black_box(&sexp)).unwrap())
+    });
+}
+
+fn bench_parse_arena(c: &mut Criterion) {
+    let mut group = c.benchmark_group("arena_parse/call");
+    for n in [4usize, 64, 4096] {
+        let sexp = call_with_args(n);
+        group.throughput(Throughput::Bytes(sexp.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &sexp, |b, sexp| {
+            b.iter(|| ValueArena::parse(black_box(sexp)).unwrap().node_count())
+        });
+    }
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("to_sexp/call");
+    for n in [0usize, 4, 64] {
+        let args = Value::list((0..n as i64).map(Value::from).collect::<Vec<_>>());
+        let message = Message::new_call(42, "bench", args);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &message, |b, message| {
+            b.iter(|| black_box(message).to_sexp().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_framing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("framing");
+    for size in [16usize, 1024, 64 * 1024] {
+        let payload = vec![b'x'; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("frame", size), &payload, |b, payload| {
+            b.iter(|| Framer::frame(black_box(payload)))
+        });
+
+        let framed = Framer::frame(&payload);
+        group.bench_with_input(BenchmarkId::new("extract", size), &framed, |b, framed| {
+            b.iter(|| {
+                let mut buf = BytesMut::from(&framed[..]);
+                Framer::extract_message(black_box(&mut buf)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_parse_call,
+    bench_parse_return,
+    bench_parse_arena,
+    bench_encode,
+    bench_framing
+);
+criterion_main!(benches);
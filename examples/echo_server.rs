@@ -63,7 +63,7 @@ async fn main() -> Result<()> {
         .await?;
 
     // Print port for Emacs compatibility
-    server.print_port()?;
+    server.announce_port()?;
 
     // Start serving - this will run in the background
     println!(
@@ -0,0 +1,42 @@
+use elrpc::stress::{self, StressConfig};
+use elrpc::{ERPCError, Result};
+
+fn parse_arg(args: &[String], index: usize, default: usize) -> Result<usize> {
+    match args.get(index) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| ERPCError::InvalidArgument(format!("not a number: {}", value))),
+        None => Ok(default),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    // Usage: stress [addr] [clients] [concurrency] [calls-per-client] [payload-size]
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let defaults = StressConfig::default();
+    let config = StressConfig {
+        addr: args.first().cloned().unwrap_or(defaults.addr),
+        clients: parse_arg(&args, 1, defaults.clients)?,
+        concurrency: parse_arg(&args, 2, defaults.concurrency)?,
+        calls_per_client: parse_arg(&args, 3, defaults.calls_per_client)?,
+        payload_size: parse_arg(&args, 4, defaults.payload_size)?,
+        ..defaults
+    };
+
+    println!(
+        "Stressing {} with {} clients x {} concurrent calls ({} calls each, {} byte payload)",
+        config.addr,
+        config.clients,
+        config.concurrency,
+        config.calls_per_client,
+        config.payload_size
+    );
+
+    let report = stress::run(&config).await?;
+    println!("{}", report);
+
+    Ok(())
+}
@@ -0,0 +1,316 @@
+//! Abuse detection with temporary bans
+//!
+//! An [`AbuseDetector`] counts offenses per peer address: protocol
+//! violations, calls over the rate quota and failed authentication. A peer
+//! that commits too many within the window is banned for a while; the
+//! server refuses its new connections and fails its calls on connections
+//! that are already open. Each ban is reported to the event callback.
+//!
+//! ```
+//! use std::time::Duration;
+//! use elrpc::abuse::{AbuseDetector, AbuseEvent};
+//!
+//! let detector = AbuseDetector::new()
+//!     .threshold(10)
+//!     .window(Duration::from_secs(60))
+//!     .ban_duration(Duration::from_secs(300))
+//!     .on_event(|event| match event {
+//!         AbuseEvent::Banned { peer, offenses, .. } => {
+//!             eprintln!("banned {} after {} offenses", peer, offenses)
+//!         }
+//!     });
+//! ```
+//!
+//! Unix socket peers are not tracked, since they all share one address.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::error::ERPCError;
+use crate::log::warn;
+
+/// Peers tracked before records that no longer matter are dropped
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Something a peer did wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    /// A malformed, oversized or too deeply nested message
+    ProtocolViolation,
+    /// A call refused by the rate quota
+    RateLimit,
+    /// A connection refused by the authenticator
+    AuthenticationFailure,
+}
+
+/// What an [`AbuseDetector`] reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AbuseEvent {
+    /// A peer reached the threshold and is refused for `duration`
+    Banned {
+        peer: IpAddr,
+        offenses: u32,
+        last: Offense,
+        duration: Duration,
+    },
+}
+
+/// Callback receiving abuse events
+pub type AbuseFn = dyn Fn(&AbuseEvent) + Send + Sync;
+
+#[derive(Debug)]
+struct PeerRecord {
+    window_start: Instant,
+    offenses: u32,
+    ban: Option<Ban>,
+}
+
+impl PeerRecord {
+    fn is_banned(&self, now: Instant) -> bool {
+        match self.ban {
+            Some(Ban::Until(until)) => until > now,
+            Some(Ban::Permanent) => true,
+            None => false,
+        }
+    }
+}
+
+/// When a ban ends
+#[derive(Debug, Clone, Copy)]
+enum Ban {
+    Until(Instant),
+    /// The ban duration reaches past what an [`Instant`] can hold
+    Permanent,
+}
+
+/// Per-peer offense counter with temporary bans, set on
+/// [`ServerConfig::abuse`](crate::server::ServerConfig)
+///
+/// Clones share their records, so one detector can guard several servers.
+#[derive(Clone)]
+pub struct AbuseDetector {
+    threshold: u32,
+    window: Duration,
+    ban_duration: Duration,
+    on_event: Option<Arc<AbuseFn>>,
+    peers: Arc<Mutex<HashMap<IpAddr, PeerRecord>>>,
+}
+
+impl fmt::Debug for AbuseDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AbuseDetector")
+            .field("threshold", &self.threshold)
+            .field("window", &self.window)
+            .field("ban_duration", &self.ban_duration)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for AbuseDetector {
+    fn default() -> Self {
+        AbuseDetector {
+            threshold: 20,
+            window: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(300),
+            on_event: None,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl AbuseDetector {
+    /// Ban peers with 20 offenses in a minute for five minutes
+    pub fn new() -> Self {
+        AbuseDetector::default()
+    }
+
+    /// Set how many offenses within the window earn a ban
+    pub fn threshold(mut self, offenses: u32) -> Self {
+        self.threshold = offenses.max(1);
+        self
+    }
+
+    /// Set the window offenses are counted in
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Set how long a ban lasts
+    ///
+    /// A duration too long to add to the current time, such as
+    /// [`Duration::MAX`], bans for good, until [`AbuseDetector::unban`].
+    pub fn ban_duration(mut self, duration: Duration) -> Self {
+        self.ban_duration = duration;
+        self
+    }
+
+    /// Hand every event to `callback`
+    pub fn on_event(mut self, callback: impl Fn(&AbuseEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(Arc::new(callback));
+        self
+    }
+
+    fn peers(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, PeerRecord>> {
+        self.peers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether `peer` is banned right now
+    pub fn is_banned(&self, peer: IpAddr) -> bool {
+        let now = Instant::now();
+        self.peers()
+            .get(&peer)
+            .is_some_and(|record| record.is_banned(now))
+    }
+
+    /// Lift the ban on `peer` and forget its offenses
+    pub fn unban(&self, peer: IpAddr) {
+        self.peers().remove(&peer);
+    }
+
+    /// Count an offense by `peer`, banning it at the threshold
+    pub fn record(&self, peer: IpAddr, offense: Offense) {
+        let now = Instant::now();
+        let event = {
+            let mut peers = self.peers();
+            if peers.len() >= PRUNE_THRESHOLD {
+                let window = self.window;
+                peers.retain(|_, record| {
+                    record.is_banned(now) || now.duration_since(record.window_start) < window
+                });
+            }
+            let record = peers.entry(peer).or_insert(PeerRecord {
+                window_start: now,
+                offenses: 0,
+                ban: None,
+            });
+            if record.is_banned(now) {
+                return;
+            }
+            if now.duration_since(record.window_start) >= self.window {
+                record.window_start = now;
+                record.offenses = 0;
+            }
+            record.offenses += 1;
+            if record.offenses < self.threshold {
+                return;
+            }
+            record.ban = Some(
+                now.checked_add(self.ban_duration)
+                    .map_or(Ban::Permanent, Ban::Until),
+            );
+            let offenses = record.offenses;
+            record.window_start = now;
+            record.offenses = 0;
+            AbuseEvent::Banned {
+                peer,
+                offenses,
+                last: offense,
+                duration: self.ban_duration,
+            }
+        };
+        warn!(
+            "Banning {} for {:?} after {} offenses",
+            peer, self.ban_duration, self.threshold
+        );
+        if let Some(callback) = &self.on_event {
+            callback(&event);
+        }
+    }
+}
+
+/// Whether `error` from processing a message is the peer's fault
+pub(crate) fn offense_of(error: &ERPCError) -> Option<Offense> {
+    match error {
+        ERPCError::QuotaExceeded(_) => Some(Offense::RateLimit),
+        ERPCError::InvalidMessageFormat(_)
+        | ERPCError::Parse(_)
+        | ERPCError::Utf8(_)
+        | ERPCError::ProtocolError(_)
+        | ERPCError::DuplicateReturn(_)
+        | ERPCError::UnknownUid(_)
+        | ERPCError::UidReuse(_) => Some(Offense::ProtocolViolation),
+        _ => None,
+    }
+}
+
+/// The error calls from a banned peer fail with
+pub(crate) fn banned() -> ERPCError {
+    ERPCError::PermissionDenied("peer is temporarily banned".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ban_at_threshold() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let detector = AbuseDetector::new()
+            .threshold(3)
+            .ban_duration(Duration::from_millis(50))
+            .on_event(move |event| seen.lock().unwrap().push(event.clone()));
+        let peer: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "192.0.2.8".parse().unwrap();
+
+        detector.record(peer, Offense::ProtocolViolation);
+        detector.record(other, Offense::RateLimit);
+        detector.record(peer, Offense::ProtocolViolation);
+        assert!(!detector.is_banned(peer));
+        detector.record(peer, Offense::RateLimit);
+        assert!(detector.is_banned(peer));
+        assert!(!detector.is_banned(other));
+
+        // Offenses during a ban do not extend it
+        detector.record(peer, Offense::RateLimit);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![AbuseEvent::Banned {
+                peer,
+                offenses: 3,
+                last: Offense::RateLimit,
+                duration: Duration::from_millis(50),
+            }]
+        );
+
+        tokio::time::advance(Duration::from_millis(60)).await;
+        assert!(!detector.is_banned(peer));
+        detector.record(other, Offense::RateLimit);
+        detector.unban(other);
+        detector.record(other, Offense::RateLimit);
+        assert!(!detector.is_banned(other));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_window_resets_count() {
+        let detector = AbuseDetector::new()
+            .threshold(2)
+            .window(Duration::from_millis(20));
+        let peer: IpAddr = "::1".parse().unwrap();
+        detector.record(peer, Offense::AuthenticationFailure);
+        tokio::time::advance(Duration::from_millis(30)).await;
+        detector.record(peer, Offense::AuthenticationFailure);
+        assert!(!detector.is_banned(peer));
+        detector.record(peer, Offense::AuthenticationFailure);
+        assert!(detector.is_banned(peer));
+    }
+
+    #[test]
+    fn test_unrepresentable_ban_is_permanent() {
+        let detector = AbuseDetector::new()
+            .threshold(1)
+            .ban_duration(Duration::MAX);
+        let peer: IpAddr = "192.0.2.9".parse().unwrap();
+
+        detector.record(peer, Offense::AuthenticationFailure);
+        assert!(detector.is_banned(peer));
+        detector.unban(peer);
+        assert!(!detector.is_banned(peer));
+    }
+}
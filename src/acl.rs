@@ -0,0 +1,284 @@
+//! Per-peer method access control
+//!
+//! EPC has no authentication, so a peer is identified by its address. A
+//! [`MethodAcl`] lists which methods peers in each address block may call;
+//! the server checks it before dispatching a call, and leaves methods a peer
+//! may not call out of its answer to a `methods` query. On a server with an
+//! [`Authenticator`](crate::Authenticator), rules can also name the identity
+//! a peer authenticated as, with [`MethodAcl::allow_identity`].
+//!
+//! Methods are named by patterns: an exact name such as `get-user`, a
+//! namespace prefix ending in `*` such as `db-*`, or `*` for every method.
+//!
+//! Peers on a Unix socket have no address and never match a block, not even
+//! `127.0.0.0/8`. They are granted methods with [`MethodAcl::allow_unix`], or
+//! by the user id the kernel reports for them with [`MethodAcl::allow_uid`].
+//!
+//! ```
+//! use elrpc::MethodAcl;
+//!
+//! // Anyone may read; only the local machine may write
+//! let acl = MethodAcl::new()
+//!     .allow_everyone(["get-*", "list-*"])
+//!     .allow("127.0.0.0/8".parse().unwrap(), ["*"]);
+//! assert!(acl.permits("10.0.0.7".parse().unwrap(), "get-user"));
+//! assert!(!acl.permits("10.0.0.7".parse().unwrap(), "delete-user"));
+//! ```
+
+use std::net::IpAddr;
+
+use crate::auth::Identity;
+use crate::context::Peer;
+use crate::error::ERPCError;
+use crate::peer_filter::Cidr;
+
+/// Methods matched by a list of patterns
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct MethodSet(Vec<String>);
+
+impl MethodSet {
+    fn extend<I, S>(&mut self, patterns: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.0.extend(patterns.into_iter().map(Into::into));
+    }
+
+    /// Whether any pattern names `method`
+    pub(crate) fn contains(&self, method: &str) -> bool {
+        self.0
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => method.starts_with(prefix),
+                None => pattern == method,
+            })
+    }
+}
+
+/// Which methods each peer may call
+///
+/// A peer may call a method if a pattern given to [`allow_everyone`], to
+/// [`allow`] for a block containing the peer, to [`allow_identity`] for the
+/// identity it authenticated as or, for a Unix socket peer, to
+/// [`allow_unix`] or [`allow_uid`] names it. Everything else is denied, so
+/// an empty ACL denies every call.
+///
+/// [`allow_everyone`]: MethodAcl::allow_everyone
+/// [`allow`]: MethodAcl::allow
+/// [`allow_identity`]: MethodAcl::allow_identity
+/// [`allow_unix`]: MethodAcl::allow_unix
+/// [`allow_uid`]: MethodAcl::allow_uid
+#[derive(Debug, Clone, Default)]
+pub struct MethodAcl {
+    everyone: MethodSet,
+    rules: Vec<(Cidr, MethodSet)>,
+    identities: Vec<(String, MethodSet)>,
+    unix: MethodSet,
+    uids: Vec<(u32, MethodSet)>,
+}
+
+impl MethodAcl {
+    /// Deny every call
+    pub fn new() -> Self {
+        MethodAcl::default()
+    }
+
+    /// Let every peer call the methods matching `patterns`
+    pub fn allow_everyone<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.everyone.extend(patterns);
+        self
+    }
+
+    /// Let peers in `block` call the methods matching `patterns`
+    pub fn allow<I, S>(mut self, block: Cidr, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut methods = MethodSet::default();
+        methods.extend(patterns);
+        self.rules.push((block, methods));
+        self
+    }
+
+    /// Let peers authenticated as `name` call the methods matching `patterns`
+    ///
+    /// The name is compared with [`Identity::name`]. Peers on a server
+    /// without an authenticator have no identity and match no name.
+    pub fn allow_identity<I, S>(mut self, name: impl Into<String>, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut methods = MethodSet::default();
+        methods.extend(patterns);
+        self.identities.push((name.into(), methods));
+        self
+    }
+
+    /// Let every peer on a Unix socket call the methods matching `patterns`
+    pub fn allow_unix<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.unix.extend(patterns);
+        self
+    }
+
+    /// Let Unix socket peers running as user `uid` call the methods matching
+    /// `patterns`
+    ///
+    /// Peers whose credentials the kernel does not report match no uid.
+    pub fn allow_uid<I, S>(mut self, uid: u32, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut methods = MethodSet::default();
+        methods.extend(patterns);
+        self.uids.push((uid, methods));
+        self
+    }
+
+    /// The methods `peer`, authenticated as `identity`, may call
+    pub(crate) fn for_peer(&self, peer: &Peer, identity: Option<&Identity>) -> MethodSet {
+        let mut methods = self.everyone.clone();
+        if let Some(identity) = identity {
+            for (name, allowed) in &self.identities {
+                if name == identity.name() {
+                    methods.extend(allowed.0.iter().cloned());
+                }
+            }
+        }
+        match peer {
+            Peer::Tcp(addr) => {
+                for (block, allowed) in &self.rules {
+                    if block.contains(addr.ip()) {
+                        methods.extend(allowed.0.iter().cloned());
+                    }
+                }
+            }
+            Peer::Unix(credentials) => {
+                methods.extend(self.unix.0.iter().cloned());
+                let uid = credentials.map(|credentials| credentials.uid);
+                for (rule_uid, allowed) in &self.uids {
+                    if uid == Some(*rule_uid) {
+                        methods.extend(allowed.0.iter().cloned());
+                    }
+                }
+            }
+        }
+        methods
+    }
+
+    /// Whether an unauthenticated peer at `ip` may call `method`
+    pub fn permits(&self, ip: IpAddr, method: &str) -> bool {
+        self.permits_peer(&Peer::Tcp((ip, 0).into()), method)
+    }
+
+    /// Whether unauthenticated `peer` may call `method`
+    pub fn permits_peer(&self, peer: &Peer, method: &str) -> bool {
+        self.for_peer(peer, None).contains(method)
+    }
+
+    /// Whether `peer`, authenticated as `identity`, may call `method`
+    pub fn permits_identity(&self, peer: &Peer, identity: &Identity, method: &str) -> bool {
+        self.for_peer(peer, Some(identity)).contains(method)
+    }
+}
+
+/// The error a call denied by an ACL fails with
+pub(crate) fn denied(method: &str) -> ERPCError {
+    ERPCError::PermissionDenied(format!("method {} is not allowed for this peer", method))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_method_patterns() {
+        let mut methods = MethodSet::default();
+        methods.extend(["echo", "db-*"]);
+        assert!(methods.contains("echo"));
+        assert!(!methods.contains("echo2"));
+        assert!(methods.contains("db-get"));
+        assert!(methods.contains("db-"));
+        assert!(!methods.contains("dbx"));
+
+        methods.extend(["*"]);
+        assert!(methods.contains("anything"));
+        assert!(!MethodSet::default().contains("echo"));
+    }
+
+    #[test]
+    fn test_rules_per_peer() {
+        let acl = MethodAcl::new()
+            .allow_everyone(["get-*"])
+            .allow("10.1.0.0/16".parse().unwrap(), ["set-*"])
+            .allow("10.1.2.3".parse().unwrap(), ["admin-reset"]);
+
+        assert!(acl.permits(ip("192.0.2.1"), "get-user"));
+        assert!(!acl.permits(ip("192.0.2.1"), "set-user"));
+        assert!(acl.permits(ip("10.1.9.9"), "set-user"));
+        assert!(!acl.permits(ip("10.1.9.9"), "admin-reset"));
+        // Rules for every matching block add up
+        assert!(acl.permits(ip("10.1.2.3"), "admin-reset"));
+        assert!(acl.permits(ip("::ffff:10.1.2.3"), "set-user"));
+
+        assert!(!MethodAcl::new().permits(ip("127.0.0.1"), "echo"));
+    }
+
+    #[test]
+    fn test_unix_peers_match_no_block() {
+        use crate::context::PeerCredentials;
+
+        let unix = |uid| {
+            Peer::Unix(Some(PeerCredentials {
+                uid,
+                gid: uid,
+                pid: None,
+            }))
+        };
+        let acl = MethodAcl::new()
+            .allow_everyone(["get-*"])
+            .allow("127.0.0.0/8".parse().unwrap(), ["*"])
+            .allow("0.0.0.0/0".parse().unwrap(), ["set-*"]);
+        assert!(acl.permits_peer(&unix(1000), "get-user"));
+        assert!(!acl.permits_peer(&unix(1000), "set-user"));
+        assert!(!acl.permits_peer(&Peer::Unix(None), "delete-user"));
+
+        let acl = acl.allow_unix(["list-*"]).allow_uid(0, ["admin-*"]);
+        assert!(acl.permits_peer(&unix(1000), "list-users"));
+        assert!(!acl.permits_peer(&unix(1000), "admin-reset"));
+        assert!(acl.permits_peer(&unix(0), "admin-reset"));
+        assert!(!acl.permits_peer(&Peer::Unix(None), "admin-reset"));
+        assert!(!acl.permits(ip("10.0.0.1"), "list-users"));
+    }
+
+    #[test]
+    fn test_identity_rules() {
+        let peer = Peer::Tcp("192.0.2.1:4000".parse().unwrap());
+        let acl = MethodAcl::new()
+            .allow_everyone(["get-*"])
+            .allow_identity("admin", ["*"])
+            .allow_identity("writer", ["set-*"]);
+
+        assert!(acl.permits_identity(&peer, &Identity::new("admin"), "delete-user"));
+        assert!(acl.permits_identity(&peer, &Identity::new("writer"), "set-user"));
+        assert!(acl.permits_identity(&peer, &Identity::new("writer"), "get-user"));
+        assert!(!acl.permits_identity(&peer, &Identity::new("writer"), "delete-user"));
+        assert!(!acl.permits_identity(&peer, &Identity::new("Admin"), "delete-user"));
+        assert!(!acl.permits_peer(&peer, "set-user"));
+    }
+}
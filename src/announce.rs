@@ -0,0 +1,164 @@
+//! Telling the parent process which port the server listens on
+//!
+//! EPC peers expect the port as the first line of stdout. Servers that use
+//! stdout for other output can announce it through a file, an inherited file
+//! descriptor or a callback instead; the parent must then use the matching
+//! [`PortHandshake`](crate::process::PortHandshake).
+
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::error::ERPCError;
+use crate::log::debug;
+
+/// Environment variable used by [`PortAnnouncer::fd`]
+pub const PORT_FD_ENV: &str = "ELRPC_PORT_FD";
+
+/// Environment variable used by [`PortAnnouncer::env_file`]
+pub const PORT_FILE_ENV: &str = "ELRPC_PORT_FILE";
+
+/// Environment variable naming the listening socket a server inherits, read
+/// by [`Server::bind_inherited`](crate::server::Server::bind_inherited)
+pub const LISTEN_FD_ENV: &str = "ELRPC_LISTEN_FD";
+
+/// Callback receiving the bound port
+pub type PortCallback = dyn Fn(u16) -> std::result::Result<(), ERPCError> + Send + Sync;
+
+/// Where a server announces its port
+#[derive(Clone, Default)]
+pub enum PortAnnouncer {
+    /// Print the port as a single flushed line to stdout (the EPC convention)
+    #[default]
+    Stdout,
+    /// Write the port, followed by a newline, to this file
+    File(PathBuf),
+    /// Write the port to the file named by the environment variable `env`
+    EnvFile { env: String },
+    /// Write the port to the descriptor whose number is in the environment
+    /// variable `env`, then close it
+    #[cfg(unix)]
+    Fd { env: String },
+    /// Hand the port to a callback
+    Callback(Arc<PortCallback>),
+}
+
+impl fmt::Debug for PortAnnouncer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortAnnouncer::Stdout => f.write_str("Stdout"),
+            PortAnnouncer::File(path) => f.debug_tuple("File").field(path).finish(),
+            PortAnnouncer::EnvFile { env } => f.debug_struct("EnvFile").field("env", env).finish(),
+            #[cfg(unix)]
+            PortAnnouncer::Fd { env } => f.debug_struct("Fd").field("env", env).finish(),
+            PortAnnouncer::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+impl PortAnnouncer {
+    /// Port file named by [`PORT_FILE_ENV`], matching [`PortHandshake::temp_file`](crate::process::PortHandshake::temp_file)
+    pub fn env_file() -> Self {
+        PortAnnouncer::EnvFile {
+            env: PORT_FILE_ENV.to_string(),
+        }
+    }
+
+    /// Descriptor named by [`PORT_FD_ENV`]
+    #[cfg(unix)]
+    pub fn fd() -> Self {
+        PortAnnouncer::Fd {
+            env: PORT_FD_ENV.to_string(),
+        }
+    }
+
+    /// Announce through `callback`
+    pub fn callback(
+        callback: impl Fn(u16) -> std::result::Result<(), ERPCError> + Send + Sync + 'static,
+    ) -> Self {
+        PortAnnouncer::Callback(Arc::new(callback))
+    }
+
+    /// Announce `port`
+    pub fn announce(&self, port: u16) -> std::result::Result<(), ERPCError> {
+        debug!("Announcing port {} via {:?}", port, self);
+        let line = format!("{}\n", port);
+
+        match self {
+            PortAnnouncer::Stdout => {
+                // One write under the lock, so concurrent output cannot split the line
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(line.as_bytes())?;
+                stdout.flush()?;
+                Ok(())
+            }
+            PortAnnouncer::File(path) => write_port_file(path, &line),
+            PortAnnouncer::EnvFile { env } => {
+                let path = std::env::var_os(env)
+                    .ok_or_else(|| ERPCError::ProtocolError(format!("{} is not set", env)))?;
+                write_port_file(Path::new(&path), &line)
+            }
+            #[cfg(unix)]
+            PortAnnouncer::Fd { env } => {
+                use std::os::fd::FromRawFd;
+
+                let fd: i32 = std::env::var(env)
+                    .ok()
+                    .and_then(|fd| fd.parse().ok())
+                    .ok_or_else(|| {
+                        ERPCError::ProtocolError(format!("{} does not name a file descriptor", env))
+                    })?;
+                // SAFETY: the parent hands this descriptor to us; it is closed on drop
+                let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+                file.write_all(line.as_bytes())?;
+                Ok(())
+            }
+            PortAnnouncer::Callback(callback) => callback(port),
+        }
+    }
+}
+
+/// Write the port line to a temporary sibling and rename it into place, so
+/// readers never observe a partial line
+fn write_port_file(path: &Path, line: &str) -> std::result::Result<(), ERPCError> {
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".partial");
+    std::fs::write(&partial, line)?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    #[test]
+    fn test_file_announcer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("port");
+
+        PortAnnouncer::File(path.clone()).announce(4242).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "4242\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_callback_announcer() {
+        let seen = Arc::new(AtomicU16::new(0));
+        let port = seen.clone();
+        let announcer = PortAnnouncer::callback(move |p| {
+            port.store(p, Ordering::SeqCst);
+            Ok(())
+        });
+
+        announcer.announce(4242).unwrap();
+        assert_eq!(seen.load(Ordering::SeqCst), 4242);
+        assert!(PortAnnouncer::EnvFile {
+            env: "ELRPC_TEST_UNSET_PORT_FILE".to_string()
+        }
+        .announce(4242)
+        .is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! proptest strategies for values and messages
+//!
+//! [`values`] generates the S-expressions EPC peers exchange: `()`, integers,
+//! finite floats, strings, symbols and keywords, nested in lists, vectors and
+//! dotted pairs. [`messages`] wraps such values in calls, returns and errors.
+//! Both are bounded by [`ValueParams`], and [`Message`] (and `EpcValue` with
+//! the `json` feature) implement [`Arbitrary`] with them:
+//!
+//! ```no_run
+//! use elrpc::Message;
+//! use proptest::prelude::*;
+//!
+//! proptest!(|(message: Message)| {
+//!     let uid = message.uid();
+//!     prop_assert_eq!(Message::from_sexp(&message.to_sexp()?)?.uid(), uid);
+//! });
+//! ```
+//!
+//! Values the reader produces from other syntax, booleans and characters
+//! among them, are left out, as epc.el never sends them.
+
+use lexpr::Value;
+use proptest::prelude::*;
+
+use crate::protocol::Message;
+use crate::uid::MAX_UID;
+
+/// Bounds of generated values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueParams {
+    /// Deepest nesting of lists, vectors and pairs
+    pub depth: u32,
+    /// Rough upper bound on the number of atoms in a value
+    pub size: u32,
+    /// Most elements of one list or vector
+    pub branch: u32,
+    /// Longest string, symbol or keyword, in characters
+    pub text_len: usize,
+}
+
+impl Default for ValueParams {
+    fn default() -> Self {
+        ValueParams {
+            depth: 4,
+            size: 64,
+            branch: 8,
+            text_len: 24,
+        }
+    }
+}
+
+impl ValueParams {
+    /// Atoms only, no nesting
+    pub fn flat() -> Self {
+        ValueParams {
+            depth: 0,
+            ..ValueParams::default()
+        }
+    }
+}
+
+/// Strings matching `pattern`
+fn matching(pattern: &str) -> BoxedStrategy<String> {
+    proptest::string::string_regex(pattern)
+        .expect("generated patterns are valid")
+        .boxed()
+}
+
+/// Printable text of at most `max_len` characters
+fn text(max_len: usize) -> BoxedStrategy<String> {
+    matching(&format!("\\PC{{0,{}}}", max_len))
+}
+
+/// Symbol names: lowercase words joined by dashes, like most Elisp names
+fn name(max_len: usize) -> BoxedStrategy<String> {
+    matching(&format!(
+        "[a-z][a-z0-9-]{{0,{}}}",
+        max_len.saturating_sub(1)
+    ))
+}
+
+/// Atoms EPC peers exchange
+fn atoms(params: ValueParams) -> BoxedStrategy<Value> {
+    prop_oneof![
+        Just(Value::Null),
+        any::<i64>().prop_map(Value::from),
+        any::<f64>()
+            .prop_filter("finite", |f| f.is_finite())
+            .prop_map(Value::from),
+        text(params.text_len).prop_map(Value::string),
+        name(params.text_len).prop_map(Value::symbol),
+        name(params.text_len).prop_map(Value::keyword),
+    ]
+    .boxed()
+}
+
+/// Values within `params`
+pub fn values(params: ValueParams) -> BoxedStrategy<Value> {
+    let branch = params.branch as usize;
+    atoms(params)
+        .prop_recursive(params.depth, params.size, params.branch, move |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..=branch).prop_map(Value::list),
+                prop::collection::vec(inner.clone(), 0..=branch).prop_map(Value::vector),
+                (inner.clone(), inner).prop_map(|(car, cdr)| Value::cons(car, cdr)),
+            ]
+        })
+        .boxed()
+}
+
+/// Calls, returns, errors and method queries carrying values within
+/// `params`
+///
+/// Extension messages are not generated.
+pub fn messages(params: ValueParams) -> BoxedStrategy<Message> {
+    let uid = 1..=MAX_UID;
+    let branch = params.branch as usize;
+    let args = prop::collection::vec(values(params), 0..=branch).prop_map(Value::list);
+    prop_oneof![
+        (uid.clone(), name(params.text_len), args)
+            .prop_map(|(uid, method, args)| Message::new_call(uid, method, args)),
+        (uid.clone(), values(params)).prop_map(|(uid, result)| Message::new_return(uid, result)),
+        (uid.clone(), text(params.text_len))
+            .prop_map(|(uid, error)| Message::new_return_error(uid, error)),
+        (uid.clone(), text(params.text_len))
+            .prop_map(|(uid, error)| Message::new_epc_error(uid, error)),
+        uid.prop_map(Message::new_methods),
+    ]
+    .boxed()
+}
+
+impl Arbitrary for Message {
+    type Parameters = ValueParams;
+    type Strategy = BoxedStrategy<Message>;
+
+    fn arbitrary_with(params: ValueParams) -> Self::Strategy {
+        messages(params)
+    }
+}
+
+#[cfg(feature = "json")]
+impl Arbitrary for crate::json::EpcValue {
+    type Parameters = ValueParams;
+    type Strategy = BoxedStrategy<crate::json::EpcValue>;
+
+    fn arbitrary_with(params: ValueParams) -> Self::Strategy {
+        values(params).prop_map(crate::json::EpcValue).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn test_messages_roundtrip(message in messages(ValueParams::default())) {
+            let text = message.to_sexp().unwrap();
+            prop_assert_eq!(Message::from_sexp(&text).unwrap(), message);
+        }
+    }
+}
@@ -0,0 +1,608 @@
+//! Arena-backed S-expression values scoped to a single request
+//!
+//! `ValueArena` parses a frame into one flat vector of nodes that borrow
+//! from the frame text wherever possible. The whole tree is released with a
+//! single deallocation when the arena is dropped, instead of one drop per
+//! `lexpr::Value` node, which matters for servers handling large frames.
+
+use std::borrow::Cow;
+
+use lexpr::Value;
+
+use crate::error::ERPCError;
+
+/// Maximum list nesting accepted by the arena parser
+const MAX_DEPTH: usize = 512;
+
+type NodeId = u32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node<'a> {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    Char(char),
+    String(Cow<'a, str>),
+    Symbol(Cow<'a, str>),
+    Keyword(&'a str),
+    List {
+        start: u32,
+        len: u32,
+        tail: Option<NodeId>,
+    },
+    Vector {
+        start: u32,
+        len: u32,
+    },
+}
+
+/// Flat storage for one parsed S-expression
+#[derive(Debug, Default)]
+pub struct ValueArena<'a> {
+    nodes: Vec<Node<'a>>,
+    children: Vec<NodeId>,
+    root: NodeId,
+}
+
+impl<'a> ValueArena<'a> {
+    /// Parse a single S-expression from `text` into a new arena
+    pub fn parse(text: &'a str) -> std::result::Result<Self, ERPCError> {
+        let mut arena = ValueArena::default();
+        let mut parser = Parser {
+            text,
+            pos: 0,
+            stack: Vec::new(),
+        };
+
+        parser.skip_whitespace();
+        arena.root = parser.parse_value(&mut arena, 0)?;
+        parser.skip_whitespace();
+        if parser.pos != text.len() {
+            return Err(parser.error("trailing characters after expression"));
+        }
+
+        Ok(arena)
+    }
+
+    /// The top-level value
+    pub fn root(&self) -> ArenaValue<'_> {
+        ArenaValue {
+            arena: self,
+            id: self.root,
+        }
+    }
+
+    /// Number of nodes allocated in the arena
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn push(&mut self, node: Node<'a>) -> NodeId {
+        self.nodes.push(node);
+        (self.nodes.len() - 1) as NodeId
+    }
+}
+
+/// A borrowed view of one value inside a [`ValueArena`]
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaValue<'v> {
+    arena: &'v ValueArena<'v>,
+    id: NodeId,
+}
+
+impl<'v> ArenaValue<'v> {
+    fn node(&self) -> &'v Node<'v> {
+        &self.arena.nodes[self.id as usize]
+    }
+
+    fn child_ids(&self) -> &'v [NodeId] {
+        match *self.node() {
+            Node::List { start, len, .. } | Node::Vector { start, len } => {
+                &self.arena.children[start as usize..(start + len) as usize]
+            }
+            _ => &[],
+        }
+    }
+
+    /// Whether this is the empty list `()`
+    pub fn is_null(&self) -> bool {
+        matches!(self.node(), Node::Null)
+    }
+
+    /// Whether this is `()` or the symbol `nil`
+    pub fn is_nil(&self) -> bool {
+        self.is_null() || self.as_symbol() == Some("nil")
+    }
+
+    /// Whether this is a non-empty list
+    pub fn is_list(&self) -> bool {
+        matches!(self.node(), Node::List { .. })
+    }
+
+    /// Whether this is a vector
+    pub fn is_vector(&self) -> bool {
+        matches!(self.node(), Node::Vector { .. })
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.node() {
+            Node::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self.node() {
+            Node::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_i64().and_then(|n| u64::try_from(n).ok())
+    }
+
+    /// Numeric value as a float (integers are converted)
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.node() {
+            Node::Float(f) => Some(*f),
+            Node::Integer(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(&self) -> Option<char> {
+        match self.node() {
+            Node::Char(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'v str> {
+        match self.node() {
+            Node::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_symbol(&self) -> Option<&'v str> {
+        match self.node() {
+            Node::Symbol(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_keyword(&self) -> Option<&'v str> {
+        match self.node() {
+            Node::Keyword(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Number of elements of a list or vector (0 for atoms)
+    pub fn len(&self) -> usize {
+        self.child_ids().len()
+    }
+
+    /// Whether this value has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Element `index` of a list or vector
+    pub fn get(&self, index: usize) -> Option<ArenaValue<'v>> {
+        self.child_ids().get(index).map(|&id| ArenaValue {
+            arena: self.arena,
+            id,
+        })
+    }
+
+    /// Iterate over the elements of a list or vector
+    pub fn iter(&self) -> impl Iterator<Item = ArenaValue<'v>> + 'v {
+        let arena = self.arena;
+        self.child_ids()
+            .iter()
+            .map(move |&id| ArenaValue { arena, id })
+    }
+
+    /// The tail of a dotted list such as `(a b . c)`
+    pub fn tail(&self) -> Option<ArenaValue<'v>> {
+        match *self.node() {
+            Node::List { tail: Some(id), .. } => Some(ArenaValue {
+                arena: self.arena,
+                id,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Copy this value into an owned `lexpr::Value`
+    pub fn to_value(&self) -> Value {
+        match self.node() {
+            Node::Null => Value::Null,
+            Node::Bool(b) => Value::Bool(*b),
+            Node::Integer(n) => Value::from(*n),
+            Node::Float(f) => Value::from(*f),
+            Node::Char(c) => Value::Char(*c),
+            Node::String(s) => Value::string(s.as_ref()),
+            Node::Symbol(s) => Value::symbol(s.as_ref()),
+            Node::Keyword(s) => Value::keyword(*s),
+            Node::List { .. } => {
+                let items: Vec<Value> = self.iter().map(|v| v.to_value()).collect();
+                match self.tail() {
+                    Some(tail) => Value::append(items, tail.to_value()),
+                    None => Value::list(items),
+                }
+            }
+            Node::Vector { .. } => Value::vector(self.iter().map(|v| v.to_value())),
+        }
+    }
+}
+
+/// Recursive-descent parser producing arena nodes
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    /// Scratch stack of child ids shared by all nesting levels
+    stack: Vec<NodeId>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, what: &str) -> ERPCError {
+        ERPCError::InvalidMessageFormat(format!("{} at byte {}", what, self.pos))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b) = self.peek() {
+            match b {
+                b' ' | b'\t' | b'\n' | b'\r' | b'\x0c' => self.pos += 1,
+                b';' => {
+                    while !matches!(self.peek(), None | Some(b'\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_value(
+        &mut self,
+        arena: &mut ValueArena<'a>,
+        depth: usize,
+    ) -> std::result::Result<NodeId, ERPCError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("expression nested too deeply"));
+        }
+
+        match self.peek() {
+            None => Err(self.error("unexpected end of input")),
+            Some(b'(') => {
+                self.pos += 1;
+                self.parse_list(arena, depth)
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                self.parse_vector(arena, depth, b']')
+            }
+            Some(b'#') if self.text[self.pos..].starts_with("#(") => {
+                self.pos += 2;
+                self.parse_vector(arena, depth, b')')
+            }
+            Some(b'"') => {
+                self.pos += 1;
+                let s = self.parse_string()?;
+                Ok(arena.push(Node::String(s)))
+            }
+            Some(b'?') => {
+                self.pos += 1;
+                let c = self.parse_char()?;
+                Ok(arena.push(Node::Char(c)))
+            }
+            Some(b'\'') => {
+                self.pos += 1;
+                self.skip_whitespace();
+                let quoted = self.parse_value(arena, depth + 1)?;
+                let quote = arena.push(Node::Symbol(Cow::Borrowed("quote")));
+                let start = arena.children.len() as u32;
+                arena.children.extend([quote, quoted]);
+                Ok(arena.push(Node::List {
+                    start,
+                    len: 2,
+                    tail: None,
+                }))
+            }
+            Some(b')') | Some(b']') => Err(self.error("unexpected closing delimiter")),
+            Some(_) => self.parse_atom(arena),
+        }
+    }
+
+    fn parse_list(
+        &mut self,
+        arena: &mut ValueArena<'a>,
+        depth: usize,
+    ) -> std::result::Result<NodeId, ERPCError> {
+        let base = self.stack.len();
+        let mut tail = None;
+
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => return Err(self.error("unterminated list")),
+                Some(b')') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'.') if self.stack.len() > base && self.is_lone_dot() => {
+                    self.pos += 1;
+                    self.skip_whitespace();
+                    tail = Some(self.parse_value(arena, depth + 1)?);
+                    self.skip_whitespace();
+                    if self.peek() != Some(b')') {
+                        return Err(self.error("expected ')' after dotted tail"));
+                    }
+                    self.pos += 1;
+                    break;
+                }
+                Some(_) => {
+                    let child = self.parse_value(arena, depth + 1)?;
+                    self.stack.push(child);
+                }
+            }
+        }
+
+        if self.stack.len() == base && tail.is_none() {
+            return Ok(arena.push(Node::Null));
+        }
+
+        let start = arena.children.len() as u32;
+        let len = (self.stack.len() - base) as u32;
+        arena.children.extend(self.stack.drain(base..));
+        Ok(arena.push(Node::List { start, len, tail }))
+    }
+
+    fn parse_vector(
+        &mut self,
+        arena: &mut ValueArena<'a>,
+        depth: usize,
+        close: u8,
+    ) -> std::result::Result<NodeId, ERPCError> {
+        let base = self.stack.len();
+
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => return Err(self.error("unterminated vector")),
+                Some(b) if b == close => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(_) => {
+                    let child = self.parse_value(arena, depth + 1)?;
+                    self.stack.push(child);
+                }
+            }
+        }
+
+        let start = arena.children.len() as u32;
+        let len = (self.stack.len() - base) as u32;
+        arena.children.extend(self.stack.drain(base..));
+        Ok(arena.push(Node::Vector { start, len }))
+    }
+
+    /// Whether the `.` at the cursor is a dotted-pair separator
+    fn is_lone_dot(&self) -> bool {
+        matches!(
+            self.text.as_bytes().get(self.pos + 1),
+            None | Some(b' ' | b'\t' | b'\n' | b'\r' | b'(' | b')' | b'"')
+        )
+    }
+
+    fn parse_string(&mut self) -> std::result::Result<Cow<'a, str>, ERPCError> {
+        let start = self.pos;
+        let bytes = self.text.as_bytes();
+
+        // Fast path: borrow the text when the string has no escapes
+        while let Some(&b) = bytes.get(self.pos) {
+            match b {
+                b'"' => {
+                    let s = &self.text[start..self.pos];
+                    self.pos += 1;
+                    return Ok(Cow::Borrowed(s));
+                }
+                b'\\' => break,
+                _ => self.pos += 1,
+            }
+        }
+
+        let mut owned = String::from(&self.text[start..self.pos]);
+        loop {
+            let c = self.text[self.pos..]
+                .chars()
+                .next()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(Cow::Owned(owned)),
+                '\\' => {
+                    if let Some(escaped) = self.parse_escape()? {
+                        owned.push(escaped);
+                    }
+                }
+                c => owned.push(c),
+            }
+        }
+    }
+
+    /// Parse the character after a backslash; `None` for a line continuation
+    fn parse_escape(&mut self) -> std::result::Result<Option<char>, ERPCError> {
+        let c = self.text[self.pos..]
+            .chars()
+            .next()
+            .ok_or_else(|| self.error("unterminated escape"))?;
+        self.pos += c.len_utf8();
+        Ok(match c {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            'e' => Some('\x1b'),
+            'a' => Some('\x07'),
+            'f' => Some('\x0c'),
+            's' => Some(' '),
+            '\n' | ' ' => None,
+            'x' | 'u' | 'U' => {
+                let digits: String = self.text[self.pos..]
+                    .chars()
+                    .take_while(|c| c.is_ascii_hexdigit())
+                    .collect();
+                self.pos += digits.len();
+                // A trailing backslash-space terminates an \x escape in elisp
+                if self.text[self.pos..].starts_with("\\ ") {
+                    self.pos += 2;
+                }
+                let code = u32::from_str_radix(&digits, 16)
+                    .map_err(|_| self.error("invalid hex escape"))?;
+                Some(char::from_u32(code).ok_or_else(|| self.error("invalid code point"))?)
+            }
+            other => Some(other),
+        })
+    }
+
+    fn parse_char(&mut self) -> std::result::Result<char, ERPCError> {
+        let c = self.text[self.pos..]
+            .chars()
+            .next()
+            .ok_or_else(|| self.error("unterminated character"))?;
+        self.pos += c.len_utf8();
+        if c == '\\' {
+            self.parse_escape()?
+                .ok_or_else(|| self.error("invalid character escape"))
+        } else {
+            Ok(c)
+        }
+    }
+
+    fn parse_atom(&mut self, arena: &mut ValueArena<'a>) -> std::result::Result<NodeId, ERPCError> {
+        let start = self.pos;
+        let mut escaped = false;
+
+        while let Some(b) = self.peek() {
+            match b {
+                b' ' | b'\t' | b'\n' | b'\r' | b'(' | b')' | b'[' | b']' | b'"' | b';' => break,
+                b'\\' => {
+                    escaped = true;
+                    self.pos += 2.min(self.text.len() - self.pos);
+                }
+                _ => self.pos += 1,
+            }
+        }
+
+        let token = &self.text[start..self.pos];
+        if token.is_empty() || !self.text.is_char_boundary(self.pos) {
+            return Err(self.error("invalid token"));
+        }
+
+        let node = if escaped {
+            Node::Symbol(Cow::Owned(token.replace('\\', "")))
+        } else if token == "#t" {
+            Node::Bool(true)
+        } else if token == "#f" {
+            Node::Bool(false)
+        } else if let Some(name) = token.strip_prefix(':') {
+            Node::Keyword(name)
+        } else if let Some(n) = parse_integer(token) {
+            Node::Integer(n)
+        } else if let Some(f) = parse_float(token) {
+            Node::Float(f)
+        } else {
+            Node::Symbol(Cow::Borrowed(token))
+        };
+
+        Ok(arena.push(node))
+    }
+}
+
+fn parse_integer(token: &str) -> Option<i64> {
+    let digits = token.strip_prefix(['+', '-']).unwrap_or(token);
+    let digits = digits.strip_suffix('.').unwrap_or(digits);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.trim_end_matches('.').parse().ok()
+}
+
+fn parse_float(token: &str) -> Option<f64> {
+    let starts_numeric = token
+        .trim_start_matches(['+', '-'])
+        .starts_with(|c: char| c.is_ascii_digit() || c == '.');
+    if !starts_numeric {
+        return None;
+    }
+    token.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_call() {
+        let arena = ValueArena::parse("(call 12 echo (\"hi\" 42 1.5 :key sym))").unwrap();
+        let root = arena.root();
+
+        assert_eq!(root.len(), 4);
+        assert_eq!(root.get(0).unwrap().as_symbol(), Some("call"));
+        assert_eq!(root.get(1).unwrap().as_u64(), Some(12));
+        assert_eq!(root.get(2).unwrap().as_symbol(), Some("echo"));
+
+        let args: Vec<_> = root.get(3).unwrap().iter().collect();
+        assert_eq!(args[0].as_str(), Some("hi"));
+        assert_eq!(args[1].as_i64(), Some(42));
+        assert_eq!(args[2].as_f64(), Some(1.5));
+        assert_eq!(args[3].as_keyword(), Some("key"));
+        assert_eq!(args[4].as_symbol(), Some("sym"));
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let arena = ValueArena::parse(r#""a\"b\nc\\""#).unwrap();
+        assert_eq!(arena.root().as_str(), Some("a\"b\nc\\"));
+
+        let arena = ValueArena::parse("\"plain\"").unwrap();
+        assert!(matches!(
+            arena.nodes[0],
+            Node::String(Cow::Borrowed("plain"))
+        ));
+    }
+
+    #[test]
+    fn test_dotted_pairs_vectors_and_nil() {
+        let arena = ValueArena::parse("((name . \"Alice\") [1 2] () nil ?a)").unwrap();
+        let root = arena.root();
+
+        let pair = root.get(0).unwrap();
+        assert_eq!(pair.get(0).unwrap().as_symbol(), Some("name"));
+        assert_eq!(pair.tail().unwrap().as_str(), Some("Alice"));
+
+        let vector = root.get(1).unwrap();
+        assert!(vector.is_vector());
+        assert_eq!(vector.len(), 2);
+
+        assert!(root.get(2).unwrap().is_null());
+        assert!(root.get(3).unwrap().is_nil());
+        assert_eq!(root.get(4).unwrap().as_char(), Some('a'));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(ValueArena::parse("(call 1").is_err());
+        assert!(ValueArena::parse("(a) b").is_err());
+        assert!(ValueArena::parse(")").is_err());
+        assert!(ValueArena::parse(&"(".repeat(MAX_DEPTH + 2)).is_err());
+    }
+}
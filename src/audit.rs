@@ -0,0 +1,345 @@
+//! Append-only audit log of calls
+//!
+//! Where the request log is for debugging and goes through `tracing`, an
+//! [`AuditLog`] keeps a durable record for compliance: one line per call with
+//! when it happened, the peer, the method, the outcome and, depending on
+//! [`AuditArgs`], a hash or a redacted copy of the arguments. Calls refused
+//! by an ACL or quota are recorded too.
+//!
+//! ```no_run
+//! use elrpc::{AuditArgs, AuditFormat, AuditLog, ServerConfig};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let config = ServerConfig {
+//!     audit_log: Some(
+//!         AuditLog::file("/var/log/elrpc-audit.jsonl", AuditFormat::JsonLines)?
+//!             .args(AuditArgs::Hashed),
+//!     ),
+//!     ..Default::default()
+//! };
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A JSON line looks like
+//!
+//! ```text
+//! {"time":1718000000.123456,"peer":"127.0.0.1:50000","uid":7,"method":"save","outcome":"ok","duration_us":120,"args":"sha256:9f86d0..."}
+//! ```
+//!
+//! and the same record as an S-expression like
+//!
+//! ```text
+//! (:time 1718000000.123456 :peer "127.0.0.1:50000" :uid 7 :method "save" :outcome ok :duration-us 120 :args "sha256:9f86d0...")
+//! ```
+//!
+//! Failed calls have an `error` entry with the error message.
+
+use std::fmt::{self, Write as _};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lexpr::Value;
+use sha2::{Digest, Sha256};
+
+use crate::context::Peer;
+use crate::error::ERPCError;
+use crate::request_log::redact_fields;
+
+/// How audit records are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditFormat {
+    /// One JSON object per line
+    JsonLines,
+    /// One plist per line
+    Sexp,
+}
+
+/// What an audit record says about the arguments of a call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditArgs {
+    /// Leave them out
+    Omit,
+    /// SHA-256 of their printed form, to prove what was sent without
+    /// keeping it
+    Hashed,
+    /// Their printed form with these fields redacted, as in the request log
+    Redacted(Vec<String>),
+}
+
+/// One audited call
+#[derive(Debug, Clone, Copy)]
+pub struct AuditRecord<'a> {
+    pub timestamp: SystemTime,
+    pub peer: Peer,
+    pub uid: u64,
+    pub method: &'a str,
+    /// Arguments as configured by [`AuditArgs`]
+    pub args: Option<&'a str>,
+    /// Handler time; zero for calls that were refused
+    pub duration: Duration,
+    pub outcome: std::result::Result<(), &'a ERPCError>,
+}
+
+impl AuditRecord<'_> {
+    fn time(&self) -> String {
+        let since_epoch = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!(
+            "{}.{:06}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros()
+        )
+    }
+
+    /// The record as a line in `format`, without the newline
+    pub fn render(&self, format: AuditFormat) -> String {
+        let mut line = String::new();
+        let duration_us = self.duration.as_micros() as u64;
+        let outcome = if self.outcome.is_ok() { "ok" } else { "error" };
+        // Writing to a String cannot fail
+        match format {
+            AuditFormat::JsonLines => {
+                let _ = write!(
+                    line,
+                    "{{\"time\":{},\"peer\":{},\"uid\":{},\"method\":{},\"outcome\":\"{}\",\"duration_us\":{}",
+                    self.time(),
+                    json_string(&self.peer.to_string()),
+                    self.uid,
+                    json_string(self.method),
+                    outcome,
+                    duration_us
+                );
+                if let Err(e) = self.outcome {
+                    let _ = write!(line, ",\"error\":{}", json_string(&e.to_string()));
+                }
+                if let Some(args) = self.args {
+                    let _ = write!(line, ",\"args\":{}", json_string(args));
+                }
+                line.push('}');
+            }
+            AuditFormat::Sexp => {
+                let _ = write!(
+                    line,
+                    "(:time {} :peer {} :uid {} :method {} :outcome {} :duration-us {}",
+                    self.time(),
+                    sexp_string(&self.peer.to_string()),
+                    self.uid,
+                    sexp_string(self.method),
+                    outcome,
+                    duration_us
+                );
+                if let Err(e) = self.outcome {
+                    let _ = write!(line, " :error {}", sexp_string(&e.to_string()));
+                }
+                if let Some(args) = self.args {
+                    let _ = write!(line, " :args {}", sexp_string(args));
+                }
+                line.push(')');
+            }
+        }
+        line
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn sexp_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            // Keep each record on one line
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Callback receiving audit records
+pub type AuditFn = dyn Fn(&AuditRecord<'_>) + Send + Sync;
+
+/// Destination for audit records, set on
+/// [`ServerConfig::audit_log`](crate::server::ServerConfig)
+///
+/// The sink runs inline when a call finishes, so it should be quick.
+#[derive(Clone)]
+pub struct AuditLog {
+    sink: Arc<AuditFn>,
+    args: AuditArgs,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("args", &self.args)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Hand every record to `sink`
+    pub fn new(sink: impl Fn(&AuditRecord<'_>) + Send + Sync + 'static) -> Self {
+        AuditLog {
+            sink: Arc::new(sink),
+            args: AuditArgs::Hashed,
+        }
+    }
+
+    /// Write one line per record to `writer`
+    pub fn writer(writer: impl Write + Send + 'static, format: AuditFormat) -> Self {
+        let writer = Mutex::new(writer);
+        AuditLog::new(move |record| {
+            let line = record.render(format);
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            // A failing audit sink must not fail the call; report it instead
+            if let Err(e) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+                crate::log::error!("Failed to write audit record: {}", e);
+            }
+        })
+    }
+
+    /// Append records to the file at `path`, creating it if needed
+    pub fn file(path: impl AsRef<Path>, format: AuditFormat) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog::writer(file, format))
+    }
+
+    /// Choose what records say about arguments; [`AuditArgs::Hashed`] by default
+    pub fn args(mut self, args: AuditArgs) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Arguments of a call as they appear in the record
+    pub(crate) fn render_args(&self, args: &Value) -> Option<String> {
+        match &self.args {
+            AuditArgs::Omit => None,
+            AuditArgs::Hashed => {
+                let digest = Sha256::digest(args.to_string().as_bytes());
+                let mut hashed = String::from("sha256:");
+                for byte in digest {
+                    let _ = write!(hashed, "{:02x}", byte);
+                }
+                Some(hashed)
+            }
+            AuditArgs::Redacted(fields) => {
+                let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+                Some(redact_fields(args, &fields).to_string())
+            }
+        }
+    }
+
+    /// Record one call
+    pub(crate) fn record(
+        &self,
+        peer: Peer,
+        uid: u64,
+        method: &str,
+        args: Option<&str>,
+        duration: Duration,
+        outcome: std::result::Result<(), &ERPCError>,
+    ) {
+        (self.sink)(&AuditRecord {
+            timestamp: SystemTime::now(),
+            peer,
+            uid,
+            method,
+            args,
+            duration,
+            outcome,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record<'a>(outcome: std::result::Result<(), &'a ERPCError>) -> AuditRecord<'a> {
+        AuditRecord {
+            timestamp: UNIX_EPOCH + Duration::from_micros(1_718_000_000_123_456),
+            peer: Peer::Tcp("127.0.0.1:50000".parse().unwrap()),
+            uid: 7,
+            method: "save",
+            args: Some("(\"a\\\"b\")"),
+            duration: Duration::from_micros(120),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_render_formats() {
+        assert_eq!(
+            record(Ok(())).render(AuditFormat::JsonLines),
+            r#"{"time":1718000000.123456,"peer":"127.0.0.1:50000","uid":7,"method":"save","outcome":"ok","duration_us":120,"args":"(\"a\\\"b\")"}"#
+        );
+
+        let error = ERPCError::PermissionDenied("no\nway".to_string());
+        assert_eq!(
+            record(Err(&error)).render(AuditFormat::Sexp),
+            r#"(:time 1718000000.123456 :peer "127.0.0.1:50000" :uid 7 :method "save" :outcome error :duration-us 120 :error "permission denied: no\nway" :args "(\"a\\\"b\")")"#
+        );
+    }
+
+    #[test]
+    fn test_args_and_file_sink() {
+        let args = lexpr::from_str(r#"(:user "me" :password "hunter2")"#).unwrap();
+        let hashed = AuditLog::new(|_| {}).render_args(&args).unwrap();
+        assert!(hashed.starts_with("sha256:"));
+        assert_eq!(hashed.len(), 7 + 64);
+        let redacted = AuditLog::new(|_| {})
+            .args(AuditArgs::Redacted(vec!["password".to_string()]))
+            .render_args(&args)
+            .unwrap();
+        assert!(redacted.contains("\"me\"") && !redacted.contains("hunter2"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        for _ in 0..2 {
+            let log = AuditLog::file(&path, AuditFormat::JsonLines).unwrap();
+            let peer = Peer::Unix(Some(crate::context::PeerCredentials {
+                uid: 1000,
+                gid: 1000,
+                pid: None,
+            }));
+            log.record(peer, 1, "echo", None, Duration::ZERO, Ok(()));
+        }
+        // Reopening appends instead of truncating
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        assert!(written
+            .lines()
+            .all(|line| line.contains("\"peer\":\"unix:uid=1000\",")
+                && line.contains("\"method\":\"echo\"")));
+    }
+}
@@ -0,0 +1,156 @@
+//! Pluggable peer authentication
+//!
+//! An [`Authenticator`] decides whether a new connection may be served. It
+//! sees what the server knows about the peer in a [`Handshake`], and either
+//! accepts it with an [`Identity`] or refuses it with an error, which closes
+//! the connection. The identity is kept for the connection and handlers find
+//! it in [`CallContext::identity`](crate::context::CallContext).
+//!
+//! EPC itself has no handshake. Authenticators that need credentials from
+//! the peer ask for one with [`Authenticator::requires_handshake`]: the peer
+//! must then open with a call to [`AUTH_METHOD`], whose arguments become
+//! [`Handshake::data`]. A [`Client`](crate::client::Client) makes that call
+//! when [`ClientConfig::auth_data`](crate::client::ClientConfig) is set.
+//!
+//! ```
+//! use elrpc::auth::{Authenticator, Handshake, Identity};
+//! use elrpc::ERPCError;
+//!
+//! struct SharedToken(String);
+//!
+//! #[async_trait::async_trait]
+//! impl Authenticator for SharedToken {
+//!     fn requires_handshake(&self) -> bool {
+//!         true
+//!     }
+//!
+//!     async fn authenticate(&self, handshake: &Handshake) -> elrpc::Result<Identity> {
+//!         match handshake.data.as_ref().and_then(|data| data.as_str()) {
+//!             Some(token) if token == self.0 => Ok(Identity::new("token-holder")),
+//!             _ => Err(ERPCError::PermissionDenied("bad token".to_string())),
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! The server has no TLS listener, so there is no TLS identity to inspect;
+//! Unix socket peers come with their kernel-reported credentials.
+
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+use lexpr::Value;
+
+use crate::context::{Peer, PeerCredentials};
+use crate::error::ERPCError;
+
+/// Method a peer calls first when its server's authenticator requires a
+/// handshake
+pub const AUTH_METHOD: &str = "elrpc-authenticate";
+
+/// What the server knows about a connecting peer
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub peer: Peer,
+    /// Credentials of Unix socket peers
+    pub credentials: Option<PeerCredentials>,
+    /// Arguments of the peer's [`AUTH_METHOD`] call, when a handshake is
+    /// required
+    pub data: Option<Value>,
+}
+
+/// Who an authenticated peer is
+///
+/// Besides a name for logs, an identity can carry any value the
+/// authenticator wants handlers to see, such as a user record.
+#[derive(Clone)]
+pub struct Identity {
+    name: String,
+    data: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+impl Identity {
+    /// An identity with a name only
+    pub fn new(name: impl Into<String>) -> Self {
+        Identity {
+            name: name.into(),
+            data: None,
+        }
+    }
+
+    /// Attach `data` for handlers to look up with [`Identity::data`]
+    pub fn with_data<T: Any + Send + Sync>(mut self, data: T) -> Self {
+        self.data = Some(Arc::new(data));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The attached data, if it is a `T`
+    pub fn data<T: Any>(&self) -> Option<&T> {
+        self.data.as_deref()?.downcast_ref()
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Identities are equal when they have the same name and share their data
+impl PartialEq for Identity {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && match (&self.data, &other.data) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+    }
+}
+
+impl Eq for Identity {}
+
+/// Decides which peers a server serves, set on
+/// [`ServerConfig::authenticator`](crate::server::ServerConfig)
+#[async_trait::async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Whether peers must open with an [`AUTH_METHOD`] call
+    fn requires_handshake(&self) -> bool {
+        false
+    }
+
+    /// Accept the peer as an identity, or refuse it with an error
+    async fn authenticate(&self, handshake: &Handshake)
+        -> std::result::Result<Identity, ERPCError>;
+}
+
+impl fmt::Debug for dyn Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator")
+            .field("requires_handshake", &self.requires_handshake())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_data() {
+        let identity = Identity::new("alice").with_data(42u32);
+        assert_eq!(identity.name(), "alice");
+        assert_eq!(identity.data::<u32>(), Some(&42));
+        assert_eq!(identity.data::<String>(), None);
+
+        assert_eq!(identity, identity.clone());
+        assert_ne!(identity, Identity::new("alice").with_data(42u32));
+        assert_eq!(Identity::new("bob"), Identity::new("bob"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Command-line client for poking at EPC servers
+//!
+//! Build with `cargo run --features cli --bin epc-cli -- HOST:PORT COMMAND`.
+
+mod repl;
+
+use std::time::{Duration, Instant};
+
+use elrpc::conformance::Conformance;
+use elrpc::proxy::{Proxy, ProxyConfig};
+use elrpc::{pretty, Client, ERPCError, GoldenRecorder, Result};
+use lexpr::Value;
+
+const USAGE: &str = "\
+Usage: epc-cli HOST:PORT [COMMAND]
+
+Without a command, an interactive session is started.
+
+Commands:
+  methods                  List the methods the server defines
+  call METHOD [ARGS]       Call METHOD with ARGS, one S-expression (default nil)
+  time METHOD [ARGS] [N]   Call METHOD N times (default 10) and report latencies
+  codegen [FILE]           Write a Rust client module for the server's methods
+                           to FILE (default stdout)
+  conformance [ECHO]       Check the server against the protocol, round-tripping
+                           values through method ECHO if given
+  record PEER FILE         Proxy the server and write the first session through
+                           the proxy to FILE as a golden trace of PEER";
+
+fn usage_error(message: &str) -> ERPCError {
+    ERPCError::InvalidArgument(format!("{}\n\n{}", message, USAGE))
+}
+
+fn parse_args(args: Option<&String>) -> Result<Value> {
+    match args {
+        Some(args) => lexpr::from_str(args)
+            .map_err(|e| ERPCError::InvalidArgument(format!("ARGS is not an S-expression: {}", e))),
+        None => Ok(Value::Null),
+    }
+}
+
+pub(crate) async fn list_methods(client: &Client) -> Result<()> {
+    let mut methods = client.query_methods().await?;
+    methods.sort_by(|a, b| a.name.cmp(&b.name));
+    for method in methods {
+        println!(
+            "{} ({})",
+            method.name,
+            method.arg_spec.as_deref().unwrap_or_default()
+        );
+        if let Some(docstring) = method.docstring.filter(|doc| !doc.is_empty()) {
+            println!("    {}", docstring);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn time_calls(
+    client: &Client,
+    method: &str,
+    args: Value,
+    count: usize,
+) -> Result<()> {
+    let mut latencies = Vec::with_capacity(count);
+    for _ in 0..count {
+        let started = Instant::now();
+        client.call_value(method, args.clone()).await?;
+        latencies.push(started.elapsed());
+    }
+    latencies.sort();
+
+    let total: Duration = latencies.iter().sum();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{} calls: min {:?}, avg {:?}, p50 {:?}, p99 {:?}, max {:?}",
+        count,
+        latencies[0],
+        total / count as u32,
+        percentile(50),
+        percentile(99),
+        latencies[count - 1]
+    );
+    Ok(())
+}
+
+async fn run(args: &[String]) -> Result<()> {
+    let Some(addr) = args.first() else {
+        return Err(usage_error("missing HOST:PORT"));
+    };
+    if args.get(1).map(String::as_str) == Some("codegen") {
+        let module = elrpc::codegen::generate_client(addr).await?;
+        return match args.get(2) {
+            Some(path) => std::fs::write(path, module).map_err(ERPCError::Io),
+            None => {
+                print!("{}", module);
+                Ok(())
+            }
+        };
+    }
+
+    if args.get(1).map(String::as_str) == Some("conformance") {
+        let mut conformance = Conformance::new(addr.as_str());
+        if let Some(echo) = args.get(2) {
+            conformance = conformance.echo_method(echo.as_str());
+        }
+        let report = conformance.run().await;
+        print!("{}", report);
+        return match report.failed().count() {
+            0 => Ok(()),
+            failed => Err(ERPCError::ProtocolError(format!(
+                "{} conformance checks failed",
+                failed
+            ))),
+        };
+    }
+
+    if args.get(1).map(String::as_str) == Some("record") {
+        let peer = args.get(2).ok_or_else(|| usage_error("missing PEER"))?;
+        let path = args.get(3).ok_or_else(|| usage_error("missing FILE"))?;
+        return record(addr, peer, path).await;
+    }
+
+    let client = Client::connect(addr.as_str()).await?;
+    let Some(command) = args.get(1) else {
+        repl::run(&client).await?;
+        return client.close().await;
+    };
+
+    match command.as_str() {
+        "methods" => list_methods(&client).await?,
+        "call" => {
+            let method = args.get(2).ok_or_else(|| usage_error("missing METHOD"))?;
+            let result = client.call_value(method, parse_args(args.get(3))?).await?;
+            println!("{}", pretty(&result));
+        }
+        "time" => {
+            let method = args.get(2).ok_or_else(|| usage_error("missing METHOD"))?;
+            let count = match args.get(4) {
+                Some(count) => count
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| usage_error("N must be a positive number"))?,
+                None => 10,
+            };
+            time_calls(&client, method, parse_args(args.get(3))?, count).await?;
+        }
+        other => return Err(usage_error(&format!("unknown command: {}", other))),
+    }
+
+    client.close().await
+}
+
+/// Relay a peer's session to the server at `addr` and save it as a golden
+/// trace once Enter is pressed
+async fn record(addr: &str, peer: &str, path: &str) -> Result<()> {
+    let recorder = GoldenRecorder::new();
+    let config = ProxyConfig::new(addr).wire_tap(recorder.wire_tap());
+    let mut proxy = Proxy::bind("127.0.0.1:0", config).await?;
+    proxy.serve()?;
+    println!(
+        "Recording on port {}; press Enter when {} is done",
+        proxy.port(),
+        peer
+    );
+
+    tokio::task::spawn_blocking(|| std::io::stdin().read_line(&mut String::new()))
+        .await
+        .map_err(|e| ERPCError::ProtocolError(e.to_string()))??;
+    proxy.shutdown().await?;
+
+    let trace = recorder.trace(peer);
+    std::fs::write(path, trace.to_fixture())?;
+    println!("Wrote {} frames to {}", trace.frames.len(), path);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args).await {
+        eprintln!("epc-cli: {}", e);
+        std::process::exit(1);
+    }
+}
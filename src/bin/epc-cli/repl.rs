@@ -0,0 +1,223 @@
+//! Interactive session against one server
+//!
+//! Input is a call written as `(METHOD ARGS...)` or just `METHOD ARGS...`;
+//! unbalanced input continues on the next line. Lines starting with `:` are
+//! REPL commands, see [`HELP`].
+
+use std::path::PathBuf;
+
+use elrpc::{pretty, Client, ERPCError, Result};
+use lexpr::Value;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+
+const HELP: &str = "\
+(METHOD ARGS...)         Call METHOD; the parentheses may be left out
+:methods                 List methods and refresh completion
+:time N (METHOD ARGS...) Call METHOD N times and report latencies
+:help                    Show this help
+:quit                    Leave (or press Ctrl-D)";
+
+const COMMANDS: &[&str] = &[":methods", ":time", ":help", ":quit"];
+
+/// Completion of method names and commands, continuation of unbalanced input
+#[derive(Default)]
+struct ReplHelper {
+    methods: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|c: char| c.is_whitespace() || c == '(')
+            .map_or(0, |i| i + 1);
+        // Only the first word names a method or command
+        if before[..start].trim_start_matches('(').trim() != "" {
+            return Ok((pos, Vec::new()));
+        }
+
+        let word = &before[start..];
+        let candidates = self
+            .methods
+            .iter()
+            .map(String::as_str)
+            .chain(COMMANDS.iter().copied())
+            .filter(|name| name.starts_with(word))
+            .map(String::from)
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(if is_complete(ctx.input()) {
+            ValidationResult::Valid(None)
+        } else {
+            ValidationResult::Incomplete
+        })
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Whether every parenthesis outside strings is closed
+fn is_complete(input: &str) -> bool {
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in input.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0 && !in_string
+}
+
+/// Split a call into its method name and argument list
+fn parse_call(input: &str) -> Result<(String, Value)> {
+    let input = input.trim();
+    let text = if input.starts_with('(') {
+        input.to_string()
+    } else {
+        format!("({})", input)
+    };
+    let call = lexpr::from_str(&text)
+        .map_err(|e| ERPCError::InvalidArgument(format!("not an S-expression: {}", e)))?;
+
+    let Value::Cons(cons) = call else {
+        return Err(ERPCError::InvalidArgument(
+            "expected (METHOD ARGS...)".to_string(),
+        ));
+    };
+    let (method, args) = cons.into_pair();
+    let method = method
+        .as_symbol()
+        .or_else(|| method.as_str())
+        .ok_or_else(|| ERPCError::InvalidArgument("METHOD must be a symbol".to_string()))?
+        .to_string();
+    Ok((method, args))
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".epc_cli_history"))
+}
+
+async fn method_names(client: &Client) -> Result<Vec<String>> {
+    let mut names: Vec<String> = client
+        .query_methods()
+        .await?
+        .into_iter()
+        .map(|method| method.name)
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Handle one complete input, returning false to leave the REPL
+async fn eval(
+    client: &Client,
+    editor: &mut Editor<ReplHelper, rustyline::history::DefaultHistory>,
+    input: &str,
+) -> Result<bool> {
+    let input = input.trim();
+    match input.split_once(char::is_whitespace).unwrap_or((input, "")) {
+        ("", _) => {}
+        (":quit", _) => return Ok(false),
+        (":help", _) => println!("{}", HELP),
+        (":methods", _) => {
+            crate::list_methods(client).await?;
+            if let Some(helper) = editor.helper_mut() {
+                helper.methods = method_names(client).await?;
+            }
+        }
+        (":time", rest) => {
+            let (count, call) = rest.trim().split_once(char::is_whitespace).ok_or_else(|| {
+                ERPCError::InvalidArgument("usage: :time N (METHOD ARGS...)".to_string())
+            })?;
+            let count = count
+                .parse()
+                .ok()
+                .filter(|&count| count > 0)
+                .ok_or_else(|| {
+                    ERPCError::InvalidArgument("N must be a positive number".to_string())
+                })?;
+            let (method, args) = parse_call(call)?;
+            crate::time_calls(client, &method, args, count).await?;
+        }
+        (command, _) if command.starts_with(':') => {
+            return Err(ERPCError::InvalidArgument(format!(
+                "unknown command {}, try :help",
+                command
+            )))
+        }
+        _ => {
+            let (method, args) = parse_call(input)?;
+            let result = client.call_value(&method, args).await?;
+            println!("{}", pretty(&result));
+        }
+    }
+    Ok(true)
+}
+
+/// Read, call and print until the user quits
+pub async fn run(client: &Client) -> Result<()> {
+    let mut editor = Editor::new()
+        .map_err(|e| ERPCError::InvalidArgument(format!("cannot open the terminal: {}", e)))?;
+    editor.set_helper(Some(ReplHelper {
+        methods: method_names(client).await?,
+    }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means a first session
+        let _ = editor.load_history(path);
+    }
+
+    println!("Connected. Type :help for help.");
+    loop {
+        let input = match editor.readline("epc> ") {
+            Ok(input) => input,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(ERPCError::InvalidArgument(e.to_string())),
+        };
+        let _ = editor.add_history_entry(input.as_str());
+
+        match eval(client, &mut editor, &input).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            // Errors are reported and the session continues; only a lost
+            // connection ends it
+            Err(ERPCError::ConnectionClosed) => return Err(ERPCError::ConnectionClosed),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Synchronous client
+//!
+//! [`Client`] talks to an EPC server over a plain `std` TCP stream, without
+//! a tokio runtime, for CLI tools and build scripts that make a few calls
+//! and have no use for async plumbing.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! # fn main() -> elrpc::Result<()> {
+//! let mut client = elrpc::blocking::Client::connect("127.0.0.1:9000")?;
+//! client.set_timeout(Some(Duration::from_secs(5)))?;
+//! let sum: i64 = client.call("add", (1, 2))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Calls are made one at a time. After a call times out its late reply is
+//! skipped by the next call.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ERPCError;
+use crate::log::debug;
+use crate::protocol::{LengthPrefix, Message};
+use crate::registry::MethodInfo;
+use crate::uid::{UidGenerator, UidStrategy};
+
+/// Blocking EPC client
+#[derive(Debug)]
+pub struct Client {
+    stream: TcpStream,
+    buffer: BytesMut,
+    uids: Arc<dyn UidStrategy>,
+    prefix: LengthPrefix,
+}
+
+/// Timeouts surface as `Timeout` rather than an I/O error
+fn io_error(error: std::io::Error) -> ERPCError {
+    match error.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => ERPCError::Timeout,
+        _ => ERPCError::Io(error),
+    }
+}
+
+impl Client {
+    /// Connect to a server
+    pub fn connect(addr: impl ToSocketAddrs) -> std::result::Result<Self, ERPCError> {
+        Ok(Client::from_stream(TcpStream::connect(addr)?))
+    }
+
+    /// Connect to a server, giving up after `timeout`
+    pub fn connect_timeout(
+        addr: impl ToSocketAddrs,
+        timeout: Duration,
+    ) -> std::result::Result<Self, ERPCError> {
+        let mut last_error = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(Client::from_stream(stream)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.map_or_else(
+            || ERPCError::InvalidArgument("address resolved to nothing".to_string()),
+            io_error,
+        ))
+    }
+
+    fn from_stream(stream: TcpStream) -> Self {
+        debug!("Connected to EPC server at {:?}", stream.peer_addr());
+        Client {
+            stream,
+            buffer: BytesMut::new(),
+            uids: Arc::new(UidGenerator::new()),
+            prefix: LengthPrefix::EPC,
+        }
+    }
+
+    /// Take the uids of later calls from `strategy`
+    pub fn set_uid_strategy(&mut self, strategy: Arc<dyn UidStrategy>) {
+        self.uids = strategy;
+    }
+
+    /// Frame messages with a nonstandard length prefix
+    pub fn set_length_prefix(&mut self, prefix: LengthPrefix) {
+        self.prefix = prefix;
+    }
+
+    /// Fail calls that wait longer than `timeout` for the server (None
+    /// waits forever)
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> std::result::Result<(), ERPCError> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Send `message` and wait for the reply with the same uid
+    fn send_message(&mut self, message: Message) -> std::result::Result<Message, ERPCError> {
+        let uid = message.uid();
+        let text = message.to_sexp()?;
+        self.stream
+            .write_all(&self.prefix.frame(text.as_bytes())?)
+            .map_err(io_error)?;
+
+        let mut chunk = [0u8; 4096];
+        loop {
+            while let Some(frame) = self.prefix.next_frame(&mut self.buffer, true)? {
+                let reply = Message::from_sexp(std::str::from_utf8(&frame)?)?;
+                if reply.uid() == uid {
+                    return Ok(reply);
+                }
+                debug!("Skipping reply to uid {}", reply.uid());
+            }
+            let n = self.stream.read(&mut chunk).map_err(io_error)?;
+            if n == 0 {
+                return Err(ERPCError::ConnectionClosed);
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Call a method with raw S-expression arguments, returning the raw result
+    pub fn call_value(
+        &mut self,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let uid = self.uids.next_uid();
+        match self.send_message(Message::new_call(uid, method, args))? {
+            Message::Return { result, .. } => Ok(result),
+            Message::ReturnError { error, .. } => Err(ERPCError::ApplicationError {
+                class: "RuntimeError".to_string(),
+                message: error,
+                backtrace: vec![],
+            }),
+            Message::EPCError { error, .. } => Err(ERPCError::ProtocolError(error)),
+            _ => Err(ERPCError::InvalidMessageFormat(
+                "Unexpected response type".to_string(),
+            )),
+        }
+    }
+
+    /// Call a method with typed arguments and result
+    pub fn call<Args, Ret>(
+        &mut self,
+        method: &str,
+        args: Args,
+    ) -> std::result::Result<Ret, ERPCError>
+    where
+        Args: Serialize,
+        Ret: for<'de> Deserialize<'de>,
+    {
+        let args = serde_lexpr::to_value(&args)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+        let result = self.call_value(method, args)?;
+        serde_lexpr::from_value(&result).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Query available methods from the server
+    pub fn query_methods(&mut self) -> std::result::Result<Vec<MethodInfo>, ERPCError> {
+        let uid = self.uids.next_uid();
+        match self.send_message(Message::new_methods(uid))? {
+            Message::Return { result, .. } => serde_lexpr::from_value(&result)
+                .map_err(|e| ERPCError::SerializationError(e.to_string())),
+            _ => Err(ERPCError::InvalidMessageFormat(
+                "Expected methods response".to_string(),
+            )),
+        }
+    }
+
+    /// Close the connection
+    pub fn close(self) -> std::result::Result<(), ERPCError> {
+        self.stream.shutdown(std::net::Shutdown::Both)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_and_timeout() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut server = runtime.block_on(async {
+            crate::server::Server::builder()
+                .bind("127.0.0.1:0")
+                .value_method("echo", Ok)
+                .build()
+                .await
+                .unwrap()
+        });
+
+        let mut client = Client::connect(server.local_addr().unwrap()).unwrap();
+        assert_eq!(
+            client.call_value("echo", Value::string("hi")).unwrap(),
+            Value::string("hi")
+        );
+        assert!(client
+            .query_methods()
+            .unwrap()
+            .iter()
+            .any(|m| m.name == "echo"));
+        client.close().unwrap();
+
+        // A server that never answers
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = Client::connect(silent.local_addr().unwrap()).unwrap();
+        client.set_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert!(matches!(
+            client.call_value("echo", Value::Nil),
+            Err(ERPCError::Timeout)
+        ));
+        runtime.block_on(server.shutdown()).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+//! Result caching for pure methods
+//!
+//! A [`ResultCache`] memoizes successful results keyed by method name and the
+//! printed form of the arguments. Methods opt in individually through
+//! [`MethodRegistry::cache_method`](crate::registry::MethodRegistry::cache_method),
+//! which wraps the registered handler in a [`CachedHandler`]. Handlers can
+//! override the time to live or opt out per result with a
+//! [`Reply`](crate::reply::Reply).
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lexpr::Value;
+use tokio::time::Instant;
+
+use crate::error::ERPCError;
+use crate::log::debug;
+use crate::registry::{MethodHandler, MethodInfo};
+use crate::reply::CacheHint;
+
+/// Cache configuration
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// How long a cached result stays valid
+    pub ttl: Duration,
+    /// Maximum number of cached results; the oldest entry is evicted first
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            ttl: Duration::from_secs(5),
+            max_entries: 1024,
+        }
+    }
+}
+
+type CacheKey = (String, String);
+
+#[derive(Debug)]
+struct CacheEntry {
+    value: Value,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Keys in insertion order, for eviction
+    order: VecDeque<CacheKey>,
+}
+
+/// TTL- and size-bounded memo table of method results
+#[derive(Debug)]
+pub struct ResultCache {
+    config: CacheConfig,
+    state: Mutex<CacheState>,
+}
+
+impl ResultCache {
+    pub fn new(config: CacheConfig) -> Self {
+        ResultCache {
+            config,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Canonical cache key for a call
+    fn key(method: &str, args: &Value) -> CacheKey {
+        (method.to_string(), args.to_string())
+    }
+
+    /// Look up a live cached result
+    pub fn get(&self, method: &str, args: &Value) -> Option<Value> {
+        let key = ResultCache::key(method, args);
+        let mut state = self.state.lock().unwrap();
+
+        match state.entries.get(&key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                state.entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Store a result, evicting expired and then the oldest entries when full
+    pub fn insert(&self, method: &str, args: &Value, value: Value) {
+        self.insert_for(method, args, value, self.config.ttl);
+    }
+
+    /// Store a result that stays valid for `ttl` instead of the configured time
+    pub fn insert_for(&self, method: &str, args: &Value, value: Value, ttl: Duration) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+
+        let key = ResultCache::key(method, args);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        if state.entries.len() >= self.config.max_entries && !state.entries.contains_key(&key) {
+            state.entries.retain(|_, entry| entry.expires_at > now);
+        }
+        while state.entries.len() >= self.config.max_entries && !state.entries.contains_key(&key) {
+            match state.order.pop_front() {
+                Some(oldest) => {
+                    state.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+
+        let entry = CacheEntry {
+            value,
+            expires_at: now + ttl,
+        };
+        if state.entries.insert(key.clone(), entry).is_none() {
+            state.order.push_back(key);
+        }
+
+        // Drop order entries whose keys were expired or removed
+        if state.order.len() > self.config.max_entries * 2 {
+            let CacheState { entries, order } = &mut *state;
+            order.retain(|key| entries.contains_key(key));
+        }
+    }
+
+    /// Drop all cached results of one method
+    pub fn invalidate(&self, method: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.retain(|(name, _), _| name != method);
+    }
+
+    /// Drop every cached result
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.order.clear();
+    }
+
+    /// Number of cached results, including any not yet purged after expiry
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for ResultCache {
+    fn default() -> Self {
+        ResultCache::new(CacheConfig::default())
+    }
+}
+
+/// Handler wrapper that serves repeated calls from a [`ResultCache`]
+pub struct CachedHandler {
+    inner: Arc<dyn MethodHandler>,
+    cache: Arc<ResultCache>,
+    name: String,
+}
+
+impl CachedHandler {
+    pub fn new(inner: Arc<dyn MethodHandler>, cache: Arc<ResultCache>) -> Self {
+        let name = inner.info().name;
+        CachedHandler { inner, cache, name }
+    }
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for CachedHandler {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        if let Some(value) = self.cache.get(&self.name, &args) {
+            debug!("Cache hit for method '{}'", self.name);
+            return Ok(value);
+        }
+
+        let reply = self.inner.call_reply(args.clone()).await?;
+        match reply.cache {
+            CacheHint::Inherit => self.cache.insert(&self.name, &args, reply.value.clone()),
+            CacheHint::Ttl(ttl) => {
+                self.cache
+                    .insert_for(&self.name, &args, reply.value.clone(), ttl)
+            }
+            CacheHint::NoStore => {}
+        }
+        Ok(reply.value)
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.inner.info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cache_hit_and_expiry() {
+        let cache = ResultCache::new(CacheConfig {
+            ttl: Duration::from_millis(20),
+            max_entries: 8,
+        });
+        let args = Value::from("sym");
+
+        assert!(cache.get("lookup", &args).is_none());
+        cache.insert("lookup", &args, Value::from(42));
+        assert_eq!(cache.get("lookup", &args), Some(Value::from(42)));
+        assert!(cache.get("other", &args).is_none());
+
+        tokio::time::advance(Duration::from_millis(30)).await;
+        assert!(cache.get("lookup", &args).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_size_bound_evicts_oldest() {
+        let cache = ResultCache::new(CacheConfig {
+            ttl: Duration::from_secs(60),
+            max_entries: 2,
+        });
+
+        cache.insert("m", &Value::from(1), Value::from(1));
+        cache.insert("m", &Value::from(2), Value::from(2));
+        cache.insert("m", &Value::from(3), Value::from(3));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("m", &Value::from(1)).is_none());
+        assert_eq!(cache.get("m", &Value::from(3)), Some(Value::from(3)));
+
+        cache.invalidate("m");
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reply_cache_hints() {
+        use crate::extract::{Args, ExtractHandler};
+        use crate::registry::ArgsStyle;
+        use crate::reply::Reply;
+
+        let handler = ExtractHandler::new(
+            |Args(n): Args<i64>| async move {
+                let reply = Reply::new(Value::from(n));
+                Ok::<_, ERPCError>(match n {
+                    0 => reply.no_cache(),
+                    1 => reply.cache_for(Duration::from_millis(10)),
+                    _ => reply,
+                })
+            },
+            ArgsStyle::Single,
+            "hinted",
+            None::<&str>,
+            None::<&str>,
+        );
+        let cache = Arc::new(ResultCache::default());
+        let cached = CachedHandler::new(Arc::new(handler), cache.clone());
+
+        for n in 0..3 {
+            cached.call(Value::from(n)).await.unwrap();
+        }
+        assert!(cache.get("hinted", &Value::from(0)).is_none());
+        assert!(cache.get("hinted", &Value::from(2)).is_some());
+        tokio::time::advance(Duration::from_millis(20)).await;
+        assert!(cache.get("hinted", &Value::from(1)).is_none());
+    }
+}
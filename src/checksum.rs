@@ -0,0 +1,116 @@
+//! Frame integrity checksums
+//!
+//! TCP already protects the bytes it carries, but EPC also runs over serial
+//! lines and home-grown tunnels that do not. In checksum mode every frame
+//! carries the CRC-32 of its payload as eight hex digits after the length,
+//! the length counting them:
+//!
+//! ```text
+//! 00001c98718fe5(call 1 echo ("hi"))
+//! ```
+//!
+//! A client with `ClientConfig::frame_checksums` asks for the mode by calling
+//! [`FRAME_CHECKSUMS_METHOD`] right after connecting; a server with
+//! `ServerConfig::frame_checksums` agrees, and from its reply on both sides
+//! send and require checksums. Frames that fail the check are reported as
+//! `ERPCError::ChecksumMismatch`.
+//!
+//! Payloads without a checksum start with `(`, so a frame starting with hex
+//! digits is taken to carry one even before the mode is on.
+
+use bytes::Bytes;
+
+use crate::error::ERPCError;
+
+/// Method a client calls to switch its connection to checksum mode
+pub const FRAME_CHECKSUMS_METHOD: &str = "elrpc-frame-checksums";
+
+/// Hex digits of a checksum
+const DIGITS: usize = 8;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE 802.3) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// `payload` preceded by its checksum, ready to be framed
+pub(crate) fn seal(payload: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(DIGITS + payload.len());
+    sealed.extend_from_slice(format!("{:08x}", crc32(payload)).as_bytes());
+    sealed.extend_from_slice(payload);
+    sealed
+}
+
+/// Check and strip the checksum of a frame
+///
+/// Frames without one pass unless `required`.
+pub(crate) fn open(frame: Bytes, required: bool) -> std::result::Result<Bytes, ERPCError> {
+    let expected = frame
+        .get(..DIGITS)
+        .filter(|digits| digits.iter().all(u8::is_ascii_hexdigit))
+        .and_then(|digits| std::str::from_utf8(digits).ok())
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+    let Some(expected) = expected else {
+        if required {
+            return Err(ERPCError::ProtocolError(
+                "frame without checksum in checksum mode".to_string(),
+            ));
+        }
+        return Ok(frame);
+    };
+    let payload = frame.slice(DIGITS..);
+    let actual = crc32(&payload);
+    if actual != expected {
+        return Err(ERPCError::ChecksumMismatch { expected, actual });
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let sealed = Bytes::from(seal(b"(call 1 echo nil)"));
+        assert_eq!(
+            open(sealed.clone(), true).unwrap(),
+            &b"(call 1 echo nil)"[..]
+        );
+
+        let mut corrupted = sealed.to_vec();
+        corrupted[10] ^= 0x20;
+        assert!(matches!(
+            open(Bytes::from(corrupted), true),
+            Err(ERPCError::ChecksumMismatch { .. })
+        ));
+
+        let plain = Bytes::from_static(b"(return 1 nil)");
+        assert_eq!(open(plain.clone(), false).unwrap(), plain);
+        assert!(open(plain, true).is_err());
+    }
+}
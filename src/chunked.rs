@@ -0,0 +1,504 @@
+//! Chunked transfer of large strings over ordinary calls
+//!
+//! Large payloads such as whole Emacs buffers are split into bounded chunks so
+//! no single frame grows to tens of megabytes. Both directions are plain EPC
+//! calls, so an Elisp peer can speak the same convention:
+//!
+//! - upload to a sink: `(call uid NAME (ID INDEX COUNT DATA))` per chunk; every
+//!   reply is `nil` except the one completing the transfer, which carries the
+//!   handler's result.
+//! - download from a source: `(call uid NAME (begin ARGS CHUNK-SIZE))` answers
+//!   `(ID COUNT FIRST-CHUNK)`, then `(call uid NAME (chunk ID INDEX))` answers
+//!   each further chunk.
+//!
+//! A sink bounds what peers can make it hold with [`ChunkLimits`]: the chunk
+//! count and total size of an upload, and how many unfinished uploads one
+//! connection may have. Uploads breaking a limit are rejected and dropped.
+//! A source holds each download until every chunk was fetched, and limits
+//! how many unfinished downloads one connection may have the same way.
+//! Chunks may be fetched again, e.g. after a retry, until the last one was.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lexpr::Value;
+use tokio::time::Instant;
+
+use crate::context::CallContext;
+use crate::error::ERPCError;
+use crate::log::debug;
+use crate::registry::{MethodHandler, MethodInfo};
+
+/// Default chunk size in bytes
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Unfinished transfers older than this are discarded
+const TRANSFER_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Split `content` into chunks of at most `chunk_size` bytes on char boundaries
+pub fn split_chunks(content: &str, chunk_size: usize) -> Vec<&str> {
+    // Any UTF-8 character fits in four bytes
+    let chunk_size = chunk_size.max(4);
+    let mut chunks = Vec::with_capacity(content.len() / chunk_size + 1);
+    let mut rest = content;
+
+    while rest.len() > chunk_size {
+        let mut end = chunk_size;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+    chunks
+}
+
+fn invalid(method: &str, detail: &str) -> ERPCError {
+    ERPCError::InvalidArgument(format!("{}: {}", method, detail))
+}
+
+/// Bounds on the transfers a [`ChunkSink`] or [`ChunkSource`] holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkLimits {
+    /// Most chunks one upload may announce
+    pub max_chunks: usize,
+    /// Most bytes of data one upload may carry
+    pub max_size: usize,
+    /// Most unfinished uploads per connection
+    pub max_uploads: usize,
+    /// Most unfinished downloads per connection
+    pub max_downloads: usize,
+}
+
+impl Default for ChunkLimits {
+    fn default() -> Self {
+        ChunkLimits {
+            max_chunks: 4096,
+            max_size: 64 * 1024 * 1024,
+            max_uploads: 8,
+            max_downloads: 8,
+        }
+    }
+}
+
+struct Upload {
+    count: usize,
+    chunks: BTreeMap<usize, String>,
+    size: usize,
+    started: Instant,
+}
+
+/// Transfers are told apart by connection, so peers cannot touch each
+/// other's transfers
+type TransferKey = (Option<u64>, String);
+
+/// Handler that reassembles uploaded chunks before calling its function
+pub struct ChunkSink {
+    func: Box<dyn Fn(String) -> std::result::Result<Value, ERPCError> + Send + Sync>,
+    uploads: Mutex<HashMap<TransferKey, Upload>>,
+    limits: ChunkLimits,
+    info: MethodInfo,
+}
+
+impl ChunkSink {
+    pub fn new<F>(
+        func: F,
+        name: impl Into<String>,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> Self
+    where
+        F: Fn(String) -> std::result::Result<Value, ERPCError> + Send + Sync + 'static,
+    {
+        ChunkSink {
+            func: Box::new(func),
+            uploads: Mutex::new(HashMap::new()),
+            limits: ChunkLimits::default(),
+            info: MethodInfo::new(name, arg_spec, docstring),
+        }
+    }
+
+    /// Accept uploads within `limits` instead of the defaults
+    pub fn limits(mut self, limits: ChunkLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Store one chunk, returning the full content once every chunk arrived
+    fn accept(&self, args: &Value) -> std::result::Result<Option<String>, ERPCError> {
+        let name = &self.info.name;
+        let items = args
+            .to_vec()
+            .ok_or_else(|| invalid(name, "expected (ID INDEX COUNT DATA)"))?;
+        let [id, index, count, data] = items.as_slice() else {
+            return Err(invalid(name, "expected (ID INDEX COUNT DATA)"));
+        };
+        let id = id
+            .as_str()
+            .ok_or_else(|| invalid(name, "ID must be a string"))?;
+        let index = index
+            .as_u64()
+            .ok_or_else(|| invalid(name, "INDEX must be an integer"))? as usize;
+        let count = count
+            .as_u64()
+            .ok_or_else(|| invalid(name, "COUNT must be an integer"))? as usize;
+        let data = data
+            .as_str()
+            .ok_or_else(|| invalid(name, "DATA must be a string"))?;
+
+        if count == 0 || index >= count {
+            return Err(invalid(name, "INDEX out of range"));
+        }
+        if count > self.limits.max_chunks {
+            return Err(invalid(
+                name,
+                &format!("COUNT exceeds {} chunks", self.limits.max_chunks),
+            ));
+        }
+
+        let connection = CallContext::current().map(|cx| cx.connection);
+        let key = (connection, id.to_string());
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.retain(|_, upload| upload.started.elapsed() < TRANSFER_EXPIRY);
+
+        if !uploads.contains_key(&key) {
+            let open = uploads.keys().filter(|(c, _)| *c == connection).count();
+            if open >= self.limits.max_uploads {
+                return Err(invalid(
+                    name,
+                    &format!("more than {} unfinished uploads", self.limits.max_uploads),
+                ));
+            }
+        }
+        let upload = uploads.entry(key.clone()).or_insert_with(|| Upload {
+            count,
+            chunks: BTreeMap::new(),
+            size: 0,
+            started: Instant::now(),
+        });
+        if upload.count != count {
+            return Err(invalid(name, "COUNT changed during transfer"));
+        }
+        let replaced = upload.chunks.get(&index).map_or(0, String::len);
+        let size = upload.size - replaced + data.len();
+        if size > self.limits.max_size {
+            uploads.remove(&key);
+            return Err(invalid(
+                name,
+                &format!("upload exceeds {} bytes", self.limits.max_size),
+            ));
+        }
+        upload.size = size;
+        upload.chunks.insert(index, data.to_string());
+        debug!(
+            "Chunk {}/{} of transfer {} for '{}'",
+            index + 1,
+            count,
+            id,
+            name
+        );
+
+        if upload.chunks.len() < count {
+            return Ok(None);
+        }
+        let upload = uploads.remove(&key).unwrap();
+        Ok(Some(upload.chunks.into_values().collect()))
+    }
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for ChunkSink {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        match self.accept(&args)? {
+            Some(content) => (self.func)(content),
+            None => Ok(Value::Null),
+        }
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+struct Download {
+    chunks: Vec<String>,
+    /// Indices fetched at least once
+    served: HashSet<usize>,
+    started: Instant,
+}
+
+/// Handler that produces a large string and serves it in chunks
+pub struct ChunkSource {
+    func: Box<dyn Fn(Value) -> std::result::Result<String, ERPCError> + Send + Sync>,
+    downloads: Mutex<HashMap<TransferKey, Download>>,
+    limits: ChunkLimits,
+    info: MethodInfo,
+}
+
+impl ChunkSource {
+    pub fn new<F>(
+        func: F,
+        name: impl Into<String>,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> Self
+    where
+        F: Fn(Value) -> std::result::Result<String, ERPCError> + Send + Sync + 'static,
+    {
+        ChunkSource {
+            func: Box::new(func),
+            downloads: Mutex::new(HashMap::new()),
+            limits: ChunkLimits::default(),
+            info: MethodInfo::new(name, arg_spec, docstring),
+        }
+    }
+
+    /// Hold downloads within `limits` instead of the defaults
+    pub fn limits(mut self, limits: ChunkLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    fn begin(&self, args: Value, chunk_size: usize) -> std::result::Result<Value, ERPCError> {
+        let content = (self.func)(args)?;
+        let chunks: Vec<String> = split_chunks(&content, chunk_size)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let count = chunks.len();
+        let first = chunks[0].clone();
+
+        let id = uuid::Uuid::new_v4().to_string();
+        if count > 1 {
+            let connection = CallContext::current().map(|cx| cx.connection);
+            let mut downloads = self.downloads.lock().unwrap();
+            downloads.retain(|_, download| download.started.elapsed() < TRANSFER_EXPIRY);
+            let open = downloads.keys().filter(|(c, _)| *c == connection).count();
+            if open >= self.limits.max_downloads {
+                return Err(invalid(
+                    &self.info.name,
+                    &format!(
+                        "more than {} unfinished downloads",
+                        self.limits.max_downloads
+                    ),
+                ));
+            }
+            downloads.insert(
+                (connection, id.clone()),
+                Download {
+                    chunks,
+                    served: HashSet::from([0]),
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        Ok(Value::list(vec![
+            Value::string(id),
+            Value::from(count as u64),
+            Value::string(first),
+        ]))
+    }
+
+    fn chunk(&self, id: &str, index: usize) -> std::result::Result<Value, ERPCError> {
+        let name = &self.info.name;
+        let key = (
+            CallContext::current().map(|cx| cx.connection),
+            id.to_string(),
+        );
+        let mut downloads = self.downloads.lock().unwrap();
+        let download = downloads
+            .get_mut(&key)
+            .ok_or_else(|| invalid(name, "unknown transfer"))?;
+        let chunk = download
+            .chunks
+            .get(index)
+            .ok_or_else(|| invalid(name, "INDEX out of range"))?
+            .clone();
+
+        download.served.insert(index);
+        if download.served.len() == download.chunks.len() {
+            downloads.remove(&key);
+        }
+        Ok(Value::string(chunk))
+    }
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for ChunkSource {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        let name = &self.info.name;
+        let items = args
+            .to_vec()
+            .ok_or_else(|| invalid(name, "expected (begin ARGS CHUNK-SIZE) or (chunk ID INDEX)"))?;
+
+        match items.as_slice() {
+            [op, args, chunk_size] if op.as_symbol() == Some("begin") => {
+                let chunk_size = chunk_size
+                    .as_u64()
+                    .ok_or_else(|| invalid(name, "CHUNK-SIZE must be an integer"))?;
+                self.begin(args.clone(), chunk_size as usize)
+            }
+            [op, id, index] if op.as_symbol() == Some("chunk") => {
+                let id = id
+                    .as_str()
+                    .ok_or_else(|| invalid(name, "ID must be a string"))?;
+                let index = index
+                    .as_u64()
+                    .ok_or_else(|| invalid(name, "INDEX must be an integer"))?;
+                self.chunk(id, index as usize)
+            }
+            _ => Err(invalid(
+                name,
+                "expected (begin ARGS CHUNK-SIZE) or (chunk ID INDEX)",
+            )),
+        }
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_chunks_respects_char_boundaries() {
+        let content = "aé€😀".repeat(10);
+        let chunks = split_chunks(&content, 5);
+
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 5));
+        assert_eq!(chunks.concat(), content);
+        assert_eq!(split_chunks("", 16), vec![""]);
+    }
+
+    #[tokio::test]
+    async fn test_sink_reassembles_out_of_order_chunks() {
+        let sink = ChunkSink::new(
+            |content: String| Ok(Value::from(content.len() as u64)),
+            "upload",
+            None::<&str>,
+            None::<&str>,
+        );
+        let chunk = |index: u64, data: &str| {
+            Value::list(vec![
+                Value::from("t1"),
+                Value::from(index),
+                Value::from(2u64),
+                Value::from(data),
+            ])
+        };
+
+        assert_eq!(sink.call(chunk(1, "world")).await.unwrap(), Value::Null);
+        assert_eq!(
+            sink.call(chunk(0, "hello ")).await.unwrap(),
+            Value::from(11u64)
+        );
+        assert!(sink.uploads.lock().unwrap().is_empty());
+    }
+
+    fn header(id: &str, index: u64, count: u64, data: &str) -> Value {
+        Value::list(vec![
+            Value::from(id),
+            Value::from(index),
+            Value::from(count),
+            Value::from(data),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_sink_rejects_oversized_uploads() {
+        let sink = ChunkSink::new(
+            |content: String| Ok(Value::from(content.len() as u64)),
+            "upload",
+            None::<&str>,
+            None::<&str>,
+        )
+        .limits(ChunkLimits {
+            max_chunks: 4,
+            max_size: 8,
+            ..ChunkLimits::default()
+        });
+
+        // A huge count is refused before anything is stored
+        assert!(sink.call(header("t1", 0, u64::MAX, "x")).await.is_err());
+        assert!(sink.call(header("t1", 0, 5, "x")).await.is_err());
+        assert!(sink.uploads.lock().unwrap().is_empty());
+
+        // Going over the size drops the upload
+        assert_eq!(
+            sink.call(header("t2", 0, 2, "12345")).await.unwrap(),
+            Value::Null
+        );
+        assert!(sink.call(header("t2", 1, 2, "6789")).await.is_err());
+        assert!(sink.uploads.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sink_limits_unfinished_uploads() {
+        let sink = ChunkSink::new(
+            |_: String| Ok(Value::Null),
+            "upload",
+            None::<&str>,
+            None::<&str>,
+        )
+        .limits(ChunkLimits {
+            max_uploads: 2,
+            ..ChunkLimits::default()
+        });
+
+        for id in ["a", "b"] {
+            sink.call(header(id, 0, 2, "x")).await.unwrap();
+        }
+        assert!(sink.call(header("c", 0, 2, "x")).await.is_err());
+        // Chunks of open uploads are still taken
+        sink.call(header("a", 1, 2, "y")).await.unwrap();
+        sink.call(header("c", 0, 2, "x")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_source_serves_repeated_chunks() {
+        let source = ChunkSource::new(
+            |_: Value| Ok("abcdefghij".to_string()),
+            "download",
+            None::<&str>,
+            None::<&str>,
+        );
+        let first = source.begin(Value::Null, 4).unwrap().to_vec().unwrap();
+        let id = first[0].as_str().unwrap().to_string();
+        assert_eq!(first[1], Value::from(3u64));
+
+        // Fetching the first chunk again does not count as progress
+        assert_eq!(source.chunk(&id, 0).unwrap(), Value::from("abcd"));
+        assert_eq!(source.chunk(&id, 1).unwrap(), Value::from("efgh"));
+        assert_eq!(source.chunk(&id, 1).unwrap(), Value::from("efgh"));
+        assert_eq!(source.chunk(&id, 2).unwrap(), Value::from("ij"));
+        assert!(source.chunk(&id, 2).is_err());
+        assert!(source.downloads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_source_limits_unfinished_downloads() {
+        let source = ChunkSource::new(
+            |_: Value| Ok("abcdefgh".to_string()),
+            "download",
+            None::<&str>,
+            None::<&str>,
+        )
+        .limits(ChunkLimits {
+            max_downloads: 2,
+            ..ChunkLimits::default()
+        });
+
+        assert!(source.begin(Value::Null, 4).is_ok());
+        assert!(source.begin(Value::Null, 4).is_ok());
+        assert!(source.begin(Value::Null, 4).is_err());
+        // Single-chunk downloads are not held
+        assert!(source.begin(Value::Null, 16).is_ok());
+    }
+}
@@ -1,86 +1,449 @@
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
 
 use bytes::BytesMut;
+use lexpr::Value;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tracing::debug;
+use tokio::sync::{oneshot, Mutex, Semaphore};
+use tokio::task::JoinHandle;
 
+use crate::auth::AUTH_METHOD;
+use crate::checksum::{self, FRAME_CHECKSUMS_METHOD};
+#[cfg(feature = "chunked")]
+use crate::chunked::split_chunks;
+use crate::clock::{Clock, SystemClock};
+use crate::compat::Compat;
+use crate::context::CallContext;
 use crate::error::ERPCError;
-use crate::protocol::{Framer, Message};
-use crate::registry::{MethodInfo, MethodRegistry};
+use crate::log::{debug, info_span, warn, Instrument};
+use crate::metadata::{self, CallMetadata, CALL_METADATA_METHOD};
+use crate::pool::{BufferPool, ReadSizer};
+use crate::protocol::{LengthPrefix, Message, Transport, UidSpace};
+use crate::registry::{method_list, ArgsStyle, MethodInfo, MethodRegistry};
+#[cfg(feature = "ssh")]
+use crate::ssh::SshConfig;
+use crate::stats::{UidErrorCounters, UidErrors};
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::uid::{PartitionedUids, UidGenerator, UidHalf, UidStrategy, PARTITION_UIDS_METHOD};
+use crate::wiretap::{next_connection_id, Direction, WireTap};
+
+#[cfg(feature = "process")]
+pub use crate::process::Process;
+
+/// Client configuration
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Maximum number of calls that may be outstanding at once
+    pub max_in_flight: usize,
+    /// Wait for capacity when the limit is reached instead of failing with
+    /// `ERPCError::TooManyInFlight`
+    pub wait_for_capacity: bool,
+    /// Send the caller's trace context along with each call (needs the
+    /// `otel` feature and a server that understands it, see the `otel` module)
+    pub propagate_trace_context: bool,
+    /// Hook receiving every raw frame sent and received
+    pub wire_tap: Option<WireTap>,
+    /// Time the frames seen by `wire_tap` are stamped with
+    pub clock: Arc<dyn Clock>,
+    /// Quirks of the server, see the `compat` module
+    pub compat: Compat,
+    /// How typed client-side methods receive the argument list of a call
+    pub args_style: ArgsStyle,
+    /// Talk TLS to the server, see the `tls` module
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// Handshake data for servers whose authenticator requires it, sent in
+    /// a call to [`AUTH_METHOD`] right after connecting
+    pub auth_data: Option<Value>,
+    /// Where the uids of calls come from, see the `uid` module; clones of
+    /// the config share it
+    pub uid_strategy: Arc<dyn UidStrategy>,
+    /// Ask the server for a half of the uid space of its own, see the `uid`
+    /// module; servers that do not support it are used as they are
+    pub partition_uids: bool,
+    /// Send metadata with calls, see the `metadata` module; turned off when
+    /// the server does not take it
+    pub call_metadata: bool,
+    /// Ask the server for checksummed frames, see the `checksum` module;
+    /// turned off when the server does not support them
+    pub frame_checksums: bool,
+    /// Skip whitespace peers such as epc.el send between frames
+    pub skip_frame_whitespace: bool,
+    /// Format of the length prefix of frames, six hex digits by default
+    pub length_prefix: LengthPrefix,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            max_in_flight: 256,
+            wait_for_capacity: true,
+            propagate_trace_context: false,
+            wire_tap: None,
+            clock: Arc::new(SystemClock),
+            compat: Compat::Standard,
+            args_style: ArgsStyle::Single,
+            #[cfg(feature = "tls")]
+            tls: None,
+            auth_data: None,
+            uid_strategy: Arc::new(UidGenerator::new()),
+            partition_uids: false,
+            call_metadata: false,
+            frame_checksums: false,
+            skip_frame_whitespace: true,
+            length_prefix: LengthPrefix::EPC,
+        }
+    }
+}
+
+/// The client's end of the connection, shared with the task reading it
+struct Connection {
+    writer: Mutex<WriteHalf<Box<dyn Transport>>>,
+    replies: StdMutex<Replies>,
+    registry: Arc<MethodRegistry>,
+    uid_errors: UidErrorCounters,
+    peer: String,
+    peer_addr: SocketAddr,
+    connection_id: u64,
+    compat: Compat,
+    length_prefix: LengthPrefix,
+    skip_frame_whitespace: bool,
+    wire_tap: Option<WireTap>,
+    clock: Arc<dyn Clock>,
+    /// Whether frames are checksummed, set once the server agrees
+    frame_checksums: AtomicBool,
+}
+
+/// Calls waiting for their reply
+#[derive(Default)]
+struct Replies {
+    waiting: HashMap<u64, oneshot::Sender<std::result::Result<Message, ERPCError>>>,
+    uids: UidLog,
+    /// Set once the reader stops; later calls fail straight away
+    closed: bool,
+}
+
+/// How many answered uids a client remembers to spot second replies
+const ANSWERED_KEPT: usize = 256;
+
+/// Uids of the client's calls, to tell stray replies apart
+#[derive(Default)]
+struct UidLog {
+    /// Sent and not answered yet, calls whose callers gave up included
+    outstanding: HashSet<u64>,
+    /// Answered lately, oldest first
+    answered: VecDeque<u64>,
+}
+
+impl UidLog {
+    fn sent(&mut self, uid: u64) {
+        self.outstanding.insert(uid);
+    }
+
+    /// Record a reply to call `uid`, failing if no call expects one
+    fn answer(&mut self, uid: u64) -> std::result::Result<(), ERPCError> {
+        if !self.outstanding.remove(&uid) {
+            return Err(if self.answered.contains(&uid) {
+                ERPCError::DuplicateReturn(uid)
+            } else {
+                ERPCError::UnknownUid(uid)
+            });
+        }
+        if self.answered.len() == ANSWERED_KEPT {
+            self.answered.pop_front();
+        }
+        self.answered.push_back(uid);
+        Ok(())
+    }
+}
 
 /// EPC Client
+///
+/// Calls may be made concurrently: a background task reads the connection
+/// and hands each reply to the call with its uid. The server may call
+/// methods of the client's [`registry`](Client::registry) at any time; each
+/// such call is served in a task of its own.
 pub struct Client {
-    stream: Arc<Mutex<TcpStream>>,
-    registry: Arc<MethodRegistry>,
-    next_uid: Arc<AtomicU64>,
+    connection: Arc<Connection>,
+    reader: JoinHandle<()>,
+    pool: Arc<BufferPool>,
+    config: ClientConfig,
+    in_flight: Arc<Semaphore>,
 }
 
 impl Client {
     /// Connect to a server
     pub async fn connect(addr: impl Into<String>) -> std::result::Result<Self, ERPCError> {
+        Client::connect_with_config(addr, ClientConfig::default()).await
+    }
+
+    /// Connect to a server with custom configuration
+    pub async fn connect_with_config(
+        addr: impl Into<String>,
+        config: ClientConfig,
+    ) -> std::result::Result<Self, ERPCError> {
+        let registry = Arc::new(MethodRegistry::with_args_style(config.args_style));
+        Client::connect_with_registry(addr, config, registry).await
+    }
+
+    /// Connect, serving the server's calls from `registry`, which other
+    /// clients may share
+    pub(crate) async fn connect_with_registry(
+        addr: impl Into<String>,
+        config: ClientConfig,
+        registry: Arc<MethodRegistry>,
+    ) -> std::result::Result<Self, ERPCError> {
         let addr = addr.into();
         let stream = TcpStream::connect(&addr)
             .await
             .map_err(|e| ERPCError::Io(e))?;
 
+        let peer_addr = stream.peer_addr().map_err(ERPCError::Io)?;
+        #[cfg(feature = "tls")]
+        let stream: Box<dyn Transport> = match &config.tls {
+            Some(tls) => Box::new(tls.connect(&addr, stream).await?),
+            None => Box::new(stream),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream: Box<dyn Transport> = Box::new(stream);
         debug!("Connected to EPC server at {}", addr);
 
-        Ok(Client {
-            stream: Arc::new(Mutex::new(stream)),
-            registry: Arc::new(MethodRegistry::new()),
-            next_uid: Arc::new(AtomicU64::new(1)),
-        })
+        Client::over(stream, addr, peer_addr, config, registry).await
+    }
+
+    /// Run the session over an established `stream` to `peer`
+    async fn over(
+        stream: Box<dyn Transport>,
+        peer: String,
+        peer_addr: SocketAddr,
+        config: ClientConfig,
+        registry: Arc<MethodRegistry>,
+    ) -> std::result::Result<Self, ERPCError> {
+        let (reader, writer) = tokio::io::split(stream);
+        let connection = Arc::new(Connection {
+            writer: Mutex::new(writer),
+            replies: StdMutex::default(),
+            registry,
+            uid_errors: UidErrorCounters::default(),
+            peer,
+            peer_addr,
+            connection_id: next_connection_id(),
+            compat: config.compat,
+            length_prefix: config.length_prefix,
+            skip_frame_whitespace: config.skip_frame_whitespace,
+            wire_tap: config.wire_tap.clone(),
+            clock: config.clock.clone(),
+            frame_checksums: AtomicBool::new(false),
+        });
+        let mut client = Client {
+            reader: tokio::spawn(connection.clone().read(reader)),
+            connection,
+            pool: Arc::new(BufferPool::new(4)),
+            in_flight: Arc::new(Semaphore::new(config.max_in_flight.max(1))),
+            config,
+        };
+        if let Some(data) = client.config.auth_data.clone() {
+            client.call_value(AUTH_METHOD, data).await?;
+            debug!("Authenticated to {}", client.connection.peer);
+        }
+        if client.config.partition_uids {
+            client.partition_uids().await?;
+        }
+        if client.config.call_metadata {
+            client.negotiate_metadata().await?;
+        }
+        if client.config.frame_checksums {
+            client.negotiate_checksums().await?;
+        }
+        Ok(client)
+    }
+
+    /// Connect to the service registered as `name` with the broker at
+    /// `broker_addr`
+    ///
+    /// See [`crate::discovery`]. Addresses are tried in the order the broker
+    /// returns them; the error of the last one is returned if none answers.
+    #[cfg(feature = "discovery")]
+    pub async fn connect_named(
+        name: &str,
+        broker_addr: &str,
+    ) -> std::result::Result<Self, ERPCError> {
+        Client::connect_named_with_config(name, broker_addr, ClientConfig::default()).await
+    }
+
+    /// [`connect_named`](Client::connect_named) with custom configuration
+    #[cfg(feature = "discovery")]
+    pub async fn connect_named_with_config(
+        name: &str,
+        broker_addr: &str,
+        config: ClientConfig,
+    ) -> std::result::Result<Self, ERPCError> {
+        let mut error = ERPCError::ServiceNotFound(name.to_string());
+        for record in crate::discovery::resolve(broker_addr, name).await? {
+            match Client::connect_with_config(record.addr.as_str(), config.clone()).await {
+                Ok(client) => return Ok(client),
+                Err(e) => {
+                    debug!("Service {} at {} unreachable: {}", name, record.addr, e);
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Connect to a server listening on `remote_port` of `destination`,
+    /// tunneled through `ssh`
+    ///
+    /// See [`crate::ssh`]; `destination` is anything `ssh` accepts, such as
+    /// `user@host` or a host alias from `~/.ssh/config`.
+    #[cfg(feature = "ssh")]
+    pub async fn connect_ssh(
+        destination: &str,
+        remote_port: u16,
+    ) -> std::result::Result<Self, ERPCError> {
+        Client::connect_ssh_with_config(
+            SshConfig::new(destination, remote_port),
+            ClientConfig::default(),
+        )
+        .await
+    }
+
+    /// [`connect_ssh`](Client::connect_ssh) with custom SSH and client
+    /// configuration
+    #[cfg(feature = "ssh")]
+    pub async fn connect_ssh_with_config(
+        ssh: SshConfig,
+        config: ClientConfig,
+    ) -> std::result::Result<Self, ERPCError> {
+        let stream = ssh.open()?;
+        debug!("Tunneling to EPC server at {}", ssh);
+        let registry = Arc::new(MethodRegistry::with_args_style(config.args_style));
+        // The server is only reachable through the tunnel, like a Unix peer
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        Client::over(
+            Box::new(stream),
+            ssh.to_string(),
+            peer_addr,
+            config,
+            registry,
+        )
+        .await
     }
 
     /// Get the method registry for registering client-side methods
     pub fn registry(&self) -> &Arc<MethodRegistry> {
-        &self.registry
+        &self.connection.registry
+    }
+
+    /// Number of calls currently outstanding
+    pub fn in_flight(&self) -> usize {
+        self.config.max_in_flight.max(1) - self.in_flight.available_permits()
+    }
+
+    /// Take the odd half of the uid space for the client's calls, if the
+    /// server agrees
+    async fn partition_uids(&mut self) -> std::result::Result<(), ERPCError> {
+        let args = Value::list(vec![Value::symbol(UidHalf::Odd.name())]);
+        match self.call_value(PARTITION_UIDS_METHOD, args).await {
+            Ok(_) => {
+                let strategy = self.config.uid_strategy.clone();
+                self.config.uid_strategy = Arc::new(PartitionedUids::new(strategy, UidHalf::Odd));
+                debug!("Using odd uids with {}", self.connection.peer);
+                Ok(())
+            }
+            Err(ERPCError::ConnectionClosed) => Err(ERPCError::ConnectionClosed),
+            Err(e) => {
+                debug!("{} does not partition uids: {}", self.connection.peer, e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Check that the server takes call metadata, and stop sending it if not
+    async fn negotiate_metadata(&mut self) -> std::result::Result<(), ERPCError> {
+        match self.call_value(CALL_METADATA_METHOD, Value::Nil).await {
+            Ok(_) => Ok(()),
+            Err(ERPCError::ConnectionClosed) => Err(ERPCError::ConnectionClosed),
+            Err(e) => {
+                debug!(
+                    "{} does not take call metadata: {}",
+                    self.connection.peer, e
+                );
+                self.config.call_metadata = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// Switch the connection to checksummed frames if the server supports
+    /// them
+    async fn negotiate_checksums(&mut self) -> std::result::Result<(), ERPCError> {
+        // The call itself goes out plain; the server seals its reply
+        match self.call_value(FRAME_CHECKSUMS_METHOD, Value::Nil).await {
+            Ok(_) => {
+                self.connection
+                    .frame_checksums
+                    .store(true, Ordering::Relaxed);
+                debug!("Using checksummed frames with {}", self.connection.peer);
+                Ok(())
+            }
+            Err(ERPCError::ConnectionClosed) => Err(ERPCError::ConnectionClosed),
+            Err(e) => {
+                debug!("{} does not checksum frames: {}", self.connection.peer, e);
+                self.config.frame_checksums = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// Stray replies the server sent: second replies to a call and replies
+    /// to calls never made
+    pub fn uid_errors(&self) -> UidErrors {
+        self.connection.uid_errors.snapshot()
     }
 
     /// Generate next UID
     fn next_uid(&self) -> u64 {
-        self.next_uid.fetch_add(1, Ordering::Relaxed)
+        self.config.uid_strategy.next_uid()
     }
 
     /// Send a message and wait for response
+    ///
+    /// Only writing the frame takes the connection's lock; the reader task
+    /// hands the reply over once it arrives. Replies to calls whose callers
+    /// gave up are discarded.
     async fn send_message(&self, message: Message) -> std::result::Result<Message, ERPCError> {
-        let message_str = message.to_sexp()?;
-        let framed = Framer::frame(message_str.as_bytes());
-
-        {
-            let mut stream = self.stream.lock().await;
-            stream
-                .write_all(&framed)
+        let _permit = if self.config.wait_for_capacity {
+            self.in_flight
+                .acquire()
                 .await
-                .map_err(|e| ERPCError::Io(e))?;
-        }
-
-        let mut buffer = BytesMut::with_capacity(1024);
-
-        loop {
-            {
-                let mut stream = self.stream.lock().await;
-                let bytes_read = stream
-                    .read_buf(&mut buffer)
-                    .await
-                    .map_err(|e| ERPCError::Io(e))?;
-
-                if bytes_read == 0 {
-                    return Err(ERPCError::ConnectionClosed);
-                }
-            }
+                .map_err(|_| ERPCError::ConnectionClosed)?
+        } else {
+            self.in_flight
+                .try_acquire()
+                .map_err(|_| ERPCError::TooManyInFlight(self.config.max_in_flight))?
+        };
 
-            if let Some(message_bytes) = Framer::extract_message(&mut buffer) {
-                let message_str = std::str::from_utf8(&message_bytes)
-                    .map_err(|e| ERPCError::InvalidMessageFormat(e.to_string()))?;
+        let uid = message.uid();
+        let message_str = self.connection.compat.encode(&message)?;
+        self.connection
+            .tap(Direction::Outbound, message_str.as_bytes());
+        let mut framed = self.pool.get();
+        self.connection
+            .frame_into(&mut framed, message_str.as_bytes())?;
 
-                return Message::from_sexp(message_str);
-            }
+        // Wait before writing so that a quick reply finds its caller
+        let reply = self.connection.expect(uid)?;
+        if let Err(e) = self.connection.write(&framed).await {
+            self.connection.forget(uid);
+            return Err(e);
         }
+        reply.await.map_err(|_| ERPCError::ConnectionClosed)?
     }
 
     /// Call a method synchronously
@@ -96,14 +459,68 @@ impl Client {
         let args_value = serde_lexpr::to_value(&args)
             .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
 
+        let result = self.call_value(method, args_value).await?;
+
+        serde_lexpr::from_value(&result).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Call a method with raw S-expression arguments, returning the raw result
+    ///
+    /// With `call_metadata` the call carries the metadata of the call the
+    /// current task is serving, if any.
+    pub async fn call_value(
+        &self,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let metadata = CallContext::current().and_then(|cx| cx.metadata);
+        self.call_value_with(method, args, metadata).await
+    }
+
+    /// Call a method with raw S-expression arguments, sending `metadata`
+    /// along if the server takes it
+    pub async fn call_value_with_metadata(
+        &self,
+        method: &str,
+        args: Value,
+        metadata: CallMetadata,
+    ) -> std::result::Result<Value, ERPCError> {
+        self.call_value_with(method, args, Some(metadata)).await
+    }
+
+    async fn call_value_with(
+        &self,
+        method: &str,
+        args: Value,
+        metadata: Option<CallMetadata>,
+    ) -> std::result::Result<Value, ERPCError> {
         let uid = self.next_uid();
-        let message = Message::new_call(uid, method, args_value);
+        let span = info_span!(
+            "call",
+            peer = %self.connection.peer,
+            uid,
+            method,
+            duration_us = tracing::field::Empty,
+        );
+        #[cfg(feature = "otel")]
+        let args = if self.config.propagate_trace_context {
+            crate::otel::inject(&span, args)
+        } else {
+            args
+        };
+        let args = match metadata.filter(|_| self.config.call_metadata) {
+            Some(metadata) => metadata::wrap(&metadata, args),
+            None => args,
+        };
+        let message = Message::new_call(uid, method, args);
 
-        let response = self.send_message(message).await?;
+        let started = std::time::Instant::now();
+        let response = self.send_message(message).instrument(span.clone()).await;
+        let elapsed = started.elapsed();
+        span.record("duration_us", elapsed.as_micros() as u64);
 
-        match response {
-            Message::Return { result, .. } => serde_lexpr::from_value(&result)
-                .map_err(|e| ERPCError::SerializationError(e.to_string())),
+        let result = response.and_then(|response| match response {
+            Message::Return { result, .. } => Ok(result),
             Message::ReturnError { error, .. } => Err(ERPCError::ApplicationError {
                 class: "RuntimeError".to_string(),
                 message: error,
@@ -113,7 +530,83 @@ impl Client {
             _ => Err(ERPCError::InvalidMessageFormat(
                 "Unexpected response type".to_string(),
             )),
+        });
+        #[cfg(feature = "otel")]
+        crate::otel::record_call(crate::otel::Side::Client, method, elapsed, result.is_ok());
+        result
+    }
+
+    /// Upload a large string to a chunked sink method, returning its result
+    ///
+    /// Chunks are sent one call at a time so at most `chunk_size` bytes of
+    /// payload are in a frame.
+    #[cfg(feature = "chunked")]
+    pub async fn send_buffer_chunked(
+        &self,
+        method: &str,
+        content: &str,
+        chunk_size: usize,
+    ) -> std::result::Result<Value, ERPCError> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let chunks = split_chunks(content, chunk_size);
+        let count = chunks.len() as u64;
+        debug!(
+            "Sending {} bytes to '{}' in {} chunks",
+            content.len(),
+            method,
+            count
+        );
+
+        let mut result = Value::Null;
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let args = Value::list(vec![
+                Value::string(id.as_str()),
+                Value::from(index as u64),
+                Value::from(count),
+                Value::string(chunk),
+            ]);
+            result = self.call_value(method, args).await?;
         }
+        Ok(result)
+    }
+
+    /// Download a large string from a chunked source method
+    #[cfg(feature = "chunked")]
+    pub async fn recv_buffer_chunked(
+        &self,
+        method: &str,
+        args: Value,
+        chunk_size: usize,
+    ) -> std::result::Result<String, ERPCError> {
+        let begin = Value::list(vec![
+            Value::symbol("begin"),
+            args,
+            Value::from(chunk_size as u64),
+        ]);
+        let header = self.call_value(method, begin).await?;
+
+        let unexpected =
+            || ERPCError::InvalidMessageFormat("Expected (ID COUNT FIRST-CHUNK)".to_string());
+        let items = header.to_vec().ok_or_else(unexpected)?;
+        let [id, count, first] = items.as_slice() else {
+            return Err(unexpected());
+        };
+        let id = id.as_str().ok_or_else(unexpected)?;
+        let count = count.as_u64().ok_or_else(unexpected)?;
+        let mut content = first.as_str().ok_or_else(unexpected)?.to_string();
+
+        for index in 1..count {
+            let request = Value::list(vec![
+                Value::symbol("chunk"),
+                Value::string(id),
+                Value::from(index),
+            ]);
+            let chunk = self.call_value(method, request).await?;
+            content.push_str(chunk.as_str().ok_or_else(|| {
+                ERPCError::InvalidMessageFormat("Expected a string chunk".to_string())
+            })?);
+        }
+        Ok(content)
     }
 
     /// Call a method asynchronously (returns a future)
@@ -161,127 +654,213 @@ impl Client {
         Args: for<'de> Deserialize<'de> + Send,
         Ret: Serialize + Send,
     {
-        self.registry
+        self.connection
+            .registry
             .register_closure(name, func, arg_spec, docstring)
             .await
     }
 
     /// Close the connection
     pub async fn close(&self) -> std::result::Result<(), ERPCError> {
-        let mut stream = self.stream.lock().await;
-        stream.shutdown().await.map_err(|e| ERPCError::Io(e))?;
+        let mut writer = self.connection.writer.lock().await;
+        writer.shutdown().await.map_err(|e| ERPCError::Io(e))?;
         Ok(())
     }
 }
 
-/// Process management for starting external processes
-pub struct Process {
-    command: String,
-    args: Vec<String>,
-    port: Option<u16>,
-    client: Option<Client>,
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
 }
 
-impl Process {
-    /// Create a new process configuration
-    pub fn new(command: impl Into<String>, args: Vec<impl Into<String>>) -> Self {
-        Process {
-            command: command.into(),
-            args: args.into_iter().map(Into::into).collect(),
-            port: None,
-            client: None,
+impl Connection {
+    /// Frame `payload` for the server
+    fn frame_into(&self, dst: &mut BytesMut, payload: &[u8]) -> std::result::Result<(), ERPCError> {
+        if self.frame_checksums.load(Ordering::Relaxed) {
+            self.length_prefix.frame_into(dst, &checksum::seal(payload))
+        } else {
+            self.length_prefix.frame_into(dst, payload)
         }
     }
 
-    /// Start the process and connect to it
-    pub async fn start(&mut self) -> std::result::Result<(), ERPCError> {
-        use tokio::process::Command;
-
-        let mut child = Command::new(&self.command)
-            .args(&self.args)
-            .stdout(std::process::Stdio::piped())
-            .spawn()
-            .map_err(|e| ERPCError::ProcessError(e.to_string()))?;
-
-        // Read port from stdout
-        if let Some(stdout) = child.stdout.take() {
-            use tokio::io::AsyncBufReadExt;
-            let reader = tokio::io::BufReader::new(stdout);
-            let mut lines = reader.lines();
-
-            if let Some(line) = lines
-                .next_line()
-                .await
-                .map_err(|e| ERPCError::ProcessError(e.to_string()))?
-            {
-                let port: u16 = line
-                    .trim()
-                    .parse()
-                    .map_err(|_| ERPCError::ProcessError("Invalid port format".to_string()))?;
+    /// Hand a frame to the configured wire tap
+    fn tap(&self, direction: Direction, payload: &[u8]) {
+        if let Some(tap) = &self.wire_tap {
+            tap.record(
+                direction,
+                self.connection_id,
+                self.peer_addr.into(),
+                self.clock.now(),
+                payload,
+            );
+        }
+    }
 
-                self.port = Some(port);
+    /// Write a framed message
+    async fn write(&self, framed: &[u8]) -> std::result::Result<(), ERPCError> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(framed).await.map_err(ERPCError::Io)
+    }
 
-                // Wait a bit for the server to start
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    /// Register a call awaiting the reply to `uid`
+    fn expect(
+        &self,
+        uid: u64,
+    ) -> std::result::Result<oneshot::Receiver<std::result::Result<Message, ERPCError>>, ERPCError>
+    {
+        let mut replies = self.replies.lock().unwrap();
+        if replies.closed {
+            return Err(ERPCError::ConnectionClosed);
+        }
+        let (tx, rx) = oneshot::channel();
+        replies.waiting.insert(uid, tx);
+        replies.uids.sent(uid);
+        Ok(rx)
+    }
 
-                // Connect to the server
-                let client = Client::connect(format!("127.0.0.1:{}", port)).await?;
-                self.client = Some(client);
+    /// Drop a call whose message never went out
+    fn forget(&self, uid: u64) {
+        let mut replies = self.replies.lock().unwrap();
+        replies.waiting.remove(&uid);
+        replies.uids.outstanding.remove(&uid);
+    }
 
-                Ok(())
-            } else {
-                Err(ERPCError::ProcessError(
-                    "No port received from process".to_string(),
-                ))
-            }
-        } else {
-            Err(ERPCError::ProcessError(
-                "No stdout from process".to_string(),
-            ))
+    /// Read the connection until it fails, then fail the calls still
+    /// waiting
+    async fn read(self: Arc<Self>, mut reader: ReadHalf<Box<dyn Transport>>) {
+        let e = self.read_frames(&mut reader).await;
+        debug!("Stopped reading from {}: {}", self.peer, e);
+        let mut replies = self.replies.lock().unwrap();
+        replies.closed = true;
+        for (_, tx) in replies.waiting.drain() {
+            let _ = tx.send(Err(closing_error(&e)));
         }
     }
 
-    /// Get the underlying client
-    pub fn client(&self) -> Option<&Client> {
-        self.client.as_ref()
+    /// Dispatch frames as they arrive, returning the error that ended the
+    /// connection
+    async fn read_frames(self: &Arc<Self>, reader: &mut ReadHalf<Box<dyn Transport>>) -> ERPCError {
+        let sizer = ReadSizer::default().length_prefix(self.length_prefix);
+        let mut buffer = BytesMut::new();
+        loop {
+            if let Err(e) = self.dispatch_frames(&mut buffer) {
+                return e;
+            }
+            sizer.prepare(&mut buffer);
+            match reader.read_buf(&mut buffer).await {
+                Ok(0) => return ERPCError::ConnectionClosed,
+                Ok(_) => {}
+                Err(e) => return ERPCError::Io(e),
+            }
+        }
     }
 
-    /// Get the port number
-    pub fn port(&self) -> Option<u16> {
-        self.port
-    }
+    /// Dispatch every complete frame in `buffer`
+    fn dispatch_frames(
+        self: &Arc<Self>,
+        buffer: &mut BytesMut,
+    ) -> std::result::Result<(), ERPCError> {
+        while let Some(message_bytes) = self
+            .length_prefix
+            .next_frame(buffer, self.skip_frame_whitespace)?
+        {
+            self.tap(Direction::Inbound, &message_bytes);
+            let required = self.frame_checksums.load(Ordering::Relaxed);
+            let message_bytes = checksum::open(message_bytes, required)?;
+            let message_str = std::str::from_utf8(&message_bytes)
+                .map_err(|e| ERPCError::InvalidMessageFormat(e.to_string()))?;
+            let received = self.compat.decode(message_str)?;
 
-    /// Stop the process
-    pub async fn stop(&mut self) -> std::result::Result<(), ERPCError> {
-        if let Some(client) = &self.client {
-            client.close().await?;
+            // The server's calls are numbered in its own uid space, so only
+            // replies are matched against ours
+            match received.uid_space() {
+                UidSpace::Sender => {
+                    let connection = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = connection.serve_peer(received).await {
+                            warn!("Failed to answer {}: {}", connection.peer, e);
+                        }
+                    });
+                }
+                UidSpace::Receiver => self.deliver(received),
+            }
         }
-        self.client = None;
         Ok(())
     }
 
-    /// Delegate calls to underlying client
-    pub async fn call_sync<Args, Ret>(
-        &self,
-        method: &str,
-        args: Args,
-    ) -> std::result::Result<Ret, ERPCError>
-    where
-        Args: Serialize,
-        Ret: for<'de> Deserialize<'de>,
-    {
-        if let Some(client) = &self.client {
-            client.call_sync(method, args).await
-        } else {
-            Err(ERPCError::ConnectionClosed)
+    /// Hand a reply to the call waiting for it
+    fn deliver(&self, reply: Message) {
+        let uid = reply.uid();
+        let mut replies = self.replies.lock().unwrap();
+        if let Err(e) = replies.uids.answer(uid) {
+            warn!("Discarding stray reply from {}: {}", self.peer, e);
+            self.uid_errors.record(&e);
+            return;
         }
+        let delivered = match replies.waiting.remove(&uid) {
+            Some(tx) => tx.send(Ok(reply)).is_ok(),
+            None => false,
+        };
+        if !delivered {
+            warn!("Discarding reply to call {}, which no longer waits", uid);
+        }
+    }
+
+    /// Answer a call, methods query or extension message the server sent
+    async fn serve_peer(&self, message: Message) -> std::result::Result<(), ERPCError> {
+        let reply = match message {
+            Message::Call { uid, method, args } => {
+                debug!("Serving call '{}' from {}", method, self.peer);
+                match self.registry.call_method(&method, args).await {
+                    Ok(result) => self.compat.encode(&Message::new_return(uid, result))?,
+                    Err(e) => self.compat.encode_error(uid, &e)?,
+                }
+            }
+            Message::Methods { uid } => {
+                let methods = self.registry.query_methods().await?;
+                self.compat
+                    .encode(&Message::new_return(uid, method_list(methods)))?
+            }
+            message @ Message::Extension { .. } => {
+                match self.registry.handle_extension(message).await? {
+                    Some(reply) => self.compat.encode(&reply)?,
+                    None => return Ok(()),
+                }
+            }
+            _ => return Ok(()),
+        };
+        self.tap(Direction::Outbound, reply.as_bytes());
+        let mut framed = BytesMut::new();
+        self.frame_into(&mut framed, reply.as_bytes())?;
+        self.write(&framed).await
+    }
+}
+
+/// The error a waiting call sees when the connection fails under it
+fn closing_error(e: &ERPCError) -> ERPCError {
+    match e {
+        ERPCError::ConnectionClosed => ERPCError::ConnectionClosed,
+        ERPCError::Io(e) => ERPCError::Io(std::io::Error::new(e.kind(), e.to_string())),
+        e => ERPCError::ProtocolError(e.to_string()),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use lexpr::Value;
+    use crate::protocol::Framer;
+
+    #[test]
+    fn test_uid_log_tells_stray_replies_apart() {
+        let mut uids = UidLog::default();
+        uids.sent(1);
+        uids.sent(2);
+        uids.answer(2).unwrap();
+        uids.answer(1).unwrap();
+        assert!(matches!(uids.answer(2), Err(ERPCError::DuplicateReturn(2))));
+        assert!(matches!(uids.answer(9), Err(ERPCError::UnknownUid(9))));
+    }
 
     #[tokio::test]
     async fn test_client_connection() {
@@ -300,4 +879,184 @@ mod tests {
         assert!(sexp.contains("methods"));
         assert!(sexp.contains("123"));
     }
+
+    #[tokio::test]
+    async fn test_crossed_calls_keep_their_uid_spaces() {
+        use tokio::net::TcpListener;
+
+        async fn read_frame(stream: &mut TcpStream) -> String {
+            let mut header = [0u8; 6];
+            stream.read_exact(&mut header).await.unwrap();
+            let len = usize::from_str_radix(std::str::from_utf8(&header).unwrap(), 16).unwrap();
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body).await.unwrap();
+            String::from_utf8(body).unwrap()
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let call = Message::from_sexp(&read_frame(&mut stream).await).unwrap();
+            assert_eq!(call.uid(), 1);
+
+            // The server's own call 1 crosses the client's call 1
+            let ping = Message::new_call(1, "ping", Value::Null);
+            let frame = Framer::frame(ping.to_sexp().unwrap().as_bytes());
+            stream.write_all(&frame).await.unwrap();
+            let pong = Message::from_sexp(&read_frame(&mut stream).await).unwrap();
+            assert_eq!(pong, Message::new_return(1, Value::string("pong")));
+
+            for reply in [
+                Message::new_return(7, Value::symbol("stale")),
+                Message::new_return(1, Value::symbol("done")),
+            ] {
+                let frame = Framer::frame(reply.to_sexp().unwrap().as_bytes());
+                stream.write_all(&frame).await.unwrap();
+            }
+        });
+
+        let client = Client::connect(addr.to_string()).await.unwrap();
+        client
+            .registry()
+            .register_value_method(
+                "ping",
+                |_| Ok(Value::string("pong")),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let result = client.call_value("echo", Value::Null).await.unwrap();
+        assert_eq!(result, Value::symbol("done"));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_calls_get_their_own_replies() {
+        use tokio::net::TcpListener;
+
+        async fn read_call(stream: &mut TcpStream) -> Message {
+            let mut header = [0u8; 6];
+            stream.read_exact(&mut header).await.unwrap();
+            let len = usize::from_str_radix(std::str::from_utf8(&header).unwrap(), 16).unwrap();
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body).await.unwrap();
+            Message::from_sexp(std::str::from_utf8(&body).unwrap()).unwrap()
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // Both calls must be out before either is answered
+            let mut calls = vec![read_call(&mut stream).await, read_call(&mut stream).await];
+            calls.reverse();
+            for call in calls {
+                let Message::Call { uid, args, .. } = call else {
+                    panic!("expected a call");
+                };
+                let reply = Message::new_return(uid, args);
+                let frame = Framer::frame(reply.to_sexp().unwrap().as_bytes());
+                stream.write_all(&frame).await.unwrap();
+            }
+        });
+
+        let client = Client::connect(addr.to_string()).await.unwrap();
+        let (first, second) = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            tokio::join!(
+                client.call_value("echo", Value::from(1)),
+                client.call_value("echo", Value::from(2)),
+            )
+        })
+        .await
+        .unwrap();
+        assert_eq!(first.unwrap(), Value::from(1));
+        assert_eq!(second.unwrap(), Value::from(2));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_limit() {
+        let mut server = crate::server::Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_value_method("echo", Ok, Some("args"), None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let client = Client::connect_with_config(
+            format!("127.0.0.1:{}", port),
+            ClientConfig {
+                max_in_flight: 1,
+                wait_for_capacity: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        // Occupy the only slot
+        let permit = client.in_flight.clone().try_acquire_owned().unwrap();
+        assert_eq!(client.in_flight(), 1);
+        let result = client.call_value("echo", Value::from("hi")).await;
+        assert!(matches!(result, Err(ERPCError::TooManyInFlight(1))));
+
+        drop(permit);
+        let result = client.call_value("echo", Value::from("hi")).await.unwrap();
+        assert_eq!(result, Value::from("hi"));
+        assert_eq!(client.in_flight(), 0);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "chunked")]
+    async fn test_chunked_transfer_round_trip() {
+        let mut server = crate::server::Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_chunked_sink(
+                "upload",
+                |content: String| Ok(Value::from(content.len() as u64)),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        server
+            .register_chunked_source(
+                "download",
+                |args: Value| {
+                    let n = args.to_vec().unwrap()[0].as_u64().unwrap() as usize;
+                    Ok("λx".repeat(n))
+                },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let client = Client::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let content = "line of buffer text\n".repeat(5000);
+        let result = client
+            .send_buffer_chunked("upload", &content, 4096)
+            .await
+            .unwrap();
+        assert_eq!(result, Value::from(content.len() as u64));
+
+        let downloaded = client
+            .recv_buffer_chunked("download", Value::list(vec![Value::from(20000u64)]), 1000)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, "λx".repeat(20000));
+
+        server.shutdown().await.unwrap();
+    }
 }
@@ -0,0 +1,86 @@
+//! Wall clock of clients and servers
+//!
+//! The frames the [`WireTap`](crate::wiretap::WireTap) of a client or server
+//! sees are stamped by the [`Clock`] on its configuration. [`SystemClock`]
+//! reads the real time; tests set a [`ManualClock`] instead, together with a
+//! fixed [`UidStrategy`](crate::uid::UidStrategy), so that recorded traces
+//! come out the same on every run:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::{Duration, UNIX_EPOCH};
+//!
+//! use elrpc::clock::{Clock, ManualClock};
+//! use elrpc::{ClientConfig, UidGenerator};
+//!
+//! let clock = Arc::new(ManualClock::new(UNIX_EPOCH).tick(Duration::from_millis(1)));
+//! let config = ClientConfig {
+//!     clock: clock.clone(),
+//!     uid_strategy: Arc::new(UidGenerator::new()),
+//!     ..Default::default()
+//! };
+//! assert_eq!(clock.now(), UNIX_EPOCH);
+//! assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_millis(1));
+//! ```
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Source of the current time
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's real time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Time that only moves when told to
+///
+/// With a [`tick`](ManualClock::tick), every reading also advances the clock
+/// by that much, so consecutive timestamps stay distinct and ordered.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+    tick: Duration,
+}
+
+impl ManualClock {
+    /// Clock standing at `start`
+    pub fn new(start: SystemTime) -> Self {
+        ManualClock {
+            now: Mutex::new(start),
+            tick: Duration::ZERO,
+        }
+    }
+
+    /// Advance by `tick` after every reading
+    pub fn tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        let mut now = self.now.lock().unwrap();
+        let reading = *now;
+        *now += self.tick;
+        reading
+    }
+}
@@ -0,0 +1,304 @@
+//! Rust client stubs generated from a server's method list
+//!
+//! [`client_module`] turns the answer to a `methods` query into Rust source
+//! with one async function per method, so Rust callers of a Python or Elisp
+//! server get compile errors instead of runtime ones when its interface
+//! changes. Parameters come from each method's argument spec: plain names
+//! become required parameters, names after `&optional` become `Option`s and
+//! a `&rest` (or python-style `*args`) name collects the remaining arguments.
+//! Methods without a usable spec take their argument list as one [`Value`].
+//!
+//! [`Value`]: lexpr::Value
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::registry::MethodInfo;
+
+/// Words that cannot be used as identifiers, even in raw form
+const NON_RAW_KEYWORDS: &[&str] = &["self", "Self", "super", "crate", "_"];
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false",
+    "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "static", "struct", "trait", "true", "type", "unsafe", "use", "where", "while",
+    "abstract", "become", "box", "do", "final", "gen", "macro", "override", "priv", "try",
+    "typeof", "unsized", "virtual", "yield",
+];
+
+/// One parameter of a generated function
+#[derive(Debug, PartialEq)]
+enum Param {
+    Required(String),
+    Optional(String),
+    Rest(String),
+}
+
+/// Parameters described by an argument spec such as `(a b &optional c)`
+///
+/// Returns `None` when the spec is missing or cannot be read.
+fn parse_arg_spec(spec: Option<&str>) -> Option<Vec<Param>> {
+    let spec = spec?.trim().trim_start_matches('(').trim_end_matches(')');
+    let mut params = Vec::new();
+    let mut optional = false;
+    let mut rest = false;
+
+    for token in spec.split(|c: char| c.is_whitespace() || c == ',') {
+        // python-epc specs may carry defaults (`b=1`) or stars (`*args`)
+        let (name, has_default) = match token.split_once('=') {
+            Some((name, _)) => (name.trim(), true),
+            None => (token.trim(), false),
+        };
+        match name {
+            "" => continue,
+            "&optional" => optional = true,
+            "&rest" | "&body" => rest = true,
+            _ if name.starts_with("**") => return None,
+            _ if name.starts_with('*') => params.push(Param::Rest(name[1..].to_string())),
+            _ if rest => params.push(Param::Rest(name.to_string())),
+            _ if optional || has_default => params.push(Param::Optional(name.to_string())),
+            _ => params.push(Param::Required(name.to_string())),
+        }
+        if matches!(params.last(), Some(Param::Rest(_))) {
+            break;
+        }
+    }
+
+    // A spec naming something that is not a parameter list is not used
+    let valid = params.iter().all(|param| {
+        let (Param::Required(name) | Param::Optional(name) | Param::Rest(name)) = param;
+        !name.is_empty() && !name.contains(['(', ')', '"'])
+    });
+    valid.then_some(params)
+}
+
+/// A snake_case Rust identifier for `name`, not yet in `taken`
+fn identifier(name: &str, taken: &mut HashSet<String>) -> String {
+    let mut ident: String = name
+        .trim_start_matches(['&', '*'])
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    if NON_RAW_KEYWORDS.contains(&ident.as_str()) {
+        ident.push('_');
+    }
+
+    let base = ident.clone();
+    let mut suffix = 1;
+    while !taken.insert(ident.clone()) {
+        suffix += 1;
+        ident = format!("{}_{}", base, suffix);
+    }
+    if KEYWORDS.contains(&ident.as_str()) {
+        ident.insert_str(0, "r#");
+    }
+    ident
+}
+
+fn write_function(out: &mut String, method: &MethodInfo, taken: &mut HashSet<String>) {
+    let name = identifier(&method.name, taken);
+    let docstring = method.docstring.as_deref().unwrap_or_default().trim();
+    for line in docstring.lines() {
+        let _ = writeln!(out, "/// {}", line.trim_end());
+    }
+    if !docstring.is_empty() {
+        out.push_str("///\n");
+    }
+    let _ = writeln!(
+        out,
+        "/// Calls `{}` with arguments `{}`.",
+        method.name.replace('`', "'"),
+        method
+            .arg_spec
+            .as_deref()
+            .unwrap_or("...")
+            .replace('`', "'")
+    );
+
+    let literal = format!("{:?}", method.name);
+    let Some(params) = parse_arg_spec(method.arg_spec.as_deref()) else {
+        let _ = writeln!(
+            out,
+            "pub async fn {}(client: &Client, args: Value) -> Result<Value> {{\n    \
+             client.call_value({}, args).await\n}}",
+            name, literal
+        );
+        return;
+    };
+
+    // Names used by the generated body stay, arguments are renamed around them
+    let mut locals: HashSet<String> = ["client", "args", "optional", "given"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let params: Vec<(String, &Param)> = params
+        .iter()
+        .map(|param| {
+            let (Param::Required(arg) | Param::Optional(arg) | Param::Rest(arg)) = param;
+            (identifier(arg, &mut locals), param)
+        })
+        .collect();
+
+    let _ = write!(out, "pub async fn {}(client: &Client", name);
+    for (ident, param) in &params {
+        let ty = match param {
+            Param::Required(_) => "impl Into<Value>",
+            Param::Optional(_) => "Option<Value>",
+            Param::Rest(_) => "Vec<Value>",
+        };
+        let _ = write!(out, ", {}: {}", ident, ty);
+    }
+    out.push_str(") -> Result<Value> {\n");
+
+    let required: Vec<&str> = params
+        .iter()
+        .filter(|(_, param)| matches!(param, Param::Required(_)))
+        .map(|(ident, _)| ident.as_str())
+        .collect();
+    let optional: Vec<&str> = params
+        .iter()
+        .filter(|(_, param)| matches!(param, Param::Optional(_)))
+        .map(|(ident, _)| ident.as_str())
+        .collect();
+    let rest = params
+        .iter()
+        .find(|(_, param)| matches!(param, Param::Rest(_)))
+        .map(|(ident, _)| ident.as_str());
+
+    let _ = writeln!(
+        out,
+        "    {}args: Vec<Value> = vec![{}];",
+        if optional.is_empty() && rest.is_none() {
+            "let "
+        } else {
+            "let mut "
+        },
+        required
+            .iter()
+            .map(|ident| format!("{}.into()", ident))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    if !optional.is_empty() {
+        // Trailing optional arguments that were not given are left out
+        let trailing = "optional.iter().rposition(Option::is_some).map_or(0, |i| i + 1)";
+        let given = match rest {
+            Some(rest) => format!(
+                "if {}.is_empty() {{ {} }} else {{ optional.len() }}",
+                rest, trailing
+            ),
+            None => trailing.to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "    let optional = [{}];\n    \
+             let given = {};\n    \
+             args.extend(optional.into_iter().take(given).map(|arg| arg.unwrap_or(Value::Nil)));",
+            optional.join(", "),
+            given
+        );
+    }
+    if let Some(rest) = rest {
+        let _ = writeln!(out, "    args.extend({});", rest);
+    }
+    let _ = writeln!(
+        out,
+        "    client.call_value({}, Value::list(args)).await\n}}",
+        literal
+    );
+}
+
+/// Rust source of a client module for `methods`
+///
+/// Optional arguments are positional as in Emacs Lisp: a `None` is sent as
+/// `nil` when a later argument is given and left out otherwise.
+pub fn client_module(methods: &[MethodInfo], source: &str) -> String {
+    let mut methods: Vec<&MethodInfo> = methods.iter().collect();
+    methods.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut out = String::new();
+    let _ = writeln!(out, "//! Client for the EPC server at {}", source);
+    out.push_str(
+        "//!\n//! Generated by elrpc from a `methods` query; regenerate instead of editing.\n\n\
+         #![allow(dead_code, clippy::all)]\n\n\
+         use elrpc::{Client, Result};\nuse lexpr::Value;\n",
+    );
+
+    let mut taken = HashSet::new();
+    for method in methods {
+        out.push('\n');
+        write_function(&mut out, method, &mut taken);
+    }
+    out
+}
+
+/// Query the methods of the server at `addr` and generate a client module
+pub async fn generate_client(addr: &str) -> std::result::Result<String, ERPCError> {
+    let client = Client::connect(addr).await?;
+    let methods = client.query_methods().await?;
+    client.close().await?;
+    Ok(client_module(&methods, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arg_specs() {
+        assert_eq!(
+            parse_arg_spec(Some("(a b &optional c &rest more)")),
+            Some(vec![
+                Param::Required("a".to_string()),
+                Param::Required("b".to_string()),
+                Param::Optional("c".to_string()),
+                Param::Rest("more".to_string()),
+            ])
+        );
+        assert_eq!(
+            parse_arg_spec(Some("(x, y=1, *args)")),
+            Some(vec![
+                Param::Required("x".to_string()),
+                Param::Optional("y".to_string()),
+                Param::Rest("args".to_string()),
+            ])
+        );
+        assert_eq!(parse_arg_spec(Some("(**kwargs)")), None);
+        assert_eq!(parse_arg_spec(None), None);
+    }
+
+    #[test]
+    fn test_client_module() {
+        let methods = vec![
+            MethodInfo::new("echo", None::<&str>, Some("Echo back the arguments")),
+            MethodInfo::new("find-file", Some("(type &optional client)"), None::<&str>),
+        ];
+        let module = client_module(&methods, "127.0.0.1:9000");
+
+        assert!(module.contains(
+            "/// Echo back the arguments\n///\n/// Calls `echo` with arguments `...`.\n\
+             pub async fn echo(client: &Client, args: Value) -> Result<Value> {\n    \
+             client.call_value(\"echo\", args).await\n}\n"
+        ));
+        assert!(module.contains(
+            "pub async fn find_file(client: &Client, r#type: impl Into<Value>, \
+             client_2: Option<Value>) -> Result<Value> {\n    \
+             let mut args: Vec<Value> = vec![r#type.into()];\n    \
+             let optional = [client_2];\n    \
+             let given = optional.iter().rposition(Option::is_some).map_or(0, |i| i + 1);\n    \
+             args.extend(optional.into_iter().take(given).map(|arg| arg.unwrap_or(Value::Nil)));\n    \
+             client.call_value(\"find-file\", Value::list(args)).await\n}\n"
+        ));
+    }
+}
@@ -0,0 +1,241 @@
+//! Compatibility profiles for peers that bend the protocol
+//!
+//! `epc.el` defines the wire format this crate speaks by default. python-epc
+//! differs from it in three ways:
+//!
+//! - the arguments of a call arrive nested one level deeper, so `add(1, 2)`
+//!   is `(call 1 add ((1 2)))` rather than `(call 1 add (1 2))`;
+//! - `return-error` and `epc-error` carry a `(CLASS MESSAGE BACKTRACE)` list
+//!   instead of a string;
+//! - method names in calls are strings rather than symbols.
+//!
+//! [`Compat::PythonEpc`] undoes these quirks on inbound frames and reproduces
+//! them on outbound ones. A client picks its profile with
+//! [`ClientConfig::compat`](crate::client::ClientConfig); a server applies
+//! [`ServerConfig::compat`](crate::server::ServerConfig) to every connection,
+//! or asks a [`CompatSelector`] for each new one.
+//!
+//! Because a nested argument list is recognised by its shape, a call with a
+//! single list argument from a python-epc peer is indistinguishable from a
+//! nested call; such calls are always unwrapped.
+
+use std::fmt;
+use std::sync::Arc;
+
+use lexpr::Value;
+
+use crate::context::Peer;
+use crate::error::ERPCError;
+use crate::protocol::Message;
+
+/// Quirks expected from the peer of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compat {
+    /// The `epc.el` wire format
+    #[default]
+    Standard,
+    /// The python-epc wire format, see the module documentation
+    PythonEpc,
+}
+
+impl Compat {
+    /// Parse an inbound payload into a standard message
+    ///
+    /// A python-epc error reply becomes the matching error right away, so its
+    /// class and backtrace are not flattened into a string.
+    pub(crate) fn decode(self, payload: &str) -> std::result::Result<Message, ERPCError> {
+        if self == Compat::Standard {
+            return Message::from_sexp(payload);
+        }
+
+        let value = lexpr::from_str(payload)?;
+        let Some(mut items) = value.to_vec() else {
+            return Message::from_value(value);
+        };
+        match items.as_mut_slice() {
+            [tag, _, _, args] if tag.as_symbol() == Some("call") => {
+                if let Some(mut nested) = args
+                    .to_vec()
+                    .filter(|nested| nested.len() == 1 && nested[0].is_list())
+                {
+                    *args = nested.pop().unwrap();
+                }
+            }
+            [tag, _, error] if error.is_cons() => match tag.as_symbol() {
+                Some("return-error") => {
+                    let (class, message, backtrace) = error_fields(error);
+                    return Err(ERPCError::ApplicationError {
+                        class,
+                        message,
+                        backtrace,
+                    });
+                }
+                Some("epc-error") => {
+                    let (class, message, _) = error_fields(error);
+                    return Err(ERPCError::ProtocolError(format!("{}: {}", class, message)));
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        Message::from_value(Value::list(items))
+    }
+
+    /// Serialize an outbound message the way the peer expects it
+    pub(crate) fn encode(self, message: &Message) -> std::result::Result<String, ERPCError> {
+        let value = match (self, message) {
+            (Compat::Standard, _) => return message.to_sexp(),
+            (Compat::PythonEpc, Message::Call { uid, method, args }) => Value::list(vec![
+                Value::symbol("call"),
+                Value::from(*uid as i64),
+                Value::string(method.as_str()),
+                Value::list(vec![args.clone()]),
+            ]),
+            (Compat::PythonEpc, Message::ReturnError { uid, error }) => {
+                error_reply("return-error", *uid, "RuntimeError", error, &[])
+            }
+            (Compat::PythonEpc, Message::EPCError { uid, error }) => {
+                error_reply("epc-error", *uid, "EPCError", error, &[])
+            }
+            (Compat::PythonEpc, _) => message.to_value(),
+        };
+        lexpr::to_string(&value).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Serialize the `return-error` reply for a failed call
+    ///
+    /// python-epc peers get the class and backtrace of an
+    /// [`ERPCError::ApplicationError`] as separate fields.
+    pub(crate) fn encode_error(
+        self,
+        uid: u64,
+        error: &ERPCError,
+    ) -> std::result::Result<String, ERPCError> {
+        let value = match (self, error) {
+            (Compat::Standard, _) => {
+                return Message::new_return_error(uid, error.to_string()).to_sexp()
+            }
+            (
+                Compat::PythonEpc,
+                ERPCError::ApplicationError {
+                    class,
+                    message,
+                    backtrace,
+                },
+            ) => error_reply("return-error", uid, class, message, backtrace),
+            (Compat::PythonEpc, _) => {
+                error_reply("return-error", uid, "RuntimeError", &error.to_string(), &[])
+            }
+        };
+        lexpr::to_string(&value).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+}
+
+/// `(TAG UID (CLASS MESSAGE BACKTRACE))`
+fn error_reply(tag: &str, uid: u64, class: &str, message: &str, backtrace: &[String]) -> Value {
+    Value::list(vec![
+        Value::symbol(tag),
+        Value::from(uid as i64),
+        Value::list(vec![
+            Value::string(class),
+            Value::string(message),
+            Value::list(backtrace.iter().map(|line| Value::string(line.as_str()))),
+        ]),
+    ])
+}
+
+/// Fields of a `(CLASS MESSAGE BACKTRACE)` list; the backtrace may be a
+/// list of lines or one string
+fn error_fields(error: &Value) -> (String, String, Vec<String>) {
+    fn text(value: &Value) -> String {
+        value
+            .as_str()
+            .map_or_else(|| value.to_string(), str::to_string)
+    }
+
+    let parts = error.to_vec().unwrap_or_default();
+    let backtrace = match parts.get(2) {
+        Some(Value::String(trace)) => trace.lines().map(str::to_string).collect(),
+        Some(lines) => lines.list_iter().into_iter().flatten().map(text).collect(),
+        None => Vec::new(),
+    };
+    (
+        parts.first().map(text).unwrap_or_default(),
+        parts.get(1).map(text).unwrap_or_default(),
+        backtrace,
+    )
+}
+
+/// Callback choosing the profile of a new connection from its peer address
+pub type CompatFn = dyn Fn(Peer) -> Compat + Send + Sync;
+
+/// Per-connection choice of [`Compat`] profile on a server
+#[derive(Clone)]
+pub struct CompatSelector(Arc<CompatFn>);
+
+impl fmt::Debug for CompatSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompatSelector(..)")
+    }
+}
+
+impl CompatSelector {
+    /// Choose profiles with `select`
+    pub fn new(select: impl Fn(Peer) -> Compat + Send + Sync + 'static) -> Self {
+        CompatSelector(Arc::new(select))
+    }
+
+    pub(crate) fn select(&self, peer: Peer) -> Compat {
+        (self.0)(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_python_epc_calls() {
+        let compat = Compat::PythonEpc;
+        let call = compat.decode("(call 1 \"add\" ((1 2)))").unwrap();
+        assert_eq!(
+            call,
+            Message::new_call(1, "add", Value::list(vec![Value::from(1), Value::from(2)]))
+        );
+        assert_eq!(compat.encode(&call).unwrap(), "(call 1 \"add\" ((1 2)))");
+
+        // Unnested arguments pass through unchanged
+        let call = compat.decode("(call 2 add (1 2))").unwrap();
+        assert_eq!(
+            call,
+            Message::new_call(2, "add", Value::list(vec![Value::from(1), Value::from(2)]))
+        );
+    }
+
+    #[test]
+    fn test_python_epc_errors() {
+        let compat = Compat::PythonEpc;
+        let error = compat
+            .decode("(return-error 3 (\"ValueError\" \"bad value\" \"line 1\nline 2\"))")
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ERPCError::ApplicationError { class, message, backtrace }
+                if class == "ValueError" && message == "bad value" && backtrace.len() == 2
+        ));
+
+        let error = ERPCError::ApplicationError {
+            class: "KeyError".to_string(),
+            message: "missing".to_string(),
+            backtrace: vec!["frame".to_string()],
+        };
+        assert_eq!(
+            compat.encode_error(4, &error).unwrap(),
+            "(return-error 4 (\"KeyError\" \"missing\" (\"frame\")))"
+        );
+        assert_eq!(
+            Compat::Standard.encode_error(4, &error).unwrap(),
+            "(return-error 4 \"application error: KeyError: missing\")"
+        );
+    }
+}
@@ -0,0 +1,373 @@
+//! Conformance checks for EPC servers
+//!
+//! [`Conformance`] connects to a server, ours or anyone's, and runs it
+//! through the parts of the protocol peers trip over: frames split across
+//! writes or sharing one, multibyte text, large payloads, the `methods`
+//! query, errors carrying the uid of the failed call and garbage on the
+//! wire. Each check uses a connection of its own and is reported as passed,
+//! failed or skipped:
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use elrpc::conformance::Conformance;
+//!
+//! let report = Conformance::new("127.0.0.1:12345")
+//!     .echo_method("echo")
+//!     .run()
+//!     .await;
+//! print!("{}", report);
+//! assert!(report.is_success());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Without an echo method, which returns its arguments, payloads are sent
+//! to a method that does not exist, and only the framing of the error reply
+//! is checked. `epc-cli HOST:PORT conformance [ECHO]` runs the same
+//! checks from the command line.
+
+use std::fmt;
+use std::time::Duration;
+
+use lexpr::Value;
+
+use crate::error::ERPCError;
+use crate::protocol::{Framer, Message};
+use crate::testing::MockClient;
+
+/// Method the checks call expecting it not to exist
+const MISSING_METHOD: &str = "elrpc-conformance-no-such-method";
+
+/// Text mixing two, three and four byte UTF-8 sequences
+const UNICODE_TEXT: &str = "λx → 日本語 \u{1f389} «ü»";
+
+/// How one check went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    /// Not run, e.g. because it needs an echo method
+    Skipped(String),
+}
+
+/// Outcome of one named check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+/// Outcomes of all checks, in the order they ran
+#[derive(Debug, Clone, Default)]
+pub struct ConformanceReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl ConformanceReport {
+    /// Whether no check failed
+    pub fn is_success(&self) -> bool {
+        self.failed().next().is_none()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks
+            .iter()
+            .filter(|check| matches!(check.outcome, Outcome::Failed(_)))
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+        for check in &self.checks {
+            match &check.outcome {
+                Outcome::Passed => {
+                    passed += 1;
+                    writeln!(f, "PASS {}", check.name)?;
+                }
+                Outcome::Failed(reason) => {
+                    failed += 1;
+                    writeln!(f, "FAIL {}: {}", check.name, reason)?;
+                }
+                Outcome::Skipped(reason) => {
+                    skipped += 1;
+                    writeln!(f, "SKIP {}: {}", check.name, reason)?;
+                }
+            }
+        }
+        writeln!(
+            f,
+            "{} checks: {} passed, {} failed, {} skipped",
+            self.checks.len(),
+            passed,
+            failed,
+            skipped
+        )
+    }
+}
+
+/// Conformance run against one server
+#[derive(Debug, Clone)]
+pub struct Conformance {
+    addr: String,
+    echo_method: Option<String>,
+    timeout: Duration,
+    large_payload: usize,
+}
+
+impl Conformance {
+    /// Check the server at `addr`, waiting up to five seconds per reply
+    pub fn new(addr: impl Into<String>) -> Self {
+        Conformance {
+            addr: addr.into(),
+            echo_method: None,
+            timeout: Duration::from_secs(5),
+            large_payload: 1 << 20,
+        }
+    }
+
+    /// Method returning its arguments, for round-trip checks
+    pub fn echo_method(mut self, method: impl Into<String>) -> Self {
+        self.echo_method = Some(method.into());
+        self
+    }
+
+    /// How long to wait for each reply
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Size in bytes of the string sent by the large payload check, 1 MiB
+    /// by default
+    pub fn large_payload(mut self, bytes: usize) -> Self {
+        self.large_payload = bytes;
+        self
+    }
+
+    /// Run every check
+    pub async fn run(&self) -> ConformanceReport {
+        let mut report = ConformanceReport::default();
+        report
+            .checks
+            .push(self.check("methods-query", self.methods_query()).await);
+        report
+            .checks
+            .push(self.check("unknown-method", self.unknown_method()).await);
+        report
+            .checks
+            .push(self.check("split-frame", self.split_frame()).await);
+        report.checks.push(
+            self.check("pipelined-frames", self.pipelined_frames())
+                .await,
+        );
+        report.checks.push(
+            self.check("unicode", self.round_trip(UNICODE_TEXT.to_string()))
+                .await,
+        );
+        report.checks.push(
+            self.check(
+                "large-payload",
+                self.round_trip("x".repeat(self.large_payload)),
+            )
+            .await,
+        );
+        report
+            .checks
+            .push(self.check("malformed-frame", self.malformed_frame()).await);
+        report
+            .checks
+            .push(self.check("echo-values", self.echo_values()).await);
+        report
+    }
+
+    async fn check(
+        &self,
+        name: &'static str,
+        check: impl std::future::Future<Output = std::result::Result<Outcome, ERPCError>>,
+    ) -> CheckResult {
+        let outcome = check
+            .await
+            .unwrap_or_else(|e| Outcome::Failed(e.to_string()));
+        CheckResult { name, outcome }
+    }
+
+    async fn connect(&self) -> std::result::Result<MockClient, ERPCError> {
+        let mut client = MockClient::connect(self.addr.as_str()).await?;
+        client.set_timeout(self.timeout);
+        Ok(client)
+    }
+
+    async fn methods_query(&self) -> std::result::Result<Outcome, ERPCError> {
+        let mut client = self.connect().await?;
+        client.send(&Message::new_methods(1)).await?;
+        Ok(match reply_to(&mut client, 1).await? {
+            Message::Return { result, .. } if result.is_list() || result.is_null() => {
+                Outcome::Passed
+            }
+            other => Outcome::Failed(format!("expected a list of methods, got {:?}", other)),
+        })
+    }
+
+    async fn unknown_method(&self) -> std::result::Result<Outcome, ERPCError> {
+        let mut client = self.connect().await?;
+        let call = Message::new_call(2, MISSING_METHOD, Value::Null);
+        client.send(&call).await?;
+        Ok(expect_error(reply_to(&mut client, 2).await?))
+    }
+
+    async fn split_frame(&self) -> std::result::Result<Outcome, ERPCError> {
+        let mut client = self.connect().await?;
+        let frame = Framer::frame(Message::new_methods(3).to_sexp()?.as_bytes());
+        for byte in frame.iter() {
+            client.send_bytes(&[*byte]).await?;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        reply_to(&mut client, 3).await?;
+        Ok(Outcome::Passed)
+    }
+
+    async fn pipelined_frames(&self) -> std::result::Result<Outcome, ERPCError> {
+        let mut client = self.connect().await?;
+        let mut frames = Framer::frame(Message::new_methods(4).to_sexp()?.as_bytes()).to_vec();
+        frames.extend_from_slice(&Framer::frame(
+            Message::new_methods(5).to_sexp()?.as_bytes(),
+        ));
+        client.send_bytes(&frames).await?;
+
+        let mut pending = vec![4, 5];
+        while !pending.is_empty() {
+            let uid = client.receive().await?.uid();
+            pending.retain(|&expected| expected != uid);
+        }
+        Ok(Outcome::Passed)
+    }
+
+    /// Send `text` through the echo method, or to a missing method followed
+    /// by a `methods` query in the same write, which is only answered if the
+    /// server counted the bytes of the first frame right
+    async fn round_trip(&self, text: String) -> std::result::Result<Outcome, ERPCError> {
+        let mut client = self.connect().await?;
+        let args = Value::list(vec![Value::string(text.as_str())]);
+        let Some(echo) = &self.echo_method else {
+            let call = Message::new_call(6, MISSING_METHOD, args);
+            let mut frames = Framer::frame(call.to_sexp()?.as_bytes()).to_vec();
+            frames.extend_from_slice(&Framer::frame(
+                Message::new_methods(7).to_sexp()?.as_bytes(),
+            ));
+            client.send_bytes(&frames).await?;
+            let outcome = expect_error(reply_to(&mut client, 6).await?);
+            reply_to(&mut client, 7).await?;
+            return Ok(outcome);
+        };
+
+        client
+            .send(&Message::new_call(6, echo.as_str(), args.clone()))
+            .await?;
+        Ok(match reply_to(&mut client, 6).await? {
+            Message::Return { result, .. } if echoes(&result, &args, &text) => Outcome::Passed,
+            Message::Return { result, .. } => Outcome::Failed(format!(
+                "{} did not return its argument, got {}",
+                echo,
+                crate::pretty::pretty(&result)
+            )),
+            other => Outcome::Failed(format!("expected a return, got {:?}", other)),
+        })
+    }
+
+    /// Round-trip a mix of atoms and nested lists through the echo method
+    async fn echo_values(&self) -> std::result::Result<Outcome, ERPCError> {
+        let Some(echo) = &self.echo_method else {
+            return Ok(Outcome::Skipped("no echo method given".to_string()));
+        };
+        let mut client = self.connect().await?;
+        let nested = Value::list(vec![
+            Value::keyword("key"),
+            Value::string("a \"quoted\" \\ line\n"),
+        ]);
+        let args = Value::list(vec![
+            Value::from(-42),
+            Value::from(2.5),
+            Value::symbol("a-symbol"),
+            nested,
+            Value::Null,
+        ]);
+        client
+            .send(&Message::new_call(10, echo.as_str(), args.clone()))
+            .await?;
+        Ok(match reply_to(&mut client, 10).await? {
+            Message::Return { result, .. } if result == args => Outcome::Passed,
+            Message::Return { result, .. } => {
+                match crate::value_diff::first_mismatch(&args, &result) {
+                    Some(mismatch) => Outcome::Failed(mismatch.to_string()),
+                    None => Outcome::Passed,
+                }
+            }
+            other => Outcome::Failed(format!("expected a return, got {:?}", other)),
+        })
+    }
+
+    async fn malformed_frame(&self) -> std::result::Result<Outcome, ERPCError> {
+        let mut client = self.connect().await?;
+        client.send_payload(b"(call 8 (unbalanced").await?;
+        client.send(&Message::new_methods(9)).await?;
+        Ok(match reply_to(&mut client, 9).await {
+            Ok(_) => Outcome::Passed,
+            Err(ERPCError::ConnectionClosed) => {
+                Outcome::Failed("server closed the connection".to_string())
+            }
+            Err(e) => return Err(e),
+        })
+    }
+}
+
+/// Next message with `uid`, skipping others such as errors about earlier
+/// garbage
+async fn reply_to(client: &mut MockClient, uid: u64) -> std::result::Result<Message, ERPCError> {
+    loop {
+        let message = client.receive().await?;
+        if message.uid() == uid {
+            return Ok(message);
+        }
+    }
+}
+
+fn expect_error(reply: Message) -> Outcome {
+    match reply {
+        Message::ReturnError { .. } | Message::EPCError { .. } => Outcome::Passed,
+        other => Outcome::Failed(format!("expected an error reply, got {:?}", other)),
+    }
+}
+
+/// Whether an echo method's result holds `text`, as its argument list or
+/// its only argument
+fn echoes(result: &Value, args: &Value, text: &str) -> bool {
+    result == args || result.as_str() == Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_counts_outcomes() {
+        let report = ConformanceReport {
+            checks: vec![
+                CheckResult {
+                    name: "methods-query",
+                    outcome: Outcome::Passed,
+                },
+                CheckResult {
+                    name: "unicode",
+                    outcome: Outcome::Failed("garbled".to_string()),
+                },
+            ],
+        };
+        assert!(!report.is_success());
+        assert_eq!(report.failed().count(), 1);
+        assert_eq!(
+            report.to_string(),
+            "PASS methods-query\nFAIL unicode: garbled\n2 checks: 1 passed, 1 failed, 0 skipped\n"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Information about the call a handler is serving
+//!
+//! Handlers take only their arguments, so who is calling is published in a
+//! task-local [`CallContext`] while the handler runs on the server:
+//!
+//! ```
+//! use elrpc::context::CallContext;
+//!
+//! fn whoami(_: ()) -> elrpc::Result<Option<u32>> {
+//!     Ok(CallContext::current().and_then(|cx| cx.credentials).map(|creds| creds.uid))
+//! }
+//! ```
+//!
+//! The context is set for typed, value and arena methods. Handlers
+//! registered with `register_cpu_method` run on a thread pool and do not see
+//! it, and neither does code a handler spawns onto other tasks.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::auth::Identity;
+use crate::metadata::CallMetadata;
+
+/// Identity of a process connected over a Unix socket, as reported by the
+/// kernel (`SO_PEERCRED` on Linux)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not every platform reports the process
+    pub pid: Option<i32>,
+}
+
+/// Where a peer is connected from
+///
+/// Unix socket peers have no address, so they are a kind of their own:
+/// address-based rules such as ACL blocks, peer filters and abuse bans never
+/// match them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A process on this machine, with its credentials when the kernel
+    /// reports them
+    Unix(Option<PeerCredentials>),
+}
+
+impl Peer {
+    /// Address of a TCP peer
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(*addr),
+            Peer::Unix(_) => None,
+        }
+    }
+
+    /// IP address of a TCP peer
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.socket_addr().map(|addr| addr.ip())
+    }
+
+    /// Credentials of a Unix socket peer
+    pub fn credentials(&self) -> Option<PeerCredentials> {
+        match self {
+            Peer::Tcp(_) => None,
+            Peer::Unix(credentials) => *credentials,
+        }
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self, Peer::Unix(_))
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer::Tcp(addr)
+    }
+}
+
+/// TCP peers print as their address, Unix socket peers as `unix`, followed
+/// by `:uid=1000,pid=4711` when their credentials are known
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(None) => f.write_str("unix"),
+            Peer::Unix(Some(credentials)) => {
+                write!(f, "unix:uid={}", credentials.uid)?;
+                if let Some(pid) = credentials.pid {
+                    write!(f, ",pid={}", pid)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The call being served
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallContext {
+    pub peer: Peer,
+    /// Connection the call arrived on, unique within the process
+    pub connection: u64,
+    pub uid: u64,
+    pub method: String,
+    /// Credentials of Unix socket peers
+    pub credentials: Option<PeerCredentials>,
+    /// Who the server's authenticator accepted the peer as
+    pub identity: Option<Identity>,
+    /// Fields the caller sent along, see the `metadata` module
+    pub metadata: Option<CallMetadata>,
+}
+
+tokio::task_local! {
+    static CURRENT: CallContext;
+}
+
+impl CallContext {
+    /// Context of the call the current task is serving, if any
+    pub fn current() -> Option<CallContext> {
+        CURRENT.try_with(CallContext::clone).ok()
+    }
+
+    /// Run `f` with this context as the current one
+    pub(crate) async fn scope<F: std::future::Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+
+    /// Run the synchronous `f` with this context as the current one
+    pub(crate) fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        CURRENT.sync_scope(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_current_inside_scope_only() {
+        let cx = CallContext {
+            peer: Peer::Unix(Some(PeerCredentials {
+                uid: 1000,
+                gid: 1000,
+                pid: Some(42),
+            })),
+            connection: 3,
+            uid: 1,
+            method: "whoami".to_string(),
+            credentials: Some(PeerCredentials {
+                uid: 1000,
+                gid: 1000,
+                pid: Some(42),
+            }),
+            identity: Some(Identity::new("alice")),
+            metadata: None,
+        };
+        assert_eq!(CallContext::current(), None);
+        let seen = cx.clone().scope(async { CallContext::current() }).await;
+        assert_eq!(seen, Some(cx));
+        assert_eq!(CallContext::current(), None);
+    }
+
+    #[test]
+    fn test_peer_kinds() {
+        let tcp = Peer::from("10.0.0.7:4000".parse::<SocketAddr>().unwrap());
+        assert_eq!(tcp.ip(), Some("10.0.0.7".parse().unwrap()));
+        assert_eq!(tcp.to_string(), "10.0.0.7:4000");
+
+        let credentials = PeerCredentials {
+            uid: 1000,
+            gid: 100,
+            pid: Some(4711),
+        };
+        let unix = Peer::Unix(Some(credentials));
+        assert!(unix.is_unix());
+        assert_eq!(unix.ip(), None);
+        assert_eq!(unix.credentials(), Some(credentials));
+        assert_eq!(unix.to_string(), "unix:uid=1000,pid=4711");
+        assert_eq!(Peer::Unix(None).to_string(), "unix");
+    }
+}
@@ -0,0 +1,618 @@
+//! Service discovery
+//!
+//! With several EPC services talking to each other, hardcoding their ports
+//! breaks as soon as one moves. A [`Broker`] keeps a directory instead:
+//! servers register their name and address with it over EPC and renew the
+//! entry before its TTL runs out, and clients resolve a name to the
+//! addresses currently registered under it. The broker's built-in methods:
+//!
+//! - [`REGISTER_SERVICE_METHOD`] `(NAME ADDR TTL [METADATA])` registers or
+//!   renews ADDR under NAME for TTL seconds, METADATA being a plist of
+//!   keywords and strings, and returns `t`,
+//! - [`DEREGISTER_SERVICE_METHOD`] `(NAME ADDR)` removes the entry, returning
+//!   `t` if there was one,
+//! - [`RESOLVE_SERVICE_METHOD`] `(NAME)` returns the live entries of NAME in
+//!   the order they were first registered, each a plist
+//!   `(:name "indexer" :addr "127.0.0.1:4001" :metadata (:version "2"))`,
+//! - [`LIST_SERVICES_METHOD`] `()` returns the names with live entries.
+//!
+//! Any peer may register, so the broker bounds what it keeps: TTLs above
+//! [`Broker::max_ttl`] and new addresses beyond
+//! [`Broker::max_entries_per_name`] are refused.
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use std::time::Duration;
+//!
+//! use elrpc::discovery::{announce, Broker, ServiceRecord};
+//! use elrpc::{Client, Server};
+//!
+//! // The broker is an ordinary server with the built-in methods
+//! let mut broker_server = Server::new();
+//! Broker::new().register_methods(broker_server.registry()).await?;
+//! let broker_addr = broker_server.bind("127.0.0.1:7070").await?.to_string();
+//! broker_server.serve().await?;
+//!
+//! // A service registers itself and keeps renewing its entry
+//! let mut indexer = Server::new();
+//! let addr = indexer.bind("127.0.0.1:0").await?;
+//! indexer.serve().await?;
+//! let registration = announce(
+//!     &broker_addr,
+//!     ServiceRecord::new("indexer", addr.to_string()),
+//!     Duration::from_secs(30),
+//! )
+//! .await?;
+//!
+//! // Clients find it by name
+//! let client = Client::connect_named("indexer", &broker_addr).await?;
+//! # registration.withdraw().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lexpr::Value;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::log::{debug, warn};
+use crate::registry::{MethodHandler, MethodInfo, MethodRegistry};
+
+pub const REGISTER_SERVICE_METHOD: &str = "elrpc-register-service";
+pub const DEREGISTER_SERVICE_METHOD: &str = "elrpc-deregister-service";
+pub const RESOLVE_SERVICE_METHOD: &str = "elrpc-resolve-service";
+pub const LIST_SERVICES_METHOD: &str = "elrpc-list-services";
+
+/// One address registered under a service name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceRecord {
+    pub name: String,
+    pub addr: String,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ServiceRecord {
+    pub fn new(name: impl Into<String>, addr: impl Into<String>) -> Self {
+        ServiceRecord {
+            name: name.into(),
+            addr: addr.into(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// Attach `value` under `key`, e.g. a version or a zone
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// The record as the plist [`RESOLVE_SERVICE_METHOD`] returns
+    pub fn to_value(&self) -> Value {
+        Value::list(vec![
+            Value::keyword("name"),
+            Value::string(self.name.as_str()),
+            Value::keyword("addr"),
+            Value::string(self.addr.as_str()),
+            Value::keyword("metadata"),
+            metadata_to_value(&self.metadata),
+        ])
+    }
+
+    /// Parse a plist returned by [`RESOLVE_SERVICE_METHOD`]
+    pub fn from_value(value: &Value) -> std::result::Result<Self, ERPCError> {
+        let fields = plist(value)?;
+        let text = |key: &str| {
+            fields
+                .get(key)
+                .and_then(|value| value.as_str())
+                .map(str::to_string)
+                .ok_or_else(|| invalid(format!("service record without :{}", key)))
+        };
+        Ok(ServiceRecord {
+            name: text("name")?,
+            addr: text("addr")?,
+            metadata: match fields.get("metadata") {
+                Some(metadata) => metadata_from_value(metadata)?,
+                None => BTreeMap::new(),
+            },
+        })
+    }
+}
+
+fn invalid(message: impl Into<String>) -> ERPCError {
+    ERPCError::InvalidArgument(message.into())
+}
+
+/// Keyword names and values of a plist
+fn plist(value: &Value) -> std::result::Result<HashMap<String, Value>, ERPCError> {
+    let items = list_items(value)?;
+    if items.len() % 2 != 0 {
+        return Err(invalid("plist with an odd number of items"));
+    }
+    items
+        .chunks(2)
+        .map(|pair| match pair[0].as_keyword() {
+            Some(key) => Ok((key.to_string(), pair[1].clone())),
+            None => Err(invalid("plist key is not a keyword")),
+        })
+        .collect()
+}
+
+fn list_items(value: &Value) -> std::result::Result<Vec<Value>, ERPCError> {
+    if value.is_null() || value.is_nil() {
+        return Ok(Vec::new());
+    }
+    value
+        .list_iter()
+        .map(|items| items.cloned().collect())
+        .ok_or_else(|| invalid("expected a list"))
+}
+
+fn metadata_to_value(metadata: &BTreeMap<String, String>) -> Value {
+    Value::list(
+        metadata
+            .iter()
+            .flat_map(|(key, value)| [Value::keyword(key.as_str()), Value::string(value.as_str())])
+            .collect::<Vec<Value>>(),
+    )
+}
+
+fn metadata_from_value(value: &Value) -> std::result::Result<BTreeMap<String, String>, ERPCError> {
+    plist(value)?
+        .into_iter()
+        .map(|(key, value)| match value.as_str() {
+            Some(text) => Ok((key, text.to_string())),
+            None => Err(invalid(format!("metadata :{} is not a string", key))),
+        })
+        .collect()
+}
+
+struct Entry {
+    record: ServiceRecord,
+    expires_at: Instant,
+}
+
+/// Directory of services, shared by the broker's built-in methods
+#[derive(Clone)]
+pub struct Broker {
+    services: Arc<Mutex<HashMap<String, Vec<Entry>>>>,
+    max_ttl: Duration,
+    max_entries: usize,
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Broker {
+            services: Arc::new(Mutex::new(HashMap::new())),
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+            max_entries: 256,
+        }
+    }
+}
+
+impl Broker {
+    /// Accept TTLs up to a day and 256 addresses per name
+    pub fn new() -> Self {
+        Broker::default()
+    }
+
+    /// Refuse registrations for longer than `ttl`
+    pub fn max_ttl(mut self, ttl: Duration) -> Self {
+        self.max_ttl = ttl;
+        self
+    }
+
+    /// Refuse new addresses for a name that has `entries` live ones
+    pub fn max_entries_per_name(mut self, entries: usize) -> Self {
+        self.max_entries = entries;
+        self
+    }
+
+    /// Register `record`, or renew it if its address is already registered
+    /// under its name, until `ttl` from now
+    ///
+    /// Fails if `ttl` exceeds the maximum, or if the address is new and its
+    /// name already has the maximum number of entries.
+    pub fn register(
+        &self,
+        record: ServiceRecord,
+        ttl: Duration,
+    ) -> std::result::Result<(), ERPCError> {
+        let expires_at = Some(ttl)
+            .filter(|ttl| *ttl <= self.max_ttl)
+            .and_then(|ttl| Instant::now().checked_add(ttl))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "TTL {:?} exceeds the maximum of {:?}",
+                    ttl, self.max_ttl
+                ))
+            })?;
+        let mut services = self.services.lock().unwrap();
+        expire(&mut services);
+        let entries = services.entry(record.name.clone()).or_default();
+        if let Some(entry) = entries
+            .iter_mut()
+            .find(|entry| entry.record.addr == record.addr)
+        {
+            *entry = Entry { record, expires_at };
+        } else if entries.len() >= self.max_entries {
+            if entries.is_empty() {
+                services.remove(&record.name);
+            }
+            return Err(ERPCError::QuotaExceeded(format!(
+                "service {} already has {} entries",
+                record.name, self.max_entries
+            )));
+        } else {
+            debug!("Registered service {} at {}", record.name, record.addr);
+            entries.push(Entry { record, expires_at });
+        }
+        Ok(())
+    }
+
+    /// Remove `addr` from `name`, returning whether it was registered
+    pub fn deregister(&self, name: &str, addr: &str) -> bool {
+        let mut services = self.services.lock().unwrap();
+        let Some(entries) = services.get_mut(name) else {
+            return false;
+        };
+        let before = entries.len();
+        entries.retain(|entry| entry.record.addr != addr);
+        let removed = entries.len() < before;
+        if entries.is_empty() {
+            services.remove(name);
+        }
+        removed
+    }
+
+    /// Live entries of `name`, in the order they were first registered
+    pub fn resolve(&self, name: &str) -> Vec<ServiceRecord> {
+        let mut services = self.services.lock().unwrap();
+        expire(&mut services);
+        services
+            .get(name)
+            .map(|entries| entries.iter().map(|entry| entry.record.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Names with live entries, sorted
+    pub fn services(&self) -> Vec<String> {
+        let mut services = self.services.lock().unwrap();
+        expire(&mut services);
+        let mut names: Vec<String> = services.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Register the built-in broker methods on `registry`
+    pub async fn register_methods(
+        &self,
+        registry: &MethodRegistry,
+    ) -> std::result::Result<(), ERPCError> {
+        for (name, op, arg_spec, docstring) in [
+            (
+                REGISTER_SERVICE_METHOD,
+                BrokerOp::Register,
+                "name addr ttl &optional metadata",
+                "Register ADDR under NAME for TTL seconds",
+            ),
+            (
+                DEREGISTER_SERVICE_METHOD,
+                BrokerOp::Deregister,
+                "name addr",
+                "Remove ADDR from NAME",
+            ),
+            (
+                RESOLVE_SERVICE_METHOD,
+                BrokerOp::Resolve,
+                "name",
+                "Return the live entries of NAME",
+            ),
+            (
+                LIST_SERVICES_METHOD,
+                BrokerOp::List,
+                "",
+                "Return the names of registered services",
+            ),
+        ] {
+            let method = BrokerMethod {
+                broker: self.clone(),
+                op,
+                info: MethodInfo::new(name, Some(arg_spec), Some(docstring)),
+            };
+            registry.register_handler(name, Arc::new(method)).await;
+        }
+        Ok(())
+    }
+}
+
+/// Drop expired entries and names left without any
+fn expire(services: &mut HashMap<String, Vec<Entry>>) {
+    let now = Instant::now();
+    services.retain(|_, entries| {
+        entries.retain(|entry| entry.expires_at > now);
+        !entries.is_empty()
+    });
+}
+
+#[derive(Clone, Copy)]
+enum BrokerOp {
+    Register,
+    Deregister,
+    Resolve,
+    List,
+}
+
+/// One of the built-in broker methods
+struct BrokerMethod {
+    broker: Broker,
+    op: BrokerOp,
+    info: MethodInfo,
+}
+
+fn string_arg(args: &[Value], index: usize, what: &str) -> std::result::Result<String, ERPCError> {
+    args.get(index)
+        .and_then(|arg| arg.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| invalid(format!("expected {} as a string", what)))
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for BrokerMethod {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        let args = list_items(&args)?;
+        match self.op {
+            BrokerOp::Register => {
+                let ttl = args
+                    .get(2)
+                    .and_then(|ttl| ttl.as_f64())
+                    .filter(|ttl| *ttl > 0.0)
+                    .and_then(|ttl| Duration::try_from_secs_f64(ttl).ok())
+                    .ok_or_else(|| invalid("expected TTL as a positive number of seconds"))?;
+                let record = ServiceRecord {
+                    name: string_arg(&args, 0, "NAME")?,
+                    addr: string_arg(&args, 1, "ADDR")?,
+                    metadata: match args.get(3) {
+                        Some(metadata) => metadata_from_value(metadata)?,
+                        None => BTreeMap::new(),
+                    },
+                };
+                self.broker.register(record, ttl)?;
+                Ok(Value::Bool(true))
+            }
+            BrokerOp::Deregister => {
+                let name = string_arg(&args, 0, "NAME")?;
+                let addr = string_arg(&args, 1, "ADDR")?;
+                Ok(Value::from(self.broker.deregister(&name, &addr)))
+            }
+            BrokerOp::Resolve => {
+                let name = string_arg(&args, 0, "NAME")?;
+                Ok(Value::list(
+                    self.broker
+                        .resolve(&name)
+                        .iter()
+                        .map(ServiceRecord::to_value)
+                        .collect::<Vec<Value>>(),
+                ))
+            }
+            BrokerOp::List => Ok(Value::list(
+                self.broker
+                    .services()
+                    .into_iter()
+                    .map(Value::string)
+                    .collect::<Vec<Value>>(),
+            )),
+        }
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+/// Ask the broker at `broker_addr` for the live entries of `name`
+pub async fn resolve(
+    broker_addr: &str,
+    name: &str,
+) -> std::result::Result<Vec<ServiceRecord>, ERPCError> {
+    let broker = Client::connect(broker_addr).await?;
+    let result = broker
+        .call_value(
+            RESOLVE_SERVICE_METHOD,
+            Value::list(vec![Value::string(name)]),
+        )
+        .await;
+    let _ = broker.close().await;
+    list_items(&result?)?
+        .iter()
+        .map(ServiceRecord::from_value)
+        .collect()
+}
+
+fn register_args(record: &ServiceRecord, ttl: Duration) -> Value {
+    Value::list(vec![
+        Value::string(record.name.as_str()),
+        Value::string(record.addr.as_str()),
+        Value::from(ttl.as_secs_f64()),
+        metadata_to_value(&record.metadata),
+    ])
+}
+
+/// A service kept registered with a broker
+///
+/// Dropping it stops the renewals, and the entry expires after its TTL;
+/// [`withdraw`](Registration::withdraw) removes it right away.
+#[derive(Debug)]
+pub struct Registration {
+    broker_addr: String,
+    record: ServiceRecord,
+    renewals: JoinHandle<()>,
+}
+
+/// Register `record` with the broker at `broker_addr` and renew it every
+/// third of `ttl`
+///
+/// Fails if the first registration does. Later failures are logged, and
+/// the broker is reconnected to at the next renewal.
+pub async fn announce(
+    broker_addr: &str,
+    record: ServiceRecord,
+    ttl: Duration,
+) -> std::result::Result<Registration, ERPCError> {
+    let args = register_args(&record, ttl);
+    let client = Client::connect(broker_addr).await?;
+    client
+        .call_value(REGISTER_SERVICE_METHOD, args.clone())
+        .await?;
+
+    let addr = broker_addr.to_string();
+    let name = record.name.clone();
+    let renewals = tokio::spawn(async move {
+        let mut client = Some(client);
+        let interval = ttl / 3;
+        loop {
+            tokio::time::sleep(interval).await;
+            if client.is_none() {
+                client = Client::connect(addr.as_str()).await.ok();
+            }
+            let Some(broker) = &client else {
+                warn!("Cannot reach broker {} to renew {}", addr, name);
+                continue;
+            };
+            if let Err(e) = broker
+                .call_value(REGISTER_SERVICE_METHOD, args.clone())
+                .await
+            {
+                warn!("Renewing {} with broker {} failed: {}", name, addr, e);
+                client = None;
+            }
+        }
+    });
+    Ok(Registration {
+        broker_addr: broker_addr.to_string(),
+        record,
+        renewals,
+    })
+}
+
+impl Registration {
+    pub fn record(&self) -> &ServiceRecord {
+        &self.record
+    }
+
+    /// Stop renewing and remove the entry from the broker
+    pub async fn withdraw(self) -> std::result::Result<(), ERPCError> {
+        self.renewals.abort();
+        let broker = Client::connect(self.broker_addr.as_str()).await?;
+        let result = broker
+            .call_value(
+                DEREGISTER_SERVICE_METHOD,
+                Value::list(vec![
+                    Value::string(self.record.name.as_str()),
+                    Value::string(self.record.addr.as_str()),
+                ]),
+            )
+            .await;
+        let _ = broker.close().await;
+        result.map(|_| ())
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.renewals.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_entries_expire_after_ttl() {
+        let broker = Broker::new();
+        broker
+            .register(
+                ServiceRecord::new("indexer", "127.0.0.1:4001"),
+                Duration::from_secs(10),
+            )
+            .unwrap();
+        broker
+            .register(
+                ServiceRecord::new("indexer", "127.0.0.1:4002"),
+                Duration::from_secs(30),
+            )
+            .unwrap();
+        assert_eq!(broker.resolve("indexer").len(), 2);
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        let live = broker.resolve("indexer");
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].addr, "127.0.0.1:4002");
+
+        // Renewing keeps the entry past its first expiry
+        broker
+            .register(
+                ServiceRecord::new("indexer", "127.0.0.1:4002").with_metadata("version", "2"),
+                Duration::from_secs(30),
+            )
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(25)).await;
+        assert_eq!(broker.resolve("indexer")[0].metadata["version"], "2");
+        assert_eq!(broker.services(), vec!["indexer".to_string()]);
+
+        assert!(broker.deregister("indexer", "127.0.0.1:4002"));
+        assert!(!broker.deregister("indexer", "127.0.0.1:4002"));
+        assert!(broker.services().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_registrations_are_bounded() {
+        let broker = Broker::new()
+            .max_ttl(Duration::from_secs(60))
+            .max_entries_per_name(2);
+        let record = |addr: &str| ServiceRecord::new("indexer", addr);
+
+        assert!(matches!(
+            broker.register(record("127.0.0.1:4001"), Duration::from_secs(61)),
+            Err(ERPCError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            broker.register(record("127.0.0.1:4001"), Duration::MAX),
+            Err(ERPCError::InvalidArgument(_))
+        ));
+
+        let ttl = Duration::from_secs(60);
+        broker.register(record("127.0.0.1:4001"), ttl).unwrap();
+        broker.register(record("127.0.0.1:4002"), ttl).unwrap();
+        assert!(matches!(
+            broker.register(record("127.0.0.1:4003"), ttl),
+            Err(ERPCError::QuotaExceeded(_))
+        ));
+        // Renewals do not count as new entries
+        broker.register(record("127.0.0.1:4002"), ttl).unwrap();
+
+        let method = BrokerMethod {
+            broker: Broker::new(),
+            op: BrokerOp::Register,
+            info: MethodInfo::new(REGISTER_SERVICE_METHOD, None::<&str>, None::<&str>),
+        };
+        for ttl in [1e20, f64::INFINITY, f64::NAN, -1.0] {
+            let args = Value::list(vec![
+                Value::string("indexer"),
+                Value::string("127.0.0.1:4001"),
+                Value::from(ttl),
+            ]);
+            assert!(matches!(
+                method.call(args).await,
+                Err(ERPCError::InvalidArgument(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! Emacs-in-the-loop interop tests
+//!
+//! An [`ElispTest`] runs an Elisp script in `emacs --batch` with `epc.el`
+//! connected to a server started by the test, then reports the assertions
+//! the script made. The script can use:
+//!
+//! - `(elrpc-call METHOD ARGS...)` to call a method synchronously,
+//! - `(elrpc-assert FORM)` to check that FORM is non-nil,
+//! - `(elrpc-assert-equal EXPECTED ACTUAL)` to compare with `equal`,
+//! - `elrpc-test-mngr`, the EPC manager, for anything else.
+//!
+//! ```no_run
+//! # async fn example(server: &elrpc::Server) {
+//! use elrpc::elisp_test::ElispTest;
+//!
+//! let report = ElispTest::new("(elrpc-assert-equal 3 (elrpc-call 'add 1 2))")
+//!     .load_path("/usr/share/emacs/site-lisp/epc")
+//!     .run(server.port().unwrap())
+//!     .await
+//!     .unwrap();
+//! report.assert_success();
+//! # }
+//! ```
+//!
+//! Tests that need Emacs should check [`find_emacs`] and skip themselves
+//! when it returns `None`.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::emacs::elisp_string;
+use crate::error::ERPCError;
+
+const PASS: &str = "ELRPC-PASS ";
+const FAIL: &str = "ELRPC-FAIL ";
+const ERROR: &str = "ELRPC-ERROR ";
+const DONE: &str = "ELRPC-DONE";
+
+/// Helpers loaded before the script; `{port}` and `{script}` are filled in
+const PRELUDE: &str = r#";;; -*- lexical-binding: t -*-
+(defvar elrpc-test-mngr (epc:start-epc-debug {port}))
+
+(defun elrpc-test-report (tag text)
+  (princ (format "%s %s\n" tag (replace-regexp-in-string "\n" " " text))))
+
+(defun elrpc-call (method &rest args)
+  (epc:call-sync elrpc-test-mngr method args))
+
+(defmacro elrpc-assert (form)
+  `(elrpc-test-report (if ,form "ELRPC-PASS" "ELRPC-FAIL") ,(prin1-to-string form)))
+
+(defmacro elrpc-assert-equal (expected actual)
+  (let ((e (make-symbol "expected")) (a (make-symbol "actual")))
+    `(let ((,e ,expected) (,a ,actual))
+       (elrpc-test-report (if (equal ,e ,a) "ELRPC-PASS" "ELRPC-FAIL")
+                          (format "%S: expected %S, got %S" ',actual ,e ,a)))))
+
+(condition-case err
+    (progn
+{script}
+     )
+  (error (elrpc-test-report "ELRPC-ERROR" (error-message-string err))))
+(ignore-errors (epc:stop-epc elrpc-test-mngr))
+(elrpc-test-report "ELRPC-DONE" "")
+"#;
+
+/// Path of the Emacs to test with: `$EMACS` if set, else `emacs` on `PATH`
+pub fn find_emacs() -> Option<PathBuf> {
+    if let Some(emacs) = std::env::var_os("EMACS").filter(|emacs| !emacs.is_empty()) {
+        return Some(PathBuf::from(emacs));
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join("emacs"))
+        .find(|path| path.is_file())
+}
+
+/// An Elisp script run against an EPC server
+#[derive(Debug, Clone)]
+pub struct ElispTest {
+    script: String,
+    emacs: Option<PathBuf>,
+    args: Vec<String>,
+    load_path: Vec<PathBuf>,
+    timeout: Duration,
+}
+
+impl ElispTest {
+    /// Run the forms in `script`
+    pub fn new(script: impl Into<String>) -> Self {
+        ElispTest {
+            script: script.into(),
+            emacs: None,
+            args: Vec::new(),
+            load_path: Vec::new(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Emacs executable to use instead of [`find_emacs`]
+    pub fn emacs(mut self, emacs: impl Into<PathBuf>) -> Self {
+        self.emacs = Some(emacs.into());
+        self
+    }
+
+    /// Extra command line argument; `-Q` is always passed
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Directory added to `load-path`, e.g. where `epc.el` is installed
+    pub fn load_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.load_path.push(dir.into());
+        self
+    }
+
+    /// Kill Emacs if the script has not finished after `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Contents of the file Emacs loads for a server on `port`
+    fn source(&self, port: u16) -> String {
+        PRELUDE
+            .replace("{port}", &port.to_string())
+            .replace("{script}", &self.script)
+    }
+
+    fn command_args(&self, file: &Path) -> Vec<String> {
+        let mut args = vec!["--batch".to_string(), "-Q".to_string()];
+        args.extend(self.args.iter().cloned());
+        for dir in &self.load_path {
+            args.push("-L".to_string());
+            args.push(dir.display().to_string());
+        }
+        args.push("-l".to_string());
+        args.push("epc".to_string());
+        args.push("--eval".to_string());
+        args.push(format!("(load {} nil t)", elisp_string(file)));
+        args
+    }
+
+    /// Run the script with `epc.el` connected to the server on `localhost:port`
+    pub async fn run(&self, port: u16) -> std::result::Result<ElispReport, ERPCError> {
+        let emacs = self
+            .emacs
+            .clone()
+            .or_else(find_emacs)
+            .ok_or_else(|| ERPCError::ProcessError("emacs not found; set EMACS".to_string()))?;
+
+        let file = std::env::temp_dir().join(format!("elrpc-test-{}.el", uuid::Uuid::new_v4()));
+        std::fs::write(&file, self.source(port))?;
+
+        let child = tokio::process::Command::new(&emacs)
+            .args(self.command_args(&file))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| ERPCError::ProcessError(format!("{}: {}", emacs.display(), e)));
+        let output = match child {
+            Ok(child) => tokio::time::timeout(self.timeout, child.wait_with_output()).await,
+            Err(e) => {
+                let _ = std::fs::remove_file(&file);
+                return Err(e);
+            }
+        };
+        let _ = std::fs::remove_file(&file);
+
+        let output = output.map_err(|_| ERPCError::Timeout)??;
+        Ok(ElispReport::parse(
+            &String::from_utf8_lossy(&output.stdout),
+            &String::from_utf8_lossy(&output.stderr),
+        ))
+    }
+}
+
+/// Outcome of an [`ElispTest`]
+#[derive(Debug, Clone, Default)]
+pub struct ElispReport {
+    /// Assertions that held
+    pub passed: Vec<String>,
+    /// Assertions that failed
+    pub failures: Vec<String>,
+    /// Error that ended the script early
+    pub error: Option<String>,
+    /// Whether the script ran to its end
+    pub finished: bool,
+    /// What Emacs wrote to stderr
+    pub stderr: String,
+}
+
+impl ElispReport {
+    fn parse(stdout: &str, stderr: &str) -> Self {
+        let mut report = ElispReport {
+            stderr: stderr.to_string(),
+            ..ElispReport::default()
+        };
+        for line in stdout.lines() {
+            if let Some(text) = line.strip_prefix(PASS) {
+                report.passed.push(text.to_string());
+            } else if let Some(text) = line.strip_prefix(FAIL) {
+                report.failures.push(text.to_string());
+            } else if let Some(text) = line.strip_prefix(ERROR) {
+                report.error = Some(text.to_string());
+            } else if line.trim_end() == DONE {
+                report.finished = true;
+            }
+        }
+        report
+    }
+
+    /// Whether the script finished without failed assertions or errors
+    pub fn is_success(&self) -> bool {
+        self.finished && self.failures.is_empty() && self.error.is_none()
+    }
+
+    /// Panic with the failures unless the script succeeded
+    pub fn assert_success(&self) {
+        if self.is_success() {
+            return;
+        }
+        let mut message = format!(
+            "elisp test failed ({} passed, {} failed)",
+            self.passed.len(),
+            self.failures.len()
+        );
+        for failure in &self.failures {
+            message.push_str(&format!("\n  FAIL {}", failure));
+        }
+        if let Some(error) = &self.error {
+            message.push_str(&format!("\n  ERROR {}", error));
+        }
+        if !self.finished {
+            message.push_str(&format!(
+                "\n  script did not finish; stderr:\n{}",
+                self.stderr
+            ));
+        }
+        panic!("{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    #[test]
+    fn test_report_parsing() {
+        let report = ElispReport::parse(
+            "ELRPC-PASS (equal 1 1)\nnoise\nELRPC-FAIL (elrpc-call 'add 1 2): expected 4, got 3\nELRPC-DONE \n",
+            "",
+        );
+        assert_eq!(report.passed, vec!["(equal 1 1)"]);
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.finished);
+        assert!(!report.is_success());
+
+        let report = ElispReport::parse("ELRPC-ERROR Wrong type argument\n", "Loading...");
+        assert_eq!(report.error.as_deref(), Some("Wrong type argument"));
+        assert!(!report.finished);
+    }
+
+    #[test]
+    fn test_script_source() {
+        let test = ElispTest::new("(elrpc-assert t)").load_path("/opt/epc");
+        let source = test.source(4000);
+        assert!(source.contains("(epc:start-epc-debug 4000)"));
+        assert!(source.contains("(progn\n(elrpc-assert t)\n"));
+
+        let args = test.command_args(Path::new("/tmp/t.el"));
+        assert_eq!(
+            args,
+            [
+                "--batch",
+                "-Q",
+                "-L",
+                "/opt/epc",
+                "-l",
+                "epc",
+                "--eval",
+                "(load \"/tmp/t.el\" nil t)"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_against_server() {
+        // Needs an Emacs with epc.el on its load path
+        if find_emacs().is_none() {
+            return;
+        }
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .method("add", |(a, b): (i64, i64)| Ok(a + b))
+            .build()
+            .await
+            .unwrap();
+
+        let script = "(elrpc-assert-equal 3 (elrpc-call 'add 1 2))\n\
+                      (elrpc-assert (condition-case nil (elrpc-call 'nope) (error t)))";
+        let report = ElispTest::new(script)
+            .run(server.port().unwrap())
+            .await
+            .unwrap();
+        server.shutdown().await.unwrap();
+        report.assert_success();
+        assert_eq!(report.passed.len(), 2);
+    }
+}
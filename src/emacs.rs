@@ -0,0 +1,208 @@
+//! Driving Emacs as an EPC server
+//!
+//! Normally Emacs spawns the Rust side; for tests and automation the roles are
+//! reversed and Rust starts Emacs, which serves methods defined in Elisp. The
+//! files loaded by [`Emacs`] register their methods by adding a function to
+//! `elrpc-connect-functions`, which is called with the EPC manager of every
+//! new connection:
+//!
+//! ```elisp
+//! (add-hook 'elrpc-connect-functions
+//!           (lambda (mngr)
+//!             (epc:define-method mngr 'echo (lambda (x) x))))
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use crate::error::ERPCError;
+use crate::process::{Process, StdinMode};
+
+/// How Emacs is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmacsMode {
+    /// Spawn a fresh `emacs --batch` that lives as long as the process
+    Batch,
+    /// Ask a running Emacs daemon through `emacsclient`
+    ///
+    /// The EPC server keeps running inside the daemon after the connection
+    /// is closed.
+    Client,
+}
+
+/// Configuration for starting Emacs as an EPC server
+#[derive(Debug, Clone)]
+pub struct Emacs {
+    program: String,
+    mode: EmacsMode,
+    args: Vec<String>,
+    load_path: Vec<PathBuf>,
+    load: Vec<PathBuf>,
+}
+
+impl Emacs {
+    /// Spawn `emacs --batch`
+    pub fn batch() -> Self {
+        Emacs::new("emacs", EmacsMode::Batch)
+    }
+
+    /// Use `emacsclient` against a running daemon
+    pub fn client() -> Self {
+        Emacs::new("emacsclient", EmacsMode::Client)
+    }
+
+    fn new(program: &str, mode: EmacsMode) -> Self {
+        Emacs {
+            program: program.to_string(),
+            mode,
+            args: Vec::new(),
+            load_path: Vec::new(),
+            load: Vec::new(),
+        }
+    }
+
+    /// Executable to run instead of `emacs` or `emacsclient`
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Extra command line argument, such as `-Q` or `--socket-name=NAME`
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Directory added to `load-path`, e.g. where `epc.el` is installed
+    pub fn load_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.load_path.push(dir.into());
+        self
+    }
+
+    /// Elisp file loaded before the server starts
+    pub fn load(mut self, file: impl Into<PathBuf>) -> Self {
+        self.load.push(file.into());
+        self
+    }
+
+    /// Elisp that starts the EPC server and reports its port
+    fn bootstrap(&self) -> String {
+        let mut forms = vec!["(defvar elrpc-connect-functions nil)".to_string()];
+        if self.mode == EmacsMode::Client {
+            for dir in &self.load_path {
+                forms.push(format!("(add-to-list 'load-path {})", elisp_string(dir)));
+            }
+            forms.push("(require 'epc)".to_string());
+            for file in &self.load {
+                forms.push(format!("(load {} nil t)", elisp_string(file)));
+            }
+        }
+        forms.push("(require 'epcs)".to_string());
+
+        let server = "(epcs:server-start \
+                      (lambda (mngr) (run-hook-with-args 'elrpc-connect-functions mngr)) 0)";
+        match self.mode {
+            // The port goes to stdout, then Emacs keeps serving until killed
+            EmacsMode::Batch => forms.push(format!(
+                "(let ((server {})) \
+                 (princ (format \"%d\\n\" (process-contact server :service))) \
+                 (while t (accept-process-output nil 1)))",
+                server
+            )),
+            // emacsclient prints the value of the form, which is the port
+            EmacsMode::Client => forms.push(format!("(process-contact {} :service)", server)),
+        }
+
+        format!("(progn {})", forms.join(" "))
+    }
+
+    fn command_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.mode == EmacsMode::Batch {
+            args.push("--batch".to_string());
+        }
+        args.extend(self.args.iter().cloned());
+
+        if self.mode == EmacsMode::Batch {
+            for dir in &self.load_path {
+                args.push("-L".to_string());
+                args.push(dir.display().to_string());
+            }
+            args.push("-l".to_string());
+            args.push("epc".to_string());
+            for file in &self.load {
+                args.push("-l".to_string());
+                args.push(file.display().to_string());
+            }
+        }
+
+        args.push("--eval".to_string());
+        args.push(self.bootstrap());
+        args
+    }
+
+    /// Process that starts Emacs; further options can be set before starting
+    pub fn process(&self) -> Process {
+        Process::new(self.program.clone(), self.command_args()).stdin(StdinMode::Null)
+    }
+
+    /// Start Emacs and connect to its EPC server
+    pub async fn start(&self) -> std::result::Result<Process, ERPCError> {
+        let mut process = self.process();
+        process.start().await?;
+        Ok(process)
+    }
+}
+
+/// Spawn `emacs --batch` loading `user_el` and connect to it
+pub async fn start_emacs(user_el: impl AsRef<Path>) -> std::result::Result<Process, ERPCError> {
+    Emacs::batch().load(user_el.as_ref()).start().await
+}
+
+/// Quote a path as an Elisp string literal
+pub(crate) fn elisp_string(path: &Path) -> String {
+    let mut quoted = String::from("\"");
+    for c in path.display().to_string().chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_command_line() {
+        let emacs = Emacs::batch()
+            .arg("-Q")
+            .load_path("/opt/epc")
+            .load("/tmp/methods.el");
+        let args = emacs.command_args();
+
+        assert_eq!(
+            &args[..7],
+            &["--batch", "-Q", "-L", "/opt/epc", "-l", "epc", "-l"]
+        );
+        assert_eq!(args[7], "/tmp/methods.el");
+        assert_eq!(args[8], "--eval");
+        assert!(args[9].contains("(require 'epcs)"));
+        assert!(args[9].contains("accept-process-output"));
+    }
+
+    #[test]
+    fn test_client_bootstrap_loads_files() {
+        let bootstrap = Emacs::client()
+            .load_path("/opt/epc")
+            .load("/tmp/my \"methods\".el")
+            .bootstrap();
+
+        assert!(bootstrap.contains("(add-to-list 'load-path \"/opt/epc\")"));
+        assert!(bootstrap.contains("(load \"/tmp/my \\\"methods\\\".el\" nil t)"));
+        assert!(bootstrap.ends_with("(process-contact (epcs:server-start (lambda (mngr) (run-hook-with-args 'elrpc-connect-functions mngr)) 0) :service))"));
+        assert!(!bootstrap.contains("accept-process-output"));
+    }
+}
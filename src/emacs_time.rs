@@ -0,0 +1,266 @@
+//! Emacs time values
+//!
+//! Emacs has used several representations of time over the years, and peers
+//! send all of them:
+//!
+//! - `(HIGH LOW USEC PSEC)` lists, counting `HIGH * 65536 + LOW` seconds
+//!   since the epoch; older code may send only the first two or three items;
+//! - `(TICKS . HZ)` pairs, what `current-time` returns since Emacs 29;
+//! - integers and floats counting seconds, as from `float-time`.
+//!
+//! [`EmacsTime`] reads any of them and writes the four-item list, which every
+//! Emacs version accepts. It also implements `Serialize` and `Deserialize`,
+//! so typed methods can take and return it directly; through serde, a
+//! `(TICKS . HZ)` pair cannot be read, use [`EmacsTime::from_value`] for those.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lexpr::Value;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::ERPCError;
+
+const PICOS_PER_SEC: i128 = 1_000_000_000_000;
+
+/// A point in time exchanged with Emacs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EmacsTime(pub SystemTime);
+
+impl EmacsTime {
+    /// The current time
+    pub fn now() -> Self {
+        EmacsTime(SystemTime::now())
+    }
+
+    /// Picoseconds since the epoch, negative before it
+    fn picos(self) -> i128 {
+        match self.0.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_nanos() as i128 * 1000,
+            Err(before) => -(before.duration().as_nanos() as i128) * 1000,
+        }
+    }
+
+    fn from_picos(picos: i128) -> Self {
+        // Precision below a nanosecond is dropped, rounding towards the past
+        let nanos = picos.div_euclid(1000);
+        let duration = |nanos: i128| {
+            Duration::new(
+                (nanos / 1_000_000_000) as u64,
+                (nanos % 1_000_000_000) as u32,
+            )
+        };
+        if nanos >= 0 {
+            EmacsTime(UNIX_EPOCH + duration(nanos))
+        } else {
+            EmacsTime(UNIX_EPOCH - duration(-nanos))
+        }
+    }
+
+    /// The `(HIGH LOW USEC PSEC)` components of this time
+    pub fn parts(self) -> (i64, i64, i64, i64) {
+        let picos = self.picos();
+        let secs = picos.div_euclid(PICOS_PER_SEC) as i64;
+        let sub = picos.rem_euclid(PICOS_PER_SEC) as i64;
+        (
+            secs.div_euclid(65536),
+            secs.rem_euclid(65536),
+            sub / 1_000_000,
+            sub % 1_000_000,
+        )
+    }
+
+    /// The time described by `(HIGH LOW USEC PSEC)` components
+    pub fn from_parts(high: i64, low: i64, usec: i64, psec: i64) -> Self {
+        let secs = high as i128 * 65536 + low as i128;
+        EmacsTime::from_picos(secs * PICOS_PER_SEC + usec as i128 * 1_000_000 + psec as i128)
+    }
+
+    /// Seconds since the epoch, as `float-time` returns them
+    pub fn as_secs_f64(self) -> f64 {
+        self.picos() as f64 / PICOS_PER_SEC as f64
+    }
+
+    /// The time `secs` seconds after the epoch
+    pub fn from_secs_f64(secs: f64) -> std::result::Result<Self, ERPCError> {
+        if !secs.is_finite() {
+            return Err(ERPCError::InvalidArgument(format!(
+                "not an Emacs time value: {}",
+                secs
+            )));
+        }
+        // Whole seconds and the fraction are scaled apart to keep precision
+        let whole = secs.floor();
+        let fraction = ((secs - whole) * PICOS_PER_SEC as f64).round() as i128;
+        Ok(EmacsTime::from_picos(
+            whole as i128 * PICOS_PER_SEC + fraction,
+        ))
+    }
+
+    /// The `(HIGH LOW USEC PSEC)` list for this time
+    pub fn to_value(self) -> Value {
+        let (high, low, usec, psec) = self.parts();
+        Value::list(vec![
+            Value::from(high),
+            Value::from(low),
+            Value::from(usec),
+            Value::from(psec),
+        ])
+    }
+
+    /// Read any of the representations listed in the module documentation
+    pub fn from_value(value: &Value) -> std::result::Result<Self, ERPCError> {
+        let invalid = || ERPCError::InvalidArgument(format!("not an Emacs time value: {}", value));
+
+        if let Some(secs) = value.as_i64() {
+            return Ok(EmacsTime::from_picos(secs as i128 * PICOS_PER_SEC));
+        }
+        if let Some(secs) = value.as_f64() {
+            return EmacsTime::from_secs_f64(secs);
+        }
+        if let Some(pair) = value.as_cons() {
+            if let (Some(ticks), Some(hz)) = (pair.car().as_i64(), pair.cdr().as_i64()) {
+                if hz <= 0 {
+                    return Err(invalid());
+                }
+                return Ok(EmacsTime::from_picos(
+                    ticks as i128 * PICOS_PER_SEC / hz as i128,
+                ));
+            }
+        }
+
+        let parts = value
+            .to_vec()
+            .filter(|parts| (2..=4).contains(&parts.len()))
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|part| part.as_i64().ok_or_else(invalid))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let part = |i: usize| parts.get(i).copied().unwrap_or(0);
+        Ok(EmacsTime::from_parts(part(0), part(1), part(2), part(3)))
+    }
+}
+
+impl From<SystemTime> for EmacsTime {
+    fn from(time: SystemTime) -> Self {
+        EmacsTime(time)
+    }
+}
+
+impl From<EmacsTime> for SystemTime {
+    fn from(time: EmacsTime) -> Self {
+        time.0
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<chrono::DateTime<chrono::Utc>> for EmacsTime {
+    fn from(time: chrono::DateTime<chrono::Utc>) -> Self {
+        EmacsTime(time.into())
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<EmacsTime> for chrono::DateTime<chrono::Utc> {
+    fn from(time: EmacsTime) -> Self {
+        time.0.into()
+    }
+}
+
+impl Serialize for EmacsTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let (high, low, usec, psec) = self.parts();
+        let mut tuple = serializer.serialize_tuple(4)?;
+        tuple.serialize_element(&high)?;
+        tuple.serialize_element(&low)?;
+        tuple.serialize_element(&usec)?;
+        tuple.serialize_element(&psec)?;
+        tuple.end()
+    }
+}
+
+struct EmacsTimeVisitor;
+
+impl<'de> Visitor<'de> for EmacsTimeVisitor {
+    type Value = EmacsTime;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an Emacs time: seconds or a (HIGH LOW USEC PSEC) list")
+    }
+
+    fn visit_i64<E: de::Error>(self, secs: i64) -> std::result::Result<EmacsTime, E> {
+        Ok(EmacsTime::from_picos(secs as i128 * PICOS_PER_SEC))
+    }
+
+    fn visit_u64<E: de::Error>(self, secs: u64) -> std::result::Result<EmacsTime, E> {
+        Ok(EmacsTime::from_picos(secs as i128 * PICOS_PER_SEC))
+    }
+
+    fn visit_f64<E: de::Error>(self, secs: f64) -> std::result::Result<EmacsTime, E> {
+        EmacsTime::from_secs_f64(secs).map_err(E::custom)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<EmacsTime, A::Error> {
+        let mut parts = [0i64; 4];
+        let mut len = 0;
+        while let Some(part) = seq.next_element::<i64>()? {
+            if len == parts.len() {
+                return Err(de::Error::invalid_length(len + 1, &self));
+            }
+            parts[len] = part;
+            len += 1;
+        }
+        if len < 2 {
+            return Err(de::Error::invalid_length(len, &self));
+        }
+        Ok(EmacsTime::from_parts(
+            parts[0], parts[1], parts[2], parts[3],
+        ))
+    }
+}
+
+impl<'de> Deserialize<'de> for EmacsTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(EmacsTimeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parts() {
+        // 2024-01-01T00:00:00.000001500Z
+        let time = EmacsTime(UNIX_EPOCH + Duration::new(1_704_067_200, 1_500));
+        assert_eq!(time.parts(), (26002, 128, 1, 500_000));
+        assert_eq!(EmacsTime::from_parts(26002, 128, 1, 500_000), time);
+
+        // Before the epoch the seconds round down and the fraction stays positive
+        let time = EmacsTime(UNIX_EPOCH - Duration::from_millis(1500));
+        assert_eq!(time.parts(), (-1, 65534, 500_000, 0));
+        assert_eq!(EmacsTime::from_parts(-1, 65534, 500_000, 0), time);
+        assert_eq!(time.as_secs_f64(), -1.5);
+    }
+
+    #[test]
+    fn test_from_value() {
+        let expected = EmacsTime(UNIX_EPOCH + Duration::from_millis(1_704_067_200_250));
+        for text in [
+            "(26002 128 250000 0)",
+            "(26002 128 250000)",
+            "(6816268801000 . 4000)",
+            "1704067200.25",
+        ] {
+            let value = lexpr::from_str(text).unwrap();
+            assert_eq!(EmacsTime::from_value(&value).unwrap(), expected, "{}", text);
+        }
+        assert_eq!(
+            lexpr::to_string(&expected.to_value()).unwrap(),
+            "(26002 128 250000 0)"
+        );
+        assert!(EmacsTime::from_value(&lexpr::from_str("(1 2 3 4 5)").unwrap()).is_err());
+    }
+}
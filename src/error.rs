@@ -44,6 +44,127 @@ pub enum ERPCError {
 
     #[error("invalid argument: {0}")]
     InvalidArgument(String),
+
+    #[error("outbound queue full")]
+    QueueFull,
+
+    #[error("too many calls in flight (limit {0})")]
+    TooManyInFlight(usize),
+
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("log control error: {0}")]
+    LogControl(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("method {method} violated its guard: {violation}")]
+    GuardViolation {
+        method: String,
+        violation: crate::guard::Violation,
+    },
+
+    #[error("second reply to call {0}")]
+    DuplicateReturn(u64),
+
+    #[error("reply to unknown call {0}")]
+    UnknownUid(u64),
+
+    #[error("uid {0} reused while its call is in flight")]
+    UidReuse(u64),
+
+    #[error("frame checksum mismatch: expected {expected:08x}, got {actual:08x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+
+    #[error("no live service named {0}")]
+    ServiceNotFound(String),
 }
 
 pub type Result<T> = std::result::Result<T, ERPCError>;
+
+/// Conversion of a handler's own error type into the error reported to the
+/// peer
+///
+/// Handlers registered with
+/// [`MethodRegistry::register_fallible`](crate::registry::MethodRegistry::register_fallible)
+/// may fail with any type implementing this trait. Enums usually implement
+/// it with [`epc_error_classes!`](crate::epc_error_classes).
+pub trait IntoEpcError {
+    fn into_epc_error(self) -> ERPCError;
+}
+
+impl IntoEpcError for ERPCError {
+    fn into_epc_error(self) -> ERPCError {
+        self
+    }
+}
+
+impl IntoEpcError for std::io::Error {
+    fn into_epc_error(self) -> ERPCError {
+        ERPCError::Io(self)
+    }
+}
+
+impl IntoEpcError for Box<dyn std::error::Error + Send + Sync> {
+    fn into_epc_error(self) -> ERPCError {
+        match self.downcast::<ERPCError>() {
+            Ok(error) => *error,
+            Err(error) => ERPCError::ApplicationError {
+                class: "Error".to_string(),
+                message: error.to_string(),
+                backtrace: vec![],
+            },
+        }
+    }
+}
+
+/// Implement [`IntoEpcError`] for an error type by mapping its variants to
+/// error classes
+///
+/// Each pattern is matched against a reference to the error; the error's
+/// `Display` output becomes the message.
+///
+/// ```
+/// #[derive(Debug)]
+/// enum StoreError {
+///     NotFound(String),
+///     Locked,
+/// }
+///
+/// impl std::fmt::Display for StoreError {
+///     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+///         match self {
+///             StoreError::NotFound(key) => write!(f, "no entry for {}", key),
+///             StoreError::Locked => f.write_str("store is locked"),
+///         }
+///     }
+/// }
+///
+/// elrpc::epc_error_classes!(StoreError {
+///     StoreError::NotFound(_) => "NotFound",
+///     StoreError::Locked => "Locked",
+/// });
+///
+/// use elrpc::error::IntoEpcError;
+/// let error = StoreError::NotFound("k".to_string()).into_epc_error();
+/// assert_eq!(error.to_string(), "application error: NotFound: no entry for k");
+/// ```
+#[macro_export]
+macro_rules! epc_error_classes {
+    ($type:ty { $($pattern:pat => $class:expr),+ $(,)? }) => {
+        impl $crate::error::IntoEpcError for $type {
+            fn into_epc_error(self) -> $crate::error::ERPCError {
+                let class = match &self {
+                    $($pattern => $class,)+
+                };
+                $crate::error::ERPCError::ApplicationError {
+                    class: ::std::string::ToString::to_string(class),
+                    message: ::std::string::ToString::to_string(&self),
+                    backtrace: ::std::vec::Vec::new(),
+                }
+            }
+        }
+    };
+}
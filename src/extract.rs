@@ -0,0 +1,462 @@
+//! Handlers taking extractors
+//!
+//! A handler registered with
+//! [`MethodRegistry::register_fn`](crate::registry::MethodRegistry::register_fn)
+//! is an async function whose parameters say what it needs from the call;
+//! each parameter type implements [`FromCall`]:
+//!
+//! - [`Args<T>`] decodes the argument list like a typed method does.
+//! - [`Plist<T>`] decodes keyword arguments, `(:query "x" :max-results 5)`,
+//!   into a struct.
+//! - [`RawValue`] is the argument list as sent.
+//! - [`PeerAddr`] and [`Context`] describe the caller.
+//! - [`State<S>`] is application state shared by all handlers, set with
+//!   [`Server::with_state`](crate::server::Server::with_state).
+//!
+//! ```ignore
+//! async fn search(
+//!     Plist(options): Plist<SearchOptions>,
+//!     PeerAddr(peer): PeerAddr,
+//! ) -> elrpc::Result<Vec<String>> {
+//!     ...
+//! }
+//!
+//! registry.register_fn("search", search, Some("&rest options"), None::<&str>).await?;
+//! ```
+//!
+//! The caller is only known to calls served by a server; called directly
+//! on a registry, handlers taking [`PeerAddr`] or [`Context`] fail.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use lexpr::Value;
+use serde::Deserialize;
+
+use crate::context::{CallContext, Peer};
+use crate::error::ERPCError;
+use crate::registry::{ArgsStyle, MethodHandler, MethodInfo};
+use crate::reply::{IntoEpcReply, Reply};
+
+/// Boxed future of a handler's reply
+pub type HandlerFuture =
+    Pin<Box<dyn Future<Output = std::result::Result<Reply, ERPCError>> + Send + 'static>>;
+
+/// Application state shared by handlers, one value per type
+///
+/// Clones share the values; inserting into a clone leaves the others alone.
+#[derive(Clone, Default)]
+pub struct AppState {
+    values: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl fmt::Debug for AppState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppState")
+            .field("values", &self.values.len())
+            .finish()
+    }
+}
+
+tokio::task_local! {
+    static STATE: AppState;
+}
+
+impl AppState {
+    pub fn new() -> Self {
+        AppState::default()
+    }
+
+    /// Add `value`, replacing an earlier value of the same type
+    pub fn insert<S: Send + Sync + 'static>(&mut self, value: S) {
+        Arc::make_mut(&mut self.values).insert(TypeId::of::<S>(), Arc::new(value));
+    }
+
+    /// The value of type `S`, if one was inserted
+    pub fn get<S: Send + Sync + 'static>(&self) -> Option<Arc<S>> {
+        let value = self.values.get(&TypeId::of::<S>())?.clone();
+        value.downcast().ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// State of the registry serving the current task's call
+    pub(crate) fn current() -> AppState {
+        STATE.try_with(AppState::clone).unwrap_or_default()
+    }
+
+    /// Run `f` with this state as the current one
+    pub(crate) async fn scope<F: Future>(self, f: F) -> F::Output {
+        STATE.scope(self, f).await
+    }
+}
+
+/// What extractors see of a call
+#[derive(Debug, Clone)]
+pub struct CallParts {
+    pub args: Value,
+    /// How the registry passes arguments to typed methods
+    pub style: ArgsStyle,
+    pub context: Option<CallContext>,
+    pub state: AppState,
+}
+
+impl CallParts {
+    fn context(&self) -> std::result::Result<&CallContext, ERPCError> {
+        self.context
+            .as_ref()
+            .ok_or_else(|| ERPCError::InvalidArgument("call is not served by a server".to_string()))
+    }
+}
+
+/// A handler parameter taken from the call
+pub trait FromCall: Sized {
+    fn from_call(parts: &CallParts) -> std::result::Result<Self, ERPCError>;
+}
+
+/// The arguments, decoded in the registry's [`ArgsStyle`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args<T>(pub T);
+
+impl<T> FromCall for Args<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    fn from_call(parts: &CallParts) -> std::result::Result<Self, ERPCError> {
+        parts.style.decode(&parts.args).map(Args)
+    }
+}
+
+/// Keyword arguments, decoded into a struct
+///
+/// The arguments may be the plist itself or a list holding it. Keys map to
+/// fields with dashes turned into underscores, so `:max-results` fills
+/// `max_results`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plist<T>(pub T);
+
+impl<T> FromCall for Plist<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    fn from_call(parts: &CallParts) -> std::result::Result<Self, ERPCError> {
+        let alist = plist_to_alist(&parts.args)?;
+        serde_lexpr::from_value(&alist)
+            .map(Plist)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+}
+
+/// Turn `(:a 1 :b-c 2)`, or `((:a 1 :b-c 2))`, into `((a . 1) (b_c . 2))`
+fn plist_to_alist(args: &Value) -> std::result::Result<Value, ERPCError> {
+    let items = args.to_vec().unwrap_or_default();
+    let items = match items.as_slice() {
+        [inner] if inner.is_list() => inner.to_vec().unwrap_or_default(),
+        _ => items,
+    };
+    if items.len() % 2 != 0 {
+        return Err(ERPCError::InvalidArgument(
+            "plist has a key without a value".to_string(),
+        ));
+    }
+    let entries = items
+        .chunks(2)
+        .map(|pair| {
+            let key = pair[0].as_keyword().ok_or_else(|| {
+                ERPCError::InvalidArgument(format!("plist key {} is not a keyword", pair[0]))
+            })?;
+            Ok(Value::cons(
+                Value::symbol(key.replace('-', "_")),
+                pair[1].clone(),
+            ))
+        })
+        .collect::<std::result::Result<Vec<_>, ERPCError>>()?;
+    Ok(Value::list(entries))
+}
+
+/// The argument list as sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawValue(pub Value);
+
+impl FromCall for RawValue {
+    fn from_call(parts: &CallParts) -> std::result::Result<Self, ERPCError> {
+        Ok(RawValue(parts.args.clone()))
+    }
+}
+
+/// Where the caller is connected from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddr(pub Peer);
+
+impl FromCall for PeerAddr {
+    fn from_call(parts: &CallParts) -> std::result::Result<Self, ERPCError> {
+        parts.context().map(|cx| PeerAddr(cx.peer))
+    }
+}
+
+/// Everything known about the call, as in [`CallContext::current`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Context(pub CallContext);
+
+impl FromCall for Context {
+    fn from_call(parts: &CallParts) -> std::result::Result<Self, ERPCError> {
+        parts.context().cloned().map(Context)
+    }
+}
+
+/// Shared application state of type `S`
+///
+/// Handlers that do not take extractors can use [`State::current`].
+#[derive(Debug)]
+pub struct State<S>(pub Arc<S>);
+
+impl<S: Send + Sync + 'static> State<S> {
+    /// The state of type `S` of the registry serving the current call
+    pub fn current() -> Option<State<S>> {
+        AppState::current().get().map(State)
+    }
+}
+
+impl<S> Clone for State<S> {
+    fn clone(&self) -> Self {
+        State(self.0.clone())
+    }
+}
+
+impl<S> Deref for State<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.0
+    }
+}
+
+impl<S: Send + Sync + 'static> FromCall for State<S> {
+    fn from_call(parts: &CallParts) -> std::result::Result<Self, ERPCError> {
+        parts.state.get().map(State).ok_or_else(|| {
+            ERPCError::InvalidArgument(format!(
+                "no state of type {} was set",
+                std::any::type_name::<S>()
+            ))
+        })
+    }
+}
+
+/// An extractor that may be unavailable, such as the caller of a call made
+/// directly on a registry
+impl<T: FromCall> FromCall for Option<T> {
+    fn from_call(parts: &CallParts) -> std::result::Result<Self, ERPCError> {
+        Ok(T::from_call(parts).ok())
+    }
+}
+
+/// An async function of extractors
+///
+/// Implemented for functions of up to six [`FromCall`] parameters returning
+/// a future of anything implementing [`IntoEpcReply`]. `M` only tells the
+/// implementations apart.
+pub trait Handler<M>: Send + Sync + 'static {
+    fn call(&self, parts: &CallParts) -> HandlerFuture;
+}
+
+macro_rules! impl_handler {
+    ($($extractor:ident $value:ident),*) => {
+        impl<F, Fut, R, $($extractor,)*> Handler<(R, $($extractor,)*)> for F
+        where
+            F: Fn($($extractor),*) -> Fut + Send + Sync + 'static,
+            Fut: Future + Send + 'static,
+            Fut::Output: IntoEpcReply<R>,
+            $($extractor: FromCall,)*
+        {
+            #[allow(unused_variables)]
+            fn call(&self, parts: &CallParts) -> HandlerFuture {
+                $(
+                    let $value = match $extractor::from_call(parts) {
+                        Ok(value) => value,
+                        Err(e) => return Box::pin(async move { Err(e) }),
+                    };
+                )*
+                let future = self($($value),*);
+                Box::pin(async move { future.await.into_deferred().resolve().await })
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(T1 a1);
+impl_handler!(T1 a1, T2 a2);
+impl_handler!(T1 a1, T2 a2, T3 a3);
+impl_handler!(T1 a1, T2 a2, T3 a3, T4 a4);
+impl_handler!(T1 a1, T2 a2, T3 a3, T4 a4, T5 a5);
+impl_handler!(T1 a1, T2 a2, T3 a3, T4 a4, T5 a5, T6 a6);
+
+/// [`MethodHandler`] running a [`Handler`]
+pub struct ExtractHandler<H, M> {
+    handler: H,
+    style: ArgsStyle,
+    info: MethodInfo,
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<H, M> ExtractHandler<H, M>
+where
+    H: Handler<M>,
+{
+    pub fn new(
+        handler: H,
+        style: ArgsStyle,
+        name: impl Into<String>,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> Self {
+        ExtractHandler {
+            handler,
+            style,
+            info: MethodInfo::new(name, arg_spec, docstring),
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<H, M> MethodHandler for ExtractHandler<H, M>
+where
+    H: Handler<M>,
+{
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        self.call_reply(args).await.map(|reply| reply.value)
+    }
+
+    async fn call_reply(&self, args: Value) -> std::result::Result<Reply, ERPCError> {
+        let parts = CallParts {
+            args,
+            style: self.style,
+            context: CallContext::current(),
+            state: AppState::current(),
+        };
+        self.handler.call(&parts).await
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::MethodRegistry;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Options {
+        query: String,
+        max_results: u32,
+    }
+
+    #[tokio::test]
+    async fn test_extractors() {
+        let registry = MethodRegistry::new();
+        registry
+            .register_fn(
+                "describe",
+                |RawValue(args): RawValue, peer: Option<PeerAddr>| async move {
+                    Ok::<_, ERPCError>(format!("{} from {:?}", args, peer))
+                },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        registry
+            .register_fn(
+                "peer",
+                |PeerAddr(peer): PeerAddr| async move { Ok::<_, ERPCError>(peer.to_string()) },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+
+        let described = registry
+            .call_method("describe", Value::list(vec![Value::from(1)]))
+            .await
+            .unwrap();
+        assert_eq!(described, Value::string("(1) from None"));
+        assert!(matches!(
+            registry.call_method("peer", Value::Nil).await,
+            Err(ERPCError::InvalidArgument(_))
+        ));
+    }
+
+    #[derive(Debug)]
+    struct Prefix(&'static str);
+
+    #[tokio::test]
+    async fn test_state() {
+        let registry = MethodRegistry::new();
+        registry
+            .register_fn(
+                "greet",
+                |State(prefix): State<Prefix>, Args(name): Args<String>| async move {
+                    Ok::<_, ERPCError>(format!("{}{}", prefix.0, name))
+                },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            registry.call_method("greet", Value::string("a")).await,
+            Err(ERPCError::InvalidArgument(_))
+        ));
+
+        registry.insert_state(Prefix("hi "));
+        assert_eq!(
+            registry
+                .call_method("greet", Value::string("a"))
+                .await
+                .unwrap(),
+            Value::string("hi a")
+        );
+        assert_eq!(registry.state().get::<Prefix>().unwrap().0, "hi ");
+        assert!(State::<Prefix>::current().is_none());
+    }
+
+    #[test]
+    fn test_plist() {
+        let parts = CallParts {
+            args: Value::list(vec![
+                Value::keyword("query"),
+                Value::string("rust"),
+                Value::keyword("max-results"),
+                Value::from(5),
+            ]),
+            style: ArgsStyle::Single,
+            context: None,
+            state: AppState::new(),
+        };
+        let Plist(options) = Plist::<Options>::from_call(&parts).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                query: "rust".to_string(),
+                max_results: 5,
+            }
+        );
+
+        let odd = CallParts {
+            args: Value::list(vec![Value::keyword("query")]),
+            ..parts
+        };
+        assert!(Plist::<Options>::from_call(&odd).is_err());
+    }
+}
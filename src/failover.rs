@@ -0,0 +1,408 @@
+//! Hot-standby failover between two servers
+//!
+//! A [`FailoverClient`] keeps connections to a primary and a standby
+//! server. Calls go to the active one; when its connection fails, the client
+//! switches to the other, retries the failed call there once and reports
+//! the [`Switchover`] to a callback:
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use elrpc::failover::FailoverClient;
+//!
+//! let client = FailoverClient::connect("10.0.0.1:7000", "10.0.0.2:7000")
+//!     .await?
+//!     .on_switchover(|switchover| eprintln!("failed over: {}", switchover));
+//! client
+//!     .register_method("notify", |message: String| Ok(message.len()), None::<&str>, None::<&str>)
+//!     .await?;
+//! let sum: i64 = client.call_sync("add", (5, 3)).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Both connections serve the server's calls from one registry, so methods
+//! registered on the failover client are available to whichever server is
+//! active without registering them again. The standby is connected up
+//! front, keeping the switch itself to a lookup; if it cannot be reached
+//! then, it is connected to at switchover time. After a switchover the
+//! former primary is only used again when the standby fails in turn.
+//!
+//! A call whose connection broke may or may not have run on the failed
+//! server, so retrying it is only safe for idempotent methods.
+
+use std::fmt;
+use std::sync::Arc;
+
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::client::{Client, ClientConfig};
+use crate::error::ERPCError;
+use crate::log::{info, warn};
+use crate::registry::MethodRegistry;
+
+/// One of the two servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Primary,
+    Standby,
+}
+
+impl Endpoint {
+    fn index(self) -> usize {
+        match self {
+            Endpoint::Primary => 0,
+            Endpoint::Standby => 1,
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Endpoint::Primary => Endpoint::Standby,
+            Endpoint::Standby => Endpoint::Primary,
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Primary => f.write_str("primary"),
+            Endpoint::Standby => f.write_str("standby"),
+        }
+    }
+}
+
+/// A switch from one server to the other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Switchover {
+    pub from: Endpoint,
+    pub to: Endpoint,
+    /// Address switched to
+    pub addr: String,
+    /// Why the connection to `from` was given up
+    pub reason: String,
+}
+
+impl fmt::Display for Switchover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} ({}): {}",
+            self.from, self.to, self.addr, self.reason
+        )
+    }
+}
+
+/// Callback told about every switchover
+pub type SwitchoverCallback = dyn Fn(&Switchover) + Send + Sync;
+
+struct State {
+    active: Endpoint,
+    clients: [Option<Arc<Client>>; 2],
+}
+
+/// Client of a primary server with a hot standby
+pub struct FailoverClient {
+    addrs: [String; 2],
+    config: ClientConfig,
+    registry: Arc<MethodRegistry>,
+    state: Mutex<State>,
+    on_switchover: Option<Arc<SwitchoverCallback>>,
+}
+
+/// Whether `error` means the connection is unusable
+fn is_connection_failure(error: &ERPCError) -> bool {
+    matches!(error, ERPCError::ConnectionClosed | ERPCError::Io(_))
+}
+
+impl FailoverClient {
+    /// Connect to `primary` and `standby`
+    pub async fn connect(
+        primary: impl Into<String>,
+        standby: impl Into<String>,
+    ) -> std::result::Result<Self, ERPCError> {
+        FailoverClient::connect_with_config(primary, standby, ClientConfig::default()).await
+    }
+
+    /// Connect to `primary` and `standby` with `config`
+    ///
+    /// Starts on the standby if the primary cannot be reached, and fails
+    /// only if neither can.
+    pub async fn connect_with_config(
+        primary: impl Into<String>,
+        standby: impl Into<String>,
+        config: ClientConfig,
+    ) -> std::result::Result<Self, ERPCError> {
+        let addrs = [primary.into(), standby.into()];
+        let registry = Arc::new(MethodRegistry::with_args_style(config.args_style));
+        let mut clients = [None, None];
+        for endpoint in [Endpoint::Primary, Endpoint::Standby] {
+            let addr = &addrs[endpoint.index()];
+            match Client::connect_with_registry(addr.as_str(), config.clone(), registry.clone())
+                .await
+            {
+                Ok(client) => clients[endpoint.index()] = Some(Arc::new(client)),
+                Err(e) => warn!("Cannot connect to {} {}: {}", endpoint, addr, e),
+            }
+        }
+        let active = match &clients {
+            [Some(_), _] => Endpoint::Primary,
+            [None, Some(_)] => Endpoint::Standby,
+            [None, None] => {
+                return Err(ERPCError::ConnectionClosed);
+            }
+        };
+        Ok(FailoverClient {
+            addrs,
+            config,
+            registry,
+            state: Mutex::new(State { active, clients }),
+            on_switchover: None,
+        })
+    }
+
+    /// Call `callback` after every switchover
+    pub fn on_switchover(mut self, callback: impl Fn(&Switchover) + Send + Sync + 'static) -> Self {
+        self.on_switchover = Some(Arc::new(callback));
+        self
+    }
+
+    /// Server calls currently go to
+    pub async fn active(&self) -> Endpoint {
+        self.state.lock().await.active
+    }
+
+    /// Registry serving the calls of both servers
+    pub fn registry(&self) -> &Arc<MethodRegistry> {
+        &self.registry
+    }
+
+    /// Register a client-side method, served to both servers
+    pub async fn register_method<F, Args, Ret>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(Args) -> std::result::Result<Ret, ERPCError> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+    {
+        self.registry
+            .register_closure(name, func, arg_spec, docstring)
+            .await
+    }
+
+    /// Connection to the active server
+    async fn current(&self) -> std::result::Result<(Endpoint, Arc<Client>), ERPCError> {
+        let state = self.state.lock().await;
+        match &state.clients[state.active.index()] {
+            Some(client) => Ok((state.active, client.clone())),
+            None => Err(ERPCError::ConnectionClosed),
+        }
+    }
+
+    /// Give up on `failed` and make the other server active, unless another
+    /// call already did
+    ///
+    /// A connection to the other server is made without holding the state
+    /// lock, so a slow connect does not hold up calls. If another call made
+    /// a connection active meanwhile, that one is used instead.
+    async fn switch_from(
+        &self,
+        failed: Endpoint,
+        reason: &ERPCError,
+    ) -> std::result::Result<Arc<Client>, ERPCError> {
+        let to = failed.other();
+        {
+            let mut state = self.state.lock().await;
+            if state.active != failed {
+                if let Some(client) = &state.clients[state.active.index()] {
+                    return Ok(client.clone());
+                }
+            }
+            state.clients[failed.index()] = None;
+            if let Some(client) = state.clients[to.index()].clone() {
+                state.active = to;
+                drop(state);
+                self.report_switchover(failed, to, reason);
+                return Ok(client);
+            }
+        }
+
+        let client = Client::connect_with_registry(
+            self.addrs[to.index()].as_str(),
+            self.config.clone(),
+            self.registry.clone(),
+        )
+        .await?;
+        let mut state = self.state.lock().await;
+        if let Some(current) = state.clients[state.active.index()].clone() {
+            drop(state);
+            if let Err(e) = client.close().await {
+                warn!("Cannot close spare connection to {}: {}", to, e);
+            }
+            return Ok(current);
+        }
+        let client = Arc::new(client);
+        state.clients[to.index()] = Some(client.clone());
+        state.active = to;
+        drop(state);
+        self.report_switchover(failed, to, reason);
+        Ok(client)
+    }
+
+    fn report_switchover(&self, from: Endpoint, to: Endpoint, reason: &ERPCError) {
+        let switchover = Switchover {
+            from,
+            to,
+            addr: self.addrs[to.index()].clone(),
+            reason: reason.to_string(),
+        };
+        info!("Failed over: {}", switchover);
+        if let Some(callback) = &self.on_switchover {
+            callback(&switchover);
+        }
+    }
+
+    /// Call a method with raw S-expression arguments, failing over if the
+    /// active server's connection breaks
+    pub async fn call_value(
+        &self,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let (endpoint, client) = match self.current().await {
+            Ok(current) => current,
+            Err(e) => {
+                let active = self.active().await;
+                let client = self.switch_from(active, &e).await?;
+                return client.call_value(method, args).await;
+            }
+        };
+        match client.call_value(method, args.clone()).await {
+            Err(e) if is_connection_failure(&e) => {
+                warn!("Call to '{}' on {} failed: {}", method, endpoint, e);
+                let client = self.switch_from(endpoint, &e).await?;
+                client.call_value(method, args).await
+            }
+            result => result,
+        }
+    }
+
+    /// Call a method, failing over if the active server's connection breaks
+    pub async fn call_sync<Args, Ret>(
+        &self,
+        method: &str,
+        args: Args,
+    ) -> std::result::Result<Ret, ERPCError>
+    where
+        Args: Serialize,
+        Ret: for<'de> Deserialize<'de>,
+    {
+        let args_value = serde_lexpr::to_value(&args)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+
+        let result = self.call_value(method, args_value).await?;
+
+        serde_lexpr::from_value(&result).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Close both connections
+    pub async fn close(&self) -> std::result::Result<(), ERPCError> {
+        let mut state = self.state.lock().await;
+        let mut result = Ok(());
+        for client in state.clients.iter_mut().filter_map(Option::take) {
+            if let Err(e) = client.close().await {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    use crate::testing::MockServer;
+
+    #[tokio::test]
+    async fn test_switches_to_standby() {
+        let primary = MockServer::start().await.unwrap();
+        primary
+            .expect_call("echo")
+            .returning(Value::symbol("primary"));
+        let standby = MockServer::start().await.unwrap();
+        standby
+            .expect_call("echo")
+            .returning(Value::symbol("standby"));
+        let standby_addr = standby.addr().to_string();
+
+        let switchovers = Arc::new(StdMutex::new(Vec::new()));
+        let seen = switchovers.clone();
+        let client = FailoverClient::connect(primary.addr().to_string(), standby_addr.clone())
+            .await
+            .unwrap()
+            .on_switchover(move |switchover| seen.lock().unwrap().push(switchover.clone()));
+
+        let echo = || client.call_value("echo", Value::Null);
+        assert_eq!(echo().await.unwrap(), Value::symbol("primary"));
+        assert_eq!(client.active().await, Endpoint::Primary);
+
+        // Dropping the mock closes its connections
+        drop(primary);
+        assert_eq!(echo().await.unwrap(), Value::symbol("standby"));
+        assert_eq!(client.active().await, Endpoint::Standby);
+
+        let switchovers = switchovers.lock().unwrap();
+        assert_eq!(switchovers.len(), 1);
+        assert_eq!(
+            (
+                switchovers[0].from,
+                switchovers[0].to,
+                switchovers[0].addr.as_str()
+            ),
+            (Endpoint::Primary, Endpoint::Standby, standby_addr.as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connects_standby_at_switchover() {
+        let primary = MockServer::start().await.unwrap();
+        primary.expect_call("echo").returning(Value::Null);
+        // Reserve a port for a standby that is down at first
+        let standby_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let client = FailoverClient::connect(primary.addr().to_string(), standby_addr.to_string())
+            .await
+            .unwrap();
+        assert!(client.call_value("echo", Value::Null).await.is_ok());
+
+        let mut standby = crate::server::Server::builder()
+            .bind(standby_addr.to_string())
+            .value_method("echo", |_| Ok(Value::symbol("standby")))
+            .build()
+            .await
+            .unwrap();
+        drop(primary);
+        let (first, second) = tokio::join!(
+            client.call_value("echo", Value::Null),
+            client.call_value("echo", Value::Null)
+        );
+        assert_eq!(first.unwrap(), Value::symbol("standby"));
+        assert_eq!(second.unwrap(), Value::symbol("standby"));
+        assert_eq!(client.active().await, Endpoint::Standby);
+
+        client.close().await.unwrap();
+        standby.shutdown().await.unwrap();
+    }
+}
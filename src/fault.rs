@@ -0,0 +1,271 @@
+//! Deterministic fault injection for resilience tests
+//!
+//! A [`FaultPlan`] decides, frame by frame, whether to drop, duplicate,
+//! corrupt, fragment or reorder it. Decisions come from a seeded generator
+//! and an optional explicit schedule, so a failing test replays exactly.
+//! Set a plan on [`ProxyConfig::faults`](crate::proxy::ProxyConfig) to put a
+//! misbehaving network between a client and a server, or drive a
+//! [`FaultInjector`] directly from a custom transport.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::log::debug;
+use crate::protocol::Framer;
+
+/// What happens to one frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The frame is never delivered
+    Drop,
+    /// The frame is delivered twice
+    Duplicate,
+    /// One payload byte is flipped; the length header stays intact
+    Corrupt,
+    /// The frame is written in several pieces with pauses in between
+    Fragment,
+    /// The frame is held back and delivered after the next one
+    Reorder,
+}
+
+/// Probabilities and schedule of injected faults
+#[derive(Debug, Clone)]
+pub struct FaultPlan {
+    /// Seed of the generator behind the random faults
+    pub seed: u64,
+    pub drop: f64,
+    pub duplicate: f64,
+    pub corrupt: f64,
+    pub fragment: f64,
+    pub reorder: f64,
+    /// Faults for specific frames, counted from 0, overriding the random ones
+    pub schedule: BTreeMap<u64, Fault>,
+    /// Pause between the pieces of a fragmented frame
+    pub fragment_delay: Duration,
+}
+
+impl Default for FaultPlan {
+    fn default() -> Self {
+        FaultPlan {
+            seed: 0,
+            drop: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            fragment: 0.0,
+            reorder: 0.0,
+            schedule: BTreeMap::new(),
+            fragment_delay: Duration::from_millis(5),
+        }
+    }
+}
+
+impl FaultPlan {
+    /// A plan injecting nothing until configured, seeded with `seed`
+    pub fn seeded(seed: u64) -> Self {
+        FaultPlan {
+            seed,
+            ..Default::default()
+        }
+    }
+
+    /// Inject `fault` into each frame with probability `probability`
+    pub fn with(mut self, fault: Fault, probability: f64) -> Self {
+        let slot = match fault {
+            Fault::Drop => &mut self.drop,
+            Fault::Duplicate => &mut self.duplicate,
+            Fault::Corrupt => &mut self.corrupt,
+            Fault::Fragment => &mut self.fragment,
+            Fault::Reorder => &mut self.reorder,
+        };
+        *slot = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Inject `fault` into frame number `frame`
+    pub fn at(mut self, frame: u64, fault: Fault) -> Self {
+        self.schedule.insert(frame, fault);
+        self
+    }
+}
+
+/// SplitMix64, small and stable across releases so seeds stay meaningful
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `[0, n)`
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n.max(1) as u64) as usize
+    }
+}
+
+/// Applies a [`FaultPlan`] to a stream of frames
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    plan: FaultPlan,
+    rng: Rng,
+    frame: u64,
+    held: Option<Bytes>,
+}
+
+impl FaultInjector {
+    pub fn new(plan: FaultPlan) -> Self {
+        FaultInjector {
+            rng: Rng(plan.seed),
+            plan,
+            frame: 0,
+            held: None,
+        }
+    }
+
+    /// Pause the writer should make between consecutive writes of one frame
+    pub fn fragment_delay(&self) -> Duration {
+        self.plan.fragment_delay
+    }
+
+    fn decide(&mut self) -> Option<Fault> {
+        if let Some(fault) = self.plan.schedule.get(&self.frame) {
+            return Some(*fault);
+        }
+
+        let roll = self.rng.unit();
+        let mut threshold = 0.0;
+        for (fault, probability) in [
+            (Fault::Drop, self.plan.drop),
+            (Fault::Duplicate, self.plan.duplicate),
+            (Fault::Corrupt, self.plan.corrupt),
+            (Fault::Fragment, self.plan.fragment),
+            (Fault::Reorder, self.plan.reorder),
+        ] {
+            threshold += probability;
+            if roll < threshold {
+                return Some(fault);
+            }
+        }
+        None
+    }
+
+    /// Writes to make for the frame with `payload`, each already framed
+    ///
+    /// The result is empty for dropped and held back frames; a fragmented
+    /// frame comes back as several pieces to be written separately.
+    pub fn apply(&mut self, payload: &[u8]) -> Vec<Bytes> {
+        let fault = self.decide();
+        if let Some(fault) = fault {
+            debug!("Injecting {:?} into frame {}", fault, self.frame);
+        }
+        self.frame += 1;
+
+        let framed = Framer::frame(payload);
+        let mut writes = match fault {
+            None => vec![framed],
+            Some(Fault::Drop) => Vec::new(),
+            Some(Fault::Duplicate) => vec![framed.clone(), framed],
+            Some(Fault::Corrupt) => {
+                let mut corrupted = BytesMut::from(&framed[..]);
+                if !payload.is_empty() {
+                    let index = 6 + self.rng.below(payload.len());
+                    corrupted[index] ^= 1 << self.rng.below(8);
+                }
+                vec![corrupted.freeze()]
+            }
+            Some(Fault::Fragment) => {
+                let pieces = 2 + self.rng.below(3);
+                let mut cuts: Vec<usize> = (1..pieces)
+                    .map(|_| 1 + self.rng.below(framed.len() - 1))
+                    .collect();
+                cuts.sort_unstable();
+                cuts.dedup();
+                let mut start = 0;
+                let mut writes = Vec::with_capacity(cuts.len() + 1);
+                for cut in cuts.into_iter().chain([framed.len()]) {
+                    writes.push(framed.slice(start..cut));
+                    start = cut;
+                }
+                writes
+            }
+            Some(Fault::Reorder) => {
+                // Whatever was held before goes out now, this frame waits
+                let released = self.held.replace(framed);
+                return released.into_iter().collect();
+            }
+        };
+
+        if let Some(held) = self.held.take() {
+            writes.push(held);
+        }
+        writes
+    }
+
+    /// Frame still held back for reordering, to be written when the stream ends
+    pub fn finish(&mut self) -> Option<Bytes> {
+        self.held.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(plan: FaultPlan, frames: usize) -> Vec<Vec<Bytes>> {
+        let mut injector = FaultInjector::new(plan);
+        (0..frames)
+            .map(|i| injector.apply(format!("(return {} nil)", i).as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_scheduled_faults() {
+        let plan = FaultPlan::seeded(7)
+            .at(0, Fault::Drop)
+            .at(1, Fault::Duplicate)
+            .at(2, Fault::Reorder)
+            .at(4, Fault::Fragment)
+            .at(5, Fault::Corrupt);
+        let writes = replay(plan, 6);
+
+        assert!(writes[0].is_empty());
+        assert_eq!(writes[1].len(), 2);
+        assert_eq!(writes[1][0], writes[1][1]);
+        assert!(writes[2].is_empty());
+        assert_eq!(
+            writes[3],
+            vec![
+                Framer::frame(b"(return 3 nil)"),
+                Framer::frame(b"(return 2 nil)")
+            ]
+        );
+        assert!(writes[4].len() >= 2);
+        assert_eq!(writes[4].concat(), Framer::frame(b"(return 4 nil)"));
+        assert_eq!(writes[5].len(), 1);
+        assert_ne!(writes[5][0], Framer::frame(b"(return 5 nil)"));
+        assert_eq!(&writes[5][0][..6], b"00000e");
+    }
+
+    #[test]
+    fn test_same_seed_same_faults() {
+        let plan = FaultPlan::seeded(42)
+            .with(Fault::Drop, 0.2)
+            .with(Fault::Fragment, 0.3);
+        let first = replay(plan.clone(), 200);
+
+        assert_eq!(first, replay(plan, 200));
+        let dropped = first.iter().filter(|writes| writes.is_empty()).count();
+        assert!((20..60).contains(&dropped), "dropped {}", dropped);
+    }
+}
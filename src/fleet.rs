@@ -0,0 +1,308 @@
+//! Health of all the helper processes behind a service
+//!
+//! A [`Manager`](crate::Manager) and a [`ProcessPool`](crate::ProcessPool)
+//! each report the state of their children as a [`FleetHealth`]: whether
+//! each runs, its uptime, how often it was restarted and how long calls to
+//! it took. A [`Fleet`] gathers the reports of several of them, so one
+//! query tells whether the whole backend is healthy, from Rust or from a
+//! peer calling [`FLEET_HEALTH_METHOD`]:
+//!
+//! ```no_run
+//! # async fn run(server: elrpc::Server) -> elrpc::Result<()> {
+//! use std::sync::Arc;
+//!
+//! use elrpc::fleet::Fleet;
+//! use elrpc::{Manager, Process, ProcessPool, ProcessPoolConfig};
+//!
+//! let helpers = Arc::new(Manager::new().add("indexer", Process::new("indexer", Vec::<String>::new())));
+//! helpers.start_all().await?;
+//! let pool = Arc::new(
+//!     ProcessPool::start(|_| Process::new("worker", Vec::<String>::new()), ProcessPoolConfig::default())
+//!         .await?,
+//! );
+//!
+//! let fleet = Fleet::new().add("helpers", helpers).add("pool", pool);
+//! let health = fleet.health().await;
+//! for child in health.down() {
+//!     eprintln!("{} is down after {} restarts", child.name, child.restarts);
+//! }
+//! fleet.register_method(server.registry()).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The method returns a plist such as `(:healthy t :children ((:name
+//! "pool/worker-0" :pid 4711 :running t :uptime 12.5 ...)))`, with times in
+//! seconds. Latencies cover the calls made through the manager or pool,
+//! failed ones included, not calls made on a child's client directly.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use lexpr::Value;
+
+use crate::error::ERPCError;
+use crate::registry::{MethodHandler, MethodInfo, MethodRegistry};
+
+pub const FLEET_HEALTH_METHOD: &str = "elrpc-fleet-health";
+
+/// State and call statistics of one child process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChildHealth {
+    /// Role of a manager's child, `worker-N` for a pool worker
+    pub name: String,
+    pub pid: Option<u32>,
+    pub running: bool,
+    /// Time since the running child was started
+    pub uptime: Option<Duration>,
+    /// Starts after the first, whether after a crash or on request
+    pub restarts: u64,
+    pub calls: u64,
+    pub errors: u64,
+    pub mean_latency: Option<Duration>,
+    pub max_latency: Option<Duration>,
+}
+
+impl ChildHealth {
+    /// The child as a plist
+    pub fn to_value(&self) -> Value {
+        let seconds = |d: Option<Duration>| d.map_or(Value::Nil, |d| Value::from(d.as_secs_f64()));
+        Value::list(vec![
+            Value::keyword("name"),
+            Value::string(self.name.as_str()),
+            Value::keyword("pid"),
+            self.pid.map_or(Value::Nil, Value::from),
+            Value::keyword("running"),
+            Value::from(self.running),
+            Value::keyword("uptime"),
+            seconds(self.uptime),
+            Value::keyword("restarts"),
+            Value::from(self.restarts),
+            Value::keyword("calls"),
+            Value::from(self.calls),
+            Value::keyword("errors"),
+            Value::from(self.errors),
+            Value::keyword("mean-latency"),
+            seconds(self.mean_latency),
+            Value::keyword("max-latency"),
+            seconds(self.max_latency),
+        ])
+    }
+}
+
+/// State of every child of one or more managers and pools
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FleetHealth {
+    pub children: Vec<ChildHealth>,
+}
+
+impl FleetHealth {
+    /// Whether every child runs
+    pub fn is_healthy(&self) -> bool {
+        self.down().next().is_none()
+    }
+
+    /// Children that are not running
+    pub fn down(&self) -> impl Iterator<Item = &ChildHealth> {
+        self.children.iter().filter(|child| !child.running)
+    }
+
+    /// Restarts of all children
+    pub fn restarts(&self) -> u64 {
+        self.children.iter().map(|child| child.restarts).sum()
+    }
+
+    /// The fleet as the plist [`FLEET_HEALTH_METHOD`] returns
+    pub fn to_value(&self) -> Value {
+        Value::list(vec![
+            Value::keyword("healthy"),
+            Value::from(self.is_healthy()),
+            Value::keyword("children"),
+            Value::list(
+                self.children
+                    .iter()
+                    .map(ChildHealth::to_value)
+                    .collect::<Vec<_>>(),
+            ),
+        ])
+    }
+}
+
+/// Something owning child processes, such as a manager or a pool
+#[async_trait::async_trait]
+pub trait FleetSource: Send + Sync {
+    async fn fleet_health(&self) -> FleetHealth;
+}
+
+#[async_trait::async_trait]
+impl<T: FleetSource + ?Sized> FleetSource for Arc<T> {
+    async fn fleet_health(&self) -> FleetHealth {
+        (**self).fleet_health().await
+    }
+}
+
+/// Named managers and pools reported on together
+#[derive(Clone, Default)]
+pub struct Fleet {
+    sources: Vec<(String, Arc<dyn FleetSource>)>,
+}
+
+impl Fleet {
+    pub fn new() -> Self {
+        Fleet::default()
+    }
+
+    /// Report on `source`, naming its children `name/child`
+    pub fn add(mut self, name: impl Into<String>, source: impl FleetSource + 'static) -> Self {
+        self.sources.push((name.into(), Arc::new(source)));
+        self
+    }
+
+    /// State of every child of every source, in the order they were added
+    pub async fn health(&self) -> FleetHealth {
+        let mut health = FleetHealth::default();
+        for (name, source) in &self.sources {
+            let children = source.fleet_health().await.children;
+            health
+                .children
+                .extend(children.into_iter().map(|mut child| {
+                    child.name = format!("{}/{}", name, child.name);
+                    child
+                }));
+        }
+        health
+    }
+
+    /// Register [`FLEET_HEALTH_METHOD`], taking no arguments, in `registry`
+    pub async fn register_method(
+        &self,
+        registry: &MethodRegistry,
+    ) -> std::result::Result<(), ERPCError> {
+        let method = FleetMethod {
+            fleet: self.clone(),
+            info: MethodInfo::new(
+                FLEET_HEALTH_METHOD,
+                None::<&str>,
+                Some("Return the state of every helper process"),
+            ),
+        };
+        registry
+            .register_handler(FLEET_HEALTH_METHOD, Arc::new(method))
+            .await;
+        Ok(())
+    }
+}
+
+struct FleetMethod {
+    fleet: Fleet,
+    info: MethodInfo,
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for FleetMethod {
+    async fn call(&self, _args: Value) -> std::result::Result<Value, ERPCError> {
+        Ok(self.fleet.health().await.to_value())
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+/// Durations of the calls made to one child
+#[derive(Debug, Default)]
+pub(crate) struct CallTimes {
+    calls: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl CallTimes {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mean(&self) -> Option<Duration> {
+        match self.calls.load(Ordering::Relaxed) {
+            0 => None,
+            calls => Some(Duration::from_micros(
+                self.total_micros.load(Ordering::Relaxed) / calls,
+            )),
+        }
+    }
+
+    pub(crate) fn max(&self) -> Option<Duration> {
+        match self.calls.load(Ordering::Relaxed) {
+            0 => None,
+            _ => Some(Duration::from_micros(
+                self.max_micros.load(Ordering::Relaxed),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn child(name: &str, running: bool, restarts: u64) -> ChildHealth {
+        ChildHealth {
+            name: name.to_string(),
+            pid: None,
+            running,
+            uptime: None,
+            restarts,
+            calls: 0,
+            errors: 0,
+            mean_latency: None,
+            max_latency: None,
+        }
+    }
+
+    struct Fixed(Vec<ChildHealth>);
+
+    #[async_trait::async_trait]
+    impl FleetSource for Fixed {
+        async fn fleet_health(&self) -> FleetHealth {
+            FleetHealth {
+                children: self.0.clone(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fleet_names_and_aggregates_children() {
+        let fleet = Fleet::new()
+            .add("helpers", Fixed(vec![child("indexer", true, 0)]))
+            .add(
+                "pool",
+                Fixed(vec![
+                    child("worker-0", true, 2),
+                    child("worker-1", false, 1),
+                ]),
+            );
+        let health = fleet.health().await;
+        let names: Vec<&str> = health.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["helpers/indexer", "pool/worker-0", "pool/worker-1"]
+        );
+        assert!(!health.is_healthy());
+        assert_eq!(health.down().count(), 1);
+        assert_eq!(health.restarts(), 3);
+    }
+
+    #[test]
+    fn test_call_times() {
+        let times = CallTimes::default();
+        assert_eq!(times.mean(), None);
+        times.record(Duration::from_millis(10));
+        times.record(Duration::from_millis(30));
+        assert_eq!(times.mean(), Some(Duration::from_millis(20)));
+        assert_eq!(times.max(), Some(Duration::from_millis(30)));
+    }
+}
@@ -0,0 +1,473 @@
+//! Multiplexing gateway
+//!
+//! A [`Gateway`] accepts any number of EPC connections and forwards their
+//! calls over a single upstream connection, for backends that accept only
+//! one connection or deployments that must bound how many they hold. Each
+//! forwarded call gets a fresh uid on the upstream connection, and the reply
+//! is mapped back to the uid and connection the call came from:
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use elrpc::gateway::{Gateway, GatewayConfig};
+//!
+//! let mut gateway = Gateway::bind("127.0.0.1:7000", GatewayConfig::new("127.0.0.1:7001")).await?;
+//! gateway.serve()?;
+//! // Clients connect to port 7000; the backend sees one connection
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Calls and `methods` queries are forwarded. The backend cannot call back
+//! into a client through the gateway, as it would not know which one:
+//! its calls are answered with an `epc-error`. If the upstream connection
+//! closes, calls waiting on it fail with an `epc-error` and the next call
+//! opens a new one.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::error::ERPCError;
+use crate::log::{debug, error, info, warn};
+use crate::protocol::{Framer, Message, UidSpace};
+use crate::uid::UidGenerator;
+use crate::wiretap::next_connection_id;
+
+/// Gateway configuration
+#[derive(Debug, Clone)]
+pub struct GatewayConfig {
+    /// Address of the server calls are forwarded to
+    pub upstream: String,
+    /// Most client connections served at once; more wait to be accepted
+    pub max_clients: Option<usize>,
+}
+
+impl GatewayConfig {
+    /// Forward to `upstream`, serving any number of clients
+    pub fn new(upstream: impl Into<String>) -> Self {
+        GatewayConfig {
+            upstream: upstream.into(),
+            max_clients: None,
+        }
+    }
+
+    /// Serve at most `max` clients at once
+    pub fn max_clients(mut self, max: usize) -> Self {
+        self.max_clients = Some(max);
+        self
+    }
+}
+
+/// The call a forwarded uid stands for
+#[derive(Debug, Clone, Copy)]
+struct Origin {
+    connection: u64,
+    uid: u64,
+    /// Upstream connection the call was forwarded on
+    generation: u64,
+}
+
+/// Write half of the current upstream connection
+///
+/// Each connection gets the next generation, so the reader of a closed one
+/// cannot tear down the connection that replaced it.
+#[derive(Default)]
+struct UpstreamWriter {
+    generation: u64,
+    stream: Option<OwnedWriteHalf>,
+}
+
+/// The shared upstream connection and the calls waiting on it
+struct Upstream {
+    addr: String,
+    writer: Mutex<UpstreamWriter>,
+    uids: UidGenerator,
+    pending: StdMutex<HashMap<u64, Origin>>,
+    /// Writers of the client connections, by connection id
+    clients: StdMutex<HashMap<u64, mpsc::UnboundedSender<Bytes>>>,
+}
+
+impl Upstream {
+    fn new(addr: String) -> Self {
+        Upstream {
+            addr,
+            writer: Mutex::new(UpstreamWriter::default()),
+            uids: UidGenerator::new(),
+            pending: StdMutex::new(HashMap::new()),
+            clients: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send `message` to client `connection`, if it is still connected
+    fn reply(&self, connection: u64, message: &Message) {
+        let Ok(text) = message.to_sexp() else {
+            return;
+        };
+        if let Some(client) = self.clients.lock().unwrap().get(&connection) {
+            let _ = client.send(Framer::frame(text.as_bytes()));
+        }
+    }
+
+    /// Forward a call or methods query from client `connection` under a
+    /// fresh uid, connecting upstream first if needed
+    async fn forward(self: &Arc<Self>, connection: u64, message: Message) {
+        let origin_uid = message.uid();
+        let uid = self.uids.next();
+        let forwarded = match message {
+            Message::Call { method, args, .. } => Message::new_call(uid, method, args),
+            Message::Methods { .. } => Message::new_methods(uid),
+            _ => return,
+        };
+
+        let mut writer = self.writer.lock().await;
+        if writer.stream.is_none() {
+            match TcpStream::connect(&self.addr).await {
+                Ok(stream) => {
+                    info!("Gateway connected upstream to {}", self.addr);
+                    let (reader, write_half) = stream.into_split();
+                    writer.generation += 1;
+                    writer.stream = Some(write_half);
+                    tokio::spawn(read_upstream(self.clone(), reader, writer.generation));
+                }
+                Err(e) => {
+                    drop(writer);
+                    let error = format!("gateway cannot reach upstream: {}", e);
+                    self.reply(connection, &Message::new_epc_error(origin_uid, error));
+                    return;
+                }
+            }
+        }
+
+        let origin = Origin {
+            connection,
+            uid: origin_uid,
+            generation: writer.generation,
+        };
+        self.pending.lock().unwrap().insert(uid, origin);
+        let written = match (writer.stream.as_mut(), forwarded.to_sexp()) {
+            (Some(stream), Ok(text)) => stream
+                .write_all(&Framer::frame(text.as_bytes()))
+                .await
+                .map_err(ERPCError::Io),
+            (_, Err(e)) => Err(e),
+            (None, _) => Err(ERPCError::ConnectionClosed),
+        };
+        if let Err(e) = written {
+            warn!("Forwarding to {} failed: {}", self.addr, e);
+            // The connection's reader fails its other calls once it sees
+            // the connection close
+            writer.stream = None;
+            self.pending.lock().unwrap().remove(&uid);
+            let error = format!("gateway upstream failed: {}", e);
+            self.reply(connection, &Message::new_epc_error(origin.uid, error));
+        }
+    }
+
+    /// Fail the calls waiting on upstream connection `generation` after it
+    /// closed
+    ///
+    /// A newer connection opened after a failed write is left alone, along
+    /// with the calls forwarded on it.
+    async fn disconnected(&self, generation: u64) {
+        let mut writer = self.writer.lock().await;
+        if writer.generation == generation {
+            writer.stream = None;
+        }
+        drop(writer);
+        let mut failed = Vec::new();
+        self.pending.lock().unwrap().retain(|_, origin| {
+            let keep = origin.generation != generation;
+            if !keep {
+                failed.push(*origin);
+            }
+            keep
+        });
+        for origin in failed {
+            let error = Message::new_epc_error(origin.uid, "gateway upstream connection closed");
+            self.reply(origin.connection, &error);
+        }
+    }
+}
+
+/// `reply` renumbered to `uid`, if it is a reply
+fn renumbered(reply: Message, uid: u64) -> Option<Message> {
+    match reply {
+        Message::Return { result, .. } => Some(Message::new_return(uid, result)),
+        Message::ReturnError { error, .. } => Some(Message::new_return_error(uid, error)),
+        Message::EPCError { error, .. } => Some(Message::new_epc_error(uid, error)),
+        _ => None,
+    }
+}
+
+/// Route replies from upstream connection `generation` back to the clients
+/// that made the calls
+async fn read_upstream(
+    upstream: Arc<Upstream>,
+    mut reader: tokio::net::tcp::OwnedReadHalf,
+    generation: u64,
+) {
+    let mut buffer = BytesMut::with_capacity(4096);
+    loop {
+        match reader.read_buf(&mut buffer).await {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Reading from upstream {} failed: {}", upstream.addr, e);
+                break;
+            }
+        }
+        while let Some(frame) = Framer::next_frame(&mut buffer, true) {
+            let message = match std::str::from_utf8(&frame)
+                .map_err(ERPCError::from)
+                .and_then(Message::from_sexp)
+            {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Dropping unreadable frame from upstream: {}", e);
+                    continue;
+                }
+            };
+            if message.uid_space() == UidSpace::Sender {
+                // A call or query of the backend's own, which no client
+                // can be picked to answer
+                let refusal = Message::new_epc_error(
+                    message.uid(),
+                    "calls through a multiplexing gateway are not supported",
+                );
+                let mut writer = upstream.writer.lock().await;
+                if let (true, Some(stream), Ok(text)) = (
+                    writer.generation == generation,
+                    writer.stream.as_mut(),
+                    refusal.to_sexp(),
+                ) {
+                    let _ = stream.write_all(&Framer::frame(text.as_bytes())).await;
+                }
+                continue;
+            }
+            let uid = message.uid();
+            let Some(origin) = upstream.pending.lock().unwrap().remove(&uid) else {
+                debug!("Dropping reply to unknown upstream call {}", uid);
+                continue;
+            };
+            if let Some(reply) = renumbered(message, origin.uid) {
+                upstream.reply(origin.connection, &reply);
+            }
+        }
+    }
+    info!("Upstream connection to {} closed", upstream.addr);
+    upstream.disconnected(generation).await;
+}
+
+/// Serve one client connection until it closes
+async fn serve_client(
+    upstream: Arc<Upstream>,
+    stream: TcpStream,
+    addr: SocketAddr,
+) -> std::result::Result<(), ERPCError> {
+    let connection = next_connection_id();
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Bytes>();
+    upstream.clients.lock().unwrap().insert(connection, tx);
+    let writes = tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    debug!("Gateway client {} connected as {}", addr, connection);
+
+    let mut buffer = BytesMut::with_capacity(4096);
+    let result = loop {
+        match reader.read_buf(&mut buffer).await {
+            Ok(0) => break Ok(()),
+            Ok(_) => {}
+            Err(e) => break Err(ERPCError::Io(e)),
+        }
+        while let Some(frame) = Framer::next_frame(&mut buffer, true) {
+            match std::str::from_utf8(&frame)
+                .map_err(ERPCError::from)
+                .and_then(Message::from_sexp)
+            {
+                Ok(message @ (Message::Call { .. } | Message::Methods { .. })) => {
+                    upstream.forward(connection, message).await
+                }
+                Ok(message) => debug!("Gateway ignoring {:?} from {}", message, addr),
+                Err(e) => warn!("Dropping unreadable frame from {}: {}", addr, e),
+            }
+        }
+    };
+
+    upstream.clients.lock().unwrap().remove(&connection);
+    writes.abort();
+    debug!("Gateway client {} disconnected", addr);
+    result
+}
+
+/// Gateway multiplexing client connections onto one upstream connection
+pub struct Gateway {
+    upstream: Arc<Upstream>,
+    max_clients: Option<usize>,
+    listener: Option<TcpListener>,
+    local_addr: SocketAddr,
+    shutdown_tx: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Gateway {
+    /// Listen on `addr`; connections are accepted once [`Gateway::serve`] is
+    /// called, and upstream is connected to on the first call
+    pub async fn bind(addr: &str, config: GatewayConfig) -> std::result::Result<Self, ERPCError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("Gateway for {} bound to {}", config.upstream, local_addr);
+
+        Ok(Gateway {
+            upstream: Arc::new(Upstream::new(config.upstream)),
+            max_clients: config.max_clients,
+            listener: Some(listener),
+            local_addr,
+            shutdown_tx: None,
+            handle: None,
+        })
+    }
+
+    /// Address the gateway listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the port the gateway listens on
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Number of connected clients
+    pub fn clients(&self) -> usize {
+        self.upstream.clients.lock().unwrap().len()
+    }
+
+    /// Number of forwarded calls waiting for their reply
+    pub fn in_flight(&self) -> usize {
+        self.upstream.pending.lock().unwrap().len()
+    }
+
+    /// Start accepting clients in the background
+    pub fn serve(&mut self) -> std::result::Result<(), ERPCError> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| ERPCError::ProtocolError("Gateway is already serving".to_string()))?;
+        let upstream = self.upstream.clone();
+        let slots = self
+            .max_clients
+            .map(|max| Arc::new(tokio::sync::Semaphore::new(max)));
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        self.shutdown_tx = Some(shutdown_tx);
+
+        self.handle = Some(tokio::spawn(async move {
+            loop {
+                let slot = match &slots {
+                    Some(slots) => tokio::select! {
+                        slot = slots.clone().acquire_owned() => slot.ok(),
+                        _ = shutdown_rx.recv() => break,
+                    },
+                    None => None,
+                };
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, addr)) => {
+                            let upstream = upstream.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = serve_client(upstream, stream, addr).await {
+                                    error!("Gateway connection from {} failed: {}", addr, e);
+                                }
+                                drop(slot);
+                            });
+                        }
+                        Err(e) => {
+                            error!("Gateway failed to accept connection: {}", e);
+                            break;
+                        }
+                    },
+                    _ = shutdown_rx.recv() => break,
+                }
+            }
+            info!("Gateway listener stopped");
+        }));
+        Ok(())
+    }
+
+    /// Stop accepting clients; connected clients are served until they close
+    pub async fn shutdown(&mut self) -> std::result::Result<(), ERPCError> {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(()).await;
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::server::Server;
+
+    #[tokio::test]
+    async fn test_clients_share_one_upstream_connection() {
+        let mut server = Server::builder()
+            .method("echo", |s: String| Ok(s))
+            .build()
+            .await
+            .unwrap();
+        let config = GatewayConfig::new(format!("127.0.0.1:{}", server.port().unwrap()));
+        let mut gateway = Gateway::bind("127.0.0.1:0", config).await.unwrap();
+        gateway.serve().unwrap();
+
+        let addr = format!("127.0.0.1:{}", gateway.port());
+        let first = Client::connect(addr.as_str()).await.unwrap();
+        let second = Client::connect(addr.as_str()).await.unwrap();
+        // Both clients number their calls from 1; the gateway keeps them apart
+        let (a, b): (String, String) = tokio::try_join!(
+            first.call_sync("echo", "first"),
+            second.call_sync("echo", "second")
+        )
+        .unwrap();
+        assert_eq!((a.as_str(), b.as_str()), ("first", "second"));
+        assert_eq!(gateway.clients(), 2);
+        assert_eq!(gateway.in_flight(), 0);
+        assert_eq!(server.stats().total_connections, 1);
+
+        gateway.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_old_upstream_closing_spares_the_new_one() {
+        let upstream = Upstream::new("127.0.0.1:1".to_string());
+        upstream.writer.lock().await.generation = 2;
+        for (uid, generation) in [(1, 1), (2, 2)] {
+            let origin = Origin {
+                connection: 7,
+                uid,
+                generation,
+            };
+            upstream.pending.lock().unwrap().insert(uid, origin);
+        }
+
+        // The reader of the first connection reaching EOF late
+        upstream.disconnected(1).await;
+        let pending = upstream.pending.lock().unwrap();
+        assert_eq!(pending.keys().collect::<Vec<_>>(), vec![&2]);
+    }
+}
@@ -0,0 +1,370 @@
+//! Golden wire traces for interop regression tests
+//!
+//! A golden trace is a conversation with a server written down frame by
+//! frame, as `epc.el` or python-epc put it on the wire. Replaying it against
+//! a server and requiring byte-identical responses catches refactors that
+//! change what peers actually see, which unit tests on [`Message`] values
+//! do not.
+//!
+//! The fixture format is line based:
+//!
+//! ```text
+//! # peer: epc.el
+//! > (call 1 echo ("hello"))
+//! < (return 1 ("hello"))
+//! ```
+//!
+//! `>` lines are sent to the server and `<` lines are the responses expected
+//! back, in order. A payload may keep its six-digit length header, so lines
+//! from a capture can be pasted as they are; the header is checked against
+//! the payload. `#` starts a comment, and a `# peer:` comment names the
+//! implementation the trace was taken from.
+//!
+//! Traces are recorded rather than written: a [`GoldenRecorder`] taps a
+//! [`Proxy`] placed between a real `epc.el` or python-epc session and a
+//! server, and [`GoldenTrace::to_fixture`] writes what it saw. `epc-cli
+//! HOST:PORT record PEER FILE` does both.
+//!
+//! [`Message`]: crate::protocol::Message
+//! [`Proxy`]: crate::proxy::Proxy
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::error::ERPCError;
+use crate::protocol::Framer;
+use crate::wiretap::{Direction, WireTap};
+
+/// How long [`GoldenTrace::replay`] waits for each expected response
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A recorded conversation, seen from the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoldenTrace {
+    /// Implementation the trace was taken from, if the fixture names it
+    pub peer: Option<String>,
+    /// Payloads in wire order; inbound ones are sent to the server
+    pub frames: Vec<(Direction, String)>,
+}
+
+impl GoldenTrace {
+    /// Parse a trace in the fixture format
+    pub fn parse(text: &str) -> std::result::Result<Self, ERPCError> {
+        let mut trace = GoldenTrace {
+            peer: None,
+            frames: Vec::new(),
+        };
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                let comment = comment.trim();
+                if let Some(peer) = comment.strip_prefix("peer:") {
+                    trace.peer = Some(peer.trim().to_string());
+                }
+                continue;
+            }
+
+            let (direction, payload) = if let Some(payload) = line.strip_prefix('>') {
+                (Direction::Inbound, payload.trim())
+            } else if let Some(payload) = line.strip_prefix('<') {
+                (Direction::Outbound, payload.trim())
+            } else {
+                return Err(ERPCError::InvalidMessageFormat(format!(
+                    "golden trace line {}: expected '>' or '<', got {:?}",
+                    number + 1,
+                    line
+                )));
+            };
+            let payload = strip_header(payload).map_err(|e| {
+                ERPCError::InvalidMessageFormat(format!("golden trace line {}: {}", number + 1, e))
+            })?;
+            trace.frames.push((direction, payload.to_string()));
+        }
+
+        Ok(trace)
+    }
+
+    /// Read and parse a fixture file
+    pub fn load(path: impl AsRef<Path>) -> std::result::Result<Self, ERPCError> {
+        GoldenTrace::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Write the trace in the fixture format, payloads with their length
+    /// headers as on the wire
+    pub fn to_fixture(&self) -> String {
+        let mut fixture = String::new();
+        if let Some(peer) = &self.peer {
+            let _ = writeln!(fixture, "# peer: {}", peer);
+        }
+        for (direction, payload) in &self.frames {
+            let marker = match direction {
+                Direction::Inbound => '>',
+                Direction::Outbound => '<',
+            };
+            let wire = Framer::frame(payload.as_bytes());
+            let _ = writeln!(fixture, "{} {}", marker, String::from_utf8_lossy(&wire));
+        }
+        fixture
+    }
+
+    /// Play the inbound frames to the server at `addr` and compare every
+    /// response byte for byte
+    ///
+    /// Each inbound frame is sent once all responses expected before it have
+    /// arrived, so the server sees the calls one at a time.
+    pub async fn replay(&self, addr: SocketAddr) -> std::result::Result<(), ERPCError> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut buffer = BytesMut::new();
+
+        for (step, (direction, payload)) in self.frames.iter().enumerate() {
+            match direction {
+                Direction::Inbound => stream.write_all(&Framer::frame(payload.as_bytes())).await?,
+                Direction::Outbound => {
+                    let received = tokio::time::timeout(
+                        RESPONSE_TIMEOUT,
+                        read_frame(&mut stream, &mut buffer),
+                    )
+                    .await
+                    .map_err(|_| ERPCError::Timeout)??;
+                    if received != payload.as_bytes() {
+                        return Err(ERPCError::ProtocolError(format!(
+                            "golden trace step {}: expected {}, got {}",
+                            step + 1,
+                            payload,
+                            String::from_utf8_lossy(&received)
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects the frames of a session relayed by a [`Proxy`], to be turned
+/// into a [`GoldenTrace`]
+///
+/// Only the first connection the proxy accepts is recorded, seen from the
+/// proxy's listening side: calls from the peer are inbound and the
+/// upstream's responses outbound.
+///
+/// [`Proxy`]: crate::proxy::Proxy
+#[derive(Clone, Default)]
+pub struct GoldenRecorder {
+    session: Arc<Mutex<Recording>>,
+}
+
+#[derive(Default)]
+struct Recording {
+    connection: Option<u64>,
+    frames: Vec<(Direction, String)>,
+}
+
+impl GoldenRecorder {
+    pub fn new() -> Self {
+        GoldenRecorder::default()
+    }
+
+    /// Tap to set on the proxy's [`ProxyConfig`](crate::proxy::ProxyConfig)
+    pub fn wire_tap(&self) -> WireTap {
+        let session = self.session.clone();
+        WireTap::new(move |frame| {
+            let mut session = session.lock().unwrap();
+            // The peer speaks first, so the first frame is inbound on the
+            // connection to record
+            let connection = *session.connection.get_or_insert(frame.connection);
+            if frame.connection == connection {
+                let payload = String::from_utf8_lossy(frame.payload).into_owned();
+                session.frames.push((frame.direction, payload));
+            }
+        })
+    }
+
+    /// The frames recorded so far, as a trace of `peer`
+    pub fn trace(&self, peer: impl Into<String>) -> GoldenTrace {
+        GoldenTrace {
+            peer: Some(peer.into()),
+            frames: self.session.lock().unwrap().frames.clone(),
+        }
+    }
+}
+
+/// Remove a length header in front of `payload`, checking that it matches
+fn strip_header(payload: &str) -> std::result::Result<&str, String> {
+    let has_header = payload.len() > 6
+        && payload.is_char_boundary(6)
+        && payload[..6].bytes().all(|b| b.is_ascii_hexdigit());
+    if !has_header {
+        return Ok(payload);
+    }
+
+    let (header, rest) = payload.split_at(6);
+    match Framer::parse_length(header.as_bytes()) {
+        Some(len) if len == rest.len() => Ok(rest),
+        _ => Err(format!(
+            "length header {} does not match the {} byte payload",
+            header,
+            rest.len()
+        )),
+    }
+}
+
+async fn read_frame(
+    stream: &mut TcpStream,
+    buffer: &mut BytesMut,
+) -> std::result::Result<bytes::Bytes, ERPCError> {
+    loop {
+        if let Some(frame) = Framer::extract_message(buffer) {
+            return Ok(frame);
+        }
+        if stream.read_buf(buffer).await? == 0 {
+            return Err(ERPCError::ConnectionClosed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Message;
+    use crate::server::Server;
+    use lexpr::Value;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    fn fixtures() -> Vec<(PathBuf, GoldenTrace)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/golden");
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "epc"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty(), "no fixtures in {}", dir.display());
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let trace = GoldenTrace::load(&path).unwrap();
+                (path, trace)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_fixture_format() {
+        let trace = GoldenTrace::parse(
+            "# peer: python-epc\n\n> 000017(call 1 echo (\"hello\"))\n< (return 1 (\"hello\"))\n",
+        )
+        .unwrap();
+        assert_eq!(trace.peer.as_deref(), Some("python-epc"));
+        assert_eq!(
+            trace.frames,
+            vec![
+                (Direction::Inbound, "(call 1 echo (\"hello\"))".to_string()),
+                (Direction::Outbound, "(return 1 (\"hello\"))".to_string()),
+            ]
+        );
+
+        assert!(GoldenTrace::parse("> 000099(methods 1)").is_err());
+        assert!(GoldenTrace::parse("(methods 1)").is_err());
+    }
+
+    #[test]
+    fn test_fixture_round_trip() {
+        let trace = GoldenTrace {
+            peer: Some("epc.el".to_string()),
+            frames: vec![
+                (Direction::Inbound, "(call 1 echo (\"hello\"))".to_string()),
+                (Direction::Outbound, "(return 1 (\"hello\"))".to_string()),
+            ],
+        };
+        let fixture = trace.to_fixture();
+        assert_eq!(
+            fixture,
+            "# peer: epc.el\n> 000017(call 1 echo (\"hello\"))\n< 000014(return 1 (\"hello\"))\n"
+        );
+        assert_eq!(GoldenTrace::parse(&fixture).unwrap(), trace);
+    }
+
+    #[test]
+    fn test_recorder_keeps_the_first_session() {
+        let recorder = GoldenRecorder::new();
+        let tap = recorder.wire_tap();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 50000)).into();
+        let now = SystemTime::now();
+        tap.record(Direction::Inbound, 7, peer, now, b"(call 1 echo nil)");
+        // The same call relayed upstream, and another peer's session
+        tap.record(Direction::Outbound, 8, peer, now, b"(call 1 echo nil)");
+        tap.record(Direction::Inbound, 9, peer, now, b"(methods 1)");
+        tap.record(Direction::Outbound, 7, peer, now, b"(return 1 nil)");
+
+        let trace = recorder.trace("epc.el");
+        assert_eq!(trace.peer.as_deref(), Some("epc.el"));
+        assert_eq!(
+            trace.frames,
+            vec![
+                (Direction::Inbound, "(call 1 echo nil)".to_string()),
+                (Direction::Outbound, "(return 1 nil)".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fixtures_survive_parser_and_framer() {
+        for (path, trace) in fixtures() {
+            for (_, payload) in &trace.frames {
+                // Wrong-shaped calls are in the fixtures on purpose
+                let Ok(message) = Message::from_sexp(payload) else {
+                    continue;
+                };
+                assert_eq!(&message.to_sexp().unwrap(), payload, "{}", path.display());
+
+                let mut buffer = BytesMut::from(&Framer::frame(payload.as_bytes())[..]);
+                let frame = Framer::extract_message(&mut buffer).unwrap();
+                assert_eq!(&frame[..], payload.as_bytes());
+                assert!(buffer.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_fixtures() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .value_method("echo", Ok)
+            .value_method("add", |args: Value| {
+                let mut sum = 0;
+                for arg in args.list_iter().into_iter().flatten() {
+                    sum += arg.as_i64().ok_or_else(|| {
+                        ERPCError::InvalidArgument(format!("not a number: {}", arg))
+                    })?;
+                }
+                Ok(Value::from(sum))
+            })
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        for (path, trace) in fixtures() {
+            if let Err(e) = trace.replay(addr).await {
+                panic!("{}: {}", path.display(), e);
+            }
+        }
+
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,425 @@
+//! gRPC bridge for registered methods
+//!
+//! A [`GrpcBridge`] exposes the methods of a [`MethodRegistry`] as a generic
+//! gRPC service, and a [`GrpcClient`] calls such a service with the same
+//! `call_value`/`call_sync` API as the EPC [`Client`](crate::client::Client).
+//! The service is described by
+//!
+//! ```proto
+//! syntax = "proto3";
+//! package elrpc;
+//!
+//! service Bridge {
+//!   rpc Call(CallRequest) returns (CallReply);
+//! }
+//!
+//! message CallRequest {
+//!   string method = 1;
+//!   bytes payload = 2;  // the argument list as S-expression text
+//! }
+//!
+//! message CallReply {
+//!   bytes payload = 1;  // the result as S-expression text
+//! }
+//! ```
+//!
+//! Errors become gRPC status codes: an unknown method is `NOT_FOUND`, bad
+//! arguments are `INVALID_ARGUMENT` and an error raised by the method is
+//! `UNKNOWN`, with its class in the `elrpc-error-class` metadata entry.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{NamedService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Channel;
+use tonic::{Code, Status};
+use tonic_prost::ProstCodec;
+
+use crate::error::ERPCError;
+use crate::guard::Violation;
+use crate::log::{error, info};
+use crate::registry::{MethodHandler, MethodInfo, MethodRegistry};
+
+/// Fully qualified name of the bridge service
+pub const SERVICE_NAME: &str = "elrpc.Bridge";
+
+const CALL_PATH: &str = "/elrpc.Bridge/Call";
+
+/// Metadata key carrying the class of an application error
+const CLASS_KEY: &str = "elrpc-error-class";
+
+/// Request of `elrpc.Bridge/Call`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CallRequest {
+    #[prost(string, tag = "1")]
+    pub method: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: Vec<u8>,
+}
+
+/// Reply of `elrpc.Bridge/Call`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CallReply {
+    #[prost(bytes = "vec", tag = "1")]
+    pub payload: Vec<u8>,
+}
+
+fn encode_payload(value: &Value) -> std::result::Result<Vec<u8>, ERPCError> {
+    lexpr::to_string(value)
+        .map(String::into_bytes)
+        .map_err(|e| ERPCError::SerializationError(e.to_string()))
+}
+
+fn decode_payload(payload: &[u8]) -> std::result::Result<Value, ERPCError> {
+    let text = std::str::from_utf8(payload)?;
+    if text.trim().is_empty() {
+        return Ok(Value::Null);
+    }
+    Ok(lexpr::from_str(text)?)
+}
+
+/// The gRPC status reporting `error`
+fn to_status(error: ERPCError) -> Status {
+    match error {
+        ERPCError::MethodNotFound(_) => Status::not_found(error.to_string()),
+        ERPCError::InvalidArgument(_)
+        | ERPCError::SerializationError(_)
+        | ERPCError::Parse(_)
+        | ERPCError::Utf8(_) => Status::invalid_argument(error.to_string()),
+        ERPCError::Timeout
+        | ERPCError::GuardViolation {
+            violation: Violation::Timeout(_),
+            ..
+        } => Status::deadline_exceeded(error.to_string()),
+        ERPCError::PermissionDenied(_) => Status::permission_denied(error.to_string()),
+        ERPCError::QuotaExceeded(_) | ERPCError::QueueFull | ERPCError::TooManyInFlight(_) => {
+            Status::resource_exhausted(error.to_string())
+        }
+        ERPCError::ApplicationError { class, message, .. } => {
+            let mut status = Status::unknown(message);
+            if let Ok(class) = class.parse() {
+                status.metadata_mut().insert(CLASS_KEY, class);
+            }
+            status
+        }
+        _ => Status::internal(error.to_string()),
+    }
+}
+
+/// The error a failed call of `method` is reported as
+fn from_status(method: &str, status: Status) -> ERPCError {
+    let message = status.message().to_string();
+    match status.code() {
+        Code::NotFound => ERPCError::MethodNotFound(method.to_string()),
+        Code::InvalidArgument => ERPCError::InvalidArgument(message),
+        Code::DeadlineExceeded => ERPCError::Timeout,
+        Code::PermissionDenied => ERPCError::PermissionDenied(message),
+        Code::ResourceExhausted => ERPCError::QuotaExceeded(message),
+        Code::Unavailable => ERPCError::ConnectionClosed,
+        code => ERPCError::ApplicationError {
+            class: status
+                .metadata()
+                .get(CLASS_KEY)
+                .and_then(|class| class.to_str().ok())
+                .map_or_else(|| format!("{:?}", code), str::to_string),
+            message,
+            backtrace: vec![],
+        },
+    }
+}
+
+/// The `elrpc.Bridge` service dispatching to a [`MethodRegistry`]
+///
+/// Add it to a [`tonic::transport::Server`] next to other services, or let a
+/// [`GrpcServer`] run it alone.
+#[derive(Clone)]
+pub struct GrpcBridge {
+    registry: Arc<MethodRegistry>,
+}
+
+impl GrpcBridge {
+    pub fn new(registry: Arc<MethodRegistry>) -> Self {
+        GrpcBridge { registry }
+    }
+}
+
+impl NamedService for GrpcBridge {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+impl UnaryService<CallRequest> for GrpcBridge {
+    type Response = CallReply;
+    type Future = BoxFuture<tonic::Response<CallReply>, Status>;
+
+    fn call(&mut self, request: tonic::Request<CallRequest>) -> Self::Future {
+        let registry = self.registry.clone();
+        Box::pin(async move {
+            let CallRequest { method, payload } = request.into_inner();
+            let args = decode_payload(&payload).map_err(to_status)?;
+            let result = registry
+                .call_method(&method, args)
+                .await
+                .map_err(to_status)?;
+            let payload = encode_payload(&result).map_err(to_status)?;
+            Ok(tonic::Response::new(CallReply { payload }))
+        })
+    }
+}
+
+impl<B> Service<http::Request<B>> for GrpcBridge
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != CALL_PATH {
+            let status = Status::unimplemented(format!("no such rpc: {}", request.uri().path()));
+            return Box::pin(async move { Ok(status.into_http()) });
+        }
+        let service = self.clone();
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+            Ok(grpc.unary(service, request).await)
+        })
+    }
+}
+
+/// gRPC server running a [`GrpcBridge`]
+pub struct GrpcServer {
+    registry: Arc<MethodRegistry>,
+    listener: Option<TcpListener>,
+    local_addr: SocketAddr,
+    shutdown_tx: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl GrpcServer {
+    /// Listen on `addr`; calls are accepted once [`GrpcServer::serve`] is called
+    ///
+    /// Pass [`Server::registry`](crate::server::Server::registry) to serve the
+    /// same methods as an EPC server.
+    pub async fn bind(
+        addr: &str,
+        registry: Arc<MethodRegistry>,
+    ) -> std::result::Result<Self, ERPCError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("gRPC bridge bound to {}", local_addr);
+
+        Ok(GrpcServer {
+            registry,
+            listener: Some(listener),
+            local_addr,
+            shutdown_tx: None,
+            handle: None,
+        })
+    }
+
+    /// Address the server listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the port the server listens on
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Start serving in the background
+    pub fn serve(&mut self) -> std::result::Result<(), ERPCError> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| ERPCError::ProtocolError("Bridge is already serving".to_string()))?;
+        let bridge = GrpcBridge::new(self.registry.clone());
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
+        self.shutdown_tx = Some(shutdown_tx);
+
+        self.handle = Some(tokio::spawn(async move {
+            let served = tonic::transport::Server::builder()
+                .add_service(bridge)
+                .serve_with_incoming_shutdown(TcpIncoming::from(listener), async move {
+                    shutdown_rx.recv().await;
+                })
+                .await;
+            if let Err(e) = served {
+                error!("gRPC bridge failed: {}", e);
+            }
+            info!("gRPC bridge stopped");
+        }));
+        Ok(())
+    }
+
+    /// Stop serving, letting calls in progress finish
+    pub async fn shutdown(&mut self) -> std::result::Result<(), ERPCError> {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(()).await;
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.await;
+        }
+        Ok(())
+    }
+}
+
+/// Client for an `elrpc.Bridge` service
+#[derive(Clone)]
+pub struct GrpcClient {
+    grpc: tonic::client::Grpc<Channel>,
+}
+
+impl GrpcClient {
+    /// Connect to the service at `uri`, e.g. `http://127.0.0.1:50051`
+    pub async fn connect(uri: impl Into<String>) -> std::result::Result<Self, ERPCError> {
+        let channel = Channel::from_shared(uri.into())
+            .map_err(|e| ERPCError::InvalidArgument(e.to_string()))?
+            .connect()
+            .await
+            .map_err(std::io::Error::other)?;
+        Ok(GrpcClient {
+            grpc: tonic::client::Grpc::new(channel),
+        })
+    }
+
+    /// Call a method with typed arguments and result
+    pub async fn call_sync<Args, Ret>(
+        &self,
+        method: &str,
+        args: Args,
+    ) -> std::result::Result<Ret, ERPCError>
+    where
+        Args: Serialize,
+        Ret: for<'de> Deserialize<'de>,
+    {
+        let args_value = serde_lexpr::to_value(&args)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+
+        let result = self.call_value(method, args_value).await?;
+
+        serde_lexpr::from_value(&result).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Call a method with raw S-expression arguments, returning the raw result
+    pub async fn call_value(
+        &self,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let mut grpc = self.grpc.clone();
+        grpc.ready().await.map_err(std::io::Error::other)?;
+
+        let mut request = tonic::Request::new(CallRequest {
+            method: method.to_string(),
+            payload: encode_payload(&args)?,
+        });
+        request
+            .extensions_mut()
+            .insert(tonic::GrpcMethod::new(SERVICE_NAME, "Call"));
+        let reply: tonic::Response<CallReply> = grpc
+            .unary(
+                request,
+                http::uri::PathAndQuery::from_static(CALL_PATH),
+                ProstCodec::default(),
+            )
+            .await
+            .map_err(|status| from_status(method, status))?;
+
+        decode_payload(&reply.into_inner().payload)
+    }
+}
+
+/// EPC method forwarding its calls to a gRPC backend
+pub struct GrpcMethod {
+    client: GrpcClient,
+    info: MethodInfo,
+}
+
+impl GrpcMethod {
+    /// Forward calls of `name` to the same method behind `client`
+    pub fn new(client: GrpcClient, name: impl Into<String>) -> Self {
+        GrpcMethod {
+            client,
+            info: MethodInfo::new(name, None::<&str>, Some("Forwarded to a gRPC backend")),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for GrpcMethod {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        self.client.call_value(&self.info.name, args).await
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_mapping() {
+        let error = ERPCError::ApplicationError {
+            class: "ValueError".to_string(),
+            message: "bad value".to_string(),
+            backtrace: vec![],
+        };
+        let status = to_status(error);
+        assert_eq!(status.code(), Code::Unknown);
+        assert!(matches!(
+            from_status("parse", status),
+            ERPCError::ApplicationError { class, message, .. }
+                if class == "ValueError" && message == "bad value"
+        ));
+
+        let status = to_status(ERPCError::MethodNotFound("nope".to_string()));
+        assert_eq!(status.code(), Code::NotFound);
+        assert!(matches!(from_status("nope", status), ERPCError::MethodNotFound(m) if m == "nope"));
+    }
+
+    #[tokio::test]
+    async fn test_call_over_grpc() {
+        let registry = Arc::new(MethodRegistry::new());
+        registry
+            .register_closure(
+                "add",
+                |(a, b): (i64, i64)| Ok(a + b),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let mut server = GrpcServer::bind("127.0.0.1:0", registry).await.unwrap();
+        server.serve().unwrap();
+
+        let client = GrpcClient::connect(format!("http://{}", server.local_addr()))
+            .await
+            .unwrap();
+        let sum: i64 = client.call_sync("add", (40, 2)).await.unwrap();
+        assert_eq!(sum, 42);
+        assert!(matches!(
+            client.call_value("nope", Value::Null).await,
+            Err(ERPCError::MethodNotFound(_))
+        ));
+
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! Execution guards for handlers
+//!
+//! A [`MethodGuard`] protects the server from its own misbehaving handlers:
+//! a call that runs too long or produces too large a result fails with
+//! [`ERPCError::GuardViolation`] instead of tying up a worker or flooding the
+//! peer. Methods opt in individually through
+//! [`MethodRegistry::guard_method`](crate::registry::MethodRegistry::guard_method),
+//! which wraps the registered handler in a [`GuardedHandler`].
+//!
+//! ```no_run
+//! # async fn example(registry: &elrpc::MethodRegistry) -> elrpc::Result<()> {
+//! use std::time::Duration;
+//! use elrpc::guard::MethodGuard;
+//!
+//! registry
+//!     .guard_method(
+//!         "search",
+//!         MethodGuard::new()
+//!             .timeout(Duration::from_secs(2))
+//!             .max_output_size(256 * 1024),
+//!     )
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A timeout abandons an async handler at its next `.await`. Synchronous
+//! handlers cannot be interrupted; their late result is discarded instead.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lexpr::Value;
+use tokio::time::Instant;
+
+use crate::error::ERPCError;
+use crate::log::warn;
+use crate::pretty::approx_size;
+use crate::registry::{MethodHandler, MethodInfo};
+use crate::reply::Reply;
+
+/// Limits a guarded method runs under
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MethodGuard {
+    /// Wall-clock time a call may take
+    pub timeout: Option<Duration>,
+    /// Estimated size of a result, checked before it is printed
+    pub max_result_size: Option<usize>,
+    /// Printed length of a result, as sent to the peer
+    pub max_output_size: Option<usize>,
+}
+
+impl MethodGuard {
+    /// A guard with no limits
+    pub fn new() -> Self {
+        MethodGuard::default()
+    }
+
+    /// Fail calls that take longer than `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fail calls whose result is estimated above `bytes`
+    pub fn max_result_size(mut self, bytes: usize) -> Self {
+        self.max_result_size = Some(bytes);
+        self
+    }
+
+    /// Fail calls whose printed result is longer than `bytes`
+    pub fn max_output_size(mut self, bytes: usize) -> Self {
+        self.max_output_size = Some(bytes);
+        self
+    }
+
+    /// Check a result against the size limits
+    fn check(&self, result: &Value) -> std::result::Result<(), Violation> {
+        if let Some(limit) = self.max_result_size {
+            let size = approx_size(result);
+            if size > limit {
+                return Err(Violation::ResultSize { limit, size });
+            }
+        }
+        if let Some(limit) = self.max_output_size {
+            let size = result.to_string().len();
+            if size > limit {
+                return Err(Violation::OutputSize { limit, size });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which limit of a [`MethodGuard`] a call broke
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    Timeout(Duration),
+    ResultSize { limit: usize, size: usize },
+    OutputSize { limit: usize, size: usize },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Timeout(limit) => write!(f, "ran longer than {:?}", limit),
+            Violation::ResultSize { limit, size } => {
+                write!(f, "result of ~{} bytes exceeds {}", size, limit)
+            }
+            Violation::OutputSize { limit, size } => {
+                write!(f, "output of {} bytes exceeds {}", size, limit)
+            }
+        }
+    }
+}
+
+/// Handler wrapper enforcing a [`MethodGuard`]
+pub struct GuardedHandler {
+    inner: Arc<dyn MethodHandler>,
+    guard: MethodGuard,
+    name: String,
+}
+
+impl GuardedHandler {
+    pub fn new(inner: Arc<dyn MethodHandler>, guard: MethodGuard) -> Self {
+        let name = inner.info().name;
+        GuardedHandler { inner, guard, name }
+    }
+
+    fn violation(&self, violation: Violation) -> ERPCError {
+        warn!("Method '{}' {}", self.name, violation);
+        ERPCError::GuardViolation {
+            method: self.name.clone(),
+            violation,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for GuardedHandler {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        self.call_reply(args).await.map(|reply| reply.value)
+    }
+
+    async fn call_reply(&self, args: Value) -> std::result::Result<Reply, ERPCError> {
+        let reply = match self.guard.timeout {
+            Some(limit) => {
+                let started = Instant::now();
+                let reply = tokio::time::timeout(limit, self.inner.call_reply(args))
+                    .await
+                    .map_err(|_| self.violation(Violation::Timeout(limit)))??;
+                // A synchronous handler blocks past the timer
+                if started.elapsed() > limit {
+                    return Err(self.violation(Violation::Timeout(limit)));
+                }
+                reply
+            }
+            None => self.inner.call_reply(args).await?,
+        };
+        self.guard
+            .check(&reply.value)
+            .map_err(|violation| self.violation(violation))?;
+        Ok(reply)
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.inner.info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::{Args, ExtractHandler};
+    use crate::registry::{ArgsStyle, ValueHandler};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_guard_violations() {
+        let slow = Arc::new(ExtractHandler::new(
+            |Args(n): Args<i64>| async move {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                Ok::<_, ERPCError>(Value::from(n))
+            },
+            ArgsStyle::Single,
+            "slow",
+            None::<&str>,
+            None::<&str>,
+        ));
+        let guarded =
+            GuardedHandler::new(slow, MethodGuard::new().timeout(Duration::from_millis(10)));
+        assert!(matches!(
+            guarded.call(Value::from(1)).await,
+            Err(ERPCError::GuardViolation {
+                violation: Violation::Timeout(_),
+                ..
+            })
+        ));
+
+        let echo = Arc::new(ValueHandler::new(Ok, "echo", None::<&str>, None::<&str>));
+        let guarded = GuardedHandler::new(echo, MethodGuard::new().max_output_size(8));
+        assert!(guarded.call(Value::from(1)).await.is_ok());
+        let error = guarded
+            .call(Value::string("far too long"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "method echo violated its guard: output of 14 bytes exceeds 8"
+        );
+    }
+}
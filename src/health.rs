@@ -0,0 +1,215 @@
+//! Periodic liveness probing of a connected peer
+//!
+//! A [`HealthMonitor`] probes a client at a fixed interval and publishes the
+//! resulting [`HealthState`] on a watch channel, so UIs and supervisors can
+//! react to transitions instead of polling.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use lexpr::Value;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::log::{debug, warn};
+
+/// Health of a probed peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthState {
+    /// The last probe succeeded
+    Healthy,
+    /// Recent probes failed, but fewer than `down_after` in a row
+    Degraded,
+    /// At least `down_after` consecutive probes failed
+    Down,
+}
+
+/// How a peer is probed
+#[derive(Debug, Clone)]
+pub enum Probe {
+    /// Query the peer's methods; every EPC implementation answers this
+    Methods,
+    /// Call a method and treat any successful return as healthy
+    Call { method: String, args: Value },
+}
+
+/// Health check configuration
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Time between probes
+    pub interval: Duration,
+    /// A probe not answered within this time counts as failed
+    pub timeout: Duration,
+    pub probe: Probe,
+    /// Consecutive failures after which the peer is considered down
+    pub down_after: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            probe: Probe::Methods,
+            down_after: 3,
+        }
+    }
+}
+
+/// Run a single probe against `client`
+pub async fn probe(
+    client: &Client,
+    probe: &Probe,
+    timeout: Duration,
+) -> std::result::Result<(), ERPCError> {
+    let call = async {
+        match probe {
+            Probe::Methods => client.query_methods().await.map(|_| ()),
+            Probe::Call { method, args } => {
+                client.call_value(method, args.clone()).await.map(|_| ())
+            }
+        }
+    };
+    tokio::time::timeout(timeout, call)
+        .await
+        .map_err(|_| ERPCError::Timeout)?
+}
+
+/// Background task probing a peer and publishing its health
+pub struct HealthMonitor {
+    state: watch::Receiver<HealthState>,
+    task: JoinHandle<()>,
+}
+
+impl HealthMonitor {
+    /// Start probing `client`; the peer is assumed healthy until a probe fails
+    pub fn spawn(client: Arc<Client>, config: HealthConfig) -> Self {
+        let (state_tx, state) = watch::channel(HealthState::Healthy);
+
+        let task = tokio::spawn(async move {
+            let mut failures = 0u32;
+            let mut interval = tokio::time::interval(config.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+
+                let next = match probe(&client, &config.probe, config.timeout).await {
+                    Ok(()) => {
+                        failures = 0;
+                        HealthState::Healthy
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        warn!("Health probe failed ({} in a row): {}", failures, e);
+                        if failures >= config.down_after.max(1) {
+                            HealthState::Down
+                        } else {
+                            HealthState::Degraded
+                        }
+                    }
+                };
+
+                state_tx.send_if_modified(|state| {
+                    if *state == next {
+                        return false;
+                    }
+                    debug!("Health state {:?} -> {:?}", state, next);
+                    *state = next;
+                    true
+                });
+                if state_tx.is_closed() {
+                    return;
+                }
+            }
+        });
+
+        HealthMonitor { state, task }
+    }
+
+    /// Current health state
+    pub fn state(&self) -> HealthState {
+        *self.state.borrow()
+    }
+
+    /// Receiver notified on every state transition
+    pub fn subscribe(&self) -> watch::Receiver<HealthState> {
+        self.state.clone()
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn test_state_transitions() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let flag = failing.clone();
+
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_value_method(
+                "ping",
+                move |_| {
+                    if flag.load(Ordering::SeqCst) {
+                        Err(ERPCError::ProcessError("overloaded".to_string()))
+                    } else {
+                        Ok(Value::symbol("pong"))
+                    }
+                },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let client = Arc::new(
+            Client::connect(format!("127.0.0.1:{}", port))
+                .await
+                .unwrap(),
+        );
+        let monitor = HealthMonitor::spawn(
+            client,
+            HealthConfig {
+                interval: Duration::from_millis(10),
+                probe: Probe::Call {
+                    method: "ping".to_string(),
+                    args: Value::Null,
+                },
+                down_after: 2,
+                ..Default::default()
+            },
+        );
+        assert_eq!(monitor.state(), HealthState::Healthy);
+
+        let mut states = monitor.subscribe();
+        failing.store(true, Ordering::SeqCst);
+        let within = Duration::from_secs(2);
+        tokio::time::timeout(within, states.wait_for(|s| *s == HealthState::Down))
+            .await
+            .unwrap()
+            .unwrap();
+
+        failing.store(false, Ordering::SeqCst);
+        tokio::time::timeout(within, states.wait_for(|s| *s == HealthState::Healthy))
+            .await
+            .unwrap()
+            .unwrap();
+
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,639 @@
+//! HTTP front end for registered methods
+//!
+//! An [`HttpGateway`] serves the methods of a [`MethodRegistry`] to browser
+//! dashboards and scripts, next to the EPC server sharing the registry:
+//!
+//! - `POST /call/<method>` calls `<method>`. A JSON array body is the
+//!   argument list, an object is passed as a single alist argument, an empty
+//!   body means no arguments and any other value is the only argument. The
+//!   reply is `{"result": ...}`, or `{"error": {"class": ..., "message": ...}}`
+//!   with a 4xx or 5xx status.
+//! - `GET /methods` lists the registered methods with their argument specs
+//!   and docstrings.
+//!
+//! Values are translated as described in the [`json`](crate::json) module. Only
+//! what these clients need is implemented: one request per connection, bodies
+//! with a `Content-Length` and no TLS; put a reverse proxy in front for more.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lexpr::Value;
+use serde_json::{json, Value as Json};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::error::ERPCError;
+use crate::guard::Violation;
+use crate::json::{json_to_value, value_to_json};
+use crate::log::{debug, error, info, warn};
+use crate::registry::MethodRegistry;
+
+/// Longest accepted request line plus headers
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Request line and headers of an HTTP request, as seen by an [`AuthHook`]
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    /// HTTP method, e.g. `POST`
+    pub method: String,
+    /// Request target without the query string
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub peer: SocketAddr,
+}
+
+impl HttpRequest {
+    /// Value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Callback deciding whether a request may proceed
+pub type AuthFn = dyn Fn(&HttpRequest) -> bool + Send + Sync;
+
+/// Authorization check run before every request; rejected requests get a 401
+#[derive(Clone)]
+pub struct AuthHook(Arc<AuthFn>);
+
+impl fmt::Debug for AuthHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthHook(..)")
+    }
+}
+
+impl AuthHook {
+    /// Authorize requests with `check`
+    pub fn new(check: impl Fn(&HttpRequest) -> bool + Send + Sync + 'static) -> Self {
+        AuthHook(Arc::new(check))
+    }
+
+    /// Require an `Authorization: Bearer <token>` header
+    pub fn bearer(token: impl Into<String>) -> Self {
+        let expected = format!("Bearer {}", token.into());
+        AuthHook::new(move |request| request.header("authorization") == Some(expected.as_str()))
+    }
+
+    fn allows(&self, request: &HttpRequest) -> bool {
+        (self.0)(request)
+    }
+}
+
+/// Configuration for [`HttpGateway`]
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    /// Check run before every request (None allows everything)
+    pub auth: Option<AuthHook>,
+    /// Largest accepted request body in bytes
+    pub max_body_size: usize,
+    /// Connections served at once; further ones get a 503
+    pub max_connections: usize,
+    /// Time allowed for reading a request and running the method
+    pub request_timeout: Duration,
+    /// Value of `Access-Control-Allow-Origin`, for pages served elsewhere
+    pub allow_origin: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            auth: None,
+            max_body_size: 1024 * 1024,
+            max_connections: 64,
+            request_timeout: Duration::from_secs(30),
+            allow_origin: None,
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Check every request with `auth`
+    pub fn auth(mut self, auth: AuthHook) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Reject bodies larger than `size` bytes with a 413
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = size;
+        self
+    }
+
+    /// Serve at most `max` connections at once
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Answer requests taking longer than `timeout` with a 504
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Allow pages from `origin` (or `*`) to call methods
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Self {
+        self.allow_origin = Some(origin.into());
+        self
+    }
+}
+
+/// HTTP server dispatching to a [`MethodRegistry`]
+pub struct HttpGateway {
+    registry: Arc<MethodRegistry>,
+    config: HttpConfig,
+    listener: Option<TcpListener>,
+    local_addr: SocketAddr,
+    shutdown_tx: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl HttpGateway {
+    /// Listen on `addr`; requests are accepted once [`HttpGateway::serve`] is called
+    ///
+    /// Pass [`Server::registry`](crate::server::Server::registry) to serve the
+    /// same methods as an EPC server.
+    pub async fn bind(
+        addr: &str,
+        registry: Arc<MethodRegistry>,
+        config: HttpConfig,
+    ) -> std::result::Result<Self, ERPCError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("HTTP gateway bound to {}", local_addr);
+
+        Ok(HttpGateway {
+            registry,
+            config,
+            listener: Some(listener),
+            local_addr,
+            shutdown_tx: None,
+            handle: None,
+        })
+    }
+
+    /// Address the gateway listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the port the gateway listens on
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Start accepting connections in the background
+    pub fn serve(&mut self) -> std::result::Result<(), ERPCError> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| ERPCError::ProtocolError("Gateway is already serving".to_string()))?;
+        let registry = self.registry.clone();
+        let config = Arc::new(self.config.clone());
+        let connections = Arc::new(Semaphore::new(config.max_connections.max(1)));
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        self.shutdown_tx = Some(shutdown_tx);
+
+        self.handle = Some(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((mut stream, peer)) => {
+                            let Ok(permit) = connections.clone().try_acquire_owned() else {
+                                // Answer off the accept loop so a peer that
+                                // never reads cannot stall it
+                                let config = config.clone();
+                                tokio::spawn(async move {
+                                    let body = error_body("Unavailable", "too many connections");
+                                    let write = write_response(&mut stream, 503, &body, &config);
+                                    let _ = tokio::time::timeout(config.request_timeout, write).await;
+                                });
+                                continue;
+                            };
+                            let registry = registry.clone();
+                            let config = config.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = handle_connection(stream, peer, &registry, &config).await {
+                                    warn!("HTTP connection from {} failed: {}", peer, e);
+                                }
+                                drop(permit);
+                            });
+                        }
+                        Err(e) => {
+                            error!("HTTP gateway failed to accept connection: {}", e);
+                            break;
+                        }
+                    },
+                    _ = shutdown_rx.recv() => break,
+                }
+            }
+            info!("HTTP gateway stopped");
+        }));
+        Ok(())
+    }
+
+    /// Stop accepting connections; requests in progress are still answered
+    pub async fn shutdown(&mut self) -> std::result::Result<(), ERPCError> {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(()).await;
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.await;
+        }
+        Ok(())
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    registry: &MethodRegistry,
+    config: &HttpConfig,
+) -> std::result::Result<(), ERPCError> {
+    let answer = answer(&mut stream, peer, registry, config);
+    let (status, body) = tokio::time::timeout(config.request_timeout, answer)
+        .await
+        .unwrap_or_else(|_| (504, error_body("Timeout", "request timed out")));
+    write_response(&mut stream, status, &body, config).await
+}
+
+/// Read one request and produce its status and JSON body
+async fn answer(
+    stream: &mut TcpStream,
+    peer: SocketAddr,
+    registry: &MethodRegistry,
+    config: &HttpConfig,
+) -> (u16, Json) {
+    let mut reader = BufReader::new(stream);
+    let request = match read_head(&mut reader, peer).await {
+        Ok(request) => request,
+        Err(status) => return (status, error_body("BadRequest", "malformed request")),
+    };
+    debug!("HTTP {} {} from {}", request.method, request.path, peer);
+
+    if request.method == "OPTIONS" {
+        return (204, Json::Null);
+    }
+    if let Some(auth) = &config.auth {
+        if !auth.allows(&request) {
+            return (401, error_body("Unauthorized", "not authorized"));
+        }
+    }
+
+    let route = if let Some(name) = request.path.strip_prefix("/call/") {
+        match percent_decode(name) {
+            Some(method) => Route::Call(method),
+            None => return (400, error_body("BadRequest", "invalid method name")),
+        }
+    } else if request.path == "/methods" {
+        Route::Methods
+    } else {
+        let message = format!("no route for {}", request.path);
+        return (404, error_body("NotFound", &message));
+    };
+
+    match (request.method.as_str(), route) {
+        ("POST", Route::Call(method)) => {
+            let body = match read_body(&mut reader, &request, config.max_body_size).await {
+                Ok(body) => body,
+                Err((status, message)) => return (status, error_body("BadRequest", message)),
+            };
+            let args = match body_to_args(&body) {
+                Ok(args) => args,
+                Err(e) => return (400, error_body("InvalidJson", &e.to_string())),
+            };
+            match registry.call_method(&method, args).await {
+                Ok(result) => match value_to_json(&result) {
+                    Ok(result) => (200, json!({"result": result})),
+                    Err(e) => error_reply(&e),
+                },
+                Err(e) => error_reply(&e),
+            }
+        }
+        ("GET", Route::Methods) => match registry.query_methods().await {
+            Ok(mut methods) => {
+                methods.sort_by(|a, b| a.name.cmp(&b.name));
+                let methods: Vec<Json> = methods
+                    .into_iter()
+                    .map(|info| {
+                        json!({"name": info.name, "arg_spec": info.arg_spec, "docstring": info.docstring})
+                    })
+                    .collect();
+                (200, json!({"result": methods}))
+            }
+            Err(e) => error_reply(&e),
+        },
+        _ => (
+            405,
+            error_body(
+                "MethodNotAllowed",
+                &format!("{} not allowed", request.method),
+            ),
+        ),
+    }
+}
+
+enum Route {
+    Call(String),
+    Methods,
+}
+
+/// Read the request line and headers
+async fn read_head<R>(reader: &mut R, peer: SocketAddr) -> std::result::Result<HttpRequest, u16>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    // Reading through `take` bounds what a single endless line can buffer
+    let mut head = (&mut *reader).take(MAX_HEAD_SIZE as u64);
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = head.read_line(&mut line).await.map_err(|_| 400u16)?;
+        if head.limit() == 0 && !line.ends_with('\n') {
+            return Err(431);
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if read == 0 || line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let mut lines = lines.into_iter();
+    let request_line = lines.next().ok_or(400u16)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(400);
+    };
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            Some((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect();
+
+    Ok(HttpRequest {
+        method: method.to_string(),
+        path,
+        headers,
+        peer,
+    })
+}
+
+async fn read_body<R>(
+    reader: &mut R,
+    request: &HttpRequest,
+    max_size: usize,
+) -> std::result::Result<Vec<u8>, (u16, &'static str)>
+where
+    R: AsyncReadExt + Unpin,
+{
+    if request.header("transfer-encoding").is_some() {
+        return Err((411, "a Content-Length is required"));
+    }
+    let length = match request.header("content-length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| (400, "invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > max_size {
+        return Err((413, "request body too large"));
+    }
+
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|_| (400, "truncated request body"))?;
+    Ok(body)
+}
+
+/// The argument list described by a request body
+fn body_to_args(body: &[u8]) -> std::result::Result<Value, serde_json::Error> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Value::Null);
+    }
+    Ok(match serde_json::from_slice(body)? {
+        Json::Array(items) => Value::list(items.iter().map(json_to_value)),
+        other => Value::list(vec![json_to_value(&other)]),
+    })
+}
+
+/// Decode `%XX` escapes in a path segment
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes)
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+fn error_body(class: &str, message: &str) -> Json {
+    json!({"error": {"class": class, "message": message}})
+}
+
+fn error_reply(error: &ERPCError) -> (u16, Json) {
+    match error {
+        ERPCError::MethodNotFound(_) => (404, error_body("MethodNotFound", &error.to_string())),
+        ERPCError::InvalidArgument(_) | ERPCError::SerializationError(_) => {
+            (400, error_body("InvalidArgument", &error.to_string()))
+        }
+        ERPCError::QuotaExceeded(_) | ERPCError::QueueFull | ERPCError::TooManyInFlight(_) => {
+            (429, error_body("Overloaded", &error.to_string()))
+        }
+        ERPCError::PermissionDenied(_) => (403, error_body("PermissionDenied", &error.to_string())),
+        ERPCError::Timeout
+        | ERPCError::GuardViolation {
+            violation: Violation::Timeout(_),
+            ..
+        } => (504, error_body("Timeout", &error.to_string())),
+        ERPCError::ApplicationError {
+            class,
+            message,
+            backtrace,
+        } => (
+            500,
+            json!({"error": {"class": class, "message": message, "backtrace": backtrace}}),
+        ),
+        _ => (500, error_body("InternalError", &error.to_string())),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+async fn write_response<W>(
+    writer: &mut W,
+    status: u16,
+    body: &Json,
+    config: &HttpConfig,
+) -> std::result::Result<(), ERPCError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let body = if status == 204 {
+        String::new()
+    } else {
+        body.to_string()
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason(status),
+        body.len()
+    );
+    if let Some(origin) = &config.allow_origin {
+        head.push_str(&format!(
+            "Access-Control-Allow-Origin: {}\r\n\
+             Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
+             Access-Control-Allow-Headers: Authorization, Content-Type\r\n",
+            origin
+        ));
+    }
+    head.push_str("\r\n");
+
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(addr: SocketAddr, raw: &str) -> (u16, Json) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(raw.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Json::Null))
+    }
+
+    fn post(path: &str, body: &str, token: &str) -> String {
+        format!(
+            "POST {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            token,
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("find-file").as_deref(), Some("find-file"));
+        assert_eq!(percent_decode("a%2Fb%20c").as_deref(), Some("a/b c"));
+        assert_eq!(percent_decode("bad%2"), None);
+        assert_eq!(percent_decode(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_head_bounds_lines() {
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let endless = vec![b'a'; MAX_HEAD_SIZE * 4];
+        assert_eq!(
+            read_head(&mut endless.as_slice(), peer).await.err(),
+            Some(431)
+        );
+
+        let mut head: &[u8] = b"GET /methods HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = read_head(&mut head, peer).await.unwrap();
+        assert_eq!(request.path, "/methods");
+        assert_eq!(request.header("host"), Some("localhost"));
+    }
+
+    #[tokio::test]
+    async fn test_call_over_http() {
+        let registry = Arc::new(MethodRegistry::new());
+        registry
+            .register_closure(
+                "add",
+                |(a, b): (i64, i64)| Ok(a + b),
+                Some("a b"),
+                Some("Add two numbers"),
+            )
+            .await
+            .unwrap();
+        let config = HttpConfig::default()
+            .auth(AuthHook::bearer("secret"))
+            .max_body_size(64);
+        let mut gateway = HttpGateway::bind("127.0.0.1:0", registry, config)
+            .await
+            .unwrap();
+        gateway.serve().unwrap();
+        let addr = gateway.local_addr();
+
+        assert_eq!(
+            request(addr, &post("/call/add", "[2, 3]", "secret")).await,
+            (200, json!({"result": 5}))
+        );
+        assert_eq!(
+            request(addr, &post("/call/add", "[2, 3]", "wrong")).await.0,
+            401
+        );
+        assert_eq!(
+            request(addr, &post("/call/nope", "", "secret")).await.0,
+            404
+        );
+        assert_eq!(
+            request(addr, &post("/call/add", "[\"x\", 3]", "secret"))
+                .await
+                .0,
+            400
+        );
+        // The body is refused before it is read
+        let oversized = "POST /call/add HTTP/1.1\r\nAuthorization: Bearer secret\r\n\
+                         Content-Length: 1000\r\n\r\n";
+        assert_eq!(request(addr, oversized).await.0, 413);
+
+        let (status, methods) = request(
+            addr,
+            "GET /methods HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(methods["result"][0]["docstring"], json!("Add two numbers"));
+
+        gateway.shutdown().await.unwrap();
+    }
+}
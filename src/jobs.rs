@@ -0,0 +1,504 @@
+//! Long-running jobs
+//!
+//! A method that indexes a project or runs a build should not hold an EPC
+//! call open for minutes. A [`JobManager`] runs such work in the background:
+//! the method registered with [`JobManager::register_job`] returns a job id
+//! right away, and the peer follows the job through built-in methods:
+//!
+//! - [`JOB_STATUS_METHOD`] `(ID)` returns the job's status,
+//! - [`JOB_WAIT_METHOD`] `(ID)` waits for the next progress report or the
+//!   end of the job, then returns the status,
+//! - [`JOB_RESULT_METHOD`] `(ID)` returns the result of a finished job, `nil`
+//!   while it runs, and fails for failed or cancelled jobs,
+//! - [`JOB_CANCEL_METHOD`] `(ID)` cancels the job, returning `t` if it was
+//!   still running.
+//!
+//! A status is a plist, `(:state running :progress 0.4 :message "parsing")`,
+//! with `:state` one of `running`, `done`, `failed` and `cancelled`.
+//!
+//! ```ignore
+//! let jobs = JobManager::new();
+//! jobs.register_methods(server.registry()).await?;
+//! jobs.register_job(
+//!     server.registry(),
+//!     "index-project",
+//!     |root: String, progress: JobProgress| async move {
+//!         let files = list_files(&root)?;
+//!         for (i, file) in files.iter().enumerate() {
+//!             index(file).await?;
+//!             progress.report(i as f64 / files.len() as f64, Some(file.as_str()));
+//!         }
+//!         Ok(files.len())
+//!     },
+//!     Some("root"),
+//!     Some("Index ROOT, returning a job id"),
+//! )
+//! .await?;
+//! ```
+//!
+//! Rust code can watch a job's status with [`JobManager::watch`]. Finished
+//! jobs are forgotten after [`JobManager::retention`].
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+
+use crate::error::ERPCError;
+use crate::log::debug;
+use crate::registry::{ArgsStyle, MethodHandler, MethodInfo, MethodRegistry};
+
+pub const JOB_STATUS_METHOD: &str = "elrpc-job-status";
+pub const JOB_WAIT_METHOD: &str = "elrpc-job-wait";
+pub const JOB_RESULT_METHOD: &str = "elrpc-job-result";
+pub const JOB_CANCEL_METHOD: &str = "elrpc-job-cancel";
+
+/// How long finished jobs are kept by default
+const DEFAULT_RETENTION: Duration = Duration::from_secs(3600);
+
+pub type JobId = u64;
+
+/// Where a job is in its life
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobState {
+    Running,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+/// A job's state and its latest progress report
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobStatus {
+    pub state: JobState,
+    /// Fraction done, between 0 and 1
+    pub progress: Option<f64>,
+    pub message: Option<String>,
+}
+
+impl JobStatus {
+    fn running() -> Self {
+        JobStatus {
+            state: JobState::Running,
+            progress: None,
+            message: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state != JobState::Running
+    }
+
+    /// The status as the plist built-in methods return
+    pub fn to_value(&self) -> Value {
+        let (state, message) = match &self.state {
+            JobState::Running => ("running", self.message.clone()),
+            JobState::Done => ("done", self.message.clone()),
+            JobState::Failed(error) => ("failed", Some(error.clone())),
+            JobState::Cancelled => ("cancelled", self.message.clone()),
+        };
+        Value::list(vec![
+            Value::keyword("state"),
+            Value::symbol(state),
+            Value::keyword("progress"),
+            self.progress.map_or(Value::Nil, Value::from),
+            Value::keyword("message"),
+            message.map_or(Value::Nil, Value::string),
+        ])
+    }
+}
+
+struct Job {
+    status: watch::Sender<JobStatus>,
+    result: Mutex<Option<Value>>,
+    task: Mutex<Option<AbortHandle>>,
+    finished: Mutex<Option<Instant>>,
+}
+
+impl Job {
+    /// Move a running job to `state`; false if it had already finished
+    fn finish(&self, state: JobState) -> bool {
+        let finished = self.status.send_if_modified(|status| {
+            if status.is_finished() {
+                return false;
+            }
+            status.state = state;
+            true
+        });
+        if finished {
+            *self.finished.lock().unwrap() = Some(Instant::now());
+        }
+        finished
+    }
+}
+
+/// Fails its job if the task ends without finishing it, i.e. panics
+struct Unfinished(Arc<Job>);
+
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        self.0.finish(JobState::Failed("job panicked".to_string()));
+    }
+}
+
+/// Handed to a job to report its progress
+#[derive(Clone)]
+pub struct JobProgress {
+    job: Arc<Job>,
+}
+
+impl JobProgress {
+    /// Report that `fraction` of the work is done
+    pub fn report(&self, fraction: f64, message: Option<impl Into<String>>) {
+        let message = message.map(Into::into);
+        self.job.status.send_modify(|status| {
+            status.progress = Some(fraction.clamp(0.0, 1.0));
+            status.message = message;
+        });
+    }
+
+    /// Whether the job was cancelled
+    ///
+    /// Cancelled jobs are aborted at their next await point; synchronous
+    /// work can check this instead.
+    pub fn is_cancelled(&self) -> bool {
+        self.job.status.borrow().state == JobState::Cancelled
+    }
+}
+
+struct Inner {
+    jobs: Mutex<HashMap<JobId, Arc<Job>>>,
+    next_id: AtomicU64,
+    retention: Duration,
+}
+
+/// Runs jobs in the background and keeps their status and result
+///
+/// Clones share the jobs.
+#[derive(Clone)]
+pub struct JobManager {
+    inner: Arc<Inner>,
+}
+
+impl Default for JobManager {
+    fn default() -> Self {
+        JobManager::new()
+    }
+}
+
+impl JobManager {
+    pub fn new() -> Self {
+        JobManager::with_retention(DEFAULT_RETENTION)
+    }
+
+    /// Manager forgetting finished jobs after `retention`
+    pub fn with_retention(retention: Duration) -> Self {
+        JobManager {
+            inner: Arc::new(Inner {
+                jobs: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(1),
+                retention,
+            }),
+        }
+    }
+
+    /// How long finished jobs are kept
+    pub fn retention(&self) -> Duration {
+        self.inner.retention
+    }
+
+    fn job(&self, id: JobId) -> std::result::Result<Arc<Job>, ERPCError> {
+        self.inner
+            .jobs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| ERPCError::InvalidArgument(format!("no job {}", id)))
+    }
+
+    /// Start `job` on the runtime, returning its id
+    pub fn submit<F, Fut>(&self, job: F) -> JobId
+    where
+        F: FnOnce(JobProgress) -> Fut,
+        Fut: Future<Output = std::result::Result<Value, ERPCError>> + Send + 'static,
+    {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Job {
+            status: watch::Sender::new(JobStatus::running()),
+            result: Mutex::new(None),
+            task: Mutex::new(None),
+            finished: Mutex::new(None),
+        });
+        {
+            let mut jobs = self.inner.jobs.lock().unwrap();
+            let retention = self.inner.retention;
+            jobs.retain(|_, job| {
+                job.finished
+                    .lock()
+                    .unwrap()
+                    .is_none_or(|at| at.elapsed() < retention)
+            });
+            jobs.insert(id, entry.clone());
+        }
+
+        let future = job(JobProgress { job: entry.clone() });
+        let task = tokio::spawn({
+            let guard = Unfinished(entry.clone());
+            async move {
+                match future.await {
+                    Ok(value) => {
+                        *guard.0.result.lock().unwrap() = Some(value);
+                        guard.0.finish(JobState::Done);
+                    }
+                    Err(e) => {
+                        guard.0.finish(JobState::Failed(e.to_string()));
+                    }
+                }
+                debug!("Job {} finished", id);
+            }
+        });
+        *entry.task.lock().unwrap() = Some(task.abort_handle());
+        debug!("Job {} submitted", id);
+        id
+    }
+
+    /// Status of job `id`
+    pub fn status(&self, id: JobId) -> std::result::Result<JobStatus, ERPCError> {
+        Ok(self.job(id)?.status.borrow().clone())
+    }
+
+    /// Receiver of every status change of job `id`
+    pub fn watch(&self, id: JobId) -> std::result::Result<watch::Receiver<JobStatus>, ERPCError> {
+        Ok(self.job(id)?.status.subscribe())
+    }
+
+    /// Result of job `id`, `None` while it runs
+    pub fn result(&self, id: JobId) -> std::result::Result<Option<Value>, ERPCError> {
+        let job = self.job(id)?;
+        let state = job.status.borrow().state.clone();
+        match state {
+            JobState::Running => Ok(None),
+            JobState::Done => Ok(job.result.lock().unwrap().clone()),
+            JobState::Failed(message) => Err(ERPCError::ApplicationError {
+                class: "JobFailed".to_string(),
+                message,
+                backtrace: vec![],
+            }),
+            JobState::Cancelled => Err(ERPCError::ApplicationError {
+                class: "JobCancelled".to_string(),
+                message: format!("job {} was cancelled", id),
+                backtrace: vec![],
+            }),
+        }
+    }
+
+    /// Cancel job `id`, returning whether it was still running
+    pub fn cancel(&self, id: JobId) -> std::result::Result<bool, ERPCError> {
+        let job = self.job(id)?;
+        if !job.finish(JobState::Cancelled) {
+            return Ok(false);
+        }
+        if let Some(task) = job.task.lock().unwrap().take() {
+            task.abort();
+        }
+        debug!("Job {} cancelled", id);
+        Ok(true)
+    }
+
+    /// Wait for the next status change of job `id`, or return at once if it
+    /// has finished
+    pub async fn wait(&self, id: JobId) -> std::result::Result<JobStatus, ERPCError> {
+        let mut status = self.watch(id)?;
+        if !status.borrow_and_update().is_finished() {
+            // The job keeps the sender while it is known, so this only fails
+            // once it has been forgotten
+            let _ = status.changed().await;
+        }
+        let status = status.borrow().clone();
+        Ok(status)
+    }
+
+    /// Register the built-in job methods on `registry`
+    pub async fn register_methods(
+        &self,
+        registry: &MethodRegistry,
+    ) -> std::result::Result<(), ERPCError> {
+        for (name, op, docstring) in [
+            (
+                JOB_STATUS_METHOD,
+                JobOp::Status,
+                "Return the status of job ID",
+            ),
+            (
+                JOB_WAIT_METHOD,
+                JobOp::Wait,
+                "Wait for news of job ID and return its status",
+            ),
+            (
+                JOB_RESULT_METHOD,
+                JobOp::Result,
+                "Return the result of job ID, nil while it runs",
+            ),
+            (JOB_CANCEL_METHOD, JobOp::Cancel, "Cancel job ID"),
+        ] {
+            let method = JobMethod {
+                jobs: self.clone(),
+                op,
+                info: MethodInfo::new(name, Some("id"), Some(docstring)),
+            };
+            registry.register_handler(name, Arc::new(method)).await;
+        }
+        Ok(())
+    }
+
+    /// Register a method starting a job with its arguments and returning the
+    /// job id
+    pub async fn register_job<F, Args, Ret, Fut>(
+        &self,
+        registry: &MethodRegistry,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(Args, JobProgress) -> Fut + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send + 'static,
+        Ret: Serialize,
+        Fut: Future<Output = std::result::Result<Ret, ERPCError>> + Send + 'static,
+    {
+        let name = name.into();
+        let handler = JobHandler {
+            jobs: self.clone(),
+            func: Box::new(move |args, progress| {
+                let future = func(args, progress);
+                Box::pin(async move {
+                    let result = future.await?;
+                    serde_lexpr::to_value(&result)
+                        .map_err(|e| ERPCError::SerializationError(e.to_string()))
+                })
+            }),
+            args_style: registry.args_style(),
+            info: MethodInfo::new(name.clone(), arg_spec, docstring),
+        };
+        registry.register_handler(name, Arc::new(handler)).await;
+        Ok(())
+    }
+}
+
+type JobFuture =
+    std::pin::Pin<Box<dyn Future<Output = std::result::Result<Value, ERPCError>> + Send>>;
+
+type JobFn<Args> = dyn Fn(Args, JobProgress) -> JobFuture + Send + Sync;
+
+/// Handler submitting a job per call
+struct JobHandler<Args> {
+    jobs: JobManager,
+    func: Box<JobFn<Args>>,
+    args_style: ArgsStyle,
+    info: MethodInfo,
+}
+
+#[async_trait::async_trait]
+impl<Args> MethodHandler for JobHandler<Args>
+where
+    Args: for<'de> Deserialize<'de> + Send + 'static,
+{
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        let args: Args = self.args_style.decode(&args)?;
+        let id = self.jobs.submit(|progress| (self.func)(args, progress));
+        Ok(Value::from(id))
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum JobOp {
+    Status,
+    Wait,
+    Result,
+    Cancel,
+}
+
+/// One of the built-in job methods
+struct JobMethod {
+    jobs: JobManager,
+    op: JobOp,
+    info: MethodInfo,
+}
+
+/// The job id of `(ID)` or a bare `ID`
+fn job_id(args: &Value) -> std::result::Result<JobId, ERPCError> {
+    let id = match args {
+        Value::Cons(cons) if cons.cdr().is_null() => cons.car(),
+        _ => args,
+    };
+    id.as_u64()
+        .ok_or_else(|| ERPCError::InvalidArgument("expected a job id".to_string()))
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for JobMethod {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        let id = job_id(&args)?;
+        match self.op {
+            JobOp::Status => Ok(self.jobs.status(id)?.to_value()),
+            JobOp::Wait => Ok(self.jobs.wait(id).await?.to_value()),
+            JobOp::Result => Ok(self.jobs.result(id)?.unwrap_or(Value::Nil)),
+            JobOp::Cancel => Ok(Value::from(self.jobs.cancel(id)?)),
+        }
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_job_lifecycle() {
+        let jobs = JobManager::new();
+        let (go_tx, go_rx) = tokio::sync::oneshot::channel::<()>();
+        let id = jobs.submit(|progress| async move {
+            progress.report(0.5, Some("halfway"));
+            go_rx.await.ok();
+            Ok(Value::symbol("indexed"))
+        });
+
+        let status = jobs.wait(id).await.unwrap();
+        assert_eq!(status.progress, Some(0.5));
+        assert_eq!(status.message.as_deref(), Some("halfway"));
+        assert_eq!(jobs.result(id).unwrap(), None);
+
+        go_tx.send(()).unwrap();
+        while !jobs.wait(id).await.unwrap().is_finished() {}
+        assert_eq!(jobs.status(id).unwrap().state, JobState::Done);
+        assert_eq!(jobs.result(id).unwrap(), Some(Value::symbol("indexed")));
+        assert!(!jobs.cancel(id).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_job() {
+        let jobs = JobManager::new();
+        let id = jobs.submit(|_| std::future::pending());
+
+        assert!(jobs.cancel(id).unwrap());
+        assert_eq!(jobs.status(id).unwrap().state, JobState::Cancelled);
+        assert!(jobs.result(id).is_err());
+        assert!(jobs.status(id + 1).is_err());
+    }
+}
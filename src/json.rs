@@ -0,0 +1,256 @@
+//! Conversions between EPC values and JSON
+//!
+//! [`EpcValue`] wraps a [`Value`] so it can be converted to and from
+//! [`serde_json::Value`] with `From`/`TryFrom`. The mapping follows Emacs Lisp
+//! conventions, so that what a JSON service sends reads naturally in Elisp:
+//!
+//! | JSON            | S-expression                            |
+//! |-----------------|-----------------------------------------|
+//! | `null`, `false` | `nil`                                   |
+//! | `true`          | `t`                                     |
+//! | number          | integer or float                        |
+//! | string          | string                                  |
+//! | array           | list, `nil` when empty                  |
+//! | object          | alist with symbol keys, `((a . 1))`     |
+//!
+//! Going back to JSON:
+//!
+//! - `nil`, `()` and the symbol `nil` become `null`, and `t` becomes `true`;
+//! - other symbols become strings holding their name, keywords strings with a
+//!   leading colon (`:key`) and characters one-character strings;
+//! - a list whose elements are all dotted pairs with a symbol, keyword,
+//!   string or number key becomes an object; number keys are written in
+//!   decimal, so `((1 . "a"))` becomes `{"1": "a"}`;
+//! - any other list, vector or byte vector becomes an array, with dotted
+//!   pairs in it as two-element arrays.
+//!
+//! Symbols, keywords and characters therefore come back from a round trip as
+//! strings, and number keys as symbols. Conversion to JSON fails only for
+//! values JSON cannot hold: infinite or NaN floats and improper lists other
+//! than pairs.
+
+use std::ops::Deref;
+
+use lexpr::Value;
+use serde_json::{Map, Number, Value as Json};
+
+use crate::error::ERPCError;
+
+/// An EPC value convertible to and from JSON
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpcValue(pub Value);
+
+impl EpcValue {
+    /// The wrapped value
+    pub fn into_inner(self) -> Value {
+        self.0
+    }
+}
+
+impl Deref for EpcValue {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl From<Value> for EpcValue {
+    fn from(value: Value) -> Self {
+        EpcValue(value)
+    }
+}
+
+impl From<EpcValue> for Value {
+    fn from(value: EpcValue) -> Self {
+        value.0
+    }
+}
+
+impl From<&Json> for EpcValue {
+    fn from(json: &Json) -> Self {
+        EpcValue(json_to_value(json))
+    }
+}
+
+impl From<Json> for EpcValue {
+    fn from(json: Json) -> Self {
+        EpcValue::from(&json)
+    }
+}
+
+impl TryFrom<&EpcValue> for Json {
+    type Error = ERPCError;
+
+    fn try_from(value: &EpcValue) -> std::result::Result<Self, ERPCError> {
+        value_to_json(&value.0)
+    }
+}
+
+impl TryFrom<EpcValue> for Json {
+    type Error = ERPCError;
+
+    fn try_from(value: EpcValue) -> std::result::Result<Self, ERPCError> {
+        value_to_json(&value.0)
+    }
+}
+
+/// Translate JSON into an S-expression
+pub(crate) fn json_to_value(json: &Json) -> Value {
+    match json {
+        Json::Null | Json::Bool(false) => Value::Nil,
+        Json::Bool(true) => Value::symbol("t"),
+        Json::Number(n) => n
+            .as_i64()
+            .map(Value::from)
+            .or_else(|| n.as_u64().map(Value::from))
+            .unwrap_or_else(|| Value::from(n.as_f64().unwrap_or(f64::NAN))),
+        Json::String(s) => Value::string(s.as_str()),
+        Json::Array(items) => Value::list(items.iter().map(json_to_value)),
+        Json::Object(entries) => Value::list(
+            entries
+                .iter()
+                .map(|(key, value)| Value::cons(Value::symbol(key.as_str()), json_to_value(value))),
+        ),
+    }
+}
+
+/// Translate an S-expression into JSON
+pub(crate) fn value_to_json(value: &Value) -> std::result::Result<Json, ERPCError> {
+    Ok(match value {
+        Value::Nil | Value::Null => Json::Null,
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => Json::from(i),
+            (_, Some(u), _) => Json::from(u),
+            (_, _, f) => f
+                .and_then(Number::from_f64)
+                .map(Json::Number)
+                .ok_or_else(|| {
+                    ERPCError::SerializationError(format!("{} has no JSON representation", n))
+                })?,
+        },
+        Value::Char(c) => Json::String(c.to_string()),
+        Value::String(s) => Json::String(s.to_string()),
+        Value::Symbol(s) => match &**s {
+            "nil" => Json::Null,
+            "t" => Json::Bool(true),
+            name => Json::String(name.to_string()),
+        },
+        Value::Keyword(k) => Json::String(format!(":{}", k)),
+        Value::Bytes(bytes) => Json::Array(bytes.iter().map(|b| Json::from(*b)).collect()),
+        Value::Vector(items) => Json::Array(
+            items
+                .iter()
+                .map(value_to_json)
+                .collect::<std::result::Result<_, _>>()?,
+        ),
+        Value::Cons(cell) if is_pair(value) => {
+            Json::Array(vec![value_to_json(cell.car())?, value_to_json(cell.cdr())?])
+        }
+        Value::Cons(_) => {
+            let items = value.to_vec().ok_or_else(|| {
+                ERPCError::SerializationError(format!("improper list {} is not JSON", value))
+            })?;
+            match alist_keys(&items) {
+                Some(keys) => Json::Object(
+                    keys.into_iter()
+                        .zip(&items)
+                        .map(|(key, entry)| {
+                            let value = entry.as_cons().map_or(&Value::Nil, |pair| pair.cdr());
+                            Ok((key, value_to_json(value)?))
+                        })
+                        .collect::<std::result::Result<Map<_, _>, ERPCError>>()?,
+                ),
+                None => Json::Array(
+                    items
+                        .iter()
+                        .map(value_to_json)
+                        .collect::<std::result::Result<_, _>>()?,
+                ),
+            }
+        }
+    })
+}
+
+/// Whether `value` is a dotted pair such as `(a . 1)`
+fn is_pair(value: &Value) -> bool {
+    matches!(value, Value::Cons(cell) if !matches!(cell.cdr(), Value::Cons(_) | Value::Null))
+}
+
+/// Object keys for `items` if every one of them is a pair with a usable key
+fn alist_keys(items: &[Value]) -> Option<Vec<String>> {
+    items
+        .iter()
+        .map(|item| {
+            let key = item.as_cons().filter(|_| is_pair(item))?.car();
+            match key {
+                Value::Symbol(name) | Value::String(name) => Some(name.to_string()),
+                Value::Keyword(name) => Some(format!(":{}", name)),
+                Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_to_epc() {
+        let json = json!({"name": "elrpc", "stars": 3, "fork": null, "tags": ["epc", "json"]});
+        let value = EpcValue::from(&json);
+        assert_eq!(
+            lexpr::to_string(&value).unwrap(),
+            "((fork . nil) (name . \"elrpc\") (stars . 3) (tags \"epc\" \"json\"))"
+        );
+        // `(tags "epc" "json")` is not a pair, so the pairs become arrays
+        assert_eq!(
+            Json::try_from(&value).unwrap(),
+            json!([
+                ["fork", null],
+                ["name", "elrpc"],
+                ["stars", 3],
+                ["tags", "epc", "json"]
+            ])
+        );
+
+        let value = EpcValue::from(json!({"a": 1, "b": "x", "c": true}));
+        assert_eq!(
+            Json::try_from(value).unwrap(),
+            json!({"a": 1, "b": "x", "c": true})
+        );
+    }
+
+    #[test]
+    fn test_epc_to_json() {
+        let value = EpcValue(Value::list(vec![
+            Value::symbol("t"),
+            Value::Nil,
+            Value::keyword("key"),
+            Value::from(1.5),
+            Value::symbol("sym"),
+            Value::Char('x'),
+        ]));
+        assert_eq!(
+            Json::try_from(value).unwrap(),
+            json!([true, null, ":key", 1.5, "sym", "x"])
+        );
+
+        let numbered = Value::list(vec![
+            Value::cons(Value::from(1), Value::string("a")),
+            Value::cons(Value::keyword("k"), Value::from(2)),
+        ]);
+        assert_eq!(
+            Json::try_from(EpcValue(numbered)).unwrap(),
+            json!({"1": "a", ":k": 2})
+        );
+
+        let improper = Value::cons(Value::from(1), Value::cons(Value::from(2), Value::from(3)));
+        assert!(Json::try_from(EpcValue(improper)).is_err());
+        assert!(Json::try_from(EpcValue(Value::from(f64::NAN))).is_err());
+    }
+}
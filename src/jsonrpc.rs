@@ -0,0 +1,629 @@
+//! JSON-RPC 2.0 bridge to and from EPC
+//!
+//! A [`JsonRpcServer`] accepts JSON-RPC connections and forwards every call
+//! to an EPC backend, so editors, scripts and language tooling without an EPC
+//! client can reuse existing EPC services. The other way round, a
+//! [`JsonRpcMethod`] registered on an EPC server forwards calls to a
+//! JSON-RPC backend through a [`JsonRpcClient`].
+//!
+//! Messages are newline-delimited JSON texts, over TCP or stdio. Values are
+//! translated as described in the [`json`](crate::json) module.
+//! Positional JSON-RPC params are the EPC argument list; named params are
+//! passed as a single alist argument.
+//!
+//! Lines are limited to [`DEFAULT_MAX_LINE`] bytes, the body limit of the
+//! HTTP gateway, unless set otherwise with `max_line_length`. A peer sending
+//! a longer line gets an error and is disconnected, since the rest of the
+//! line cannot be told apart from the next request.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use lexpr::Value;
+use serde_json::{json, Value as Json};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::json::{json_to_value, value_to_json};
+use crate::log::{debug, error, info, warn};
+use crate::registry::{MethodHandler, MethodInfo};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// Start of the range JSON-RPC leaves to implementations
+const APPLICATION_ERROR: i64 = -32000;
+
+/// Longest line read from a JSON-RPC peer unless configured otherwise
+pub const DEFAULT_MAX_LINE: usize = 1024 * 1024;
+
+/// The JSON-RPC error object for a failed EPC call
+fn error_object(error: &ERPCError) -> Json {
+    match error {
+        ERPCError::MethodNotFound(_) => {
+            json!({"code": METHOD_NOT_FOUND, "message": error.to_string()})
+        }
+        // EPC servers report unknown methods as a plain return-error
+        ERPCError::ApplicationError { message, .. }
+            if message.starts_with("method not found: ") =>
+        {
+            json!({"code": METHOD_NOT_FOUND, "message": message})
+        }
+        ERPCError::ApplicationError {
+            class,
+            message,
+            backtrace,
+        } => json!({
+            "code": APPLICATION_ERROR,
+            "message": message,
+            "data": {"class": class, "backtrace": backtrace},
+        }),
+        ERPCError::InvalidArgument(_) | ERPCError::SerializationError(_) => {
+            json!({"code": INVALID_PARAMS, "message": error.to_string()})
+        }
+        _ => json!({"code": INTERNAL_ERROR, "message": error.to_string()}),
+    }
+}
+
+fn error_response(id: Json, code: i64, message: impl Into<String>) -> Json {
+    json!({"jsonrpc": "2.0", "error": {"code": code, "message": message.into()}, "id": id})
+}
+
+/// Answers JSON-RPC requests by calling an EPC backend
+pub struct JsonRpcGateway {
+    backend: Client,
+    max_line: usize,
+}
+
+impl JsonRpcGateway {
+    /// Forward calls to `backend`
+    pub fn new(backend: Client) -> Self {
+        JsonRpcGateway {
+            backend,
+            max_line: DEFAULT_MAX_LINE,
+        }
+    }
+
+    /// Refuse requests longer than `bytes`
+    pub fn max_line_length(mut self, bytes: usize) -> Self {
+        self.max_line = bytes;
+        self
+    }
+
+    /// Connect to the EPC server at `addr` and forward calls to it
+    pub async fn connect(addr: impl Into<String>) -> std::result::Result<Self, ERPCError> {
+        Ok(JsonRpcGateway::new(Client::connect(addr).await?))
+    }
+
+    /// Answer one JSON text, a request or a batch
+    ///
+    /// Returns `None` when nothing is to be sent back, i.e. for notifications.
+    pub async fn handle(&self, text: &str) -> Option<String> {
+        let request: Json = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(e) => {
+                return Some(error_response(Json::Null, PARSE_ERROR, e.to_string()).to_string())
+            }
+        };
+
+        let response = match request {
+            Json::Array(batch) if batch.is_empty() => {
+                Some(error_response(Json::Null, INVALID_REQUEST, "empty batch"))
+            }
+            Json::Array(batch) => {
+                let mut responses = Vec::new();
+                for request in batch {
+                    responses.extend(self.respond(request).await);
+                }
+                (!responses.is_empty()).then_some(Json::Array(responses))
+            }
+            request => self.respond(request).await,
+        };
+        response.map(|response| response.to_string())
+    }
+
+    async fn respond(&self, request: Json) -> Option<Json> {
+        let id = request.get("id").cloned();
+        let reply_id = id.clone().unwrap_or(Json::Null);
+        let (Some("2.0"), Some(method)) = (
+            request.get("jsonrpc").and_then(Json::as_str),
+            request.get("method").and_then(Json::as_str),
+        ) else {
+            return Some(error_response(
+                reply_id,
+                INVALID_REQUEST,
+                "not a JSON-RPC 2.0 request",
+            ));
+        };
+
+        let args = match request.get("params") {
+            None => Value::Null,
+            Some(Json::Array(params)) => Value::list(params.iter().map(json_to_value)),
+            Some(params @ Json::Object(_)) => Value::list(vec![json_to_value(params)]),
+            Some(_) => {
+                return Some(error_response(
+                    reply_id,
+                    INVALID_PARAMS,
+                    "params must be an array or object",
+                ))
+            }
+        };
+
+        debug!("Forwarding JSON-RPC call '{}' to EPC", method);
+        let result = self.backend.call_value(method, args).await;
+        // Notifications are answered with silence, even when they fail
+        let id = id?;
+        Some(match result {
+            Ok(result) => match value_to_json(&result) {
+                Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+                Err(e) => json!({"jsonrpc": "2.0", "error": error_object(&e), "id": id}),
+            },
+            Err(e) => json!({"jsonrpc": "2.0", "error": error_object(&e), "id": id}),
+        })
+    }
+
+    /// Answer newline-delimited requests from `reader` on `writer` until the
+    /// reader is exhausted
+    ///
+    /// A request longer than the line limit is answered with an error and
+    /// ends the connection.
+    pub async fn serve_lines<R, W>(
+        &self,
+        reader: R,
+        mut writer: W,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        loop {
+            let response = match read_line(&mut reader, &mut line, self.max_line).await {
+                Ok(false) => return Ok(()),
+                Ok(true) => match std::str::from_utf8(&line) {
+                    Ok(text) if text.trim().is_empty() => continue,
+                    Ok(text) => self.handle(text).await,
+                    Err(e) => {
+                        Some(error_response(Json::Null, PARSE_ERROR, e.to_string()).to_string())
+                    }
+                },
+                Err(e @ ERPCError::ProtocolError(_)) => {
+                    warn!("Refusing JSON-RPC request: {}", e);
+                    let response = error_response(Json::Null, INVALID_REQUEST, e.to_string());
+                    writer
+                        .write_all(format!("{}\n", response).as_bytes())
+                        .await?;
+                    writer.flush().await?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(response) = response {
+                writer.write_all(response.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+                writer.flush().await?;
+            }
+        }
+    }
+
+    /// Answer requests on stdin and stdout, e.g. when started by an editor
+    pub async fn serve_stdio(&self) -> std::result::Result<(), ERPCError> {
+        self.serve_lines(tokio::io::stdin(), tokio::io::stdout())
+            .await
+    }
+}
+
+/// JSON-RPC server over TCP forwarding to an EPC backend
+///
+/// Each JSON-RPC connection gets its own connection to the backend.
+pub struct JsonRpcServer {
+    backend: String,
+    max_line: usize,
+    listener: Option<TcpListener>,
+    local_addr: SocketAddr,
+    shutdown_tx: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl JsonRpcServer {
+    /// Listen on `addr`; connections are accepted once [`JsonRpcServer::serve`] is called
+    pub async fn bind(
+        addr: &str,
+        backend: impl Into<String>,
+    ) -> std::result::Result<Self, ERPCError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let backend = backend.into();
+        info!("JSON-RPC gateway for {} bound to {}", backend, local_addr);
+
+        Ok(JsonRpcServer {
+            backend,
+            max_line: DEFAULT_MAX_LINE,
+            listener: Some(listener),
+            local_addr,
+            shutdown_tx: None,
+            handle: None,
+        })
+    }
+
+    /// Refuse requests longer than `bytes`
+    pub fn max_line_length(mut self, bytes: usize) -> Self {
+        self.max_line = bytes;
+        self
+    }
+
+    /// Address the server listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the port the server listens on
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Start accepting connections in the background
+    pub fn serve(&mut self) -> std::result::Result<(), ERPCError> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| ERPCError::ProtocolError("Gateway is already serving".to_string()))?;
+        let backend = self.backend.clone();
+        let max_line = self.max_line;
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        self.shutdown_tx = Some(shutdown_tx);
+
+        self.handle = Some(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, addr)) => {
+                            let backend = backend.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = serve_connection(stream, &backend, max_line).await {
+                                    warn!("JSON-RPC connection from {} failed: {}", addr, e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!("JSON-RPC gateway failed to accept connection: {}", e);
+                            break;
+                        }
+                    },
+                    _ = shutdown_rx.recv() => break,
+                }
+            }
+            info!("JSON-RPC gateway stopped");
+        }));
+        Ok(())
+    }
+
+    /// Stop accepting connections; open connections run until the peer closes
+    pub async fn shutdown(&mut self) -> std::result::Result<(), ERPCError> {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(()).await;
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.await;
+        }
+        Ok(())
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    backend: &str,
+    max_line: usize,
+) -> std::result::Result<(), ERPCError> {
+    let gateway = JsonRpcGateway::connect(backend)
+        .await?
+        .max_line_length(max_line);
+    let (reader, writer) = stream.into_split();
+    gateway.serve_lines(reader, writer).await?;
+    gateway.backend.close().await
+}
+
+/// Client for a JSON-RPC server speaking newline-delimited JSON over TCP
+pub struct JsonRpcClient {
+    stream: Mutex<(BufReader<OwnedReadHalf>, OwnedWriteHalf)>,
+    next_id: AtomicU64,
+    max_line: usize,
+}
+
+impl JsonRpcClient {
+    pub async fn connect(addr: &str) -> std::result::Result<Self, ERPCError> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        Ok(JsonRpcClient {
+            stream: Mutex::new((BufReader::new(reader), writer)),
+            next_id: AtomicU64::new(1),
+            max_line: DEFAULT_MAX_LINE,
+        })
+    }
+
+    /// Fail calls whose response is longer than `bytes`
+    pub fn max_line_length(mut self, bytes: usize) -> Self {
+        self.max_line = bytes;
+        self
+    }
+
+    /// Call `method` with `params` and wait for its result
+    pub async fn call(&self, method: &str, params: Json) -> std::result::Result<Json, ERPCError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": id});
+
+        let mut stream = self.stream.lock().await;
+        let (reader, writer) = &mut *stream;
+        writer
+            .write_all(format!("{}\n", request).as_bytes())
+            .await?;
+
+        let mut line = Vec::new();
+        loop {
+            if !read_line(reader, &mut line, self.max_line).await? {
+                return Err(ERPCError::ConnectionClosed);
+            }
+            let response: Json = serde_json::from_slice(&line)
+                .map_err(|e| ERPCError::InvalidMessageFormat(e.to_string()))?;
+            // Anything else on the connection, e.g. server notifications, is skipped
+            if response.get("id").and_then(Json::as_u64) != Some(id) {
+                continue;
+            }
+
+            if let Some(error) = response.get("error") {
+                let code = error
+                    .get("code")
+                    .and_then(Json::as_i64)
+                    .unwrap_or(INTERNAL_ERROR);
+                let message = error
+                    .get("message")
+                    .and_then(Json::as_str)
+                    .unwrap_or_default();
+                return Err(match code {
+                    METHOD_NOT_FOUND => ERPCError::MethodNotFound(method.to_string()),
+                    INVALID_PARAMS => ERPCError::InvalidArgument(message.to_string()),
+                    _ => ERPCError::ApplicationError {
+                        class: format!("JsonRpcError({})", code),
+                        message: message.to_string(),
+                        backtrace: vec![],
+                    },
+                });
+            }
+            return Ok(response.get("result").cloned().unwrap_or(Json::Null));
+        }
+    }
+}
+
+/// Read one line into `line`, without its newline
+///
+/// Returns false at the end of the input. A line longer than `max` bytes
+/// fails as soon as that many bytes have arrived, leaving the reader in the
+/// middle of it.
+async fn read_line<R>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    max: usize,
+) -> std::result::Result<bool, ERPCError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(!line.is_empty());
+        }
+        let newline = available.iter().position(|&b| b == b'\n');
+        let chunk = &available[..newline.unwrap_or(available.len())];
+        if line.len() + chunk.len() > max {
+            return Err(ERPCError::ProtocolError(format!(
+                "line exceeds the {} byte limit",
+                max
+            )));
+        }
+        line.extend_from_slice(chunk);
+        let consumed = chunk.len() + usize::from(newline.is_some());
+        reader.consume(consumed);
+        if newline.is_some() {
+            return Ok(true);
+        }
+    }
+}
+
+/// EPC method forwarding its calls to a JSON-RPC backend
+///
+/// The EPC argument list becomes positional params.
+pub struct JsonRpcMethod {
+    client: Arc<JsonRpcClient>,
+    info: MethodInfo,
+}
+
+impl JsonRpcMethod {
+    /// Forward calls of `name` to the same method of `client`'s server
+    pub fn new(client: Arc<JsonRpcClient>, name: impl Into<String>) -> Self {
+        JsonRpcMethod {
+            client,
+            info: MethodInfo::new(name, None::<&str>, Some("Forwarded to a JSON-RPC backend")),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for JsonRpcMethod {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        let params = match value_to_json(&args)? {
+            Json::Null => Json::Array(Vec::new()),
+            Json::Array(params) => Json::Array(params),
+            other => Json::Array(vec![other]),
+        };
+        let result = self.client.call(&self.info.name, params).await?;
+        Ok(json_to_value(&result))
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use tokio::io::Lines;
+
+    async fn epc_backend() -> Server {
+        Server::builder()
+            .bind("127.0.0.1:0")
+            .value_method("echo", Ok)
+            .method("add", |(a, b): (i64, i64)| Ok(a + b))
+            .build()
+            .await
+            .unwrap()
+    }
+
+    async fn next(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Json {
+        serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_gateway_forwards_to_epc() {
+        let mut backend = epc_backend().await;
+        let mut gateway =
+            JsonRpcServer::bind("127.0.0.1:0", backend.local_addr().unwrap().to_string())
+                .await
+                .unwrap();
+        gateway.serve().unwrap();
+
+        let stream = TcpStream::connect(gateway.local_addr()).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let requests = concat!(
+            r#"{"jsonrpc": "2.0", "method": "add", "params": [2, 3], "id": 1}"#,
+            "\n",
+            r#"{"jsonrpc": "2.0", "method": "echo", "params": ["hi"]}"#,
+            "\n",
+            r#"{"jsonrpc": "2.0", "method": "nope", "id": 2}"#,
+            "\n",
+            r#"[{"jsonrpc": "2.0", "method": "echo", "params": {"a": 1}, "id": 3}, {"id": 4}]"#,
+            "\n",
+            "not json\n",
+        );
+        writer.write_all(requests.as_bytes()).await.unwrap();
+
+        assert_eq!(
+            next(&mut lines).await,
+            json!({"jsonrpc": "2.0", "result": 5, "id": 1})
+        );
+        // The notification got no answer
+        assert_eq!(
+            next(&mut lines).await["error"]["code"],
+            json!(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            next(&mut lines).await,
+            json!([
+                {"jsonrpc": "2.0", "result": [{"a": 1}], "id": 3},
+                {"jsonrpc": "2.0", "error": {"code": INVALID_REQUEST, "message": "not a JSON-RPC 2.0 request"}, "id": 4},
+            ])
+        );
+        assert_eq!(next(&mut lines).await["error"]["code"], json!(PARSE_ERROR));
+
+        gateway.shutdown().await.unwrap();
+        backend.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_epc_method_forwards_to_json_rpc() {
+        // EPC client -> EPC server -> JSON-RPC gateway -> EPC backend
+        let mut backend = epc_backend().await;
+        let mut gateway =
+            JsonRpcServer::bind("127.0.0.1:0", backend.local_addr().unwrap().to_string())
+                .await
+                .unwrap();
+        gateway.serve().unwrap();
+
+        let json_client = Arc::new(
+            JsonRpcClient::connect(&gateway.local_addr().to_string())
+                .await
+                .unwrap(),
+        );
+        let mut front = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(
+                "add",
+                Arc::new(JsonRpcMethod::new(json_client.clone(), "add")),
+            )
+            .handler(
+                "missing",
+                Arc::new(JsonRpcMethod::new(json_client, "missing")),
+            )
+            .build()
+            .await
+            .unwrap();
+
+        let client = Client::connect(front.local_addr().unwrap().to_string())
+            .await
+            .unwrap();
+        let sum = client
+            .call_value("add", Value::list(vec![Value::from(40), Value::from(2)]))
+            .await
+            .unwrap();
+        assert_eq!(sum, Value::from(42));
+        assert!(client.call_value("missing", Value::Null).await.is_err());
+
+        client.close().await.unwrap();
+        front.shutdown().await.unwrap();
+        gateway.shutdown().await.unwrap();
+        backend.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_line_limits_length() {
+        let mut reader = &b"short\r\n0123456789abcdef\nlast"[..];
+        let mut line = Vec::new();
+        assert!(read_line(&mut reader, &mut line, 8).await.unwrap());
+        assert_eq!(line, b"short\r");
+        assert!(matches!(
+            read_line(&mut reader, &mut line, 8).await,
+            Err(ERPCError::ProtocolError(_))
+        ));
+
+        let mut reader = &b"exactly8\nlast"[..];
+        assert!(read_line(&mut reader, &mut line, 8).await.unwrap());
+        assert_eq!(line, b"exactly8");
+        assert!(read_line(&mut reader, &mut line, 8).await.unwrap());
+        assert_eq!(line, b"last");
+        assert!(!read_line(&mut reader, &mut line, 8).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_gateway_refuses_long_lines() {
+        let mut backend = epc_backend().await;
+        let gateway = JsonRpcGateway::connect(&backend.local_addr().unwrap().to_string())
+            .await
+            .unwrap()
+            .max_line_length(64);
+
+        let request = format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"echo","params":["{}"]}}"#,
+            "x".repeat(100)
+        );
+        let mut out = Vec::new();
+        let result = gateway
+            .serve_lines(format!("{}\n", request).as_bytes(), &mut out)
+            .await;
+        assert!(matches!(result, Err(ERPCError::ProtocolError(_))));
+        let response: Json = serde_json::from_slice(&out).unwrap();
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+
+        backend.shutdown().await.unwrap();
+    }
+}
@@ -3,16 +3,184 @@
 //! This crate provides a complete implementation of the EPC protocol
 //! for communication between Emacs and Rust applications.
 
+pub mod abuse;
+pub mod acl;
+pub mod announce;
+#[cfg(feature = "proptest")]
+pub mod arbitrary;
+pub mod arena;
+#[cfg(feature = "audit")]
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod checksum;
+#[cfg(feature = "chunked")]
+pub mod chunked;
 pub mod client;
+pub mod clock;
+#[cfg(feature = "codegen")]
+pub mod codegen;
+pub mod compat;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+pub mod context;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+#[cfg(feature = "process")]
+pub mod elisp_test;
+#[cfg(feature = "process")]
+pub mod emacs;
+#[cfg(feature = "values")]
+pub mod emacs_time;
 pub mod error;
+pub mod extract;
+#[cfg(feature = "failover")]
+pub mod failover;
+#[cfg(feature = "proxy")]
+pub mod fault;
+#[cfg(feature = "process")]
+pub mod fleet;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+#[cfg(feature = "golden")]
+pub mod golden;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod guard;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "jobs")]
+pub mod jobs;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "jsonrpc")]
+pub mod jsonrpc;
+#[cfg(feature = "proxy")]
+pub mod link;
+mod log;
+#[cfg(feature = "logging")]
+pub mod logging;
+#[cfg(feature = "process")]
+pub mod manager;
+pub mod metadata;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod params;
+pub mod peer_filter;
+pub mod pool;
+pub mod pretty;
+#[cfg(feature = "process")]
+pub mod process;
+#[cfg(feature = "process")]
+pub mod process_pool;
 pub mod protocol;
+#[cfg(feature = "proxy")]
+pub mod proxy;
 pub mod registry;
+pub mod reply;
+pub mod request_log;
+#[cfg(feature = "scheduler")]
+pub mod scheduler;
+pub mod scoped;
+pub mod security;
 pub mod server;
+#[cfg(feature = "tower")]
+pub mod service;
+#[cfg(feature = "test-util")]
+pub mod snapshot;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod stats;
+#[cfg(feature = "stress")]
+pub mod stress;
+#[cfg(feature = "process")]
+pub mod supervisor;
+pub mod tenant;
+#[cfg(any(test, feature = "test-util", feature = "conformance"))]
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod uid;
+#[cfg(feature = "conformance")]
+pub mod value_diff;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub mod wasm;
+pub mod wiretap;
 
-pub use client::{Client, Process};
-pub use error::{ERPCError, Result};
-pub use protocol::{Framer, Message};
-pub use registry::{MethodInfo, MethodRegistry};
-pub use server::{Server, ServerConfig};
-pub use uid::UidGenerator;
+pub use abuse::{AbuseDetector, AbuseEvent};
+pub use acl::MethodAcl;
+pub use announce::PortAnnouncer;
+pub use arena::{ArenaValue, ValueArena};
+#[cfg(feature = "audit")]
+pub use audit::{AuditArgs, AuditFormat, AuditLog, AuditRecord};
+pub use auth::{Authenticator, Handshake, Identity};
+#[cfg(feature = "cache")]
+pub use cache::{CacheConfig, ResultCache};
+pub use client::{Client, ClientConfig};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use compat::{Compat, CompatSelector};
+pub use context::{CallContext, Peer, PeerCredentials};
+#[cfg(feature = "discovery")]
+pub use discovery::{Broker, Registration, ServiceRecord};
+#[cfg(feature = "process")]
+pub use emacs::{start_emacs, Emacs, EmacsMode};
+#[cfg(feature = "values")]
+pub use emacs_time::EmacsTime;
+pub use error::{ERPCError, IntoEpcError, Result};
+#[cfg(feature = "failover")]
+pub use failover::{FailoverClient, Switchover};
+#[cfg(feature = "proxy")]
+pub use fault::{Fault, FaultInjector, FaultPlan};
+#[cfg(feature = "process")]
+pub use fleet::{ChildHealth, Fleet, FleetHealth, FleetSource};
+#[cfg(feature = "gateway")]
+pub use gateway::{Gateway, GatewayConfig};
+#[cfg(feature = "golden")]
+pub use golden::{GoldenRecorder, GoldenTrace};
+pub use guard::MethodGuard;
+pub use health::{HealthConfig, HealthMonitor, HealthState, Probe};
+#[cfg(feature = "jobs")]
+pub use jobs::{JobManager, JobProgress, JobState, JobStatus};
+#[cfg(feature = "json")]
+pub use json::EpcValue;
+#[cfg(feature = "proxy")]
+pub use link::{LinkProfile, Pacer};
+#[cfg(feature = "logging")]
+pub use logging::{init_logging, set_log_level};
+#[cfg(feature = "process")]
+pub use manager::{Manager, RoleStatus};
+pub use metadata::CallMetadata;
+pub use peer_filter::{Cidr, PeerFilter};
+pub use pool::{BufferPool, ReadSizer};
+pub use pretty::{approx_size, pretty, set_log_payload_limit, PrettyConfig};
+#[cfg(feature = "process")]
+pub use process::{PortHandshake, Process, StdinMode, StdoutMode, StopStage};
+#[cfg(feature = "process")]
+pub use process_pool::{Balance, PoolStats, ProcessPool, ProcessPoolConfig};
+pub use protocol::{Framer, LengthPrefix, Message};
+#[cfg(feature = "proxy")]
+pub use proxy::{Proxy, ProxyConfig};
+pub use registry::{ArgsStyle, MethodInfo, MethodRegistry};
+pub use request_log::{Redaction, RequestLogConfig};
+#[cfg(feature = "scheduler")]
+pub use scheduler::{Scheduler, TaskInfo, TaskRun};
+pub use scoped::ConnectionMethods;
+pub use security::SecurityProfile;
+pub use server::{FlushPolicy, FlushSelector, OverflowPolicy, Server, ServerBuilder, ServerConfig};
+#[cfg(feature = "test-util")]
+pub use snapshot::WireSnapshots;
+#[cfg(feature = "ssh")]
+pub use ssh::SshConfig;
+pub use stats::{QuotaAction, QuotaConfig, StatsSnapshot, UidErrors, Usage};
+#[cfg(feature = "process")]
+pub use supervisor::{RestartPolicy, Supervisor, SupervisorEvent};
+pub use tenant::Tenants;
+pub use uid::{
+    PartitionedUids, PersistentUids, RandomUids, ScriptedUids, SessionUids, UidGenerator, UidHalf,
+    UidStrategy, Wraparound,
+};
+pub use wiretap::{Direction, Frame, WireTap};
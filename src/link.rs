@@ -0,0 +1,140 @@
+//! Simulated slow links for local tests
+//!
+//! A [`LinkProfile`] describes one direction of a connection: a fixed
+//! latency, optional jitter and an optional throughput limit. Set on
+//! [`ProxyConfig::link`](crate::proxy::ProxyConfig), it makes a local server
+//! feel like one behind an SSH tunnel or a congested VPN, without leaving
+//! the machine.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::fault::Rng;
+
+/// Latency and throughput of one direction of a link
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkProfile {
+    /// Time from sending a byte to its arrival
+    pub latency: Duration,
+    /// Random extra delay of up to this much per write
+    pub jitter: Duration,
+    /// Throughput limit in bytes per second (None is unlimited)
+    pub bytes_per_sec: Option<u64>,
+    /// Seed of the jitter generator
+    pub seed: u64,
+}
+
+impl Default for LinkProfile {
+    fn default() -> Self {
+        LinkProfile {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bytes_per_sec: None,
+            seed: 0,
+        }
+    }
+}
+
+impl LinkProfile {
+    /// A link with `latency` and no throughput limit
+    pub fn with_latency(latency: Duration) -> Self {
+        LinkProfile {
+            latency,
+            ..Default::default()
+        }
+    }
+
+    /// Add up to `jitter` of random delay per write
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Limit throughput to `bytes_per_sec`
+    pub fn bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bytes_per_sec = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    /// Roughly an SSH tunnel to a remote host: 40ms each way, some jitter, 1 MB/s
+    pub fn ssh_tunnel() -> Self {
+        LinkProfile::with_latency(Duration::from_millis(40))
+            .jitter(Duration::from_millis(10))
+            .bandwidth(1024 * 1024)
+    }
+}
+
+/// Computes when writes on a simulated link arrive
+///
+/// Writes occupy the link for their transmission time one after another,
+/// then travel for the latency; arrivals never overtake each other, as on a
+/// stream socket.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    profile: LinkProfile,
+    rng: Rng,
+    link_free: Option<Instant>,
+    last_arrival: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(profile: LinkProfile) -> Self {
+        Pacer {
+            rng: Rng(profile.seed),
+            profile,
+            link_free: None,
+            last_arrival: None,
+        }
+    }
+
+    /// Arrival time of a write of `len` bytes submitted at `now`
+    pub fn schedule(&mut self, now: Instant, len: usize) -> Instant {
+        let transmit = self.profile.bytes_per_sec.map_or(Duration::ZERO, |rate| {
+            Duration::from_nanos((len as u128 * 1_000_000_000 / rate as u128) as u64)
+        });
+        let start = self.link_free.map_or(now, |free| free.max(now));
+        let sent = start + transmit;
+        self.link_free = Some(sent);
+
+        let jitter = self.profile.jitter.mul_f64(self.rng.unit());
+        let arrival = sent + self.profile.latency + jitter;
+        let arrival = self.last_arrival.map_or(arrival, |last| last.max(arrival));
+        self.last_arrival = Some(arrival);
+        arrival
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_and_bandwidth() {
+        let now = Instant::now();
+        let mut pacer =
+            Pacer::new(LinkProfile::with_latency(Duration::from_millis(50)).bandwidth(1000));
+
+        // 100 bytes take 100ms to send, then 50ms to travel
+        assert_eq!(pacer.schedule(now, 100), now + Duration::from_millis(150));
+        // The link is still busy with the first write
+        assert_eq!(pacer.schedule(now, 100), now + Duration::from_millis(250));
+        // An idle link starts sending right away
+        let later = now + Duration::from_secs(1);
+        assert_eq!(pacer.schedule(later, 0), later + Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_jitter_never_reorders() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(
+            LinkProfile::with_latency(Duration::from_millis(10)).jitter(Duration::from_millis(30)),
+        );
+
+        let arrivals: Vec<Instant> = (0..100)
+            .map(|i| pacer.schedule(now + Duration::from_millis(i), 10))
+            .collect();
+        assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(arrivals[0] >= now + Duration::from_millis(10));
+    }
+}
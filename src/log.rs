@@ -0,0 +1,67 @@
+//! Logging through `tracing`, or nothing without the `logging` feature
+//!
+//! Modules take `debug!`, `info!` and the other macros, spans and
+//! [`Instrument`] from here rather than from `tracing`, so the crate builds
+//! without it. Disabled log macros still type-check their format arguments;
+//! disabled spans record nothing and ignore their fields.
+
+#[cfg(feature = "logging")]
+pub(crate) use tracing::{debug, error, info, info_span, trace, warn, Instrument, Span};
+
+#[cfg(not(feature = "logging"))]
+pub(crate) use disabled::{debug, error, info, info_span, trace, warn, Instrument, Span};
+
+#[cfg(not(feature = "logging"))]
+mod disabled {
+    macro_rules! log_nothing {
+        // Structured events have no format string to check
+        (target: $target:expr, $($arg:tt)*) => {
+            ()
+        };
+        ($($arg:tt)*) => {
+            if false {
+                let _ = format_args!($($arg)*);
+            }
+        };
+    }
+
+    macro_rules! span_nothing {
+        ($($arg:tt)*) => {
+            $crate::log::Span::none()
+        };
+    }
+
+    pub(crate) use log_nothing as debug;
+    pub(crate) use log_nothing as error;
+    pub(crate) use log_nothing as info;
+    pub(crate) use log_nothing as trace;
+    pub(crate) use log_nothing as warn;
+    pub(crate) use span_nothing as info_span;
+
+    /// Stands in for `tracing::Span`
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct Span;
+
+    impl Span {
+        pub(crate) fn none() -> Self {
+            Span
+        }
+
+        pub(crate) fn current() -> Self {
+            Span
+        }
+
+        pub(crate) fn record<V>(&self, _field: &str, _value: V) -> &Self {
+            self
+        }
+    }
+
+    /// Stands in for `tracing::Instrument`
+    pub(crate) trait Instrument: Sized {
+        fn instrument(self, _span: Span) -> Self {
+            self
+        }
+    }
+
+    impl<T> Instrument for T {}
+}
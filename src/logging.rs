@@ -0,0 +1,93 @@
+//! Changing the log level of a running process
+//!
+//! [`init_logging`] installs a global `fmt` subscriber behind a reloadable
+//! level filter. Afterwards the level can be changed through [`set_log_level`],
+//! [`Server::set_log_level`](crate::server::Server::set_log_level) or, when a
+//! server registers it, the [`LOG_LEVEL_METHOD`] admin method.
+
+use std::sync::OnceLock;
+
+use lexpr::Value;
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+
+use crate::error::ERPCError;
+
+/// Name of the admin method registered by
+/// [`Server::register_log_level_method`](crate::server::Server::register_log_level_method)
+pub const LOG_LEVEL_METHOD: &str = "elrpc-log-level";
+
+static CONTROL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Install the global subscriber, logging at `level` until changed
+pub fn init_logging(level: LevelFilter) -> std::result::Result<(), ERPCError> {
+    let (filter, handle) = reload::Layer::new(level);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .try_init()
+        .map_err(|e| ERPCError::LogControl(e.to_string()))?;
+
+    CONTROL
+        .set(handle)
+        .map_err(|_| ERPCError::LogControl("logging is already initialized".to_string()))
+}
+
+fn control() -> std::result::Result<&'static reload::Handle<LevelFilter, Registry>, ERPCError> {
+    CONTROL.get().ok_or_else(|| {
+        ERPCError::LogControl("logging was not set up with init_logging".to_string())
+    })
+}
+
+/// Current log level
+pub fn log_level() -> std::result::Result<LevelFilter, ERPCError> {
+    control()?
+        .clone_current()
+        .ok_or_else(|| ERPCError::LogControl("subscriber is gone".to_string()))
+}
+
+/// Change the log level, returning the previous one
+///
+/// `level` is one of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+pub fn set_log_level(level: &str) -> std::result::Result<LevelFilter, ERPCError> {
+    let level: LevelFilter = level
+        .trim()
+        .parse()
+        .map_err(|_| ERPCError::InvalidArgument(format!("unknown log level: {}", level)))?;
+
+    let previous = log_level()?;
+    control()?
+        .reload(level)
+        .map_err(|e| ERPCError::LogControl(e.to_string()))?;
+    info!("Log level changed from {} to {}", previous, level);
+    Ok(previous)
+}
+
+/// Body of the admin method: `nil` reads the level, a string or symbol sets
+/// it; either way the level in effect before the call is returned
+pub(crate) fn log_level_method(args: Value) -> std::result::Result<Value, ERPCError> {
+    let level = match &args {
+        Value::Null | Value::Nil => None,
+        Value::Cons(_) => match args.to_vec().as_deref() {
+            Some([]) => None,
+            Some([level]) => Some(level.clone()),
+            _ => return Err(ERPCError::InvalidArgument("expected (LEVEL)".to_string())),
+        },
+        level => Some(level.clone()),
+    };
+
+    let previous = match level {
+        None => log_level()?,
+        Some(level) => {
+            let name = level
+                .as_str()
+                .or_else(|| level.as_symbol())
+                .ok_or_else(|| ERPCError::InvalidArgument("LEVEL must be a string".to_string()))?;
+            set_log_level(name)?
+        }
+    };
+    Ok(Value::string(previous.to_string()))
+}
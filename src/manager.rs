@@ -0,0 +1,385 @@
+//! Groups of EPC helper processes with distinct roles
+//!
+//! Where a [`ProcessPool`](crate::process_pool::ProcessPool) runs copies of
+//! one worker, a [`Manager`] owns different helpers, say an indexer and a
+//! formatter, each under a role name. It starts and stops them as a group,
+//! routes calls by role and lists the methods of all of them:
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use elrpc::{Manager, Process};
+//!
+//! let manager = Manager::new()
+//!     .add("indexer", Process::new("python3", vec!["indexer.py"]))
+//!     .add("formatter", Process::new("formatter-server", Vec::<String>::new()));
+//! manager.start_all().await?;
+//!
+//! let hits: Vec<String> = manager.call_sync("indexer", "search", "fn main").await?;
+//! for (role, method) in manager.methods().await? {
+//!     println!("{}: {}", role, method);
+//! }
+//! manager.stop_all().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! As a [`FleetSource`], a manager reports the uptime, restarts and call
+//! latencies of every role, see [`crate::fleet`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::fleet::{CallTimes, ChildHealth, FleetHealth, FleetSource};
+use crate::log::{debug, info, warn};
+use crate::process::Process;
+use crate::registry::MethodInfo;
+
+/// Whether the process of one role runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoleStatus {
+    pub role: String,
+    pub pid: Option<u32>,
+    pub running: bool,
+}
+
+struct Role {
+    name: String,
+    process: Mutex<Process>,
+    starts: AtomicU64,
+    calls: AtomicU64,
+    errors: AtomicU64,
+    times: CallTimes,
+}
+
+impl Role {
+    /// Start the process, counting the start
+    async fn start(&self, process: &mut Process) -> std::result::Result<(), ERPCError> {
+        process.start().await?;
+        self.starts.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Helper processes by role, started and stopped together
+#[derive(Default)]
+pub struct Manager {
+    roles: Vec<Role>,
+}
+
+impl Manager {
+    pub fn new() -> Self {
+        Manager::default()
+    }
+
+    /// Run `process` as `role`, replacing an earlier process of that role
+    ///
+    /// Roles start in the order they were added and stop in reverse.
+    pub fn add(mut self, role: impl Into<String>, process: Process) -> Self {
+        let role = Role {
+            name: role.into(),
+            process: Mutex::new(process),
+            starts: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            times: CallTimes::default(),
+        };
+        match self.roles.iter_mut().find(|other| other.name == role.name) {
+            Some(other) => *other = role,
+            None => self.roles.push(role),
+        }
+        self
+    }
+
+    /// Role names, in start order
+    pub fn roles(&self) -> Vec<&str> {
+        self.roles.iter().map(|role| role.name.as_str()).collect()
+    }
+
+    fn role(&self, role: &str) -> std::result::Result<&Role, ERPCError> {
+        self.roles
+            .iter()
+            .find(|other| other.name == role)
+            .ok_or_else(|| ERPCError::InvalidArgument(format!("no role {}", role)))
+    }
+
+    /// Start every role that is not running
+    ///
+    /// Fails if any role cannot be started; roles started by this call are
+    /// stopped again.
+    pub async fn start_all(&self) -> std::result::Result<(), ERPCError> {
+        let mut started: Vec<&Role> = Vec::new();
+        for role in &self.roles {
+            let mut process = role.process.lock().await;
+            if process.is_running() {
+                continue;
+            }
+            if let Err(e) = role.start(&mut process).await {
+                warn!("Failed to start role {}: {}", role.name, e);
+                drop(process);
+                for role in started.iter().rev() {
+                    let _ = role.process.lock().await.stop().await;
+                }
+                return Err(role_error(&role.name, e));
+            }
+            debug!("Role {} started with pid {:?}", role.name, process.pid());
+            started.push(role);
+        }
+        info!("Started {} roles", self.roles.len());
+        Ok(())
+    }
+
+    /// Stop every role, last added first
+    ///
+    /// All roles are asked to stop even if some fail to; the last failure
+    /// is returned.
+    pub async fn stop_all(&self) -> std::result::Result<(), ERPCError> {
+        let mut result = Ok(());
+        for role in self.roles.iter().rev() {
+            if let Err(e) = role.process.lock().await.stop().await {
+                warn!("Failed to stop role {}: {}", role.name, e);
+                result = Err(role_error(&role.name, e));
+            }
+        }
+        result
+    }
+
+    /// Start the process of `role`
+    pub async fn start(&self, role: &str) -> std::result::Result<(), ERPCError> {
+        let role = self.role(role)?;
+        let mut process = role.process.lock().await;
+        role.start(&mut process)
+            .await
+            .map_err(|e| role_error(&role.name, e))
+    }
+
+    /// Stop the process of `role`
+    pub async fn stop(&self, role: &str) -> std::result::Result<(), ERPCError> {
+        let role = self.role(role)?;
+        role.process
+            .lock()
+            .await
+            .stop()
+            .await
+            .map(|_| ())
+            .map_err(|e| role_error(&role.name, e))
+    }
+
+    /// Client connected to the process of `role`, if it runs
+    pub async fn client(&self, role: &str) -> std::result::Result<Arc<Client>, ERPCError> {
+        let mut process = self.role(role)?.process.lock().await;
+        match process.client_handle() {
+            Some(client) if process.is_running() => Ok(client),
+            _ => Err(ERPCError::ProcessError(format!(
+                "role {} is not running",
+                role
+            ))),
+        }
+    }
+
+    /// Call `method` of `role` with raw S-expression arguments
+    pub async fn call_value(
+        &self,
+        role: &str,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let client = self.client(role).await?;
+        let role = self.role(role)?;
+        role.calls.fetch_add(1, Ordering::Relaxed);
+        let started = tokio::time::Instant::now();
+        let result = client.call_value(method, args).await;
+        role.times.record(started.elapsed());
+        if result.is_err() {
+            role.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Call `method` of `role`
+    pub async fn call_sync<Args, Ret>(
+        &self,
+        role: &str,
+        method: &str,
+        args: Args,
+    ) -> std::result::Result<Ret, ERPCError>
+    where
+        Args: Serialize,
+        Ret: for<'de> Deserialize<'de>,
+    {
+        let args_value = serde_lexpr::to_value(&args)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+
+        let result = self.call_value(role, method, args_value).await?;
+
+        serde_lexpr::from_value(&result).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Methods of every running role, by role in start order and by name
+    /// within a role
+    pub async fn methods(&self) -> std::result::Result<Vec<(String, MethodInfo)>, ERPCError> {
+        let mut methods = Vec::new();
+        for role in &self.roles {
+            let Ok(client) = self.client(&role.name).await else {
+                continue;
+            };
+            let mut infos = client
+                .query_methods()
+                .await
+                .map_err(|e| role_error(&role.name, e))?;
+            infos.sort_by(|a, b| a.name.cmp(&b.name));
+            methods.extend(infos.into_iter().map(|info| (role.name.clone(), info)));
+        }
+        Ok(methods)
+    }
+
+    /// Role offering `method`, the first in start order if several do
+    pub async fn role_of(&self, method: &str) -> std::result::Result<Option<String>, ERPCError> {
+        Ok(self
+            .methods()
+            .await?
+            .into_iter()
+            .find(|(_, info)| info.name == method)
+            .map(|(role, _)| role))
+    }
+
+    /// Pid and liveness of every role
+    pub async fn status(&self) -> Vec<RoleStatus> {
+        let mut status = Vec::with_capacity(self.roles.len());
+        for role in &self.roles {
+            let mut process = role.process.lock().await;
+            status.push(RoleStatus {
+                role: role.name.clone(),
+                pid: process.pid(),
+                running: process.is_running(),
+            });
+        }
+        status
+    }
+}
+
+#[async_trait::async_trait]
+impl FleetSource for Manager {
+    /// Children named after their roles, in start order
+    async fn fleet_health(&self) -> FleetHealth {
+        let mut health = FleetHealth::default();
+        for role in &self.roles {
+            let (pid, running, uptime) = {
+                let mut process = role.process.lock().await;
+                (process.pid(), process.is_running(), process.uptime())
+            };
+            health.children.push(ChildHealth {
+                name: role.name.clone(),
+                pid,
+                running,
+                uptime,
+                restarts: role.starts.load(Ordering::Relaxed).saturating_sub(1),
+                calls: role.calls.load(Ordering::Relaxed),
+                errors: role.errors.load(Ordering::Relaxed),
+                mean_latency: role.times.mean(),
+                max_latency: role.times.max(),
+            });
+        }
+        health
+    }
+}
+
+/// `error` prefixed with the role it happened in
+fn role_error(role: &str, error: ERPCError) -> ERPCError {
+    match error {
+        ERPCError::ProcessError(message) => {
+            ERPCError::ProcessError(format!("role {}: {}", role, message))
+        }
+        other => other,
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    async fn server(method: &str) -> (Server, u16) {
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_method(method, |s: String| Ok(s), None::<&str>, None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+        (server, port)
+    }
+
+    /// A helper announcing `port` and staying alive
+    fn helper(port: u16) -> Process {
+        Process::new(
+            "sh",
+            vec!["-c".to_string(), format!("echo {}; exec sleep 30", port)],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_routes_calls_by_role() {
+        let (mut indexer, indexer_port) = server("search").await;
+        let (mut formatter, formatter_port) = server("format").await;
+        let manager = Manager::new()
+            .add("indexer", helper(indexer_port))
+            .add("formatter", helper(formatter_port))
+            .add("broken", Process::new("sh", vec!["-c", "exit 1"]));
+        assert_eq!(manager.roles(), vec!["indexer", "formatter", "broken"]);
+
+        // The broken role fails the group start and stops the others
+        assert!(manager.start_all().await.is_err());
+        assert!(manager.status().await.iter().all(|role| !role.running));
+
+        manager.start("indexer").await.unwrap();
+        manager.start("formatter").await.unwrap();
+        let reply: String = manager.call_sync("formatter", "format", "x").await.unwrap();
+        assert_eq!(reply, "x");
+        assert_eq!(
+            manager.role_of("search").await.unwrap().as_deref(),
+            Some("indexer")
+        );
+        assert!(manager
+            .call_value("broken", "format", Value::Null)
+            .await
+            .is_err());
+
+        manager.stop_all().await.unwrap();
+        indexer.shutdown().await.unwrap();
+        formatter.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fleet_health_of_roles() {
+        let (mut indexer, port) = server("search").await;
+        let manager = Manager::new().add("indexer", helper(port));
+        manager.start_all().await.unwrap();
+        let _: String = manager.call_sync("indexer", "search", "x").await.unwrap();
+        assert!(manager
+            .call_sync::<_, String>("indexer", "missing", "x")
+            .await
+            .is_err());
+
+        manager.stop("indexer").await.unwrap();
+        assert!(!manager.fleet_health().await.is_healthy());
+        manager.start("indexer").await.unwrap();
+
+        let health = manager.fleet_health().await;
+        assert!(health.is_healthy());
+        let child = &health.children[0];
+        assert_eq!(child.name, "indexer");
+        assert!(child.uptime.is_some());
+        assert_eq!((child.restarts, child.calls, child.errors), (1, 2, 1));
+        assert!(child.mean_latency.is_some());
+
+        manager.stop_all().await.unwrap();
+        indexer.shutdown().await.unwrap();
+    }
+}
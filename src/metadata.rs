@@ -0,0 +1,193 @@
+//! Metadata carried along with calls
+//!
+//! A [`CallMetadata`] is a small set of string fields, a trace id, a span id
+//! and any header of the application's, sent with a call so that chains of
+//! EPC hops can be followed end to end. Peers agree on it first: a client
+//! with `ClientConfig::call_metadata` calls [`CALL_METADATA_METHOD`] right
+//! after connecting, and a server with `ServerConfig::call_metadata` accepts.
+//! From then on the client prepends the metadata of each call to its
+//! arguments as a plist:
+//!
+//! ```text
+//! (call 5 search (elrpc-metadata (:trace-id "4bf9" :span-id "00f0") "needle"))
+//! ```
+//!
+//! The server strips the envelope before dispatch and hands the fields to
+//! the handler in [`CallContext::metadata`](crate::context::CallContext). A
+//! client used inside a handler forwards the metadata of the call being
+//! served unless it is given other metadata, so a trace id set at the first
+//! hop reaches the last one.
+//!
+//! Servers that did not agree never see the envelope. Raw and arena
+//! methods do not see it either: it is cut off their argument text, and
+//! only the metadata itself is parsed.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use lexpr::Value;
+
+use crate::protocol::datum_len;
+
+/// Method a client calls to send metadata with its later calls
+pub const CALL_METADATA_METHOD: &str = "elrpc-call-metadata";
+
+/// Head of the arguments of a call carrying metadata
+const ENVELOPE: &str = "elrpc-metadata";
+
+const TRACE_ID: &str = "trace-id";
+const SPAN_ID: &str = "span-id";
+
+/// Fields sent along with a call
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallMetadata {
+    fields: BTreeMap<String, String>,
+}
+
+impl CallMetadata {
+    pub fn new() -> Self {
+        CallMetadata::default()
+    }
+
+    /// Metadata of a call belonging to trace `trace_id`, made from span
+    /// `span_id`
+    pub fn trace(trace_id: impl Into<String>, span_id: impl Into<String>) -> Self {
+        CallMetadata::new()
+            .field(TRACE_ID, trace_id)
+            .field(SPAN_ID, span_id)
+    }
+
+    /// Add field `key`, replacing an earlier value
+    pub fn field(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.fields.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(String::as_str)
+    }
+
+    pub fn trace_id(&self) -> Option<&str> {
+        self.get(TRACE_ID)
+    }
+
+    pub fn span_id(&self) -> Option<&str> {
+        self.get(SPAN_ID)
+    }
+
+    /// Fields sorted by key
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The fields as a plist of keywords and strings
+    pub fn to_value(&self) -> Value {
+        Value::list(
+            self.fields
+                .iter()
+                .flat_map(|(key, value)| {
+                    [Value::keyword(key.as_str()), Value::string(value.as_str())]
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Read a plist of keywords or symbols and strings, skipping other
+    /// entries
+    pub fn from_value(value: &Value) -> Self {
+        let items = value.list_iter().into_iter().flatten().collect::<Vec<_>>();
+        let fields = items
+            .chunks(2)
+            .filter_map(|pair| {
+                let [key, value] = pair else {
+                    return None;
+                };
+                let key = key.as_keyword().or_else(|| key.as_symbol())?;
+                Some((key.to_string(), value.as_str()?.to_string()))
+            })
+            .collect();
+        CallMetadata { fields }
+    }
+}
+
+/// Prepend `metadata` to call arguments
+pub(crate) fn wrap(metadata: &CallMetadata, args: Value) -> Value {
+    Value::append(vec![Value::symbol(ENVELOPE), metadata.to_value()], args)
+}
+
+/// Split the metadata off call arguments, if the caller sent any
+pub(crate) fn unwrap(args: Value) -> (Value, Option<CallMetadata>) {
+    let Value::Cons(cons) = &args else {
+        return (args, None);
+    };
+    if cons.car().as_symbol() != Some(ENVELOPE) {
+        return (args, None);
+    }
+    let Value::Cons(rest) = cons.cdr() else {
+        return (args, None);
+    };
+    let metadata = CallMetadata::from_value(rest.car());
+    (rest.cdr().clone(), Some(metadata))
+}
+
+/// Split the metadata off the unparsed text of call arguments, if the
+/// caller sent any
+///
+/// Only the metadata is parsed; the other arguments stay text.
+pub(crate) fn unwrap_text(args: &str) -> (Cow<'_, str>, Option<CallMetadata>) {
+    let Some(rest) = args
+        .strip_prefix('(')
+        .and_then(|rest| rest.trim_start().strip_prefix(ENVELOPE))
+        .filter(|rest| rest.starts_with(char::is_whitespace))
+        .map(str::trim_start)
+    else {
+        return (Cow::Borrowed(args), None);
+    };
+    let Some(len) = datum_len(rest) else {
+        return (Cow::Borrowed(args), None);
+    };
+    let Ok(fields) = lexpr::from_str(&rest[..len]) else {
+        return (Cow::Borrowed(args), None);
+    };
+    let args = format!("({}", rest[len..].trim_start());
+    (Cow::Owned(args), Some(CallMetadata::from_value(&fields)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_and_unwrap() {
+        let metadata = CallMetadata::trace("4bf9", "00f0").field("tenant", "acme");
+        let args = Value::list(vec![Value::string("needle")]);
+
+        let (unwrapped, received) = unwrap(wrap(&metadata, args.clone()));
+        assert_eq!(unwrapped, args);
+        let received = received.unwrap();
+        assert_eq!(received, metadata);
+        assert_eq!(received.trace_id(), Some("4bf9"));
+        assert_eq!(received.get("tenant"), Some("acme"));
+
+        assert_eq!(unwrap(args.clone()), (args, None));
+    }
+
+    #[test]
+    fn test_unwrap_text() {
+        let (args, metadata) =
+            unwrap_text(r#"(elrpc-metadata (:trace-id "4bf9" :span-id "00f0") "needle" 2)"#);
+        assert_eq!(args, r#"("needle" 2)"#);
+        assert_eq!(metadata.unwrap().trace_id(), Some("4bf9"));
+
+        let (args, metadata) = unwrap_text("(elrpc-metadata nil)");
+        assert_eq!((args.as_ref(), metadata.is_some()), ("()", true));
+
+        for plain in [r#"("needle")"#, "(elrpc-metadata-x 1)", "nil"] {
+            assert_eq!(unwrap_text(plain), (Cow::Borrowed(plain), None));
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! OpenTelemetry traces and metrics for calls (feature `otel`)
+//!
+//! Call spans on both sides are exported once the application installs a
+//! `tracing_opentelemetry` layer, and every call is counted in the
+//! `elrpc.calls` counter and `elrpc.call.duration` histogram of the global
+//! meter provider, which must be set before the first call.
+//!
+//! Trace context only crosses the wire when the client enables
+//! [`ClientConfig::propagate_trace_context`](crate::client::ClientConfig).
+//! The fields injected by the global text map propagator are then prepended
+//! to the call arguments:
+//!
+//! ```text
+//! (call 1 add (elrpc-trace-context (("traceparent" . "00-...")) 1 2))
+//! ```
+//!
+//! Servers built with this feature strip the envelope before dispatch and
+//! run the handler in a span whose parent is the caller's. Other EPC peers
+//! would hand the envelope to the method, so enable propagation only towards
+//! servers known to understand it.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use lexpr::Value;
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{global, KeyValue};
+use tracing::{debug, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Symbol marking call arguments that carry trace context
+pub const TRACE_CONTEXT_MARKER: &str = "elrpc-trace-context";
+
+/// Which end of a call is recording it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn as_str(self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Server => "server",
+        }
+    }
+}
+
+struct Instruments {
+    calls: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+fn instruments() -> &'static Instruments {
+    static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+    INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter("elrpc");
+        Instruments {
+            calls: meter
+                .u64_counter("elrpc.calls")
+                .with_description("EPC calls made or handled")
+                .build(),
+            duration: meter
+                .f64_histogram("elrpc.call.duration")
+                .with_description("Duration of EPC calls")
+                .with_unit("s")
+                .build(),
+        }
+    })
+}
+
+/// Count one finished call and record its duration
+pub(crate) fn record_call(side: Side, method: &str, duration: Duration, ok: bool) {
+    let attributes = [
+        KeyValue::new("rpc.system", "epc"),
+        KeyValue::new("rpc.method", method.to_string()),
+        KeyValue::new("elrpc.side", side.as_str()),
+        KeyValue::new("elrpc.outcome", if ok { "ok" } else { "error" }),
+    ];
+    let instruments = instruments();
+    instruments.calls.add(1, &attributes);
+    instruments
+        .duration
+        .record(duration.as_secs_f64(), &attributes);
+}
+
+/// Prepend the trace context of `span` to `args`
+///
+/// Arguments are returned unchanged when the span is not part of an
+/// OpenTelemetry trace.
+pub fn inject(span: &Span, args: Value) -> Value {
+    let cx = span.context();
+    if !cx.span().span_context().is_valid() {
+        return args;
+    }
+
+    let mut fields = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut fields));
+    if fields.is_empty() {
+        return args;
+    }
+
+    let carrier = Value::list(
+        fields
+            .into_iter()
+            .map(|(key, value)| Value::cons(Value::string(key), Value::string(value)))
+            .collect::<Vec<Value>>(),
+    );
+    Value::append(vec![Value::symbol(TRACE_CONTEXT_MARKER), carrier], args)
+}
+
+/// Split the trace context fields off call arguments
+///
+/// Returns the arguments the method expects, and the propagated fields if
+/// the caller sent any.
+pub fn extract(args: Value) -> (Value, Option<HashMap<String, String>>) {
+    let Value::Cons(cons) = &args else {
+        return (args, None);
+    };
+    if cons.car().as_symbol() != Some(TRACE_CONTEXT_MARKER) {
+        return (args, None);
+    }
+    let Value::Cons(rest) = cons.cdr() else {
+        return (args, None);
+    };
+
+    let fields = rest
+        .car()
+        .list_iter()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let Value::Cons(pair) = entry else {
+                return None;
+            };
+            Some((
+                pair.car().as_str()?.to_string(),
+                pair.cdr().as_str()?.to_string(),
+            ))
+        })
+        .collect();
+    (rest.cdr().clone(), Some(fields))
+}
+
+/// Span a server runs a handler in, and the arguments to pass it
+///
+/// When the call carried trace context the span continues the caller's
+/// trace; otherwise the handler runs in the current span.
+pub(crate) fn handler_span(method: &str, args: Value) -> (Value, Span) {
+    let (args, fields) = extract(args);
+    let Some(fields) = fields else {
+        return (args, Span::none());
+    };
+
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&fields));
+    let span = info_span!("handle", method);
+    if let Err(e) = span.set_parent(parent) {
+        debug!("Cannot continue remote trace: {}", e);
+    }
+    (args, span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_strips_envelope() {
+        let args = lexpr::from_str(
+            r#"(elrpc-trace-context (("traceparent" . "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")) 1 2)"#,
+        )
+        .unwrap();
+        let (args, fields) = extract(args);
+
+        assert_eq!(args, lexpr::from_str("(1 2)").unwrap());
+        let fields = fields.unwrap();
+        assert_eq!(
+            fields["traceparent"],
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+        );
+
+        let plain = lexpr::from_str("(1 2)").unwrap();
+        assert_eq!(extract(plain.clone()), (plain, None));
+    }
+
+    #[test]
+    fn test_inject_without_trace_is_a_no_op() {
+        let args = lexpr::from_str("(1 2)").unwrap();
+        assert_eq!(inject(&Span::none(), args.clone()), args);
+    }
+}
@@ -0,0 +1,365 @@
+//! Positional parameters with `&optional` and `&rest`
+//!
+//! Typed methods decode their whole argument list into one value, so every
+//! argument must be present. A method registered with
+//! [`MethodRegistry::register_params`](crate::registry::MethodRegistry::register_params)
+//! takes one Rust parameter per elisp argument instead, and each parameter
+//! type says how it binds:
+//!
+//! - common scalar types, `String`, `Vec<T>`, `Value` and [`Arg<T>`] are
+//!   required,
+//! - `Option<T>` and [`OrDefault<T>`] are `&optional`: a missing or `nil`
+//!   argument gives `None` or `T::default()`,
+//! - [`Rest`] takes the remaining arguments, like `&rest`.
+//!
+//! ```no_run
+//! # async fn example(registry: &elrpc::MethodRegistry) -> elrpc::Result<()> {
+//! use elrpc::params::{OrDefault, Rest};
+//!
+//! registry
+//!     .register_params(
+//!         "grep",
+//!         &["pattern", "limit", "files"],
+//!         |pattern: String, OrDefault(limit): OrDefault<u32>, Rest(files): Rest| {
+//!             Ok::<_, elrpc::ERPCError>(format!("{} {} {}", pattern, limit, files.len()))
+//!         },
+//!         Some("Search FILES for PATTERN"),
+//!     )
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The method's arg spec is generated from the signature, here
+//! `(pattern &optional limit &rest files)`.
+
+use std::marker::PhantomData;
+use std::slice;
+
+use lexpr::Value;
+use serde::Deserialize;
+
+use crate::error::ERPCError;
+use crate::registry::{MethodHandler, MethodInfo};
+use crate::reply::{Deferred, IntoEpcReply, Reply};
+
+/// How a parameter binds to the arguments of a call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    Required,
+    Optional,
+    Rest,
+}
+
+/// A parameter of a method registered with `register_params`
+pub trait Param: Sized {
+    const KIND: ParamKind;
+
+    /// Decode the parameter from the arguments not yet taken
+    fn take(args: &mut slice::Iter<'_, Value>) -> std::result::Result<Self, ERPCError>;
+}
+
+fn decode<T>(value: &Value) -> std::result::Result<T, ERPCError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    serde_lexpr::from_value(value).map_err(|e| ERPCError::SerializationError(e.to_string()))
+}
+
+fn take_required<T>(args: &mut slice::Iter<'_, Value>) -> std::result::Result<T, ERPCError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let arg = args
+        .next()
+        .ok_or_else(|| ERPCError::InvalidArgument("missing argument".to_string()))?;
+    decode(arg)
+}
+
+/// A required parameter of any deserializable type
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arg<T>(pub T);
+
+impl<T> Param for Arg<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    const KIND: ParamKind = ParamKind::Required;
+
+    fn take(args: &mut slice::Iter<'_, Value>) -> std::result::Result<Self, ERPCError> {
+        take_required(args).map(Arg)
+    }
+}
+
+macro_rules! required_param {
+    ($($ty:ty),*) => {
+        $(
+            impl Param for $ty {
+                const KIND: ParamKind = ParamKind::Required;
+
+                fn take(args: &mut slice::Iter<'_, Value>) -> std::result::Result<Self, ERPCError> {
+                    take_required(args)
+                }
+            }
+        )*
+    };
+}
+
+required_param!(bool, char, i8, i16, i32, i64, u8, u16, u32, u64, f32, f64, String);
+
+impl<T> Param for Vec<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    const KIND: ParamKind = ParamKind::Required;
+
+    fn take(args: &mut slice::Iter<'_, Value>) -> std::result::Result<Self, ERPCError> {
+        take_required(args)
+    }
+}
+
+impl Param for Value {
+    const KIND: ParamKind = ParamKind::Required;
+
+    fn take(args: &mut slice::Iter<'_, Value>) -> std::result::Result<Self, ERPCError> {
+        args.next()
+            .cloned()
+            .ok_or_else(|| ERPCError::InvalidArgument("missing argument".to_string()))
+    }
+}
+
+/// Whether an optional argument was left out: missing, or `nil` as elisp
+/// passes for optionals before a given one
+fn omitted(args: &mut slice::Iter<'_, Value>) -> bool {
+    match args.as_slice().first() {
+        None => true,
+        Some(arg) if arg.is_nil() || arg.is_null() => {
+            args.next();
+            true
+        }
+        Some(_) => false,
+    }
+}
+
+impl<T: Param> Param for Option<T> {
+    const KIND: ParamKind = ParamKind::Optional;
+
+    fn take(args: &mut slice::Iter<'_, Value>) -> std::result::Result<Self, ERPCError> {
+        if omitted(args) {
+            return Ok(None);
+        }
+        T::take(args).map(Some)
+    }
+}
+
+/// An `&optional` parameter defaulting to `T::default()`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OrDefault<T>(pub T);
+
+impl<T: Param + Default> Param for OrDefault<T> {
+    const KIND: ParamKind = ParamKind::Optional;
+
+    fn take(args: &mut slice::Iter<'_, Value>) -> std::result::Result<Self, ERPCError> {
+        if omitted(args) {
+            return Ok(OrDefault(T::default()));
+        }
+        T::take(args).map(OrDefault)
+    }
+}
+
+/// The remaining arguments, as `&rest`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rest(pub Vec<Value>);
+
+impl Param for Rest {
+    const KIND: ParamKind = ParamKind::Rest;
+
+    fn take(args: &mut slice::Iter<'_, Value>) -> std::result::Result<Self, ERPCError> {
+        Ok(Rest(args.cloned().collect()))
+    }
+}
+
+/// Build the arg spec for parameters of `kinds`, checking their order
+///
+/// Parameters past the given `names` are called `argN`.
+pub fn arg_spec(kinds: &[ParamKind], names: &[&str]) -> std::result::Result<String, ERPCError> {
+    let mut spec = Vec::new();
+    let mut seen = ParamKind::Required;
+    for (i, kind) in kinds.iter().enumerate() {
+        match (seen, kind) {
+            (ParamKind::Rest, _) => {
+                return Err(ERPCError::InvalidArgument(
+                    "no parameter may follow Rest".to_string(),
+                ))
+            }
+            (ParamKind::Optional, ParamKind::Required) => {
+                return Err(ERPCError::InvalidArgument(
+                    "required parameter after an optional one".to_string(),
+                ))
+            }
+            (ParamKind::Required, ParamKind::Optional) => spec.push("&optional".to_string()),
+            (_, ParamKind::Rest) => spec.push("&rest".to_string()),
+            _ => {}
+        }
+        seen = *kind;
+        spec.push(
+            names
+                .get(i)
+                .map_or_else(|| format!("arg{}", i + 1), |name| name.to_string()),
+        );
+    }
+    Ok(format!("({})", spec.join(" ")))
+}
+
+/// A function of [`Param`]s
+///
+/// Implemented for functions of up to six parameters returning anything
+/// implementing [`IntoEpcReply`]. `M` only tells the implementations apart.
+pub trait ParamsFn<M>: Send + Sync + 'static {
+    fn kinds() -> Vec<ParamKind>;
+
+    fn call(&self, args: &[Value]) -> Deferred;
+}
+
+macro_rules! impl_params_fn {
+    ($($param:ident $value:ident),*) => {
+        impl<F, Out, R, $($param,)*> ParamsFn<(R, $($param,)*)> for F
+        where
+            F: Fn($($param),*) -> Out + Send + Sync + 'static,
+            Out: IntoEpcReply<R>,
+            $($param: Param,)*
+        {
+            fn kinds() -> Vec<ParamKind> {
+                vec![$($param::KIND),*]
+            }
+
+            fn call(&self, args: &[Value]) -> Deferred {
+                let mut args = args.iter();
+                $(
+                    let $value = match $param::take(&mut args) {
+                        Ok(value) => value,
+                        Err(e) => return Deferred::Ready(Err(e)),
+                    };
+                )*
+                if args.next().is_some() {
+                    return Deferred::Ready(Err(ERPCError::InvalidArgument(
+                        "too many arguments".to_string(),
+                    )));
+                }
+                self($($value),*).into_deferred()
+            }
+        }
+    };
+}
+
+impl_params_fn!();
+impl_params_fn!(P1 a1);
+impl_params_fn!(P1 a1, P2 a2);
+impl_params_fn!(P1 a1, P2 a2, P3 a3);
+impl_params_fn!(P1 a1, P2 a2, P3 a3, P4 a4);
+impl_params_fn!(P1 a1, P2 a2, P3 a3, P4 a4, P5 a5);
+impl_params_fn!(P1 a1, P2 a2, P3 a3, P4 a4, P5 a5, P6 a6);
+
+/// [`MethodHandler`] running a [`ParamsFn`]
+pub struct ParamsHandler<F, M> {
+    func: F,
+    info: MethodInfo,
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<F, M> ParamsHandler<F, M>
+where
+    F: ParamsFn<M>,
+{
+    /// Handler for `func`, failing if its parameters are out of order
+    pub fn new(
+        func: F,
+        name: impl Into<String>,
+        names: &[&str],
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<Self, ERPCError> {
+        let spec = arg_spec(&F::kinds(), names)?;
+        Ok(ParamsHandler {
+            func,
+            info: MethodInfo::new(name, Some(spec), docstring),
+            _marker: PhantomData,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<F, M> MethodHandler for ParamsHandler<F, M>
+where
+    F: ParamsFn<M>,
+{
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        self.call_reply(args).await.map(|reply| reply.value)
+    }
+
+    async fn call_reply(&self, args: Value) -> std::result::Result<Reply, ERPCError> {
+        if args.is_nil() || args.is_null() {
+            return self.func.call(&[]).resolve().await;
+        }
+        let args = args.to_vec().ok_or_else(|| {
+            ERPCError::InvalidArgument("arguments are not a proper list".to_string())
+        })?;
+        self.func.call(&args).resolve().await
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arg_spec() {
+        use ParamKind::*;
+
+        assert_eq!(arg_spec(&[], &[]).unwrap(), "()");
+        assert_eq!(
+            arg_spec(&[Required, Optional, Optional, Rest], &["a", "b"]).unwrap(),
+            "(a &optional b arg3 &rest arg4)"
+        );
+        assert!(arg_spec(&[Optional, Required], &[]).is_err());
+        assert!(arg_spec(&[Rest, Optional], &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_optional_and_rest() {
+        let handler = ParamsHandler::new(
+            |name: String, count: Option<u32>, Rest(rest): Rest| {
+                Ok::<_, ERPCError>(format!("{} {:?} {}", name, count, rest.len()))
+            },
+            "f",
+            &["name", "count", "more"],
+            None::<&str>,
+        )
+        .unwrap();
+        assert_eq!(
+            handler.info().arg_spec.as_deref(),
+            Some("(name &optional count &rest more)")
+        );
+
+        let call = |args: Vec<Value>| handler.call(Value::list(args));
+        assert_eq!(
+            call(vec![Value::string("x")]).await.unwrap(),
+            Value::string("x None 0")
+        );
+        assert_eq!(
+            call(vec![
+                Value::string("x"),
+                Value::from(2),
+                Value::Nil,
+                Value::Nil
+            ])
+            .await
+            .unwrap(),
+            Value::string("x Some(2) 2")
+        );
+        assert!(call(vec![]).await.is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! Accept-time filtering of peer addresses
+//!
+//! A [`PeerFilter`] decides from the address alone whether a new connection
+//! is served. It complements the loopback bind address that EPC servers
+//! normally use: on a shared machine any local user can reach a loopback
+//! port, and a server bound to a wider address for remote use should still
+//! refuse most of the network. Rejected connections are closed right away,
+//! logged and counted in [`StatsSnapshot::rejected_connections`].
+//!
+//! [`StatsSnapshot::rejected_connections`]: crate::stats::StatsSnapshot
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::error::ERPCError;
+
+/// A block of addresses such as `10.0.0.0/8` or `fe80::/10`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The block of `prefix` leading bits of `addr`
+    pub fn new(addr: IpAddr, prefix: u8) -> std::result::Result<Self, ERPCError> {
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(ERPCError::InvalidArgument(format!(
+                "prefix /{} is too long for {}",
+                prefix, addr
+            )));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    /// Whether `ip` lies in this block
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    net >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = ERPCError;
+
+    /// Parse `ADDR/PREFIX`, or a bare address for a single host
+    fn from_str(s: &str) -> std::result::Result<Self, ERPCError> {
+        let invalid = || ERPCError::InvalidArgument(format!("invalid address block: {}", s));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None if addr.to_canonical().is_ipv4() => 32,
+            None => 128,
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Which peers a server accepts connections from
+///
+/// A peer is rejected if `loopback_only` is set and it is not a loopback
+/// address, if it is in a `deny` block, or if `allow` is non-empty and it is
+/// in none of its blocks. Everything else is accepted.
+#[derive(Debug, Clone, Default)]
+pub struct PeerFilter {
+    /// Blocks peers must come from (empty allows any address)
+    pub allow: Vec<Cidr>,
+    /// Blocks that are always rejected, even when allowed
+    pub deny: Vec<Cidr>,
+    /// Reject every peer that is not on a loopback address
+    pub loopback_only: bool,
+}
+
+impl PeerFilter {
+    /// Accept every peer
+    pub fn new() -> Self {
+        PeerFilter::default()
+    }
+
+    /// Accept loopback peers only
+    pub fn loopback() -> Self {
+        PeerFilter {
+            loopback_only: true,
+            ..PeerFilter::default()
+        }
+    }
+
+    /// Accept peers in `block`; once any block is allowed, others are rejected
+    pub fn allow(mut self, block: Cidr) -> Self {
+        self.allow.push(block);
+        self
+    }
+
+    /// Reject peers in `block`
+    pub fn deny(mut self, block: Cidr) -> Self {
+        self.deny.push(block);
+        self
+    }
+
+    /// Whether a peer at `ip` may connect
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.loopback_only && !ip.is_loopback() {
+            return false;
+        }
+        if self.deny.iter().any(|block| block.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|block| block.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_blocks() {
+        let block: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(block.contains(ip("10.1.200.3")));
+        assert!(!block.contains(ip("10.2.0.1")));
+        // IPv4-mapped IPv6 peers match IPv4 blocks
+        assert!(block.contains(ip("::ffff:10.1.0.9")));
+
+        let host: Cidr = "::1".parse().unwrap();
+        assert_eq!(host.to_string(), "::1/128");
+        assert!(host.contains(ip("::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_filter_rules() {
+        let filter = PeerFilter::new()
+            .allow("192.168.0.0/16".parse().unwrap())
+            .deny("192.168.66.0/24".parse().unwrap());
+        assert!(filter.permits(ip("192.168.1.10")));
+        assert!(!filter.permits(ip("192.168.66.10")));
+        assert!(!filter.permits(ip("10.0.0.1")));
+
+        let filter = PeerFilter::loopback();
+        assert!(filter.permits(ip("127.0.0.1")));
+        assert!(filter.permits(ip("::1")));
+        assert!(!filter.permits(ip("192.168.1.10")));
+        assert!(PeerFilter::new().permits(ip("203.0.113.5")));
+    }
+}
@@ -0,0 +1,300 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+
+use crate::protocol::LengthPrefix;
+
+/// Default initial capacity of pooled buffers
+pub const DEFAULT_BUFFER_CAPACITY: usize = 1024;
+
+/// Buffers that grew beyond this are dropped instead of being pooled,
+/// so one huge frame does not pin its memory for the pool's lifetime.
+pub const DEFAULT_MAX_RETAINED_CAPACITY: usize = 1024 * 1024;
+
+/// Pool of reusable `BytesMut` buffers shared by connections
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    max_pooled: usize,
+    buffer_capacity: usize,
+    max_retained_capacity: usize,
+}
+
+impl BufferPool {
+    /// Create a pool retaining at most `max_pooled` idle buffers
+    pub fn new(max_pooled: usize) -> Self {
+        BufferPool::with_capacity(max_pooled, DEFAULT_BUFFER_CAPACITY)
+    }
+
+    /// Create a pool whose fresh buffers start with `buffer_capacity` bytes
+    pub fn with_capacity(max_pooled: usize, buffer_capacity: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_pooled)),
+            max_pooled,
+            buffer_capacity,
+            max_retained_capacity: DEFAULT_MAX_RETAINED_CAPACITY.max(buffer_capacity),
+        }
+    }
+
+    /// Take a buffer from the pool, allocating a new one if it is empty
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        let buf = self
+            .buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.buffer_capacity));
+
+        PooledBuffer {
+            buf: Some(buf),
+            pool: Arc::clone(self),
+        }
+    }
+
+    /// Return a buffer to the pool
+    pub fn put(&self, mut buf: BytesMut) {
+        if buf.capacity() > self.max_retained_capacity {
+            return;
+        }
+        buf.clear();
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_pooled {
+            buffers.push(buf);
+        }
+    }
+
+    /// Number of idle buffers currently held by the pool
+    pub fn idle(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    /// Maximum number of idle buffers the pool retains
+    pub fn max_pooled(&self) -> usize {
+        self.max_pooled
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(64)
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`], returned to it on drop
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buf: Option<BytesMut>,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    /// Detach the buffer from the pool
+    pub fn into_inner(mut self) -> BytesMut {
+        self.buf.take().unwrap()
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
+
+/// Adaptive sizing of a connection's read buffer
+///
+/// Tracks a moving average of observed frame sizes, reserves space ahead of
+/// reads, and pre-reserves for a frame once its length prefix announces a
+/// payload larger than the buffer. The pre-reserve stops at the maximum
+/// capacity, so a few prefix bytes cannot pin a frame's worth of memory;
+/// larger frames grow the buffer as their data arrives.
+#[derive(Debug, Clone)]
+pub struct ReadSizer {
+    min_capacity: usize,
+    max_capacity: usize,
+    average: usize,
+    prefix: LengthPrefix,
+}
+
+impl ReadSizer {
+    /// Create a sizer starting at `min_capacity`, adapting up to `max_capacity`
+    pub fn new(min_capacity: usize, max_capacity: usize) -> Self {
+        ReadSizer {
+            min_capacity,
+            max_capacity: max_capacity.max(min_capacity),
+            average: min_capacity,
+            prefix: LengthPrefix::EPC,
+        }
+    }
+
+    /// Read frames with `prefix` instead of the six hex digits of EPC
+    pub fn length_prefix(mut self, prefix: LengthPrefix) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Record the size of a complete frame
+    pub fn observe(&mut self, frame_len: usize) {
+        // Exponential moving average weighted 1/8 towards the newest frame
+        self.average = (self.average * 7 + frame_len) / 8;
+    }
+
+    /// Current target spare capacity for reads
+    pub fn target(&self) -> usize {
+        self.average
+            .next_power_of_two()
+            .clamp(self.min_capacity, self.max_capacity)
+    }
+
+    /// Reserve space in `buf` before the next read
+    ///
+    /// At most the maximum capacity is reserved at once. Frames over the
+    /// prefix's limit are not reserved for; extracting them fails.
+    pub fn prepare(&self, buf: &mut BytesMut) {
+        if let Some(len) = self
+            .prefix
+            .parse_length(buf)
+            .filter(|&len| len <= self.prefix.limit())
+        {
+            let total = self.prefix.width() + len;
+            if total > buf.len() {
+                buf.reserve((total - buf.len()).min(self.max_capacity));
+                return;
+            }
+        }
+        buf.reserve(self.target());
+    }
+
+    /// Release excess memory once a spike in frame size has passed
+    pub fn shrink(&self, buf: &mut BytesMut) {
+        let target = self.target();
+        if buf.is_empty() && buf.capacity() > target * 4 {
+            *buf = BytesMut::with_capacity(target);
+        }
+    }
+}
+
+impl Default for ReadSizer {
+    fn default() -> Self {
+        ReadSizer::new(DEFAULT_BUFFER_CAPACITY, DEFAULT_MAX_RETAINED_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_reuse() {
+        let pool = Arc::new(BufferPool::new(4));
+        {
+            let mut buf = pool.get();
+            buf.extend_from_slice(b"hello");
+        }
+        assert_eq!(pool.idle(), 1);
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= DEFAULT_BUFFER_CAPACITY);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_pool_size_bound() {
+        let pool = Arc::new(BufferPool::new(2));
+        let buffers: Vec<_> = (0..5).map(|_| pool.get()).collect();
+        drop(buffers);
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[test]
+    fn test_oversized_buffer_not_retained() {
+        let pool = Arc::new(BufferPool::new(2));
+        pool.put(BytesMut::with_capacity(DEFAULT_MAX_RETAINED_CAPACITY + 1));
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_into_inner_detaches() {
+        let pool = Arc::new(BufferPool::new(2));
+        let buf = pool.get().into_inner();
+        drop(buf);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_read_sizer_adapts() {
+        let mut sizer = ReadSizer::new(1024, 64 * 1024);
+        assert_eq!(sizer.target(), 1024);
+
+        for _ in 0..32 {
+            sizer.observe(20_000);
+        }
+        assert_eq!(sizer.target(), 32 * 1024);
+
+        for _ in 0..64 {
+            sizer.observe(10);
+        }
+        assert_eq!(sizer.target(), 1024);
+    }
+
+    #[test]
+    fn test_read_sizer_prereserves_announced_frame() {
+        let sizer = ReadSizer::default();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"100000(call");
+        sizer.prepare(&mut buf);
+        assert!(buf.capacity() >= 6 + 0x100000);
+    }
+
+    #[test]
+    fn test_read_sizer_uses_configured_prefix() {
+        let sizer =
+            ReadSizer::new(1024, 4 * 1024 * 1024).length_prefix(LengthPrefix::decimal(8).unwrap());
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"02000000(call");
+        sizer.prepare(&mut buf);
+        assert!(buf.capacity() >= 8 + 2_000_000);
+
+        // A length over the limit is not reserved for
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"99999999(call");
+        sizer.prepare(&mut buf);
+        assert!(buf.capacity() < 99_999_999);
+    }
+
+    #[test]
+    fn test_read_sizer_caps_prereserve() {
+        let sizer = ReadSizer::new(1024, 64 * 1024);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"800000");
+        sizer.prepare(&mut buf);
+        assert!(buf.capacity() < 0x800000);
+        assert!(buf.capacity() >= 64 * 1024);
+    }
+
+    #[test]
+    fn test_read_sizer_shrinks_idle_buffer() {
+        let sizer = ReadSizer::new(1024, 4096);
+        let mut buf = BytesMut::with_capacity(1024 * 1024);
+        sizer.shrink(&mut buf);
+        assert!(buf.capacity() < 1024 * 1024);
+    }
+}
@@ -0,0 +1,261 @@
+//! Readable rendering of large S-expressions for logs and debugging
+//!
+//! Lists that fit in the configured width stay on one line; longer ones are
+//! broken with each element on its own line, aligned under the first. Long
+//! strings, long lists and deep nesting are cut off so a multi-kilobyte
+//! argument tree stays a few screens at most.
+//!
+//! Logging goes through [`for_log`], which checks the [`approx_size`] of a
+//! payload first and shrinks anything above [`log_payload_limit`] to a short
+//! summary, so debug logging of large frames stays cheap.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lexpr::Value;
+
+/// Layout and truncation limits for [`PrettyConfig::render`]
+#[derive(Debug, Clone)]
+pub struct PrettyConfig {
+    /// Preferred maximum line width
+    pub width: usize,
+    /// Lists nested deeper than this are shown as `(...)`
+    pub max_depth: usize,
+    /// Elements after this many are summarized as `... N more`
+    pub max_items: usize,
+    /// Strings are cut off after this many characters
+    pub max_string_len: usize,
+}
+
+impl Default for PrettyConfig {
+    fn default() -> Self {
+        PrettyConfig {
+            width: 80,
+            max_depth: 8,
+            max_items: 32,
+            max_string_len: 120,
+        }
+    }
+}
+
+impl PrettyConfig {
+    /// Render `value` as indented, truncated text
+    pub fn render(&self, value: &Value) -> String {
+        let mut out = String::new();
+        self.write(&mut out, value, 0, 0);
+        out
+    }
+
+    fn write(&self, out: &mut String, value: &Value, column: usize, depth: usize) {
+        let start = out.len();
+        let limit = start + self.width.saturating_sub(column);
+        let Some((open, items, tail)) = parts(value) else {
+            self.write_flat(out, value, depth, usize::MAX);
+            return;
+        };
+        if self.write_flat(out, value, depth, limit) {
+            return;
+        }
+        out.truncate(start);
+
+        // Too wide for the line: one element per line, aligned under the first
+        out.push_str(open);
+        let inner = column + open.len();
+        for (i, item) in items.iter().take(self.max_items).enumerate() {
+            if i > 0 {
+                newline(out, inner);
+            }
+            self.write(out, item, inner, depth + 1);
+        }
+        if items.len() > self.max_items {
+            newline(out, inner);
+            let _ = write!(out, "... {} more", items.len() - self.max_items);
+        }
+        if let Some(tail) = tail {
+            newline(out, inner);
+            out.push_str(". ");
+            self.write(out, tail, inner + 2, depth + 1);
+        }
+        out.push(')');
+    }
+
+    /// Write `value` on one line, returning false as soon as the output
+    /// grows past `limit`
+    fn write_flat(&self, out: &mut String, value: &Value, depth: usize, limit: usize) -> bool {
+        let Some((open, items, tail)) = parts(value) else {
+            match value {
+                Value::String(s) if s.chars().count() > self.max_string_len => {
+                    let cut: String = s.chars().take(self.max_string_len).collect();
+                    let _ = write!(out, "{}...", Value::string(cut));
+                }
+                atom => {
+                    let _ = write!(out, "{}", atom);
+                }
+            }
+            return out.len() <= limit;
+        };
+
+        out.push_str(open);
+        if depth >= self.max_depth {
+            out.push_str("...)");
+            return out.len() <= limit;
+        }
+        for (i, item) in items.iter().take(self.max_items).enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            if !self.write_flat(out, item, depth + 1, limit) {
+                return false;
+            }
+        }
+        if items.len() > self.max_items {
+            let _ = write!(out, " ... {} more", items.len() - self.max_items);
+        }
+        if let Some(tail) = tail {
+            out.push_str(" . ");
+            if !self.write_flat(out, tail, depth + 1, limit) {
+                return false;
+            }
+        }
+        out.push(')');
+        out.len() <= limit
+    }
+}
+
+/// Render `value` with the default [`PrettyConfig`]
+pub fn pretty(value: &Value) -> String {
+    PrettyConfig::default().render(value)
+}
+
+/// Default for [`log_payload_limit`]
+pub const DEFAULT_LOG_PAYLOAD_LIMIT: usize = 4096;
+
+static LOG_PAYLOAD_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_LOG_PAYLOAD_LIMIT);
+
+/// Payloads estimated to be larger than this many bytes are truncated in logs
+pub fn log_payload_limit() -> usize {
+    LOG_PAYLOAD_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Change [`log_payload_limit`] for the whole process
+pub fn set_log_payload_limit(bytes: usize) {
+    LOG_PAYLOAD_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+/// Rendering of `value` for a log line
+///
+/// Payloads above [`log_payload_limit`] are shown with tight limits on
+/// depth, length and strings, followed by a note of their estimated size.
+pub fn for_log(value: &Value) -> String {
+    let size = approx_size(value);
+    if size <= log_payload_limit() {
+        return pretty(value);
+    }
+
+    let summary = PrettyConfig {
+        width: usize::MAX,
+        max_depth: 3,
+        max_items: 8,
+        max_string_len: 40,
+    }
+    .render(value);
+    format!("{} ;; truncated, ~{} bytes", summary, size)
+}
+
+/// Estimated length of `value` when serialized, without serializing it
+///
+/// Exact for most values; numbers, characters and escapes in strings are
+/// approximated.
+pub fn approx_size(value: &Value) -> usize {
+    match value {
+        Value::Nil | Value::Null => 2,
+        Value::Bool(_) => 2,
+        Value::Number(_) => 8,
+        Value::Char(_) => 3,
+        Value::String(s) => s.len() + 2,
+        Value::Symbol(name) => name.len(),
+        Value::Keyword(name) => name.len() + 1,
+        Value::Bytes(bytes) => bytes.len() * 2 + 4,
+        Value::Cons(_) => {
+            let mut size = 1;
+            let mut rest = value;
+            while let Value::Cons(cons) = rest {
+                size += approx_size(cons.car()) + 1;
+                rest = cons.cdr();
+            }
+            if !rest.is_null() {
+                size += approx_size(rest) + 3;
+            }
+            size
+        }
+        Value::Vector(items) => {
+            3 + items
+                .iter()
+                .map(|item| approx_size(item) + 1)
+                .sum::<usize>()
+        }
+    }
+}
+
+/// Opening delimiter, elements and improper tail of a list or vector
+fn parts(value: &Value) -> Option<(&'static str, Vec<&Value>, Option<&Value>)> {
+    match value {
+        Value::Cons(_) => {
+            let mut items = Vec::new();
+            let mut rest = value;
+            while let Value::Cons(cons) = rest {
+                items.push(cons.car());
+                rest = cons.cdr();
+            }
+            let tail = (!rest.is_null()).then_some(rest);
+            Some(("(", items, tail))
+        }
+        Value::Vector(items) => Some(("#(", items.iter().collect(), None)),
+        _ => None,
+    }
+}
+
+fn newline(out: &mut String, column: usize) {
+    out.push('\n');
+    out.extend(std::iter::repeat_n(' ', column));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_values_stay_on_one_line() {
+        let value = lexpr::from_str(r#"(call 1 echo (1 "two" (3 . 4)))"#).unwrap();
+        assert_eq!(pretty(&value), r#"(call 1 echo (1 "two" (3 . 4)))"#);
+    }
+
+    #[test]
+    fn test_breaks_and_truncates_long_values() {
+        let config = PrettyConfig {
+            width: 20,
+            max_items: 3,
+            max_string_len: 5,
+            ..Default::default()
+        };
+        let value =
+            lexpr::from_str(r#"(open "a-very-long-path" (1 2 3 4 5 6) (:mode "read"))"#).unwrap();
+
+        assert_eq!(
+            config.render(&value),
+            "(open\n \"a-ver\"...\n (1 2 3 ... 3 more)\n ... 1 more)"
+        );
+    }
+
+    #[test]
+    fn test_large_payloads_are_truncated_for_logs() {
+        let small = lexpr::from_str(r#"(echo "hi" sym)"#).unwrap();
+        assert_eq!(approx_size(&small), lexpr::to_string(&small).unwrap().len());
+        assert_eq!(for_log(&small), r#"(echo "hi" sym)"#);
+
+        let large = Value::list(vec![Value::string("x".repeat(10_000))]);
+        let logged = for_log(&large);
+        assert!(logged.len() < 100);
+        assert!(logged.ends_with(";; truncated, ~10004 bytes"));
+    }
+}
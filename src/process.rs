@@ -0,0 +1,992 @@
+//! Spawning and owning external EPC server processes
+
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncBufReadExt;
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::task::JoinHandle;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::health::{HealthConfig, HealthMonitor};
+use crate::log::{debug, warn};
+
+/// Default time a child gets to exit on its own before it is terminated
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Default time a child gets to exit after SIGTERM before it is killed
+pub const DEFAULT_TERMINATE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default time allowed for the child to print its port
+pub const DEFAULT_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default number of stderr lines kept for [`Process::stderr_tail`]
+pub const DEFAULT_STDERR_LINES: usize = 100;
+
+/// Environment variables used by [`PortHandshake::temp_file`] and
+/// [`PortHandshake::inherited_socket`]
+pub use crate::announce::{LISTEN_FD_ENV, PORT_FILE_ENV};
+
+/// Descriptor at which the child inherits a pre-bound listening socket
+#[cfg(unix)]
+pub const LISTEN_FD: i32 = 3;
+
+/// How often a port file is checked while waiting for the child
+const PORT_FILE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Callback receiving each line the child writes to stderr
+pub type StderrCallback = dyn Fn(&str) + Send + Sync;
+
+/// Hook adjusting the `Command` right before it is spawned
+pub type CommandHook = dyn Fn(&mut Command) + Send + Sync;
+
+/// What the child's stdin is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdinMode {
+    /// Share the parent's stdin
+    Inherit,
+    /// Connect stdin to the null device
+    Null,
+}
+
+/// What happens to stdout output after the port line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdoutMode {
+    /// Forward each line to the tracing log
+    Log,
+    /// Read and drop the output
+    Discard,
+}
+
+/// How the child tells the parent which port it listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortHandshake {
+    /// The first line of stdout is the port (the EPC convention)
+    Stdout,
+    /// The child writes its port, followed by a newline, to this file
+    File(PathBuf),
+    /// Like `File`, on a fresh temporary file whose path is passed to the
+    /// child in the environment variable `env`
+    TempFile { env: String },
+    /// The parent binds a listening socket on 127.0.0.1 and the child
+    /// inherits it at [`LISTEN_FD`]; `env` is set to the descriptor number
+    #[cfg(unix)]
+    InheritedSocket { env: String },
+}
+
+impl PortHandshake {
+    /// Temporary port file announced in [`PORT_FILE_ENV`]
+    pub fn temp_file() -> Self {
+        PortHandshake::TempFile {
+            env: PORT_FILE_ENV.to_string(),
+        }
+    }
+
+    /// Inherited listening socket announced in [`LISTEN_FD_ENV`]
+    #[cfg(unix)]
+    pub fn inherited_socket() -> Self {
+        PortHandshake::InheritedSocket {
+            env: LISTEN_FD_ENV.to_string(),
+        }
+    }
+}
+
+/// Stage of [`Process::stop`] at which the child exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopStage {
+    /// No child was running
+    NotRunning,
+    /// The child exited after the shutdown call or the connection closing
+    Shutdown,
+    /// The child exited after SIGTERM (CTRL_BREAK on Windows)
+    Terminated,
+    /// The child had to be killed
+    Killed,
+}
+
+/// Where the port of a freshly spawned child will come from
+enum PendingPort {
+    Stdout,
+    File { path: PathBuf, remove: bool },
+    Known(u16),
+}
+
+/// Process management for starting external processes
+///
+/// The process owns the spawned child: [`Process::stop`] closes the
+/// connection, waits up to the grace period for the child to exit and kills
+/// it otherwise. Unless disabled with [`Process::kill_on_drop`], dropping the
+/// `Process` kills the child as well.
+pub struct Process {
+    command: String,
+    args: Vec<String>,
+    envs: Vec<(OsString, Option<OsString>)>,
+    env_clear: bool,
+    current_dir: Option<PathBuf>,
+    stdin: StdinMode,
+    stdout: StdoutMode,
+    #[cfg(unix)]
+    process_group: Option<i32>,
+    #[cfg(windows)]
+    creation_flags: Option<u32>,
+    configure: Option<Arc<CommandHook>>,
+    handshake: PortHandshake,
+    port: Option<u16>,
+    client: Option<Arc<Client>>,
+    child: Option<Child>,
+    /// When the running child was started
+    started: Option<tokio::time::Instant>,
+    kill_on_drop: bool,
+    grace_period: Duration,
+    shutdown_method: Option<String>,
+    terminate_timeout: Duration,
+    startup_timeout: Duration,
+    stderr_lines: usize,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    stderr_callback: Option<Arc<StderrCallback>>,
+    stderr_task: Option<JoinHandle<()>>,
+}
+
+impl Process {
+    /// Create a new process configuration
+    pub fn new(command: impl Into<String>, args: Vec<impl Into<String>>) -> Self {
+        Process {
+            command: command.into(),
+            args: args.into_iter().map(Into::into).collect(),
+            envs: Vec::new(),
+            env_clear: false,
+            current_dir: None,
+            stdin: StdinMode::Inherit,
+            stdout: StdoutMode::Log,
+            #[cfg(unix)]
+            process_group: None,
+            #[cfg(windows)]
+            creation_flags: None,
+            configure: None,
+            handshake: PortHandshake::Stdout,
+            port: None,
+            client: None,
+            child: None,
+            started: None,
+            kill_on_drop: true,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            shutdown_method: None,
+            terminate_timeout: DEFAULT_TERMINATE_TIMEOUT,
+            startup_timeout: DEFAULT_STARTUP_TIMEOUT,
+            stderr_lines: DEFAULT_STDERR_LINES,
+            stderr_tail: Arc::new(Mutex::new(VecDeque::new())),
+            stderr_callback: None,
+            stderr_task: None,
+        }
+    }
+
+    /// Set an environment variable for the child
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.envs.push((key.into(), Some(value.into())));
+        self
+    }
+
+    /// Set several environment variables for the child
+    pub fn envs<I, K, V>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<OsString>,
+        V: Into<OsString>,
+    {
+        self.envs
+            .extend(vars.into_iter().map(|(k, v)| (k.into(), Some(v.into()))));
+        self
+    }
+
+    /// Remove an inherited environment variable
+    pub fn env_remove(mut self, key: impl Into<OsString>) -> Self {
+        self.envs.push((key.into(), None));
+        self
+    }
+
+    /// Start the child with an empty environment plus variables set via `env`
+    pub fn env_clear(mut self) -> Self {
+        self.env_clear = true;
+        self.envs.retain(|(_, value)| value.is_some());
+        self
+    }
+
+    /// Working directory of the child
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// What the child's stdin is connected to (default inherited)
+    pub fn stdin(mut self, mode: StdinMode) -> Self {
+        self.stdin = mode;
+        self
+    }
+
+    /// What happens to stdout after the port line (default logged)
+    pub fn stdout(mut self, mode: StdoutMode) -> Self {
+        self.stdout = mode;
+        self
+    }
+
+    /// Place the child in the given process group (0 creates a new group)
+    #[cfg(unix)]
+    pub fn process_group(mut self, pgid: i32) -> Self {
+        self.process_group = Some(pgid);
+        self
+    }
+
+    /// Windows process creation flags, e.g. `CREATE_NO_WINDOW`
+    #[cfg(windows)]
+    pub fn creation_flags(mut self, flags: u32) -> Self {
+        self.creation_flags = Some(flags);
+        self
+    }
+
+    /// Adjust the `Command` before each spawn, for settings not covered above
+    pub fn configure(mut self, hook: impl Fn(&mut Command) + Send + Sync + 'static) -> Self {
+        self.configure = Some(Arc::new(hook));
+        self
+    }
+
+    /// Build the command for spawning the child
+    fn build_command(&self) -> Command {
+        let mut command = Command::new(&self.command);
+        command
+            .args(&self.args)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(self.kill_on_drop);
+
+        if self.env_clear {
+            command.env_clear();
+        }
+        for (key, value) in &self.envs {
+            match value {
+                Some(value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        command.stdin(match self.stdin {
+            StdinMode::Inherit => std::process::Stdio::inherit(),
+            StdinMode::Null => std::process::Stdio::null(),
+        });
+        #[cfg(unix)]
+        if let Some(pgid) = self.process_group {
+            command.process_group(pgid);
+        }
+        #[cfg(windows)]
+        if let Some(flags) = self.creation_flags {
+            command.creation_flags(flags);
+        }
+        if let Some(configure) = &self.configure {
+            configure(&mut command);
+        }
+        command
+    }
+
+    /// How the child announces its port (default [`PortHandshake::Stdout`])
+    ///
+    /// Children that write other output to stdout can use one of the other
+    /// mechanisms; their stdout is then handled entirely by [`StdoutMode`].
+    pub fn handshake(mut self, handshake: PortHandshake) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Prepare `command` for the configured handshake
+    ///
+    /// Returns a pre-bound listener that must stay open until the child is
+    /// spawned.
+    fn prepare_handshake(
+        &self,
+        command: &mut Command,
+    ) -> std::result::Result<(PendingPort, Option<std::net::TcpListener>), ERPCError> {
+        match &self.handshake {
+            PortHandshake::Stdout => Ok((PendingPort::Stdout, None)),
+            PortHandshake::File(path) => {
+                // A file left over from an earlier run must not be mistaken for the answer
+                let _ = std::fs::remove_file(path);
+                Ok((
+                    PendingPort::File {
+                        path: path.clone(),
+                        remove: false,
+                    },
+                    None,
+                ))
+            }
+            PortHandshake::TempFile { env } => {
+                let path =
+                    std::env::temp_dir().join(format!("elrpc-port-{}", uuid::Uuid::new_v4()));
+                command.env(env, &path);
+                Ok((PendingPort::File { path, remove: true }, None))
+            }
+            #[cfg(unix)]
+            PortHandshake::InheritedSocket { env } => {
+                use std::os::fd::AsRawFd;
+
+                let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+                let port = listener.local_addr()?.port();
+                let fd = listener.as_raw_fd();
+                command.env(env, LISTEN_FD.to_string());
+
+                // SAFETY: only async-signal-safe calls run between fork and exec
+                unsafe {
+                    command.pre_exec(move || {
+                        // dup2 clears close-on-exec on the copy, unless it is a no-op
+                        let result = if fd == LISTEN_FD {
+                            libc::fcntl(fd, libc::F_SETFD, 0)
+                        } else {
+                            libc::dup2(fd, LISTEN_FD)
+                        };
+                        if result == -1 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+                Ok((PendingPort::Known(port), Some(listener)))
+            }
+        }
+    }
+
+    /// Whether dropping the `Process` kills the child (default `true`)
+    pub fn kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+
+    /// How long `stop` waits for the child to exit before terminating it
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Method `stop` calls first, so the child can flush state and exit
+    pub fn shutdown_method(mut self, method: impl Into<String>) -> Self {
+        self.shutdown_method = Some(method.into());
+        self
+    }
+
+    /// How long `stop` waits after SIGTERM (CTRL_BREAK) before killing the child
+    ///
+    /// On Windows CTRL_BREAK only reaches children spawned in their own
+    /// process group, see [`Process::creation_flags`].
+    pub fn terminate_timeout(mut self, timeout: Duration) -> Self {
+        self.terminate_timeout = timeout;
+        self
+    }
+
+    /// How long `start` waits for the port before killing the child
+    pub fn startup_timeout(mut self, timeout: Duration) -> Self {
+        self.startup_timeout = timeout;
+        self
+    }
+
+    /// Number of most recent stderr lines to retain (0 disables the buffer)
+    pub fn stderr_lines(mut self, lines: usize) -> Self {
+        self.stderr_lines = lines;
+        self
+    }
+
+    /// Call `callback` with every line the child writes to stderr
+    pub fn on_stderr(mut self, callback: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.stderr_callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Most recent lines the child wrote to stderr, oldest first
+    pub fn stderr_tail(&self) -> Vec<String> {
+        self.stderr_tail.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Forward the child's stderr to the log, the callback and the tail buffer
+    fn capture_stderr(&mut self, stderr: ChildStderr) {
+        let command = self.command.clone();
+        let max_lines = self.stderr_lines;
+        let tail = self.stderr_tail.clone();
+        let callback = self.stderr_callback.clone();
+
+        self.stderr_task = Some(tokio::spawn(async move {
+            let mut lines = tokio::io::BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                debug!(target: "elrpc::process::stderr", "[{}] {}", command, line);
+                if let Some(callback) = &callback {
+                    callback(&line);
+                }
+                if max_lines > 0 {
+                    let mut tail = tail.lock().unwrap();
+                    if tail.len() == max_lines {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            }
+        }));
+    }
+
+    /// Describe a startup failure, including what the child wrote to stderr
+    async fn startup_error(&mut self, error: ERPCError) -> ERPCError {
+        // Give the reader a moment to collect output of a crashing child
+        if let Some(task) = self.stderr_task.as_mut() {
+            let _ = tokio::time::timeout(Duration::from_millis(200), task).await;
+        }
+
+        let tail = self.stderr_tail();
+        if tail.is_empty() {
+            return error;
+        }
+        let message = match error {
+            ERPCError::ProcessError(message) => message,
+            other => other.to_string(),
+        };
+        ERPCError::ProcessError(format!("{}; stderr:\n{}", message, tail.join("\n")))
+    }
+
+    /// Start the process and connect to it
+    pub async fn start(&mut self) -> std::result::Result<(), ERPCError> {
+        if self.is_running() {
+            return Err(ERPCError::ProcessError(
+                "Process already started".to_string(),
+            ));
+        }
+        // Forget a previous, exited child so the process can be restarted
+        self.child = None;
+        self.client = None;
+        self.port = None;
+
+        let mut command = self.build_command();
+        let (pending, listener) = self.prepare_handshake(&mut command)?;
+        let spawned = command.spawn();
+        // The child holds its own copy of a pre-bound listener
+        drop(listener);
+        let mut child = spawned.map_err(|e| ERPCError::ProcessError(e.to_string()))?;
+        debug!("Spawned '{}' with pid {:?}", self.command, child.id());
+
+        let stdout = child.stdout.take();
+        if let Some(stderr) = child.stderr.take() {
+            self.capture_stderr(stderr);
+        }
+        self.child = Some(child);
+
+        if let Err(e) = self.connect(stdout, pending).await {
+            // Do not leave a half-started child behind
+            if let Some(mut child) = self.child.take() {
+                let _ = child.kill().await;
+            }
+            return Err(self.startup_error(e).await);
+        }
+        self.started = Some(tokio::time::Instant::now());
+        Ok(())
+    }
+
+    /// Learn the child's port through the handshake and connect to it
+    async fn connect(
+        &mut self,
+        stdout: Option<tokio::process::ChildStdout>,
+        pending: PendingPort,
+    ) -> std::result::Result<(), ERPCError> {
+        let stdout =
+            stdout.ok_or_else(|| ERPCError::ProcessError("No stdout from process".to_string()))?;
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        let listening = matches!(pending, PendingPort::Known(_));
+
+        let port = match pending {
+            PendingPort::Stdout => {
+                let first_line = tokio::time::timeout(self.startup_timeout, lines.next_line())
+                    .await
+                    .map_err(|_| {
+                        ERPCError::ProcessError(format!(
+                            "'{}' did not print a port within {:?}",
+                            self.command, self.startup_timeout
+                        ))
+                    })?
+                    .map_err(|e| ERPCError::ProcessError(e.to_string()))?;
+                let line = first_line.ok_or_else(|| {
+                    ERPCError::ProcessError("No port received from process".to_string())
+                })?;
+                let port = parse_port_line(&line)?;
+                self.drain_stdout(lines);
+                port
+            }
+            PendingPort::File { path, remove } => {
+                // Stdout is not part of the handshake; keep it from filling up meanwhile
+                self.drain_stdout(lines);
+                let port = self.wait_for_port_file(&path).await;
+                if remove {
+                    let _ = std::fs::remove_file(&path);
+                }
+                port?
+            }
+            PendingPort::Known(port) => {
+                self.drain_stdout(lines);
+                port
+            }
+        };
+        self.port = Some(port);
+
+        // Wait a bit for the server to start, unless the socket already listens
+        if !listening {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        // Connect to the server
+        let client = Client::connect(format!("127.0.0.1:{}", port)).await?;
+        self.client = Some(Arc::new(client));
+        Ok(())
+    }
+
+    /// Keep draining stdout so the child never blocks or gets SIGPIPE
+    fn drain_stdout(
+        &self,
+        mut lines: tokio::io::Lines<tokio::io::BufReader<tokio::process::ChildStdout>>,
+    ) {
+        let command = self.command.clone();
+        let mode = self.stdout;
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = lines.next_line().await {
+                if mode == StdoutMode::Log {
+                    debug!(target: "elrpc::process::stdout", "[{}] {}", command, line);
+                }
+            }
+        });
+    }
+
+    /// Poll `path` until the child has written a complete port line
+    async fn wait_for_port_file(&mut self, path: &Path) -> std::result::Result<u16, ERPCError> {
+        let deadline = tokio::time::Instant::now() + self.startup_timeout;
+        loop {
+            if let Ok(contents) = tokio::fs::read_to_string(path).await {
+                // A partial write could look like a shorter, valid port
+                if let Some((line, _)) = contents.split_once('\n') {
+                    return parse_port_line(line);
+                }
+            }
+            if let Some(status) = self.exit_status() {
+                return Err(ERPCError::ProcessError(format!(
+                    "'{}' exited with {} before writing a port to {}",
+                    self.command,
+                    status,
+                    path.display()
+                )));
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(ERPCError::ProcessError(format!(
+                    "'{}' did not write a port to {} within {:?}",
+                    self.command,
+                    path.display(),
+                    self.startup_timeout
+                )));
+            }
+            tokio::time::sleep(PORT_FILE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Get the underlying client
+    pub fn client(&self) -> Option<&Client> {
+        self.client.as_deref()
+    }
+
+    /// Shared handle to the underlying client, usable while `self` is borrowed elsewhere
+    pub fn client_handle(&self) -> Option<Arc<Client>> {
+        self.client.clone()
+    }
+
+    /// Get the port number
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Start periodic health probing of the connected child
+    ///
+    /// Returns `None` until the process has been started.
+    pub fn health_monitor(&self, config: HealthConfig) -> Option<HealthMonitor> {
+        self.client_handle()
+            .map(|client| HealthMonitor::spawn(client, config))
+    }
+
+    /// OS process id of the running child
+    pub fn pid(&self) -> Option<u32> {
+        self.child.as_ref().and_then(|child| child.id())
+    }
+
+    /// Whether the child is still running
+    pub fn is_running(&mut self) -> bool {
+        match self.child.as_mut() {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => false,
+        }
+    }
+
+    /// How long the running child has been up
+    pub fn uptime(&mut self) -> Option<Duration> {
+        if !self.is_running() {
+            return None;
+        }
+        self.started.map(|started| started.elapsed())
+    }
+
+    /// Exit status of a child that has exited on its own
+    pub fn exit_status(&mut self) -> Option<ExitStatus> {
+        self.child
+            .as_mut()
+            .and_then(|child| child.try_wait().ok().flatten())
+    }
+
+    /// Stop the process
+    ///
+    /// Calls the shutdown method if one is configured, closes the connection
+    /// and gives the child the grace period to exit. A child still running
+    /// afterwards is sent SIGTERM (CTRL_BREAK on Windows) and killed once the
+    /// terminate timeout passes. Returns the stage at which the child exited.
+    pub async fn stop(&mut self) -> std::result::Result<StopStage, ERPCError> {
+        let deadline = tokio::time::Instant::now() + self.grace_period;
+
+        if let Some(client) = self.client.take() {
+            if let Some(method) = &self.shutdown_method {
+                debug!("Asking '{}' to shut down via '{}'", self.command, method);
+                let call = client.call_value(method, Value::Null);
+                match tokio::time::timeout_at(deadline, call).await {
+                    Ok(Ok(_)) => {}
+                    // The child may exit before it gets to answer
+                    Ok(Err(e)) => debug!("Shutdown call to '{}' failed: {}", self.command, e),
+                    Err(_) => debug!("Shutdown call to '{}' timed out", self.command),
+                }
+            }
+            if let Err(e) = client.close().await {
+                debug!("Error closing connection to '{}': {}", self.command, e);
+            }
+        }
+
+        let Some(mut child) = self.child.take() else {
+            return Ok(StopStage::NotRunning);
+        };
+
+        if self.wait_until(&mut child, deadline).await? {
+            return Ok(StopStage::Shutdown);
+        }
+
+        warn!(
+            "Process '{}' did not exit within {:?}, terminating it",
+            self.command, self.grace_period
+        );
+        match terminate(&child) {
+            Ok(()) => {
+                let deadline = tokio::time::Instant::now() + self.terminate_timeout;
+                if self.wait_until(&mut child, deadline).await? {
+                    return Ok(StopStage::Terminated);
+                }
+                warn!(
+                    "Process '{}' did not exit within {:?} of being terminated, killing it",
+                    self.command, self.terminate_timeout
+                );
+            }
+            Err(e) => warn!("Failed to terminate '{}': {}, killing it", self.command, e),
+        }
+
+        child
+            .kill()
+            .await
+            .map_err(|e| ERPCError::ProcessError(e.to_string()))?;
+        Ok(StopStage::Killed)
+    }
+
+    /// Wait for `child` to exit until `deadline`, returning whether it did
+    async fn wait_until(
+        &self,
+        child: &mut Child,
+        deadline: tokio::time::Instant,
+    ) -> std::result::Result<bool, ERPCError> {
+        match tokio::time::timeout_at(deadline, child.wait()).await {
+            Ok(status) => {
+                let status = status.map_err(|e| ERPCError::ProcessError(e.to_string()))?;
+                debug!("Process '{}' exited with {}", self.command, status);
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Delegate calls to underlying client
+    pub async fn call_sync<Args, Ret>(
+        &self,
+        method: &str,
+        args: Args,
+    ) -> std::result::Result<Ret, ERPCError>
+    where
+        Args: Serialize,
+        Ret: for<'de> Deserialize<'de>,
+    {
+        if let Some(client) = &self.client {
+            client.call_sync(method, args).await
+        } else {
+            Err(ERPCError::ConnectionClosed)
+        }
+    }
+}
+
+/// Ask `child` to exit: SIGTERM on Unix, CTRL_BREAK on Windows
+fn terminate(child: &Child) -> std::io::Result<()> {
+    // Already reaped, nothing to signal
+    let Some(pid) = child.id() else {
+        return Ok(());
+    };
+
+    // SAFETY: plain system calls on a pid we own and have not reaped yet
+    #[cfg(unix)]
+    let sent = unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == 0;
+    #[cfg(windows)]
+    let sent = unsafe {
+        windows_sys::Win32::System::Console::GenerateConsoleCtrlEvent(
+            windows_sys::Win32::System::Console::CTRL_BREAK_EVENT,
+            pid,
+        )
+    } != 0;
+
+    if sent {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Validate a port line announced by the child
+fn parse_port_line(line: &str) -> std::result::Result<u16, ERPCError> {
+    match line.trim().parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => {
+            let shown: String = line.chars().take(80).collect();
+            Err(ERPCError::ProcessError(format!(
+                "Expected a port number as the first line of output, got {:?}",
+                shown
+            )))
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    /// A child that announces `port` and then ignores its connection closing
+    fn sleeper(port: u16) -> Process {
+        Process::new(
+            "sh",
+            vec!["-c".to_string(), format!("echo {}; exec sleep 30", port)],
+        )
+    }
+
+    /// Like `sleeper`, but also ignoring SIGTERM
+    fn stubborn_sleeper(port: u16) -> Process {
+        Process::new(
+            "sh",
+            vec![
+                "-c".to_string(),
+                format!("trap '' TERM; echo {}; exec sleep 30", port),
+            ],
+        )
+    }
+
+    /// Whether `pid` is running and not merely a zombie awaiting reaping
+    fn pid_alive(pid: u32) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.contains(") Z "),
+            Err(_) => false,
+        }
+    }
+
+    async fn server() -> (Server, u16) {
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+        (server, port)
+    }
+
+    #[tokio::test]
+    async fn test_stop_kills_after_grace_period() {
+        let (mut server, port) = server().await;
+        let mut process = stubborn_sleeper(port)
+            .grace_period(Duration::from_millis(50))
+            .terminate_timeout(Duration::from_millis(50));
+
+        process.start().await.unwrap();
+        assert!(process.is_running());
+        let pid = process.pid().unwrap();
+
+        assert_eq!(process.stop().await.unwrap(), StopStage::Killed);
+        assert!(!process.is_running());
+        assert!(!pid_alive(pid));
+        assert_eq!(process.stop().await.unwrap(), StopStage::NotRunning);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_calls_shutdown_method_then_terminates() {
+        let called = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = called.clone();
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_value_method(
+                "shutdown",
+                move |_| {
+                    flag.store(true, std::sync::atomic::Ordering::SeqCst);
+                    Ok(Value::Null)
+                },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let mut process = sleeper(port)
+            .shutdown_method("shutdown")
+            .grace_period(Duration::from_millis(50));
+        process.start().await.unwrap();
+
+        assert_eq!(process.stop().await.unwrap(), StopStage::Terminated);
+        assert!(called.load(std::sync::atomic::Ordering::SeqCst));
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_kill_on_drop() {
+        let (mut server, port) = server().await;
+        let mut process = sleeper(port);
+
+        process.start().await.unwrap();
+        let pid = process.pid().unwrap();
+        drop(process);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!pid_alive(pid));
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stderr_surfaces_in_startup_error() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let seen = lines.clone();
+        let mut process = Process::new(
+            "sh",
+            vec![
+                "-c",
+                "echo 'config not found' >&2; echo 'giving up' >&2; exit 1",
+            ],
+        )
+        .stderr_lines(1)
+        .on_stderr(move |line| seen.lock().unwrap().push(line.to_string()));
+
+        let error = process.start().await.unwrap_err().to_string();
+        assert!(error.contains("No port received"));
+        assert!(error.contains("giving up"));
+        assert_eq!(process.stderr_tail(), vec!["giving up".to_string()]);
+        assert_eq!(lines.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_configuration() {
+        let (mut server, port) = server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut process = Process::new(
+            "sh",
+            vec![
+                "-c",
+                "echo \"$ELRPC_PORT\"; echo \"$(pwd) ${HOME:-unset}\" >&2; exec sleep 30",
+            ],
+        )
+        .env("ELRPC_PORT", port.to_string())
+        .env_remove("HOME")
+        .current_dir(dir.path())
+        .stdin(StdinMode::Null)
+        .grace_period(Duration::from_millis(10));
+
+        process.start().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let expected = format!("{} unset", dir.path().canonicalize().unwrap().display());
+        assert_eq!(process.stderr_tail(), vec![expected]);
+
+        process.stop().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_temp_file_handshake_ignores_stdout_noise() {
+        let (mut server, port) = server().await;
+        let mut process = Process::new(
+            "sh",
+            vec![
+                "-c",
+                "echo 'starting up'; echo \"$PORT\" > \"$ELRPC_PORT_FILE\"; \
+                 echo \"$ELRPC_PORT_FILE\" >&2; exec sleep 30",
+            ],
+        )
+        .env("PORT", port.to_string())
+        .handshake(PortHandshake::temp_file())
+        .grace_period(Duration::from_millis(10));
+
+        process.start().await.unwrap();
+        assert_eq!(process.port(), Some(port));
+        let path = process.stderr_tail().pop().unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+
+        process.stop().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inherited_socket_handshake() {
+        let mut process = Process::new(
+            "sh",
+            vec![
+                "-c",
+                "echo \"$ELRPC_LISTEN_FD $(readlink /proc/$$/fd/3)\" >&2; exec sleep 30",
+            ],
+        )
+        .handshake(PortHandshake::inherited_socket())
+        .grace_period(Duration::from_millis(10));
+
+        process.start().await.unwrap();
+        assert!(process.port().is_some());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(process.stderr_tail()[0].starts_with("3 socket:"));
+
+        process.stop().await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_port_line() {
+        assert_eq!(parse_port_line(" 4242\r").unwrap(), 4242);
+        assert!(parse_port_line("0").is_err());
+        assert!(parse_port_line("Listening on 4242").is_err());
+        assert!(parse_port_line("70000").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_startup_timeout_kills_child() {
+        let mut process = Process::new("sh", vec!["-c", "exec sleep 30"])
+            .startup_timeout(Duration::from_millis(50));
+
+        let error = process.start().await.unwrap_err().to_string();
+        assert!(error.contains("did not print a port"));
+        assert!(!process.is_running());
+        assert!(process.pid().is_none());
+    }
+}
@@ -0,0 +1,642 @@
+//! Pool of identical EPC worker processes
+//!
+//! A [`ProcessPool`] spawns several copies of the same worker, spreads calls
+//! across them and restarts workers that die, giving CPU-heavy workloads
+//! process-level parallelism behind a single call interface.
+//!
+//! Stateful workers, which keep per-buffer or per-project data between
+//! calls, need related calls to land on the same worker. Calls made with
+//! [`ProcessPool::call_value_in`] or [`ProcessPool::call_sync_in`] carry a
+//! session key, such as a buffer name or project root: the first call of a
+//! session picks the live worker with the fewest sessions and every later
+//! one goes to that worker, until [`ProcessPool::end_session`]. A session
+//! whose worker died waits up to [`ProcessPoolConfig::session_wait`] for its
+//! restart rather than moving, and the restarted worker starts without the
+//! state of the old one.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
+use std::time::Duration;
+
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::fleet::{CallTimes, ChildHealth, FleetHealth, FleetSource};
+use crate::log::{debug, info, warn};
+use crate::process::Process;
+
+/// How the pool picks a worker for each call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    /// Cycle through the workers in order
+    RoundRobin,
+    /// Pick the worker with the fewest calls in flight
+    LeastLoaded,
+}
+
+/// Process pool configuration
+#[derive(Debug, Clone)]
+pub struct ProcessPoolConfig {
+    /// Number of worker processes
+    pub size: usize,
+    pub balance: Balance,
+    /// How often workers are checked for exit
+    pub check_interval: Duration,
+    /// How long a call of a session whose worker died waits for the restart
+    pub session_wait: Duration,
+}
+
+impl Default for ProcessPoolConfig {
+    fn default() -> Self {
+        ProcessPoolConfig {
+            size: 4,
+            balance: Balance::LeastLoaded,
+            check_interval: Duration::from_millis(500),
+            session_wait: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Statistics of a single worker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStats {
+    pub index: usize,
+    pub pid: Option<u32>,
+    pub alive: bool,
+    pub in_flight: usize,
+    pub calls: u64,
+    pub errors: u64,
+    pub restarts: u64,
+    /// Sessions routed to this worker
+    pub sessions: usize,
+}
+
+/// Aggregate statistics of a pool
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub alive: usize,
+    pub calls: u64,
+    pub errors: u64,
+    pub restarts: u64,
+    pub workers: Vec<WorkerStats>,
+}
+
+struct Worker {
+    index: usize,
+    process: Mutex<Process>,
+    client: RwLock<Option<Arc<Client>>>,
+    calls: AtomicU64,
+    errors: AtomicU64,
+    restarts: AtomicU64,
+    times: CallTimes,
+    /// Woken when the worker has been restarted
+    restarted: Notify,
+}
+
+impl Worker {
+    fn client(&self) -> Option<Arc<Client>> {
+        self.client.read().unwrap().clone()
+    }
+
+    fn in_flight(&self) -> usize {
+        self.client().map_or(0, |client| client.in_flight())
+    }
+}
+
+/// A fixed-size pool of identical worker processes
+pub struct ProcessPool {
+    workers: Arc<Vec<Arc<Worker>>>,
+    balance: Balance,
+    next: AtomicUsize,
+    session_wait: Duration,
+    /// Worker index of each session key
+    sessions: StdMutex<HashMap<String, usize>>,
+    monitor: JoinHandle<()>,
+}
+
+impl ProcessPool {
+    /// Spawn `config.size` workers built by `factory` from their index
+    ///
+    /// Fails if any worker cannot be started; workers already running are
+    /// stopped again.
+    pub async fn start<F>(
+        factory: F,
+        config: ProcessPoolConfig,
+    ) -> std::result::Result<Self, ERPCError>
+    where
+        F: Fn(usize) -> Process,
+    {
+        if config.size == 0 {
+            return Err(ERPCError::InvalidArgument(
+                "process pool size must be at least 1".to_string(),
+            ));
+        }
+
+        let mut workers: Vec<Arc<Worker>> = Vec::with_capacity(config.size);
+        for index in 0..config.size {
+            let mut process = factory(index);
+            if let Err(e) = process.start().await {
+                warn!("Failed to start pool worker {}: {}", index, e);
+                for worker in &workers {
+                    let _ = worker.process.lock().await.stop().await;
+                }
+                return Err(e);
+            }
+            debug!("Pool worker {} started with pid {:?}", index, process.pid());
+
+            workers.push(Arc::new(Worker {
+                index,
+                client: RwLock::new(process.client_handle()),
+                process: Mutex::new(process),
+                calls: AtomicU64::new(0),
+                errors: AtomicU64::new(0),
+                restarts: AtomicU64::new(0),
+                times: CallTimes::default(),
+                restarted: Notify::new(),
+            }));
+        }
+        info!("Process pool started with {} workers", workers.len());
+
+        let workers = Arc::new(workers);
+        let monitor = tokio::spawn(monitor(workers.clone(), config.check_interval));
+
+        Ok(ProcessPool {
+            workers,
+            balance: config.balance,
+            next: AtomicUsize::new(0),
+            session_wait: config.session_wait,
+            sessions: StdMutex::new(HashMap::new()),
+            monitor,
+        })
+    }
+
+    /// Number of workers in the pool
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Pick a worker with a live connection
+    fn pick(&self) -> Option<(&Arc<Worker>, Arc<Client>)> {
+        fn live(worker: &Arc<Worker>) -> Option<(&Arc<Worker>, Arc<Client>)> {
+            worker.client().map(|client| (worker, client))
+        }
+
+        match self.balance {
+            Balance::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..self.workers.len())
+                    .map(|offset| &self.workers[(start + offset) % self.workers.len()])
+                    .find_map(live)
+            }
+            Balance::LeastLoaded => self
+                .workers
+                .iter()
+                .filter_map(live)
+                .min_by_key(|(_, client)| client.in_flight()),
+        }
+    }
+
+    /// Call a method on one of the workers with raw S-expression arguments
+    pub async fn call_value(
+        &self,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let (worker, client) = self
+            .pick()
+            .ok_or_else(|| ERPCError::ProcessError("no pool worker is running".to_string()))?;
+        call_on(worker, &client, method, args).await
+    }
+
+    /// Worker of `session`, picking the one with the fewest sessions for a
+    /// new session
+    ///
+    /// The client is None while the session's worker is being restarted.
+    fn pick_for(
+        &self,
+        session: &str,
+    ) -> std::result::Result<(&Arc<Worker>, Option<Arc<Client>>), ERPCError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(&index) = sessions.get(session) {
+            let worker = &self.workers[index];
+            return Ok((worker, worker.client()));
+        }
+        let mut counts = vec![0usize; self.workers.len()];
+        for &index in sessions.values() {
+            counts[index] += 1;
+        }
+        let (worker, client) = self
+            .workers
+            .iter()
+            .filter_map(|worker| worker.client().map(|client| (worker, client)))
+            .min_by_key(|(worker, client)| (counts[worker.index], client.in_flight()))
+            .ok_or_else(|| ERPCError::ProcessError("no pool worker is running".to_string()))?;
+        debug!("Session {} routed to pool worker {}", session, worker.index);
+        sessions.insert(session.to_string(), worker.index);
+        Ok((worker, Some(client)))
+    }
+
+    /// Worker of `session` with a live connection, waiting up to
+    /// `session_wait` for a dead one to be restarted
+    async fn session_client(
+        &self,
+        session: &str,
+    ) -> std::result::Result<(&Arc<Worker>, Arc<Client>), ERPCError> {
+        let (worker, client) = self.pick_for(session)?;
+        if let Some(client) = client {
+            return Ok((worker, client));
+        }
+        debug!(
+            "Session {} waits for pool worker {} to restart",
+            session, worker.index
+        );
+        let restarted = async {
+            loop {
+                // Registered before the check, so a restart in between is
+                // not missed
+                let notified = worker.restarted.notified();
+                if let Some(client) = worker.client() {
+                    return client;
+                }
+                notified.await;
+            }
+        };
+        match tokio::time::timeout(self.session_wait, restarted).await {
+            Ok(client) => Ok((worker, client)),
+            Err(_) => Err(ERPCError::ProcessError(format!(
+                "pool worker {} of session {} did not restart within {:?}",
+                worker.index, session, self.session_wait
+            ))),
+        }
+    }
+
+    /// Call a method with raw S-expression arguments on the worker of
+    /// `session`
+    pub async fn call_value_in(
+        &self,
+        session: &str,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let (worker, client) = self.session_client(session).await?;
+        call_on(worker, &client, method, args).await
+    }
+
+    /// Call a method on the worker of `session`
+    pub async fn call_sync_in<Args, Ret>(
+        &self,
+        session: &str,
+        method: &str,
+        args: Args,
+    ) -> std::result::Result<Ret, ERPCError>
+    where
+        Args: Serialize,
+        Ret: for<'de> Deserialize<'de>,
+    {
+        let args_value = serde_lexpr::to_value(&args)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+
+        let result = self.call_value_in(session, method, args_value).await?;
+
+        serde_lexpr::from_value(&result).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Index of the worker `session` is routed to, if it has one
+    pub fn session_worker(&self, session: &str) -> Option<usize> {
+        self.sessions.lock().unwrap().get(session).copied()
+    }
+
+    /// Forget `session`, so its next call picks a worker afresh; false if
+    /// it had none
+    pub fn end_session(&self, session: &str) -> bool {
+        self.sessions.lock().unwrap().remove(session).is_some()
+    }
+
+    /// Call a method on one of the workers
+    pub async fn call_sync<Args, Ret>(
+        &self,
+        method: &str,
+        args: Args,
+    ) -> std::result::Result<Ret, ERPCError>
+    where
+        Args: Serialize,
+        Ret: for<'de> Deserialize<'de>,
+    {
+        let args_value = serde_lexpr::to_value(&args)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+
+        let result = self.call_value(method, args_value).await?;
+
+        serde_lexpr::from_value(&result).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Per-worker and aggregate statistics
+    pub async fn stats(&self) -> PoolStats {
+        let mut stats = PoolStats::default();
+        let mut sessions = vec![0; self.workers.len()];
+        for &index in self.sessions.lock().unwrap().values() {
+            sessions[index] += 1;
+        }
+
+        for worker in self.workers.iter() {
+            let (pid, alive) = {
+                let mut process = worker.process.lock().await;
+                (process.pid(), process.is_running())
+            };
+            let worker = WorkerStats {
+                index: worker.index,
+                pid,
+                alive,
+                in_flight: worker.in_flight(),
+                calls: worker.calls.load(Ordering::Relaxed),
+                errors: worker.errors.load(Ordering::Relaxed),
+                restarts: worker.restarts.load(Ordering::Relaxed),
+                sessions: sessions[worker.index],
+            };
+
+            stats.alive += worker.alive as usize;
+            stats.calls += worker.calls;
+            stats.errors += worker.errors;
+            stats.restarts += worker.restarts;
+            stats.workers.push(worker);
+        }
+        stats
+    }
+
+    /// Stop the monitor and every worker
+    pub async fn stop(self) -> std::result::Result<(), ERPCError> {
+        self.monitor.abort();
+
+        let mut result = Ok(());
+        for worker in self.workers.iter() {
+            worker.client.write().unwrap().take();
+            if let Err(e) = worker.process.lock().await.stop().await {
+                warn!("Failed to stop pool worker {}: {}", worker.index, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl FleetSource for ProcessPool {
+    /// Workers named `worker-N`
+    async fn fleet_health(&self) -> FleetHealth {
+        let mut health = FleetHealth::default();
+        for worker in self.workers.iter() {
+            let (pid, running, uptime) = {
+                let mut process = worker.process.lock().await;
+                (process.pid(), process.is_running(), process.uptime())
+            };
+            health.children.push(ChildHealth {
+                name: format!("worker-{}", worker.index),
+                pid,
+                running,
+                uptime,
+                restarts: worker.restarts.load(Ordering::Relaxed),
+                calls: worker.calls.load(Ordering::Relaxed),
+                errors: worker.errors.load(Ordering::Relaxed),
+                mean_latency: worker.times.mean(),
+                max_latency: worker.times.max(),
+            });
+        }
+        health
+    }
+}
+
+impl Drop for ProcessPool {
+    fn drop(&mut self) {
+        self.monitor.abort();
+    }
+}
+
+/// Call `method` on `worker`, counting the call and its failure
+async fn call_on(
+    worker: &Worker,
+    client: &Client,
+    method: &str,
+    args: Value,
+) -> std::result::Result<Value, ERPCError> {
+    worker.calls.fetch_add(1, Ordering::Relaxed);
+    let started = tokio::time::Instant::now();
+    let result = client.call_value(method, args).await;
+    worker.times.record(started.elapsed());
+    if let Err(e) = &result {
+        debug!(
+            "Call to '{}' on pool worker {} failed: {}",
+            method, worker.index, e
+        );
+        worker.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Restart workers whose process has exited
+async fn monitor(workers: Arc<Vec<Arc<Worker>>>, check_interval: Duration) {
+    loop {
+        tokio::time::sleep(check_interval).await;
+
+        for worker in workers.iter() {
+            let mut process = worker.process.lock().await;
+            if process.is_running() {
+                continue;
+            }
+
+            warn!(
+                "Pool worker {} exited with {:?}, restarting",
+                worker.index,
+                process.exit_status()
+            );
+            worker.client.write().unwrap().take();
+
+            match process.start().await {
+                Ok(()) => {
+                    info!(
+                        "Restarted pool worker {}, pid {:?}",
+                        worker.index,
+                        process.pid()
+                    );
+                    *worker.client.write().unwrap() = process.client_handle();
+                    worker.restarts.fetch_add(1, Ordering::Relaxed);
+                    worker.restarted.notify_waiters();
+                }
+                // Retried on the next check
+                Err(e) => warn!("Failed to restart pool worker {}: {}", worker.index, e),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    async fn server() -> (Server, u16) {
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_method("echo", |s: String| Ok(s), None::<&str>, None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+        (server, port)
+    }
+
+    /// Workers that announce the test server's port and stay alive
+    fn worker(port: u16) -> impl Fn(usize) -> Process {
+        move |_| {
+            Process::new(
+                "sh",
+                vec!["-c".to_string(), format!("echo {}; exec sleep 30", port)],
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_spreads_calls() {
+        let (mut server, port) = server().await;
+        let config = ProcessPoolConfig {
+            size: 3,
+            balance: Balance::RoundRobin,
+            ..Default::default()
+        };
+        let pool = ProcessPool::start(worker(port), config).await.unwrap();
+
+        for i in 0..6 {
+            let reply: String = pool.call_sync("echo", format!("msg {}", i)).await.unwrap();
+            assert_eq!(reply, format!("msg {}", i));
+        }
+
+        let stats = pool.stats().await;
+        assert_eq!(stats.alive, 3);
+        assert_eq!(stats.calls, 6);
+        assert!(stats.workers.iter().all(|worker| worker.calls == 2));
+
+        pool.stop().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replaces_dead_worker() {
+        let (mut server, port) = server().await;
+        let config = ProcessPoolConfig {
+            size: 2,
+            check_interval: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let pool = ProcessPool::start(worker(port), config).await.unwrap();
+
+        let pid = pool.stats().await.workers[0].pid.unwrap();
+        std::process::Command::new("kill")
+            .arg(pid.to_string())
+            .status()
+            .unwrap();
+
+        let mut stats = pool.stats().await;
+        for _ in 0..100 {
+            if stats.restarts == 1 && stats.alive == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            stats = pool.stats().await;
+        }
+        assert_eq!(stats.restarts, 1);
+        assert_eq!(stats.alive, 2);
+        assert_ne!(stats.workers[0].pid, Some(pid));
+
+        let reply: String = pool.call_sync("echo", "again").await.unwrap();
+        assert_eq!(reply, "again");
+
+        pool.stop().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sessions_stick_to_a_worker() {
+        let (mut server, port) = server().await;
+        let config = ProcessPoolConfig {
+            size: 2,
+            balance: Balance::RoundRobin,
+            ..Default::default()
+        };
+        let pool = ProcessPool::start(worker(port), config).await.unwrap();
+
+        for i in 0..3 {
+            let reply: String = pool
+                .call_sync_in("a.el", "echo", format!("a {}", i))
+                .await
+                .unwrap();
+            assert_eq!(reply, format!("a {}", i));
+            let _: String = pool.call_sync_in("b.el", "echo", "b").await.unwrap();
+        }
+        let (a, b) = (
+            pool.session_worker("a.el").unwrap(),
+            pool.session_worker("b.el").unwrap(),
+        );
+        assert_ne!(a, b);
+        let stats = pool.stats().await;
+        assert!(stats
+            .workers
+            .iter()
+            .all(|worker| worker.calls == 3 && worker.sessions == 1));
+
+        assert!(pool.end_session("a.el"));
+        assert_eq!(pool.session_worker("a.el"), None);
+
+        pool.stop().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_waits_for_its_worker_to_restart() {
+        let (mut server, port) = server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("started");
+        // Restarts take a while to announce their port
+        let factory = move |_| {
+            Process::new(
+                "sh",
+                vec![
+                    "-c".to_string(),
+                    format!(
+                        "[ -e {0} ] && sleep 0.5; touch {0}; echo {1}; exec sleep 30",
+                        marker.display(),
+                        port
+                    ),
+                ],
+            )
+        };
+        let config = ProcessPoolConfig {
+            size: 1,
+            check_interval: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let pool = ProcessPool::start(factory, config).await.unwrap();
+        let _: String = pool.call_sync_in("a.el", "echo", "a").await.unwrap();
+
+        let pid = pool.stats().await.workers[0].pid.unwrap();
+        std::process::Command::new("kill")
+            .arg(pid.to_string())
+            .status()
+            .unwrap();
+        // The monitor has noticed and is restarting the worker
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let reply: String = pool.call_sync_in("a.el", "echo", "again").await.unwrap();
+        assert_eq!(reply, "again");
+        let stats = pool.stats().await;
+        assert_eq!(stats.restarts, 1);
+        assert_ne!(stats.workers[0].pid, Some(pid));
+
+        pool.stop().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+}
@@ -1,6 +1,15 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use lexpr::Value;
-use tracing::{debug, warn};
+use smallvec::{smallvec, SmallVec};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::log::{debug, trace, warn};
+
+/// Inline capacity for the top-level items of a message.
+///
+/// Every standard EPC message has at most four elements (`call uid method
+/// args`), so parsing one never needs to spill onto the heap.
+const MESSAGE_ITEMS_INLINE: usize = 4;
 
 /// EPC Protocol message enum
 #[derive(Debug, Clone, PartialEq)]
@@ -23,6 +32,25 @@ pub enum Message {
 
     /// Query available methods: (methods uid)
     Methods { uid: u64 },
+
+    /// Message of a protocol extension: (verb uid items...)
+    ///
+    /// Handled by the [verb handler](crate::registry::MethodRegistry::register_verb)
+    /// registered for `verb`; peers without one reject it.
+    Extension {
+        verb: String,
+        uid: u64,
+        items: Vec<Value>,
+    },
+}
+
+/// Which peer allocated the uid of a message, see [`Message::uid_space`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UidSpace {
+    /// The peer sending the message: it starts a new exchange
+    Sender,
+    /// The peer receiving the message: it answers one of its calls
+    Receiver,
 }
 
 impl Message {
@@ -61,6 +89,15 @@ impl Message {
         Message::Methods { uid }
     }
 
+    /// Create a new message of a protocol extension
+    pub fn new_extension(uid: u64, verb: impl Into<String>, items: Vec<Value>) -> Self {
+        Message::Extension {
+            verb: verb.into(),
+            uid,
+            items,
+        }
+    }
+
     /// Get the UID of the message
     pub fn uid(&self) -> u64 {
         match self {
@@ -69,81 +106,134 @@ impl Message {
             Message::ReturnError { uid, .. } => *uid,
             Message::EPCError { uid, .. } => *uid,
             Message::Methods { uid } => *uid,
+            Message::Extension { uid, .. } => *uid,
         }
     }
 
-    /// Serialize message to S-expression string
-    pub fn to_sexp(&self) -> std::result::Result<String, crate::error::ERPCError> {
-        debug!("Serializing message: {:?}", self);
-        let sexp = match self {
-            Message::Call { uid, method, args } => {
-                debug!(
-                    "Serializing CALL uid={}, method={}, args={:?}",
-                    uid, method, args
-                );
-                Value::list(vec![
-                    Value::symbol("call"),
-                    Value::from(*uid as i64),
-                    Value::symbol(method.clone()),
-                    args.clone(),
-                ])
-            }
-            Message::Return { uid, result } => {
-                debug!("Serializing RETURN uid={}, result={:?}", uid, result);
-                Value::list(vec![
-                    Value::symbol("return"),
-                    Value::from(*uid as i64),
-                    result.clone(),
-                ])
-            }
-            Message::ReturnError { uid, error } => {
-                debug!("Serializing RETURN-ERROR uid={}, error={}", uid, error);
-                Value::list(vec![
-                    Value::symbol("return-error"),
-                    Value::from(*uid as i64),
-                    Value::string(error.clone()),
-                ])
+    /// Whose uid space the message's uid belongs to
+    ///
+    /// Each peer numbers the calls it issues on its own, so when both sides
+    /// make calls on one connection the same uid can name two different
+    /// calls. Calls and method queries carry a uid of their sender; replies
+    /// carry a uid of the peer they are sent to. Extension messages are
+    /// taken to start exchanges of their own.
+    pub fn uid_space(&self) -> UidSpace {
+        match self {
+            Message::Call { .. } | Message::Methods { .. } | Message::Extension { .. } => {
+                UidSpace::Sender
             }
-            Message::EPCError { uid, error } => {
-                debug!("Serializing EPC-ERROR uid={}, error={}", uid, error);
-                Value::list(vec![
-                    Value::symbol("epc-error"),
-                    Value::from(*uid as i64),
-                    Value::string(error.clone()),
-                ])
+            Message::Return { .. } | Message::ReturnError { .. } | Message::EPCError { .. } => {
+                UidSpace::Receiver
             }
+        }
+    }
+
+    /// The message as an S-expression
+    pub fn to_value(&self) -> Value {
+        match self {
+            Message::Call { uid, method, args } => Value::list(vec![
+                Value::symbol("call"),
+                Value::from(*uid as i64),
+                Value::symbol(method.clone()),
+                args.clone(),
+            ]),
+            Message::Return { uid, result } => Value::list(vec![
+                Value::symbol("return"),
+                Value::from(*uid as i64),
+                result.clone(),
+            ]),
+            Message::ReturnError { uid, error } => Value::list(vec![
+                Value::symbol("return-error"),
+                Value::from(*uid as i64),
+                Value::string(error.clone()),
+            ]),
+            Message::EPCError { uid, error } => Value::list(vec![
+                Value::symbol("epc-error"),
+                Value::from(*uid as i64),
+                Value::string(error.clone()),
+            ]),
             Message::Methods { uid } => {
-                debug!("Serializing METHODS uid={}", uid);
                 Value::list(vec![Value::symbol("methods"), Value::from(*uid as i64)])
             }
-        };
+            Message::Extension { verb, uid, items } => Value::list(
+                [Value::symbol(verb.clone()), Value::from(*uid as i64)]
+                    .into_iter()
+                    .chain(items.iter().cloned())
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+
+    /// Indented, truncated rendering for logs, see [`crate::pretty`]
+    pub fn pretty(&self) -> String {
+        crate::pretty::pretty(&self.to_value())
+    }
+
+    /// Estimated length of the serialized message, without the frame header
+    pub fn approx_size(&self) -> usize {
+        use crate::pretty::approx_size;
+
+        // Parentheses and an eight-digit uid, then the tag and its separators
+        let envelope = 2 + 8;
+        envelope
+            + match self {
+                Message::Call { method, args, .. } => 7 + method.len() + approx_size(args),
+                Message::Return { result, .. } => 8 + approx_size(result),
+                Message::ReturnError { error, .. } => 16 + error.len(),
+                Message::EPCError { error, .. } => 13 + error.len(),
+                Message::Methods { .. } => 8,
+                Message::Extension { verb, items, .. } => {
+                    1 + verb.len()
+                        + items
+                            .iter()
+                            .map(|item| 1 + approx_size(item))
+                            .sum::<usize>()
+                }
+            }
+    }
+
+    /// Serialize message to S-expression string
+    pub fn to_sexp(&self) -> std::result::Result<String, crate::error::ERPCError> {
+        let sexp = self.to_value();
+        debug!("Serializing message:\n{}", crate::pretty::for_log(&sexp));
 
         let result = lexpr::to_string(&sexp)
             .map_err(|e| crate::error::ERPCError::SerializationError(e.to_string()));
-        debug!(
-            "Serialized to: {}",
-            result.as_ref().unwrap_or(&"ERROR".to_string())
+        trace!(
+            "Serialized to {} bytes",
+            result.as_ref().map_or(0, String::len)
         );
         result
     }
 
     /// Parse message from S-expression string
     pub fn from_sexp(s: &str) -> std::result::Result<Self, crate::error::ERPCError> {
-        debug!("Parsing S-expression: {}", s);
+        trace!("Parsing {} bytes", s.len());
         let value = lexpr::from_str(s)?;
 
-        debug!("Parsed value: {:?}", value);
+        debug!("Parsed message:\n{}", crate::pretty::for_log(&value));
+        Message::from_value(value)
+    }
 
+    /// Build a message from an already parsed S-expression
+    pub fn from_value(value: Value) -> std::result::Result<Self, crate::error::ERPCError> {
         // Handle both Cons and proper list formats
-        let items: Vec<Value> = match value {
+        let mut items: SmallVec<[Value; MESSAGE_ITEMS_INLINE]> = match value {
             Value::Cons(cons) => {
-                let items: Vec<Value> = cons.list_iter().map(|v| v.clone()).collect();
-                debug!("Parsed Cons as list: {:?}", items);
+                // Move the elements out of the cons cells instead of cloning
+                // them, so large argument trees are never deep-copied.
+                let mut items: SmallVec<[Value; MESSAGE_ITEMS_INLINE]> = SmallVec::new();
+                let mut rest = Value::Cons(cons);
+                while let Value::Cons(cell) = rest {
+                    let (car, cdr) = cell.into_pair();
+                    items.push(car);
+                    rest = cdr;
+                }
                 items
             }
             Value::Null => {
                 debug!("Parsed Null value");
-                vec![Value::Null]
+                smallvec![Value::Null]
             }
             _ => {
                 warn!("Expected list format, got: {:?}", value);
@@ -154,8 +244,6 @@ impl Message {
             }
         };
 
-        debug!("Message items: {:?}", items);
-
         if items.len() < 2 {
             warn!(
                 "Message too short: {} items, expected at least 2",
@@ -224,8 +312,8 @@ impl Message {
                         )));
                     }
                 };
-                debug!("Method call: {} with args: {:?}", method, items[3]);
-                Ok(Message::new_call(uid, method, items[3].clone()))
+                debug!("Method call: {}", method);
+                Ok(Message::new_call(uid, method, items.swap_remove(3)))
             }
             "return" => {
                 if items.len() != 3 {
@@ -235,8 +323,7 @@ impl Message {
                         items.len()
                     )));
                 }
-                debug!("Return message with result: {:?}", items[2]);
-                Ok(Message::new_return(uid, items[2].clone()))
+                Ok(Message::new_return(uid, items.swap_remove(2)))
             }
             "return-error" => {
                 if items.len() != 3 {
@@ -295,85 +382,317 @@ impl Message {
                 Ok(Message::new_methods(uid))
             }
             _ => {
-                warn!("Unknown message type: {}", msg_type);
-                Err(crate::error::ERPCError::InvalidMessageFormat(format!(
-                    "Unknown message type: {}",
-                    msg_type
-                )))
+                debug!("Extension message: {}", msg_type);
+                Ok(Message::Extension {
+                    verb: msg_type,
+                    uid,
+                    items: items.into_iter().skip(2).collect(),
+                })
             }
         }
     }
 }
 
-/// Message framing utilities
-pub struct Framer;
+/// Length of the S-expression at the start of `text`, without parsing it
+///
+/// Strings and escaped characters are skipped over, so brackets inside them
+/// do not count. Returns `None` if `text` ends before the expression does.
+pub(crate) fn datum_len(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'"' if in_string => {
+                in_string = false;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ if in_string => {}
+            b'"' => in_string = true,
+            b'(' | b'[' => depth += 1,
+            b')' | b']' if depth == 0 => return Some(i).filter(|&len| len > 0),
+            b')' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            b if depth == 0 && b.is_ascii_whitespace() => return Some(i).filter(|&len| len > 0),
+            _ => {}
+        }
+        i += 1;
+    }
+    Some(bytes.len()).filter(|&len| depth == 0 && !in_string && len > 0)
+}
 
-impl Framer {
-    /// Frame a message with 6-byte length prefix
-    pub fn frame(message: &[u8]) -> Bytes {
-        let len = message.len();
-        debug!("Framing message: {} bytes", len);
+/// Byte stream an EPC connection runs over
+pub(crate) trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// Format of the length prefix in front of every frame
+///
+/// EPC uses six hex digits, but some EPC-like peers use other widths or
+/// decimal lengths. Both ends of a connection must agree on the format.
+///
+/// Frames are limited to [`DEFAULT_MAX_FRAME`] bytes unless raised with
+/// [`LengthPrefix::max_frame`], so a wide prefix announcing a huge length
+/// is refused instead of buffered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthPrefix {
+    width: usize,
+    radix: u32,
+    max_frame: usize,
+}
 
-        let mut buf = BytesMut::with_capacity(6 + len);
-        let len_str = format!("{:06x}", len);
-        debug!("Length prefix: {}", len_str);
+/// Default limit on the payload of a frame, what six hex digits allow
+pub const DEFAULT_MAX_FRAME: usize = 0xff_ffff;
 
-        buf.put_slice(len_str.as_bytes());
-        buf.put_slice(message);
+impl Default for LengthPrefix {
+    fn default() -> Self {
+        LengthPrefix::EPC
+    }
+}
 
-        let result = buf.freeze();
-        debug!("Framed message total size: {} bytes", result.len());
-        result
+impl LengthPrefix {
+    /// Six hex digits, as in standard EPC
+    pub const EPC: LengthPrefix = LengthPrefix {
+        width: 6,
+        radix: 16,
+        max_frame: DEFAULT_MAX_FRAME,
+    };
+
+    /// `width` digits in base `radix`, which must be 10 or 16
+    pub fn new(width: usize, radix: u32) -> std::result::Result<Self, crate::error::ERPCError> {
+        if width == 0 || width > 16 || !matches!(radix, 10 | 16) {
+            return Err(crate::error::ERPCError::InvalidArgument(format!(
+                "unsupported length prefix: {} digits in base {}",
+                width, radix
+            )));
+        }
+        Ok(LengthPrefix {
+            width,
+            radix,
+            max_frame: DEFAULT_MAX_FRAME,
+        })
     }
 
-    /// Parse length prefix from buffer
-    pub fn parse_length(buf: &[u8]) -> Option<usize> {
-        debug!("Parsing length from buffer: {} bytes", buf.len());
+    /// Accept and send payloads of up to `bytes`, as far as the prefix can
+    /// express them
+    pub fn max_frame(mut self, bytes: usize) -> Self {
+        self.max_frame = bytes;
+        self
+    }
+
+    /// `width` hex digits
+    pub fn hex(width: usize) -> std::result::Result<Self, crate::error::ERPCError> {
+        LengthPrefix::new(width, 16)
+    }
+
+    /// `width` decimal digits
+    pub fn decimal(width: usize) -> std::result::Result<Self, crate::error::ERPCError> {
+        LengthPrefix::new(width, 10)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn radix(&self) -> u32 {
+        self.radix
+    }
+
+    /// Largest payload the prefix can announce
+    pub fn max_len(&self) -> usize {
+        (self.radix as u128)
+            .checked_pow(self.width as u32)
+            .map_or(usize::MAX, |limit| {
+                usize::try_from(limit - 1).unwrap_or(usize::MAX)
+            })
+    }
+
+    /// Largest payload framed or extracted, the smaller of the frame limit
+    /// and what the prefix can announce
+    pub fn limit(&self) -> usize {
+        self.max_frame.min(self.max_len())
+    }
+
+    fn too_large(&self, len: usize) -> crate::error::ERPCError {
+        crate::error::ERPCError::ProtocolError(format!(
+            "frame of {} bytes exceeds the {} byte limit",
+            len,
+            self.limit()
+        ))
+    }
+
+    fn encode(&self, len: usize) -> String {
+        match self.radix {
+            10 => format!("{:0width$}", len, width = self.width),
+            _ => format!("{:0width$x}", len, width = self.width),
+        }
+    }
+
+    /// Frame a message with this prefix
+    pub fn frame(&self, message: &[u8]) -> std::result::Result<Bytes, crate::error::ERPCError> {
+        let mut buf = BytesMut::with_capacity(self.width + message.len());
+        self.frame_into(&mut buf, message)?;
+        Ok(buf.freeze())
+    }
+
+    /// Append a framed message to an existing buffer
+    ///
+    /// Fails for payloads over [`limit`](LengthPrefix::limit), whose prefix
+    /// would not fit its width and throw the peer off.
+    pub fn frame_into(
+        &self,
+        dst: &mut BytesMut,
+        message: &[u8],
+    ) -> std::result::Result<(), crate::error::ERPCError> {
+        if message.len() > self.limit() {
+            return Err(self.too_large(message.len()));
+        }
+        self.put_frame(dst, message);
+        Ok(())
+    }
+
+    fn put_frame(&self, dst: &mut BytesMut, message: &[u8]) {
+        let len = message.len();
+        debug!("Framing message into buffer: {} bytes", len);
+
+        dst.reserve(self.width + len);
+        dst.put_slice(self.encode(len).as_bytes());
+        dst.put_slice(message);
+    }
 
-        if buf.len() < 6 {
-            debug!("Buffer too short for length prefix: {} < 6", buf.len());
+    /// Parse the length prefix at the start of `buf`
+    pub fn parse_length(&self, buf: &[u8]) -> Option<usize> {
+        if buf.len() < self.width {
+            debug!(
+                "Buffer too short for length prefix: {} < {}",
+                buf.len(),
+                self.width
+            );
             return None;
         }
 
-        let len_str = std::str::from_utf8(&buf[..6]).ok()?;
+        let len_str = std::str::from_utf8(&buf[..self.width]).ok()?;
         debug!("Length string: {}", len_str);
 
-        let result = usize::from_str_radix(len_str, 16).ok();
+        let result = usize::from_str_radix(len_str, self.radix).ok();
         debug!("Parsed length: {:?}", result);
         result
     }
 
+    /// Extract the next complete message, first skipping whitespace between
+    /// frames if `skip_whitespace`
+    pub fn next_frame(
+        &self,
+        buf: &mut BytesMut,
+        skip_whitespace: bool,
+    ) -> std::result::Result<Option<Bytes>, crate::error::ERPCError> {
+        if skip_whitespace {
+            Framer::skip_whitespace(buf);
+        }
+        self.extract_message(buf)
+    }
+
     /// Extract complete message from buffer
-    pub fn extract_message(buf: &mut BytesMut) -> Option<Bytes> {
+    ///
+    /// Fails once the prefix announces more than
+    /// [`limit`](LengthPrefix::limit) bytes; the stream cannot be resynced
+    /// after that.
+    pub fn extract_message(
+        &self,
+        buf: &mut BytesMut,
+    ) -> std::result::Result<Option<Bytes>, crate::error::ERPCError> {
         debug!("Extracting message from buffer: {} bytes", buf.len());
 
-        if buf.len() < 6 {
-            debug!("Buffer too short for header: {} < 6", buf.len());
-            return None;
-        }
-
-        let len = Self::parse_length(buf)?;
+        let Some(len) = self.parse_length(buf) else {
+            return Ok(None);
+        };
         debug!("Message length: {}", len);
+        if len > self.limit() {
+            return Err(self.too_large(len));
+        }
 
-        let total_len = 6 + len;
-        debug!("Total frame length: {}", total_len);
-
+        let total_len = self
+            .width
+            .checked_add(len)
+            .ok_or_else(|| self.too_large(len))?;
         if buf.len() < total_len {
             debug!(
                 "Buffer too short for complete message: {} < {}",
                 buf.len(),
                 total_len
             );
-            return None;
+            return Ok(None);
         }
 
-        let message = buf[6..total_len].to_vec();
+        buf.advance(self.width);
+        let message = buf.split_to(len).freeze();
         debug!("Extracted message: {} bytes", message.len());
+        Ok(Some(message))
+    }
+}
 
-        buf.advance(total_len);
-        debug!("Buffer advanced, remaining: {} bytes", buf.len());
+/// Message framing utilities for the standard six hex digit prefix
+///
+/// See [`LengthPrefix`] for other prefix formats. Payloads must fit six hex
+/// digits; [`LengthPrefix::EPC`] reports larger ones as errors.
+pub struct Framer;
+
+impl Framer {
+    /// Frame a message with 6-byte length prefix
+    pub fn frame(message: &[u8]) -> Bytes {
+        let mut buf = BytesMut::with_capacity(6 + message.len());
+        Framer::frame_into(&mut buf, message);
+        buf.freeze()
+    }
 
-        Some(Bytes::from(message))
+    /// Append a framed message to an existing buffer
+    pub fn frame_into(dst: &mut BytesMut, message: &[u8]) {
+        LengthPrefix::EPC.put_frame(dst, message)
+    }
+
+    /// Parse length prefix from buffer
+    pub fn parse_length(buf: &[u8]) -> Option<usize> {
+        LengthPrefix::EPC.parse_length(buf)
+    }
+
+    /// Drop whitespace in front of the next frame, returning how many bytes
+    /// were dropped
+    ///
+    /// Some implementations, epc.el among them, send a newline after each
+    /// frame, which would otherwise be read as the start of a length prefix.
+    pub fn skip_whitespace(buf: &mut BytesMut) -> usize {
+        let skipped = buf
+            .iter()
+            .take_while(|byte| matches!(byte, b' ' | b'\t' | b'\r' | b'\n'))
+            .count();
+        if skipped > 0 {
+            trace!("Skipping {} bytes of whitespace between frames", skipped);
+            buf.advance(skipped);
+        }
+        skipped
+    }
+
+    /// Extract the next complete message, first skipping whitespace between
+    /// frames if `skip_whitespace`
+    pub fn next_frame(buf: &mut BytesMut, skip_whitespace: bool) -> Option<Bytes> {
+        if skip_whitespace {
+            Framer::skip_whitespace(buf);
+        }
+        Framer::extract_message(buf)
+    }
+
+    /// Extract complete message from buffer
+    pub fn extract_message(buf: &mut BytesMut) -> Option<Bytes> {
+        // Six hex digits cannot announce more than the default limit
+        LengthPrefix::EPC.extract_message(buf).unwrap_or(None)
     }
 }
 
@@ -381,12 +700,106 @@ impl Framer {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_datum_len() {
+        assert_eq!(datum_len("(a (b \")\") [c]) rest"), Some(15));
+        assert_eq!(datum_len("\"x\\\"y\" z"), Some(6));
+        assert_eq!(datum_len("nil)"), Some(3));
+        assert_eq!(datum_len("42"), Some(2));
+        assert_eq!(datum_len("?\\) 1"), Some(3));
+        assert_eq!(datum_len("(a (b)"), None);
+        assert_eq!(datum_len("\"open"), None);
+        assert_eq!(datum_len(")"), None);
+        assert_eq!(datum_len(""), None);
+    }
+
     #[test]
     fn test_message_creation() {
         let msg = Message::new_call(123, "test", Value::string("hello"));
         assert!(matches!(msg, Message::Call { uid: 123, .. }));
     }
 
+    #[test]
+    fn test_uid_space() {
+        assert_eq!(
+            Message::new_call(1, "f", Value::Nil).uid_space(),
+            UidSpace::Sender
+        );
+        assert_eq!(Message::new_methods(1).uid_space(), UidSpace::Sender);
+        assert_eq!(
+            Message::new_return_error(1, "boom").uid_space(),
+            UidSpace::Receiver
+        );
+    }
+
+    #[test]
+    fn test_unknown_verb_parses_as_extension() {
+        let msg = Message::new_extension(7, "my-ext", vec![Value::symbol("ping"), Value::Nil]);
+        assert_eq!(msg.uid_space(), UidSpace::Sender);
+        match Message::from_value(msg.to_value()).unwrap() {
+            Message::Extension { verb, uid, items } => {
+                assert_eq!(verb, "my-ext");
+                assert_eq!(uid, 7);
+                assert_eq!(items, [Value::symbol("ping"), Value::Nil]);
+            }
+            other => panic!("expected an extension message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_length_prefix_formats() {
+        let decimal = LengthPrefix::decimal(8).unwrap();
+        let framed = decimal.frame(b"(methods 1)").unwrap();
+        assert_eq!(&framed[..], &b"00000011(methods 1)"[..]);
+        assert_eq!(decimal.max_len(), 99_999_999);
+
+        let mut buf = BytesMut::from(&framed[..]);
+        assert_eq!(
+            decimal.extract_message(&mut buf).unwrap().unwrap(),
+            &b"(methods 1)"[..]
+        );
+        assert!(buf.is_empty());
+
+        assert_eq!(LengthPrefix::default(), LengthPrefix::EPC);
+        assert_eq!(LengthPrefix::EPC.max_len(), 0xff_ffff);
+        assert!(LengthPrefix::new(6, 8).is_err());
+        assert!(LengthPrefix::hex(0).is_err());
+    }
+
+    #[test]
+    fn test_length_prefix_limits() {
+        // A length that would overflow the total frame size is refused
+        let wide = LengthPrefix::hex(16).unwrap();
+        let mut buf = BytesMut::from(&b"ffffffffffffffff(call"[..]);
+        assert!(wide.extract_message(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&b"0000000001000000"[..]);
+        assert!(wide.extract_message(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"0000000001000000"[..]);
+        assert_eq!(
+            wide.max_frame(1 << 24).extract_message(&mut buf).unwrap(),
+            None
+        );
+
+        // Payloads the prefix cannot announce are not framed
+        let narrow = LengthPrefix::decimal(2).unwrap();
+        assert!(narrow.frame(&[b'x'; 99]).is_ok());
+        assert!(narrow.frame(&[b'x'; 100]).is_err());
+    }
+
+    #[test]
+    fn test_whitespace_between_frames() {
+        let mut buf = BytesMut::from(&b"000003(a)\n\r\n000003(b) "[..]);
+        assert_eq!(Framer::next_frame(&mut buf, true).unwrap(), &b"(a)"[..]);
+        assert_eq!(Framer::next_frame(&mut buf, true).unwrap(), &b"(b)"[..]);
+        assert!(Framer::next_frame(&mut buf, true).is_none());
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"\n000003(a)"[..]);
+        assert!(Framer::next_frame(&mut buf, false).is_none());
+        assert_eq!(buf.len(), 10);
+    }
+
     #[test]
     fn test_serialization_roundtrip() {
         let msg = Message::new_call(123, "test", Value::string("hello"));
@@ -403,6 +816,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_approx_size_matches_serialized_length() {
+        let messages = [
+            Message::new_call(12345678, "echo", lexpr::from_str(r#"("a" b)"#).unwrap()),
+            Message::new_return_error(12345678, "boom"),
+            Message::new_methods(12345678),
+        ];
+        for msg in messages {
+            assert_eq!(msg.approx_size(), msg.to_sexp().unwrap().len());
+        }
+    }
+
     #[test]
     fn test_return_message() {
         let msg = Message::new_return(456, Value::from(42));
@@ -430,4 +855,12 @@ mod tests {
 
         assert_eq!(extracted, Bytes::from_static(message));
     }
+
+    #[test]
+    fn test_frame_into_appends() {
+        let mut buf = BytesMut::new();
+        Framer::frame_into(&mut buf, b"(methods 1)");
+        Framer::frame_into(&mut buf, b"(methods 2)");
+        assert_eq!(&buf[..], b"00000b(methods 1)00000b(methods 2)");
+    }
 }
@@ -0,0 +1,488 @@
+//! Transparent EPC proxy
+//!
+//! A [`Proxy`] accepts EPC connections and relays every frame, in both
+//! directions, to an upstream endpoint. Frames pass through unchanged except
+//! for calls to methods listed in [`ProxyConfig::rename`]. A [`WireTap`] sees
+//! each frame twice, once per connection it crosses, which makes a proxy in
+//! front of an existing server a convenient recorder for debugging, audit or
+//! migration shims. With a [`FaultPlan`] or a [`LinkProfile`] it becomes an
+//! unreliable or slow network for resilience tests instead.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::error::ERPCError;
+use crate::fault::{FaultInjector, FaultPlan};
+use crate::link::{LinkProfile, Pacer};
+use crate::log::{debug, error, info, trace};
+use crate::protocol::{Framer, Message};
+use crate::wiretap::{next_connection_id, Direction, WireTap};
+
+/// Proxy configuration
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Address of the server traffic is forwarded to
+    pub upstream: String,
+    /// Calls to a key are forwarded as calls to its value
+    pub rename: HashMap<String, String>,
+    /// Hook receiving every frame on both sides of the proxy
+    pub wire_tap: Option<WireTap>,
+    /// Faults injected into relayed frames; calls use the plan's seed and
+    /// responses the seed plus one
+    pub faults: Option<FaultPlan>,
+    /// Latency and throughput of each direction (None relays at full speed)
+    pub link: Option<LinkProfile>,
+}
+
+impl ProxyConfig {
+    /// Forward to `upstream` without rewriting anything
+    pub fn new(upstream: impl Into<String>) -> Self {
+        ProxyConfig {
+            upstream: upstream.into(),
+            rename: HashMap::new(),
+            wire_tap: None,
+            faults: None,
+            link: None,
+        }
+    }
+
+    /// Forward calls to `from` as calls to `to`
+    ///
+    /// Only calls are rewritten; a `methods` query still lists the upstream
+    /// names.
+    pub fn rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.rename.insert(from.into(), to.into());
+        self
+    }
+
+    /// Hand every frame to `tap`
+    pub fn wire_tap(mut self, tap: WireTap) -> Self {
+        self.wire_tap = Some(tap);
+        self
+    }
+
+    /// Drop, duplicate, corrupt, fragment or reorder frames according to `plan`
+    pub fn faults(mut self, plan: FaultPlan) -> Self {
+        self.faults = Some(plan);
+        self
+    }
+
+    /// Slow both directions down to `profile`
+    pub fn link(mut self, profile: LinkProfile) -> Self {
+        self.link = Some(profile);
+        self
+    }
+}
+
+/// One side of a relayed connection
+#[derive(Debug, Clone, Copy)]
+struct Endpoint {
+    connection: u64,
+    addr: SocketAddr,
+}
+
+/// EPC proxy
+pub struct Proxy {
+    config: Arc<ProxyConfig>,
+    listener: Option<TcpListener>,
+    local_addr: SocketAddr,
+    shutdown_tx: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Proxy {
+    /// Listen on `addr`; connections are accepted once [`Proxy::serve`] is called
+    pub async fn bind(addr: &str, config: ProxyConfig) -> std::result::Result<Self, ERPCError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("Proxy for {} bound to {}", config.upstream, local_addr);
+
+        Ok(Proxy {
+            config: Arc::new(config),
+            listener: Some(listener),
+            local_addr,
+            shutdown_tx: None,
+            handle: None,
+        })
+    }
+
+    /// Address the proxy listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the port the proxy listens on
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Start relaying connections in the background
+    pub fn serve(&mut self) -> std::result::Result<(), ERPCError> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| ERPCError::ProtocolError("Proxy is already serving".to_string()))?;
+        let config = self.config.clone();
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        self.shutdown_tx = Some(shutdown_tx);
+
+        self.handle = Some(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, addr)) => {
+                            let config = config.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = relay(stream, addr, &config).await {
+                                    error!("Proxy connection from {} failed: {}", addr, e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!("Proxy failed to accept connection: {}", e);
+                            break;
+                        }
+                    },
+                    _ = shutdown_rx.recv() => break,
+                }
+            }
+            info!("Proxy listener stopped");
+        }));
+        Ok(())
+    }
+
+    /// Stop accepting connections; relayed connections run until either end closes
+    pub async fn shutdown(&mut self) -> std::result::Result<(), ERPCError> {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(()).await;
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.await;
+        }
+        Ok(())
+    }
+}
+
+/// Relay one downstream connection to a fresh upstream connection
+async fn relay(
+    downstream: TcpStream,
+    addr: SocketAddr,
+    config: &ProxyConfig,
+) -> std::result::Result<(), ERPCError> {
+    let upstream = TcpStream::connect(&config.upstream).await?;
+    let client = Endpoint {
+        connection: next_connection_id(),
+        addr,
+    };
+    let server = Endpoint {
+        connection: next_connection_id(),
+        addr: upstream.peer_addr()?,
+    };
+    info!("Relaying {} to {}", client.addr, server.addr);
+
+    let (down_read, down_write) = downstream.into_split();
+    let (up_read, up_write) = upstream.into_split();
+    let (to_server, to_client) = tokio::join!(
+        forward(down_read, up_write, client, server, config, true),
+        forward(up_read, down_write, server, client, config, false),
+    );
+
+    info!("Relay between {} and {} closed", client.addr, server.addr);
+    to_server.and(to_client)
+}
+
+/// Where relayed writes go: straight to the socket, or through a simulated link
+enum Outlet<W> {
+    Direct(W),
+    Paced {
+        pacer: Pacer,
+        tx: mpsc::UnboundedSender<(Instant, Bytes)>,
+        delivery: JoinHandle<std::result::Result<(), ERPCError>>,
+    },
+}
+
+impl<W> Outlet<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    fn new(mut writer: W, link: Option<LinkProfile>) -> Self {
+        let Some(profile) = link else {
+            return Outlet::Direct(writer);
+        };
+
+        // Writes are queued with their arrival time, so reading continues
+        // while earlier writes are still in flight
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Bytes)>();
+        let delivery = tokio::spawn(async move {
+            while let Some((arrival, bytes)) = rx.recv().await {
+                tokio::time::sleep_until(arrival).await;
+                writer.write_all(&bytes).await?;
+            }
+            let _ = writer.shutdown().await;
+            Ok(())
+        });
+        Outlet::Paced {
+            pacer: Pacer::new(profile),
+            tx,
+            delivery,
+        }
+    }
+
+    async fn write(&mut self, bytes: Bytes) -> std::result::Result<(), ERPCError> {
+        match self {
+            Outlet::Direct(writer) => {
+                writer.write_all(&bytes).await?;
+                writer.flush().await?;
+                Ok(())
+            }
+            Outlet::Paced { pacer, tx, .. } => {
+                let arrival = pacer.schedule(Instant::now(), bytes.len());
+                tx.send((arrival, bytes))
+                    .map_err(|_| ERPCError::ConnectionClosed)
+            }
+        }
+    }
+
+    /// Deliver what is still queued, then close the socket
+    async fn close(self) -> std::result::Result<(), ERPCError> {
+        match self {
+            Outlet::Direct(mut writer) => {
+                let _ = writer.shutdown().await;
+                Ok(())
+            }
+            Outlet::Paced { tx, delivery, .. } => {
+                drop(tx);
+                delivery
+                    .await
+                    .map_err(|e| ERPCError::ProtocolError(e.to_string()))?
+            }
+        }
+    }
+}
+
+/// Copy frames from `from` to `to` until `from` closes
+///
+/// Calls are renamed only on their way upstream.
+async fn forward<R, W>(
+    mut reader: R,
+    writer: W,
+    from: Endpoint,
+    to: Endpoint,
+    config: &ProxyConfig,
+    upstream: bool,
+) -> std::result::Result<(), ERPCError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    // Each direction draws from its own generators
+    let seed_offset = if upstream { 0 } else { 1 };
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut faults = config.faults.clone().map(|mut plan| {
+        plan.seed = plan.seed.wrapping_add(seed_offset);
+        FaultInjector::new(plan)
+    });
+    let mut outlet = Outlet::new(
+        writer,
+        config.link.clone().map(|mut profile| {
+            profile.seed = profile.seed.wrapping_add(seed_offset);
+            profile
+        }),
+    );
+
+    loop {
+        if reader.read_buf(&mut buffer).await? == 0 {
+            break;
+        }
+
+        while let Some(frame) = Framer::next_frame(&mut buffer, true) {
+            trace!(
+                "Relaying {} bytes from {} to {}",
+                frame.len(),
+                from.addr,
+                to.addr
+            );
+            if let Some(tap) = &config.wire_tap {
+                tap.record(
+                    Direction::Inbound,
+                    from.connection,
+                    from.addr.into(),
+                    SystemTime::now(),
+                    &frame,
+                );
+            }
+            let frame = if upstream {
+                rename_call(frame, &config.rename)
+            } else {
+                frame
+            };
+            if let Some(tap) = &config.wire_tap {
+                tap.record(
+                    Direction::Outbound,
+                    to.connection,
+                    to.addr.into(),
+                    SystemTime::now(),
+                    &frame,
+                );
+            }
+            match &mut faults {
+                Some(injector) => {
+                    let delay = injector.fragment_delay();
+                    for (i, piece) in injector.apply(&frame).into_iter().enumerate() {
+                        if i > 0 {
+                            tokio::time::sleep(delay).await;
+                        }
+                        outlet.write(piece).await?;
+                    }
+                }
+                None => outlet.write(Framer::frame(&frame)).await?,
+            }
+        }
+    }
+
+    if let Some(held) = faults.as_mut().and_then(FaultInjector::finish) {
+        outlet.write(held).await?;
+    }
+    // Pass the close on so the other direction winds down as well
+    outlet.close().await
+}
+
+/// Rewrite the method of a call frame per `rename`; anything else, including
+/// frames that do not parse, is passed through untouched
+fn rename_call(frame: Bytes, rename: &HashMap<String, String>) -> Bytes {
+    if rename.is_empty() {
+        return frame;
+    }
+    let Some(Message::Call { uid, method, args }) = std::str::from_utf8(&frame)
+        .ok()
+        .and_then(|text| Message::from_sexp(text).ok())
+    else {
+        return frame;
+    };
+    let Some(target) = rename.get(&method) else {
+        return frame;
+    };
+
+    debug!("Renaming call {} to {}", method, target);
+    match Message::new_call(uid, target.clone(), args).to_sexp() {
+        Ok(text) => Bytes::from(text),
+        Err(_) => frame,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::fault::Fault;
+    use crate::server::Server;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_proxy_relays_and_renames_calls() {
+        let mut server = Server::builder()
+            .method("echo", |s: String| Ok(s))
+            .build()
+            .await
+            .unwrap();
+
+        let frames = Arc::new(AtomicUsize::new(0));
+        let seen = frames.clone();
+        let config = ProxyConfig::new(format!("127.0.0.1:{}", server.port().unwrap()))
+            .rename("legacy-echo", "echo")
+            .wire_tap(WireTap::new(move |_| {
+                seen.fetch_add(1, Ordering::SeqCst);
+            }));
+        let mut proxy = Proxy::bind("127.0.0.1:0", config).await.unwrap();
+        proxy.serve().unwrap();
+
+        let client = Client::connect(format!("127.0.0.1:{}", proxy.port()))
+            .await
+            .unwrap();
+        let reply: String = client.call_sync("legacy-echo", "hi").await.unwrap();
+        assert_eq!(reply, "hi");
+        let reply: String = client.call_sync("echo", "there").await.unwrap();
+        assert_eq!(reply, "there");
+        // Each call and each return is seen entering and leaving the proxy
+        assert_eq!(frames.load(Ordering::SeqCst), 8);
+
+        client.close().await.unwrap();
+        proxy.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_drops_scheduled_frames() {
+        let mut server = Server::builder()
+            .method("echo", |s: String| Ok(s))
+            .build()
+            .await
+            .unwrap();
+        // The first call is lost on its way upstream
+        let config = ProxyConfig::new(format!("127.0.0.1:{}", server.port().unwrap()))
+            .faults(FaultPlan::seeded(1).at(0, Fault::Drop));
+        let mut proxy = Proxy::bind("127.0.0.1:0", config).await.unwrap();
+        proxy.serve().unwrap();
+
+        let client = Client::connect(format!("127.0.0.1:{}", proxy.port()))
+            .await
+            .unwrap();
+        let lost = tokio::time::timeout(
+            std::time::Duration::from_millis(200),
+            client.call_value("echo", lexpr::Value::from("lost")),
+        )
+        .await;
+        assert!(lost.is_err());
+
+        client.close().await.unwrap();
+        proxy.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_adds_link_latency() {
+        let mut server = Server::builder()
+            .method("echo", |s: String| Ok(s))
+            .build()
+            .await
+            .unwrap();
+        let config = ProxyConfig::new(format!("127.0.0.1:{}", server.port().unwrap())).link(
+            LinkProfile::with_latency(std::time::Duration::from_millis(50)),
+        );
+        let mut proxy = Proxy::bind("127.0.0.1:0", config).await.unwrap();
+        proxy.serve().unwrap();
+
+        let client = Client::connect(format!("127.0.0.1:{}", proxy.port()))
+            .await
+            .unwrap();
+        let started = Instant::now();
+        let reply: String = client.call_sync("echo", "slow").await.unwrap();
+        assert_eq!(reply, "slow");
+        // Once there and once back
+        assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+
+        client.close().await.unwrap();
+        proxy.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_rename_leaves_other_frames_alone() {
+        let rename = HashMap::from([("old".to_string(), "new".to_string())]);
+
+        let renamed = rename_call(Bytes::from_static(b"(call 1 old (1 2))"), &rename);
+        assert_eq!(&renamed[..], b"(call 1 new (1 2))");
+        for frame in [&b"(call 2 other nil)"[..], b"(return 1 2)", b"not a sexp ("] {
+            assert_eq!(rename_call(Bytes::copy_from_slice(frame), &rename), frame);
+        }
+    }
+}
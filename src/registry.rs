@@ -1,12 +1,28 @@
 use std::collections::HashMap;
+#[cfg(any(test, feature = "test-util"))]
+use std::collections::VecDeque;
 use std::fmt;
 use std::sync::Arc;
+#[cfg(any(test, feature = "test-util"))]
+use std::time::Duration;
 
 use lexpr::Value;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
-use crate::error::ERPCError;
+use crate::arena::ArenaValue;
+#[cfg(feature = "cache")]
+use crate::cache::{CachedHandler, ResultCache};
+#[cfg(feature = "chunked")]
+use crate::chunked::{ChunkLimits, ChunkSink, ChunkSource};
+use crate::error::{ERPCError, IntoEpcError};
+use crate::extract::{AppState, ExtractHandler, Handler};
+use crate::guard::{GuardedHandler, MethodGuard};
+use crate::params::{ParamsFn, ParamsHandler};
+use crate::protocol::Message;
+use crate::reply::Reply;
+#[cfg(feature = "tower")]
+use crate::service::{dispatcher, BoxError, Call, Dispatcher};
 
 /// Method metadata for introspection
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -43,11 +59,51 @@ impl fmt::Display for MethodInfo {
     }
 }
 
+/// How the EPC argument list reaches a typed handler's parameters
+///
+/// `epc.el` always sends the arguments of a call as one list, so a call with
+/// a single string argument arrives as `("hello")`. Rust clients send the
+/// serialized arguments as they are, so the same call arrives as `"hello"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArgsStyle {
+    /// The argument list is the handler's single parameter; a handler taking
+    /// `(A, B)` receives `(a b)`, one taking `String` needs a bare string
+    #[default]
+    Single,
+    /// The argument list is spread over the handler's parameters: a list of
+    /// one element is unwrapped, so `("hello")` reaches a `String` handler,
+    /// and longer lists fill a tuple. Handlers taking a single sequence then
+    /// cannot be called with a one-element list.
+    Spread,
+}
+
+impl ArgsStyle {
+    /// Deserialize a typed handler's parameters from the arguments of a call
+    pub fn decode<Args>(self, args: &Value) -> std::result::Result<Args, ERPCError>
+    where
+        Args: for<'de> Deserialize<'de>,
+    {
+        let args = match (self, args) {
+            (ArgsStyle::Spread, Value::Cons(cons)) if cons.cdr().is_null() => cons.car(),
+            _ => args,
+        };
+        serde_lexpr::from_value(args).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+}
+
 /// Trait for methods that can be registered
 #[async_trait::async_trait]
 pub trait MethodHandler: Send + Sync {
     async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError>;
 
+    /// Call the method, keeping the hints of its [`Reply`]
+    ///
+    /// Wrappers such as the result cache call this; handlers whose replies
+    /// carry hints override it.
+    async fn call_reply(&self, args: Value) -> std::result::Result<Reply, ERPCError> {
+        self.call(args).await.map(Reply::new)
+    }
+
     fn info(&self) -> MethodInfo;
 }
 
@@ -72,6 +128,53 @@ impl ClosureHandler {
             info: MethodInfo::new(name, arg_spec, docstring),
         }
     }
+
+    /// Handler for a typed function, converting arguments and result via serde
+    pub fn typed<F, Args, Ret, E>(
+        func: F,
+        name: impl Into<String>,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> Self
+    where
+        F: Fn(Args) -> std::result::Result<Ret, E> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+        E: IntoEpcError,
+    {
+        ClosureHandler::typed_with_style(func, ArgsStyle::Single, name, arg_spec, docstring)
+    }
+
+    /// Handler for a typed function receiving its arguments in `style`
+    ///
+    /// Errors of the function are converted with [`IntoEpcError`].
+    pub fn typed_with_style<F, Args, Ret, E>(
+        func: F,
+        style: ArgsStyle,
+        name: impl Into<String>,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> Self
+    where
+        F: Fn(Args) -> std::result::Result<Ret, E> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+        E: IntoEpcError,
+    {
+        ClosureHandler::new(
+            move |args_val: Value| {
+                let args: Args = style.decode(&args_val)?;
+
+                let result = func(args).map_err(IntoEpcError::into_epc_error)?;
+
+                serde_lexpr::to_value(&result)
+                    .map_err(|e| ERPCError::SerializationError(e.to_string()))
+            },
+            name,
+            arg_spec,
+            docstring,
+        )
+    }
 }
 
 #[async_trait::async_trait]
@@ -119,19 +222,263 @@ impl MethodHandler for ValueHandler {
     }
 }
 
+/// Handler that runs on a rayon pool instead of a tokio worker thread
+#[cfg(feature = "cpu-pool")]
+pub struct CpuHandler {
+    func: Arc<dyn Fn(Value) -> std::result::Result<Value, ERPCError> + Send + Sync>,
+    pool: Option<Arc<rayon::ThreadPool>>,
+    info: MethodInfo,
+}
+
+#[cfg(feature = "cpu-pool")]
+impl CpuHandler {
+    /// Create a handler running on `pool`, or on rayon's global pool if `None`
+    pub fn new<F>(
+        func: F,
+        pool: Option<Arc<rayon::ThreadPool>>,
+        name: impl Into<String>,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> Self
+    where
+        F: Fn(Value) -> std::result::Result<Value, ERPCError> + Send + Sync + 'static,
+    {
+        CpuHandler {
+            func: Arc::new(func),
+            pool,
+            info: MethodInfo::new(name, arg_spec, docstring),
+        }
+    }
+}
+
+#[cfg(feature = "cpu-pool")]
+#[async_trait::async_trait]
+impl MethodHandler for CpuHandler {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let func = self.func.clone();
+        let job = move || {
+            // A panic inside a rayon job would abort the process
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| func(args)));
+            let _ = tx.send(result);
+        };
+
+        match &self.pool {
+            Some(pool) => pool.spawn(job),
+            None => rayon::spawn(job),
+        }
+
+        match rx.await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(ERPCError::ApplicationError {
+                class: "Panic".to_string(),
+                message: format!("handler '{}' panicked", self.info.name),
+                backtrace: vec![],
+            }),
+            Err(_) => Err(ERPCError::ProcessError(
+                "CPU worker pool dropped the request".to_string(),
+            )),
+        }
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+/// Signature of handlers receiving arena-backed arguments
+pub type ArenaFn =
+    dyn for<'v> Fn(ArenaValue<'v>) -> std::result::Result<Value, ERPCError> + Send + Sync;
+
+/// Method whose arguments are parsed into a per-request arena
+pub struct ArenaMethod {
+    func: Box<ArenaFn>,
+    info: MethodInfo,
+}
+
+impl ArenaMethod {
+    /// Invoke the handler with arguments borrowed from the request arena
+    pub fn call(&self, args: ArenaValue<'_>) -> std::result::Result<Value, ERPCError> {
+        (self.func)(args)
+    }
+
+    pub fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+/// Signature of handlers working on the unparsed argument text
+pub type RawFn = dyn Fn(&str) -> std::result::Result<String, ERPCError> + Send + Sync;
+
+/// Method receiving its arguments as S-expression text and replying with
+/// S-expression text, bypassing `lexpr` on both sides
+pub struct RawMethod {
+    func: Box<RawFn>,
+    info: MethodInfo,
+}
+
+impl RawMethod {
+    /// Invoke the handler with the argument text of a call
+    pub fn call(&self, args: &str) -> std::result::Result<String, ERPCError> {
+        (self.func)(args)
+    }
+
+    pub fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+/// Signature of handlers of extension messages
+///
+/// Receives the uid and the items after it, and returns the message to send
+/// back, if any.
+pub type VerbFn =
+    dyn Fn(u64, Vec<Value>) -> std::result::Result<Option<Message>, ERPCError> + Send + Sync;
+
+/// Message types of the EPC protocol itself, which cannot be taken over
+const PROTOCOL_VERBS: [&str; 5] = ["call", "return", "return-error", "epc-error", "methods"];
+
+/// Answer to a `methods` query: a list of `(NAME ARG-SPEC DOCSTRING)`
+pub(crate) fn method_list(methods: Vec<MethodInfo>) -> Value {
+    Value::list(
+        methods
+            .into_iter()
+            .map(|info| {
+                Value::list(vec![
+                    Value::string(info.name),
+                    info.arg_spec.map(Value::string).unwrap_or(Value::Null),
+                    info.docstring.map(Value::string).unwrap_or(Value::Null),
+                ])
+            })
+            .collect::<Vec<Value>>(),
+    )
+}
+
+/// Failures and latency injected into calls of one method
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+struct InjectedFaults {
+    failures: VecDeque<ERPCError>,
+    latency: Duration,
+}
+
 /// Thread-safe method registry
 #[derive(Default)]
 pub struct MethodRegistry {
     methods: RwLock<HashMap<String, Arc<dyn MethodHandler>>>,
+    arena_methods: RwLock<HashMap<String, Arc<ArenaMethod>>>,
+    raw_methods: RwLock<HashMap<String, Arc<RawMethod>>>,
+    verbs: RwLock<HashMap<String, Arc<VerbFn>>>,
+    args_style: ArgsStyle,
+    /// Handed to handlers through [`State`](crate::extract::State)
+    state: std::sync::RwLock<AppState>,
+    /// Receives calls no method matches
+    #[cfg(feature = "tower")]
+    fallback: RwLock<Option<Arc<Dispatcher>>>,
+    /// Set by tests through [`fail_next_call`](Self::fail_next_call) and
+    /// [`set_latency`](Self::set_latency); compiled out of production
+    /// builds so dispatch takes no lock for it
+    #[cfg(any(test, feature = "test-util"))]
+    faults: std::sync::Mutex<HashMap<String, InjectedFaults>>,
 }
 
 impl MethodRegistry {
     pub fn new() -> Self {
+        MethodRegistry::with_args_style(ArgsStyle::default())
+    }
+
+    /// Registry whose typed methods receive their arguments in `style`
+    pub fn with_args_style(args_style: ArgsStyle) -> Self {
         MethodRegistry {
             methods: RwLock::new(HashMap::new()),
+            arena_methods: RwLock::new(HashMap::new()),
+            raw_methods: RwLock::new(HashMap::new()),
+            verbs: RwLock::new(HashMap::new()),
+            args_style,
+            state: std::sync::RwLock::new(AppState::new()),
+            #[cfg(feature = "tower")]
+            fallback: RwLock::new(None),
+            #[cfg(any(test, feature = "test-util"))]
+            faults: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
+    /// How typed methods registered here receive their arguments
+    pub fn args_style(&self) -> ArgsStyle {
+        self.args_style
+    }
+
+    /// Share `state` with every handler, replacing earlier state of its type
+    pub fn insert_state<S: Send + Sync + 'static>(&self, state: S) {
+        self.state
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(state);
+    }
+
+    /// State shared with handlers
+    pub fn state(&self) -> AppState {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub(crate) fn set_state(&self, state: AppState) {
+        *self.state.write().unwrap_or_else(|e| e.into_inner()) = state;
+    }
+
+    /// Make the next call of `method` fail with `error` without running it
+    ///
+    /// For testing how clients cope with failures. Errors queue up: calling
+    /// this twice fails the next two calls.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn fail_next_call(&self, method: impl Into<String>, error: ERPCError) {
+        self.faults
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(method.into())
+            .or_default()
+            .failures
+            .push_back(error);
+    }
+
+    /// Delay every call of `method` by `latency` before it runs, or fails
+    /// through [`fail_next_call`](Self::fail_next_call)
+    ///
+    /// For testing timeouts; `Duration::ZERO` removes the delay.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_latency(&self, method: impl Into<String>, latency: Duration) {
+        self.faults
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(method.into())
+            .or_default()
+            .latency = latency;
+    }
+
+    /// Drop all injected failures and latencies
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn clear_faults(&self) {
+        self.faults
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    /// Apply the latency and next failure injected into `method`, if any
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) async fn inject_faults(&self, method: &str) -> std::result::Result<(), ERPCError> {
+        let (latency, failure) = {
+            let mut faults = self.faults.lock().unwrap_or_else(|e| e.into_inner());
+            match faults.get_mut(method) {
+                Some(injected) => (injected.latency, injected.failures.pop_front()),
+                None => return Ok(()),
+            }
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+        failure.map_or(Ok(()), Err)
+    }
+
     /// Register a method with closure
     pub async fn register_closure<F, Args, Ret>(
         &self,
@@ -146,16 +493,144 @@ impl MethodRegistry {
         Ret: Serialize + Send,
     {
         let name = name.into();
-        let handler = Arc::new(ClosureHandler::new(
+        let handler = Arc::new(ClosureHandler::typed_with_style(
+            func,
+            self.args_style,
+            name.clone(),
+            arg_spec,
+            docstring,
+        ));
+
+        self.methods.write().await.insert(name, handler);
+        Ok(())
+    }
+
+    /// Register a typed method failing with its own error type
+    ///
+    /// Like [`register_closure`](Self::register_closure), but `func` may
+    /// return any error implementing [`IntoEpcError`], which decides the
+    /// error class the peer sees.
+    pub async fn register_fallible<F, Args, Ret, E>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(Args) -> std::result::Result<Ret, E> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+        E: IntoEpcError,
+    {
+        let name = name.into();
+        let handler = Arc::new(ClosureHandler::typed_with_style(
+            func,
+            self.args_style,
+            name.clone(),
+            arg_spec,
+            docstring,
+        ));
+
+        self.methods.write().await.insert(name, handler);
+        Ok(())
+    }
+
+    /// Register a function taking one [parameter](crate::params) per
+    /// argument, with `&optional` and `&rest` tails
+    ///
+    /// The arg spec is generated from the signature and `names`. Fails if
+    /// a required parameter follows an optional one or anything follows
+    /// `Rest`.
+    pub async fn register_params<F, M: 'static>(
+        &self,
+        name: impl Into<String>,
+        names: &[&str],
+        func: F,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: ParamsFn<M>,
+    {
+        let name = name.into();
+        let handler = Arc::new(ParamsHandler::new(func, name.clone(), names, docstring)?);
+
+        self.methods.write().await.insert(name, handler);
+        Ok(())
+    }
+
+    /// Register an async function of [extractors](crate::extract)
+    pub async fn register_fn<H, M: 'static>(
+        &self,
+        name: impl Into<String>,
+        handler: H,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        H: Handler<M>,
+    {
+        let name = name.into();
+        let handler = Arc::new(ExtractHandler::new(
+            handler,
+            self.args_style,
+            name.clone(),
+            arg_spec,
+            docstring,
+        ));
+
+        self.methods.write().await.insert(name, handler);
+        Ok(())
+    }
+
+    /// Register a CPU-bound method that runs on rayon's global pool
+    ///
+    /// Argument decoding, the handler and result encoding all run off the
+    /// tokio worker threads; the calling task just awaits completion.
+    #[cfg(feature = "cpu-pool")]
+    pub async fn register_cpu_method<F, Args, Ret>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: Fn(Args) -> std::result::Result<Ret, ERPCError> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+    {
+        self.register_cpu_method_on(None, name, func, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a CPU-bound method that runs on the given rayon pool
+    #[cfg(feature = "cpu-pool")]
+    pub async fn register_cpu_method_on<F, Args, Ret>(
+        &self,
+        pool: Option<Arc<rayon::ThreadPool>>,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: Fn(Args) -> std::result::Result<Ret, ERPCError> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+    {
+        let name = name.into();
+        let style = self.args_style;
+        let handler = Arc::new(CpuHandler::new(
             move |args_val: Value| {
-                let args: Args = serde_lexpr::from_value(&args_val)
-                    .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+                let args: Args = style.decode(&args_val)?;
 
                 let result = func(args)?;
 
                 serde_lexpr::to_value(&result)
                     .map_err(|e| ERPCError::SerializationError(e.to_string()))
             },
+            pool,
             name.clone(),
             arg_spec,
             docstring,
@@ -165,6 +640,135 @@ impl MethodRegistry {
         Ok(())
     }
 
+    /// Serve repeated calls of a registered method from `cache`
+    ///
+    /// Only successful results are cached. The method must be pure for the
+    /// cache's TTL; several methods may share one cache.
+    #[cfg(feature = "cache")]
+    pub async fn cache_method(
+        &self,
+        name: &str,
+        cache: Arc<ResultCache>,
+    ) -> std::result::Result<(), crate::error::ERPCError> {
+        let mut methods = self.methods.write().await;
+        let handler = methods
+            .get(name)
+            .ok_or_else(|| ERPCError::MethodNotFound(name.to_string()))?
+            .clone();
+
+        methods.insert(
+            name.to_string(),
+            Arc::new(CachedHandler::new(handler, cache)),
+        );
+        Ok(())
+    }
+
+    /// Run a registered method under the limits of `guard`
+    ///
+    /// Calls breaking a limit fail with `ERPCError::GuardViolation`.
+    pub async fn guard_method(
+        &self,
+        name: &str,
+        guard: MethodGuard,
+    ) -> std::result::Result<(), crate::error::ERPCError> {
+        let mut methods = self.methods.write().await;
+        let handler = methods
+            .get(name)
+            .ok_or_else(|| ERPCError::MethodNotFound(name.to_string()))?
+            .clone();
+
+        methods.insert(
+            name.to_string(),
+            Arc::new(GuardedHandler::new(handler, guard)),
+        );
+        Ok(())
+    }
+
+    /// Register a method receiving a large string uploaded in chunks
+    ///
+    /// See [`crate::chunked`] for the wire convention; `func` runs once the
+    /// last chunk of a transfer has arrived.
+    #[cfg(feature = "chunked")]
+    pub async fn register_chunked_sink<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: Fn(String) -> std::result::Result<Value, ERPCError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let handler = Arc::new(ChunkSink::new(func, name.clone(), arg_spec, docstring));
+
+        self.methods.write().await.insert(name, handler);
+        Ok(())
+    }
+
+    /// [`register_chunked_sink`](MethodRegistry::register_chunked_sink)
+    /// accepting uploads within `limits`
+    #[cfg(feature = "chunked")]
+    pub async fn register_chunked_sink_with_limits<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        limits: ChunkLimits,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: Fn(String) -> std::result::Result<Value, ERPCError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let handler =
+            Arc::new(ChunkSink::new(func, name.clone(), arg_spec, docstring).limits(limits));
+
+        self.methods.write().await.insert(name, handler);
+        Ok(())
+    }
+
+    /// Register a method whose large string result is downloaded in chunks
+    #[cfg(feature = "chunked")]
+    pub async fn register_chunked_source<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: Fn(Value) -> std::result::Result<String, ERPCError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let handler = Arc::new(ChunkSource::new(func, name.clone(), arg_spec, docstring));
+
+        self.methods.write().await.insert(name, handler);
+        Ok(())
+    }
+
+    /// [`register_chunked_source`](MethodRegistry::register_chunked_source)
+    /// holding downloads within `limits`
+    #[cfg(feature = "chunked")]
+    pub async fn register_chunked_source_with_limits<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        limits: ChunkLimits,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: Fn(Value) -> std::result::Result<String, ERPCError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let handler =
+            Arc::new(ChunkSource::new(func, name.clone(), arg_spec, docstring).limits(limits));
+
+        self.methods.write().await.insert(name, handler);
+        Ok(())
+    }
+
     /// Register a method with handler
     pub async fn register_handler(&self, name: impl Into<String>, handler: Arc<dyn MethodHandler>) {
         let name = name.into();
@@ -177,18 +781,52 @@ impl MethodRegistry {
         name: &str,
         args: Value,
     ) -> std::result::Result<Value, crate::error::ERPCError> {
-        let methods = self.methods.read().await;
-        let handler = methods
-            .get(name)
-            .ok_or_else(|| ERPCError::MethodNotFound(name.to_string()))?
-            .clone();
+        #[cfg(any(test, feature = "test-util"))]
+        self.inject_faults(name).await?;
+        let handler = self.methods.read().await.get(name).cloned();
+        #[cfg(feature = "tower")]
+        if handler.is_none() {
+            let fallback = self.fallback.read().await.clone();
+            if let Some(dispatch) = fallback {
+                return dispatch(Call::new(name, args)).await;
+            }
+        }
+        let handler = handler.ok_or_else(|| ERPCError::MethodNotFound(name.to_string()))?;
+        self.call_handler(handler.as_ref(), args).await
+    }
 
-        handler.call(args).await
+    /// Call `handler`, registered here or not, with the registry's state
+    pub(crate) async fn call_handler(
+        &self,
+        handler: &dyn MethodHandler,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let state = self.state();
+        if state.is_empty() {
+            return handler.call(args).await;
+        }
+        state.scope(handler.call(args)).await
+    }
+
+    /// Dispatch every call that no registered method matches to `service`
+    ///
+    /// The service waits for readiness before each call. Methods it serves
+    /// are not listed in answers to `methods` queries.
+    #[cfg(feature = "tower")]
+    pub async fn mount_service<S>(&self, service: S)
+    where
+        S: tower_service::Service<Call, Response = Value> + Clone + Send + Sync + 'static,
+        S::Error: Into<BoxError>,
+        S::Future: Send + 'static,
+    {
+        *self.fallback.write().await = Some(dispatcher(service));
     }
 
     /// Check if a method exists
     pub async fn has_method(&self, name: &str) -> bool {
         self.methods.read().await.contains_key(name)
+            || self.arena_methods.read().await.contains_key(name)
+            || self.raw_methods.read().await.contains_key(name)
     }
 
     /// Get method information for introspection
@@ -196,7 +834,147 @@ impl MethodRegistry {
         &self,
     ) -> std::result::Result<Vec<MethodInfo>, crate::error::ERPCError> {
         let methods = self.methods.read().await;
-        Ok(methods.values().map(|handler| handler.info()).collect())
+        let arena_methods = self.arena_methods.read().await;
+        let raw_methods = self.raw_methods.read().await;
+        Ok(methods
+            .values()
+            .map(|handler| handler.info())
+            .chain(arena_methods.values().map(|method| method.info()))
+            .chain(raw_methods.values().map(|method| method.info()))
+            .collect())
+    }
+
+    /// Register a method whose arguments are parsed into a per-request arena
+    ///
+    /// The handler borrows its arguments straight from the request frame; the
+    /// whole parse tree is freed at once after the response is produced.
+    pub async fn register_arena_method<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: for<'v> Fn(ArenaValue<'v>) -> std::result::Result<Value, ERPCError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let name = name.into();
+        let method = Arc::new(ArenaMethod {
+            func: Box::new(func),
+            info: MethodInfo::new(name.clone(), arg_spec, docstring),
+        });
+
+        self.arena_methods.write().await.insert(name, method);
+        Ok(())
+    }
+
+    /// Look up an arena method by name
+    pub async fn arena_method(&self, name: &str) -> Option<Arc<ArenaMethod>> {
+        self.arena_methods.read().await.get(name).cloned()
+    }
+
+    /// Whether any arena methods are registered
+    pub async fn has_arena_methods(&self) -> bool {
+        !self.arena_methods.read().await.is_empty()
+    }
+
+    /// Register a method working on the unparsed argument text
+    ///
+    /// The handler receives the arguments of a call exactly as they appear in
+    /// the frame and returns the result as S-expression text. Nothing is
+    /// converted to or from `lexpr::Value`, so this suits hot paths and
+    /// syntax `lexpr` does not handle.
+    ///
+    /// The returned text must be exactly one well-formed expression, as it
+    /// is spliced into the `return` frame as is: anything else leaves the
+    /// peer unable to read the reply. Debug builds check this and answer
+    /// with an error instead; release builds send the text unchecked.
+    pub async fn register_raw_method<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: Fn(&str) -> std::result::Result<String, ERPCError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let method = Arc::new(RawMethod {
+            func: Box::new(func),
+            info: MethodInfo::new(name.clone(), arg_spec, docstring),
+        });
+
+        self.raw_methods.write().await.insert(name, method);
+        Ok(())
+    }
+
+    /// Look up a raw method by name
+    pub async fn raw_method(&self, name: &str) -> Option<Arc<RawMethod>> {
+        self.raw_methods.read().await.get(name).cloned()
+    }
+
+    /// Whether any raw methods are registered
+    pub async fn has_raw_methods(&self) -> bool {
+        !self.raw_methods.read().await.is_empty()
+    }
+
+    /// Handle messages whose head symbol is `verb`, e.g. `(my-ext uid ...)`
+    ///
+    /// Peers reject messages of types nobody registered, as before; this
+    /// lets protocol extensions and vendor messages through. The message
+    /// types of EPC itself cannot be registered.
+    pub async fn register_verb<F>(
+        &self,
+        verb: impl Into<String>,
+        func: F,
+    ) -> std::result::Result<(), crate::error::ERPCError>
+    where
+        F: Fn(u64, Vec<Value>) -> std::result::Result<Option<Message>, ERPCError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let verb = verb.into();
+        if PROTOCOL_VERBS.contains(&verb.as_str()) {
+            return Err(ERPCError::InvalidArgument(format!(
+                "{} is a message type of the protocol",
+                verb
+            )));
+        }
+        self.verbs.write().await.insert(verb, Arc::new(func));
+        Ok(())
+    }
+
+    /// Stop handling messages whose head symbol is `verb`
+    pub async fn unregister_verb(&self, verb: &str) -> bool {
+        self.verbs.write().await.remove(verb).is_some()
+    }
+
+    /// Hand an extension message to the handler of its verb
+    ///
+    /// Fails for verbs nobody registered, and for other messages.
+    pub async fn handle_extension(
+        &self,
+        message: Message,
+    ) -> std::result::Result<Option<Message>, ERPCError> {
+        let Message::Extension { verb, uid, items } = message else {
+            return Err(ERPCError::InvalidArgument(format!(
+                "not an extension message: {:?}",
+                message
+            )));
+        };
+        let handler = self.verbs.read().await.get(&verb).cloned();
+        match handler {
+            Some(handler) => handler(uid, items),
+            None => Err(ERPCError::InvalidMessageFormat(format!(
+                "Unknown message type: {}",
+                verb
+            ))),
+        }
     }
 
     /// Register a method that accepts Value directly (for maximum flexibility)
@@ -224,18 +1002,27 @@ impl MethodRegistry {
 
     /// Remove a method
     pub async fn unregister(&self, name: &str) -> std::result::Result<(), crate::error::ERPCError> {
-        self.methods
-            .write()
-            .await
-            .remove(name)
-            .ok_or_else(|| ERPCError::MethodNotFound(name.to_string()))?;
-        Ok(())
+        if self.methods.write().await.remove(name).is_some()
+            || self.arena_methods.write().await.remove(name).is_some()
+            || self.raw_methods.write().await.remove(name).is_some()
+        {
+            Ok(())
+        } else {
+            Err(ERPCError::MethodNotFound(name.to_string()))
+        }
     }
 
     /// Get list of method names
     pub async fn method_names(&self) -> Vec<String> {
         let methods = self.methods.read().await;
-        methods.keys().cloned().collect()
+        let arena_methods = self.arena_methods.read().await;
+        let raw_methods = self.raw_methods.read().await;
+        methods
+            .keys()
+            .chain(arena_methods.keys())
+            .chain(raw_methods.keys())
+            .cloned()
+            .collect()
     }
 }
 
@@ -290,6 +1077,110 @@ mod tests {
         assert_eq!(result, Value::from(8));
     }
 
+    #[derive(Debug)]
+    enum LookupError {
+        Missing(i64),
+    }
+
+    impl fmt::Display for LookupError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                LookupError::Missing(key) => write!(f, "no entry {}", key),
+            }
+        }
+    }
+
+    crate::epc_error_classes!(LookupError {
+        LookupError::Missing(_) => "Missing",
+    });
+
+    #[tokio::test]
+    async fn test_fallible_method_error_class() {
+        let registry = MethodRegistry::new();
+        registry
+            .register_fallible(
+                "lookup",
+                |key: i64| match key {
+                    1 => Ok("one".to_string()),
+                    _ => Err(LookupError::Missing(key)),
+                },
+                Some("key"),
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            registry
+                .call_method("lookup", Value::from(1))
+                .await
+                .unwrap(),
+            Value::from("one")
+        );
+        let error = registry
+            .call_method("lookup", Value::from(2))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "application error: Missing: no entry 2");
+    }
+
+    #[tokio::test]
+    async fn test_spread_args_style() {
+        let registry = MethodRegistry::with_args_style(ArgsStyle::Spread);
+        registry
+            .register_closure(
+                "upcase",
+                |s: String| Ok(s.to_uppercase()),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        registry
+            .register_closure(
+                "add",
+                |(a, b): (i64, i64)| Ok(a + b),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+
+        // As epc.el sends them, and as a Rust client sends them
+        let result = registry
+            .call_method("upcase", Value::list(vec![Value::from("epc")]))
+            .await
+            .unwrap();
+        assert_eq!(result, Value::from("EPC"));
+        let result = registry
+            .call_method("upcase", Value::from("rust"))
+            .await
+            .unwrap();
+        assert_eq!(result, Value::from("RUST"));
+
+        let result = registry
+            .call_method("add", Value::list(vec![Value::from(2), Value::from(3)]))
+            .await
+            .unwrap();
+        assert_eq!(result, Value::from(5));
+
+        // The default leaves a one-element list alone
+        let single = MethodRegistry::new();
+        single
+            .register_closure(
+                "upcase",
+                |s: String| Ok(s.to_uppercase()),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let result = single
+            .call_method("upcase", Value::list(vec![Value::from("epc")]))
+            .await;
+        assert!(matches!(result, Err(ERPCError::SerializationError(_))));
+    }
+
     #[tokio::test]
     async fn test_method_not_found() {
         let registry = MethodRegistry::new();
@@ -297,4 +1188,171 @@ mod tests {
         let result = registry.call_method("nonexistent", Value::Null).await;
         assert!(matches!(result, Err(ERPCError::MethodNotFound(_))));
     }
+
+    #[tokio::test]
+    async fn test_arena_method_registration() {
+        let registry = MethodRegistry::new();
+
+        registry
+            .register_arena_method(
+                "count",
+                |args: ArenaValue<'_>| Ok(Value::from(args.len() as u64)),
+                Some("&rest items"),
+                Some("Count arguments"),
+            )
+            .await
+            .unwrap();
+
+        assert!(registry.has_method("count").await);
+        assert!(registry.has_arena_methods().await);
+
+        let arena = crate::arena::ValueArena::parse("(a b c)").unwrap();
+        let method = registry.arena_method("count").await.unwrap();
+        assert_eq!(method.call(arena.root()).unwrap(), Value::from(3u64));
+
+        registry.unregister("count").await.unwrap();
+        assert!(!registry.has_arena_methods().await);
+    }
+
+    #[cfg(feature = "cpu-pool")]
+    #[tokio::test]
+    async fn test_cpu_method_runs_on_rayon_pool() {
+        let registry = MethodRegistry::new();
+        let pool = Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(1)
+                .thread_name(|_| "cpu-worker".to_string())
+                .build()
+                .unwrap(),
+        );
+
+        registry
+            .register_cpu_method_on(
+                Some(pool),
+                "sum",
+                |numbers: Vec<i64>| {
+                    assert_eq!(std::thread::current().name(), Some("cpu-worker"));
+                    Ok(numbers.iter().sum::<i64>())
+                },
+                Some("&rest numbers"),
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        registry
+            .register_cpu_method(
+                "boom",
+                |_: Option<i64>| -> std::result::Result<i64, ERPCError> { panic!("boom") },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+
+        let args = Value::list(vec![Value::from(1), Value::from(2), Value::from(3)]);
+        let result = registry.call_method("sum", args).await.unwrap();
+        assert_eq!(result, Value::from(6));
+
+        let result = registry.call_method("boom", Value::Null).await;
+        assert!(matches!(result, Err(ERPCError::ApplicationError { .. })));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "cache")]
+    async fn test_cached_method() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let registry = MethodRegistry::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+
+        registry
+            .register_closure(
+                "lookup",
+                move |name: String| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(name.len() as i64)
+                },
+                Some("name"),
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        registry
+            .cache_method("lookup", Arc::new(ResultCache::default()))
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            let result = registry
+                .call_method("lookup", Value::from("car"))
+                .await
+                .unwrap();
+            assert_eq!(result, Value::from(3));
+        }
+        registry
+            .call_method("lookup", Value::from("cdr-safe"))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let result = registry
+            .cache_method("missing", Arc::new(ResultCache::default()))
+            .await;
+        assert!(matches!(result, Err(ERPCError::MethodNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_extension_verbs() {
+        let registry = MethodRegistry::new();
+        assert!(registry
+            .register_verb("call", |_, _| Ok(None))
+            .await
+            .is_err());
+        registry
+            .register_verb("ping", |uid, items| {
+                Ok(Some(Message::new_extension(uid, "pong", items)))
+            })
+            .await
+            .unwrap();
+
+        let reply = registry
+            .handle_extension(Message::new_extension(4, "ping", vec![Value::symbol("hi")]))
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            Some(Message::new_extension(4, "pong", vec![Value::symbol("hi")]))
+        );
+        assert!(registry
+            .handle_extension(Message::new_extension(5, "vendor-thing", vec![]))
+            .await
+            .is_err());
+        assert!(registry.unregister_verb("ping").await);
+    }
+
+    #[tokio::test]
+    async fn test_injected_faults() {
+        let registry = MethodRegistry::new();
+        registry
+            .register_value_method("echo", Ok, None::<String>, None::<String>)
+            .await
+            .unwrap();
+
+        registry.fail_next_call("echo", ERPCError::Timeout);
+        registry.fail_next_call("echo", ERPCError::ConnectionClosed);
+        let echo = || registry.call_method("echo", Value::symbol("hi"));
+        assert!(matches!(echo().await, Err(ERPCError::Timeout)));
+        assert!(matches!(echo().await, Err(ERPCError::ConnectionClosed)));
+        assert_eq!(echo().await.unwrap(), Value::symbol("hi"));
+
+        registry.set_latency("echo", Duration::from_millis(20));
+        let started = std::time::Instant::now();
+        echo().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        registry.fail_next_call("echo", ERPCError::Timeout);
+        registry.clear_faults();
+        assert!(echo().await.is_ok());
+    }
 }
@@ -0,0 +1,316 @@
+//! What handlers may return
+//!
+//! Handlers registered with
+//! [`register_fn`](crate::registry::MethodRegistry::register_fn) or
+//! [`register_params`](crate::registry::MethodRegistry::register_params)
+//! return anything implementing [`IntoEpcReply`]:
+//!
+//! - any `T: Serialize`, `()` included, encoded through serde,
+//! - a `lexpr::Value` (or `EpcValue` with the `json` feature), sent as is,
+//! - a [`Reply`], a value with hints for the layers that dispatch it,
+//! - [`Pending`], a reply given later through a [`ReplyToken`],
+//! - a `Result` of one of these and an error implementing
+//!   [`IntoEpcError`].
+//!
+//! ```ignore
+//! async fn weather(Args(city): Args<String>) -> elrpc::Result<Reply> {
+//!     let report = fetch(&city).await?;
+//!     Ok(Reply::new(report).cache_for(Duration::from_secs(600)))
+//! }
+//! ```
+//!
+//! A handler answering from elsewhere hands a clone of a token to whoever
+//! will complete the call and returns [`Pending`]; the call stays open until
+//! [`ReplyToken::ok`] or [`ReplyToken::err`] is called, from any task:
+//!
+//! ```ignore
+//! async fn convert(State(jobs): State<JobQueue>, Args(file): Args<String>) -> Pending {
+//!     let token = ReplyToken::new();
+//!     jobs.push(file, token.clone());
+//!     Pending(token)
+//! }
+//!
+//! // In the worker
+//! token.ok(output_path);
+//! ```
+//!
+//! A `Result` whose error type also implements `Serialize` matches two
+//! conversions; such handlers have to name the marker or wrap the value.
+
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lexpr::Value;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::error::{ERPCError, IntoEpcError};
+
+/// How the result cache (the `cache` feature) treats a reply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheHint {
+    /// Cache for the configured time
+    #[default]
+    Inherit,
+    /// Do not cache
+    NoStore,
+    /// Cache for this long instead of the configured time
+    Ttl(Duration),
+}
+
+/// A result value with hints for the dispatch layers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub value: Value,
+    pub cache: CacheHint,
+}
+
+impl Reply {
+    pub fn new(value: impl Into<Value>) -> Self {
+        Reply {
+            value: value.into(),
+            cache: CacheHint::Inherit,
+        }
+    }
+
+    /// Keep this reply out of result caches
+    pub fn no_cache(mut self) -> Self {
+        self.cache = CacheHint::NoStore;
+        self
+    }
+
+    /// Cache this reply for `ttl`
+    pub fn cache_for(mut self, ttl: Duration) -> Self {
+        self.cache = CacheHint::Ttl(ttl);
+        self
+    }
+}
+
+/// Conversion of a handler's return value into a [`Reply`]
+///
+/// `M` tells the conversions apart, so a type may be both `Serialize` and
+/// convertible some other way; it is inferred.
+pub trait IntoEpcReply<M> {
+    fn into_reply(self) -> std::result::Result<Reply, ERPCError>;
+
+    /// Convert into a reply that may still be pending
+    fn into_deferred(self) -> Deferred
+    where
+        Self: Sized,
+    {
+        Deferred::Ready(self.into_reply())
+    }
+}
+
+/// A handler's reply, possibly still to come
+pub enum Deferred {
+    Ready(std::result::Result<Reply, ERPCError>),
+    Pending(ReplyToken),
+}
+
+impl Deferred {
+    /// Wait for the reply
+    pub async fn resolve(self) -> std::result::Result<Reply, ERPCError> {
+        match self {
+            Deferred::Ready(reply) => reply,
+            Deferred::Pending(token) => token.wait().await,
+        }
+    }
+}
+
+type TokenReply = std::result::Result<Reply, ERPCError>;
+
+/// Completes a deferred call, from any task
+///
+/// Clones complete the same call; the first reply wins. A call whose tokens
+/// are all dropped without a reply fails.
+#[derive(Clone)]
+pub struct ReplyToken {
+    tx: Arc<Mutex<Option<oneshot::Sender<TokenReply>>>>,
+    rx: Arc<Mutex<Option<oneshot::Receiver<TokenReply>>>>,
+}
+
+impl ReplyToken {
+    pub fn new() -> Self {
+        let (tx, rx) = oneshot::channel();
+        ReplyToken {
+            tx: Arc::new(Mutex::new(Some(tx))),
+            rx: Arc::new(Mutex::new(Some(rx))),
+        }
+    }
+
+    /// Complete the call with `value`
+    ///
+    /// Returns whether the reply was delivered: `false` if the call was
+    /// already completed or is no longer waiting.
+    pub fn ok<M>(self, value: impl IntoEpcReply<M>) -> bool {
+        self.send(value.into_reply())
+    }
+
+    /// Fail the call with `error`
+    pub fn err(self, error: impl IntoEpcError) -> bool {
+        self.send(Err(error.into_epc_error()))
+    }
+
+    /// Whether the call still waits for a reply
+    pub fn is_pending(&self) -> bool {
+        self.tx
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|tx| !tx.is_closed())
+    }
+
+    fn send(self, reply: TokenReply) -> bool {
+        let tx = self.tx.lock().unwrap().take();
+        tx.is_some_and(|tx| tx.send(reply).is_ok())
+    }
+
+    async fn wait(self) -> TokenReply {
+        let rx = self.rx.lock().unwrap().take().ok_or_else(|| {
+            ERPCError::InvalidArgument("reply token already returned by a handler".to_string())
+        })?;
+        // Give up this share of the sender, so the call fails once every
+        // other clone is gone
+        drop(self);
+        rx.await.unwrap_or_else(|_| {
+            Err(ERPCError::ApplicationError {
+                class: "ReplyDropped".to_string(),
+                message: "reply token dropped without a reply".to_string(),
+                backtrace: vec![],
+            })
+        })
+    }
+}
+
+impl Default for ReplyToken {
+    fn default() -> Self {
+        ReplyToken::new()
+    }
+}
+
+impl std::fmt::Debug for ReplyToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplyToken")
+            .field("pending", &self.is_pending())
+            .finish()
+    }
+}
+
+/// A reply given later through the token
+#[derive(Debug)]
+pub struct Pending(pub ReplyToken);
+
+/// Marker of values encoded through serde
+#[derive(Debug)]
+pub enum Serialized {}
+
+/// Marker of values sent as they are
+#[derive(Debug)]
+pub enum Raw {}
+
+/// Marker of results, converting the value with `M`
+#[derive(Debug)]
+pub struct Fallible<M>(PhantomData<M>);
+
+impl<T: Serialize> IntoEpcReply<Serialized> for T {
+    fn into_reply(self) -> std::result::Result<Reply, ERPCError> {
+        serde_lexpr::to_value(&self)
+            .map(Reply::new)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+}
+
+impl IntoEpcReply<Raw> for Value {
+    fn into_reply(self) -> std::result::Result<Reply, ERPCError> {
+        Ok(Reply::new(self))
+    }
+}
+
+impl IntoEpcReply<Raw> for Reply {
+    fn into_reply(self) -> std::result::Result<Reply, ERPCError> {
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "json")]
+impl IntoEpcReply<Raw> for crate::json::EpcValue {
+    fn into_reply(self) -> std::result::Result<Reply, ERPCError> {
+        Ok(Reply::new(self.0))
+    }
+}
+
+impl IntoEpcReply<Raw> for Pending {
+    fn into_reply(self) -> std::result::Result<Reply, ERPCError> {
+        Err(ERPCError::InvalidArgument(
+            "a pending reply cannot be converted right away".to_string(),
+        ))
+    }
+
+    fn into_deferred(self) -> Deferred {
+        Deferred::Pending(self.0)
+    }
+}
+
+impl<T, E, M> IntoEpcReply<Fallible<M>> for std::result::Result<T, E>
+where
+    T: IntoEpcReply<M>,
+    E: IntoEpcError,
+{
+    fn into_reply(self) -> std::result::Result<Reply, ERPCError> {
+        self.map_err(IntoEpcError::into_epc_error)?.into_reply()
+    }
+
+    fn into_deferred(self) -> Deferred {
+        match self {
+            Ok(value) => value.into_deferred(),
+            Err(e) => Deferred::Ready(Err(e.into_epc_error())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply<M>(value: impl IntoEpcReply<M>) -> std::result::Result<Reply, ERPCError> {
+        value.into_reply()
+    }
+
+    #[test]
+    fn test_raw_replies() {
+        assert_eq!(
+            reply(Value::symbol("t")).unwrap(),
+            Reply::new(Value::symbol("t"))
+        );
+        let hinted = Reply::new(Value::Nil).no_cache();
+        assert_eq!(reply(hinted.clone()).unwrap(), hinted);
+        assert_eq!(
+            reply(Ok::<_, ERPCError>(Value::Nil)).unwrap().cache,
+            CacheHint::Inherit
+        );
+        assert!(matches!(
+            reply(Err::<Value, _>(ERPCError::Timeout)),
+            Err(ERPCError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deferred_reply() {
+        let token = ReplyToken::new();
+        let worker = token.clone();
+        let deferred = Ok::<_, ERPCError>(Pending(token)).into_deferred();
+        assert!(worker.is_pending());
+        tokio::spawn(async move { assert!(worker.ok(Value::symbol("done"))) });
+        assert_eq!(
+            deferred.resolve().await.unwrap(),
+            Reply::new(Value::symbol("done"))
+        );
+
+        let token = ReplyToken::new();
+        let deferred = Pending(token.clone()).into_deferred();
+        drop(token);
+        assert!(deferred.resolve().await.is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Structured per-request logging with redaction of sensitive arguments
+//!
+//! When enabled through [`ServerConfig::request_log`](crate::server::ServerConfig),
+//! every call is logged under the `elrpc::request` target with its method,
+//! uid, peer, duration and outcome. Arguments are redacted before they are
+//! rendered: values of sensitive fields, in plists (`(:token "x")`) as well as
+//! alists (`((token . "x"))`), are replaced, and selected methods can hide
+//! their arguments entirely.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use lexpr::Value;
+
+use crate::context::Peer;
+use crate::error::ERPCError;
+use crate::log::{info, warn};
+
+/// Replacement for redacted values
+pub const REDACTED: &str = "<redacted>";
+
+/// Per-method redaction rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redaction {
+    /// Do not log the arguments at all
+    AllArgs,
+    /// Redact these fields in addition to the global ones
+    Fields(Vec<String>),
+}
+
+/// Request log configuration
+#[derive(Debug, Clone)]
+pub struct RequestLogConfig {
+    /// Include (redacted) arguments in the log
+    pub log_args: bool,
+    /// Fields redacted in the arguments of every method
+    pub redact_fields: Vec<String>,
+    /// Additional rules for individual methods
+    pub methods: HashMap<String, Redaction>,
+    /// Rendered arguments are cut off after this many characters
+    pub max_args_len: usize,
+}
+
+impl Default for RequestLogConfig {
+    fn default() -> Self {
+        RequestLogConfig {
+            log_args: true,
+            redact_fields: ["password", "secret", "token"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            methods: HashMap::new(),
+            max_args_len: 256,
+        }
+    }
+}
+
+impl RequestLogConfig {
+    /// Redact `field` in the arguments of every method
+    pub fn redact_field(mut self, field: impl Into<String>) -> Self {
+        self.redact_fields.push(field.into());
+        self
+    }
+
+    /// Apply `rule` to the arguments of `method`
+    pub fn redact_method(mut self, method: impl Into<String>, rule: Redaction) -> Self {
+        self.methods.insert(method.into(), rule);
+        self
+    }
+
+    /// Arguments of a call to `method` as they appear in the log
+    pub fn render_args(&self, method: &str, args: &Value) -> String {
+        let redacted = match self.methods.get(method) {
+            Some(Redaction::AllArgs) => return REDACTED.to_string(),
+            Some(Redaction::Fields(extra)) => {
+                let fields: Vec<&str> = self
+                    .redact_fields
+                    .iter()
+                    .chain(extra)
+                    .map(String::as_str)
+                    .collect();
+                redact_fields(args, &fields)
+            }
+            None => {
+                let fields: Vec<&str> = self.redact_fields.iter().map(String::as_str).collect();
+                redact_fields(args, &fields)
+            }
+        };
+
+        let mut rendered = redacted.to_string();
+        if let Some((cut, _)) = rendered.char_indices().nth(self.max_args_len) {
+            rendered.truncate(cut);
+            rendered.push_str("...");
+        }
+        rendered
+    }
+
+    /// Log one finished call
+    #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
+    pub(crate) fn record(
+        &self,
+        peer: Peer,
+        uid: u64,
+        method: &str,
+        args: Option<&str>,
+        duration: Duration,
+        outcome: std::result::Result<(), &ERPCError>,
+    ) {
+        let args = args.unwrap_or_default();
+        let duration_us = duration.as_micros() as u64;
+        match outcome {
+            Ok(()) => info!(
+                target: "elrpc::request",
+                %peer, uid, method, duration_us, outcome = "ok", args, "call"
+            ),
+            Err(e) => warn!(
+                target: "elrpc::request",
+                %peer, uid, method, duration_us, outcome = "error", error = %e, args, "call"
+            ),
+        }
+    }
+}
+
+/// Copy of `value` with the values of `fields` replaced by [`REDACTED`]
+///
+/// A field is a symbol, keyword or string naming the element that follows it
+/// in a list, which covers plists, `(key value)` pairs and `(key . value)`
+/// alist entries.
+pub fn redact_fields(value: &Value, fields: &[&str]) -> Value {
+    let is_field = |value: &Value| {
+        value
+            .as_name()
+            .or_else(|| value.as_str())
+            .is_some_and(|name| fields.contains(&name.trim_start_matches(':')))
+    };
+
+    match value {
+        Value::Cons(cons) => {
+            let (items, tail) = cons.to_vec();
+            let mut redacted = Vec::with_capacity(items.len());
+            let mut hide_next = false;
+            for item in &items {
+                if hide_next {
+                    redacted.push(Value::string(REDACTED));
+                    hide_next = false;
+                } else {
+                    hide_next = is_field(item);
+                    redacted.push(redact_fields(item, fields));
+                }
+            }
+            let tail = if hide_next && !tail.is_null() {
+                Value::string(REDACTED)
+            } else {
+                redact_fields(&tail, fields)
+            };
+            Value::append(redacted, tail)
+        }
+        Value::Vector(items) => Value::vector(items.iter().map(|item| redact_fields(item, fields))),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacts_plist_and_alist_fields() {
+        let args = lexpr::from_str(
+            r#"("open" :path "/etc/hosts" :token "s3cr3t" ((user . "me") (password . "hunter2")))"#,
+        )
+        .unwrap();
+        let rendered = RequestLogConfig::default().render_args("open", &args);
+
+        assert!(rendered.contains("/etc/hosts"));
+        assert!(rendered.contains("\"me\""));
+        assert!(!rendered.contains("s3cr3t"));
+        assert!(!rendered.contains("hunter2"));
+        assert_eq!(rendered.matches(REDACTED).count(), 2);
+    }
+
+    #[test]
+    fn test_method_rules_and_truncation() {
+        let config = RequestLogConfig::default()
+            .redact_method("save-buffer", Redaction::AllArgs)
+            .redact_method("login", Redaction::Fields(vec!["user".to_string()]));
+        let args = lexpr::from_str(r#"(:user "me" :note "hi")"#).unwrap();
+
+        assert_eq!(config.render_args("save-buffer", &args), REDACTED);
+        assert_eq!(
+            config.render_args("login", &args),
+            format!("(:user \"{}\" :note \"hi\")", REDACTED)
+        );
+        assert_eq!(
+            config.render_args("other", &args),
+            "(:user \"me\" :note \"hi\")"
+        );
+
+        let short = RequestLogConfig {
+            max_args_len: 8,
+            ..Default::default()
+        };
+        assert_eq!(short.render_args("other", &args), "(:user \"...");
+    }
+}
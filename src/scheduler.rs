@@ -0,0 +1,420 @@
+//! Scheduled and recurring tasks
+//!
+//! A [`Scheduler`] runs named tasks at a fixed interval and keeps the result
+//! of each task's latest run. Rust code receives every run through
+//! [`Scheduler::subscribe`]; EPC peers poll the latest result or wait for the
+//! next run through built-in methods, all taking the task name:
+//!
+//! - [`SCHEDULE_LIST_METHOD`] `()` lists the tasks as plists,
+//!   `(:name "refresh" :interval 60.0 :paused nil :runs 3)`,
+//! - [`SCHEDULE_RESULT_METHOD`] `(NAME)` returns the latest result, `nil`
+//!   before the first run, and fails if the latest run failed,
+//! - [`SCHEDULE_WAIT_METHOD`] `(NAME)` waits for the next run and returns
+//!   its result,
+//! - [`SCHEDULE_PAUSE_METHOD`] and [`SCHEDULE_RESUME_METHOD`] `(NAME)` stop
+//!   and restart the periodic runs,
+//! - [`SCHEDULE_TRIGGER_METHOD`] `(NAME)` runs the task now, paused or not.
+//!
+//! ```ignore
+//! let scheduler = Scheduler::new();
+//! scheduler.schedule("refresh-index", Duration::from_secs(300), move || {
+//!     let index = index.clone();
+//!     async move { index.refresh().await.map(|count| Value::from(count)) }
+//! })?;
+//! scheduler.register_methods(server.registry()).await?;
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lexpr::Value;
+use tokio::sync::{watch, Notify};
+use tokio::task::AbortHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::error::ERPCError;
+use crate::log::{debug, warn};
+use crate::registry::{MethodHandler, MethodInfo, MethodRegistry};
+
+pub const SCHEDULE_LIST_METHOD: &str = "elrpc-schedule-list";
+pub const SCHEDULE_RESULT_METHOD: &str = "elrpc-schedule-result";
+pub const SCHEDULE_WAIT_METHOD: &str = "elrpc-schedule-wait";
+pub const SCHEDULE_PAUSE_METHOD: &str = "elrpc-schedule-pause";
+pub const SCHEDULE_RESUME_METHOD: &str = "elrpc-schedule-resume";
+pub const SCHEDULE_TRIGGER_METHOD: &str = "elrpc-schedule-trigger";
+
+/// Outcome of one run of a task
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskRun {
+    /// Runs so far, this one included
+    pub count: u64,
+    /// The task's value, or the message of its error
+    pub result: std::result::Result<Value, String>,
+}
+
+impl TaskRun {
+    fn into_result(self, name: &str) -> std::result::Result<Value, ERPCError> {
+        self.result.map_err(|message| ERPCError::ApplicationError {
+            class: "TaskFailed".to_string(),
+            message: format!("{}: {}", name, message),
+            backtrace: vec![],
+        })
+    }
+}
+
+/// A scheduled task as listed
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskInfo {
+    pub name: String,
+    pub interval: Duration,
+    pub paused: bool,
+    pub runs: u64,
+}
+
+impl TaskInfo {
+    fn to_value(&self) -> Value {
+        Value::list(vec![
+            Value::keyword("name"),
+            Value::string(self.name.as_str()),
+            Value::keyword("interval"),
+            Value::from(self.interval.as_secs_f64()),
+            Value::keyword("paused"),
+            Value::from(self.paused),
+            Value::keyword("runs"),
+            Value::from(self.runs),
+        ])
+    }
+}
+
+struct Task {
+    interval: Duration,
+    paused: AtomicBool,
+    trigger: Notify,
+    latest: watch::Sender<Option<TaskRun>>,
+    handle: Mutex<Option<AbortHandle>>,
+}
+
+impl Task {
+    fn runs(&self) -> u64 {
+        self.latest.borrow().as_ref().map_or(0, |run| run.count)
+    }
+}
+
+/// Runs named tasks periodically
+///
+/// Clones share the tasks. Tasks keep running until unscheduled, even when
+/// every clone is dropped.
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    tasks: Arc<Mutex<HashMap<String, Arc<Task>>>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    fn task(&self, name: &str) -> std::result::Result<Arc<Task>, ERPCError> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ERPCError::InvalidArgument(format!("no scheduled task {}", name)))
+    }
+
+    /// Run `func` every `interval`, starting now
+    ///
+    /// Fails if a task of that name is already scheduled.
+    pub fn schedule<F, Fut>(
+        &self,
+        name: impl Into<String>,
+        interval: Duration,
+        func: F,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<Value, ERPCError>> + Send + 'static,
+    {
+        let name = name.into();
+        if interval.is_zero() {
+            return Err(ERPCError::InvalidArgument(
+                "task interval must not be zero".to_string(),
+            ));
+        }
+        let task = Arc::new(Task {
+            interval,
+            paused: AtomicBool::new(false),
+            trigger: Notify::new(),
+            latest: watch::Sender::new(None),
+            handle: Mutex::new(None),
+        });
+        {
+            let mut tasks = self.tasks.lock().unwrap();
+            if tasks.contains_key(&name) {
+                return Err(ERPCError::InvalidArgument(format!(
+                    "task {} is already scheduled",
+                    name
+                )));
+            }
+            tasks.insert(name.clone(), task.clone());
+        }
+
+        let handle = tokio::spawn({
+            let task = task.clone();
+            async move {
+                let mut ticks = tokio::time::interval(task.interval);
+                ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    tokio::select! {
+                        _ = ticks.tick() => {
+                            if task.paused.load(Ordering::Relaxed) {
+                                continue;
+                            }
+                        }
+                        _ = task.trigger.notified() => {}
+                    }
+                    let result = func().await.map_err(|e| {
+                        warn!("Scheduled task {} failed: {}", name, e);
+                        e.to_string()
+                    });
+                    debug!("Scheduled task {} ran", name);
+                    task.latest.send_modify(|latest| {
+                        let count = latest.as_ref().map_or(0, |run| run.count) + 1;
+                        *latest = Some(TaskRun { count, result });
+                    });
+                }
+            }
+        });
+        *task.handle.lock().unwrap() = Some(handle.abort_handle());
+        Ok(())
+    }
+
+    /// Stop and remove task `name`
+    pub fn unschedule(&self, name: &str) -> std::result::Result<(), ERPCError> {
+        let task = self
+            .tasks
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| ERPCError::InvalidArgument(format!("no scheduled task {}", name)))?;
+        if let Some(handle) = task.handle.lock().unwrap().take() {
+            handle.abort();
+        }
+        Ok(())
+    }
+
+    /// Skip the periodic runs of task `name` until resumed
+    pub fn pause(&self, name: &str) -> std::result::Result<(), ERPCError> {
+        self.task(name)?.paused.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn resume(&self, name: &str) -> std::result::Result<(), ERPCError> {
+        self.task(name)?.paused.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Run task `name` now, even if paused
+    pub fn trigger(&self, name: &str) -> std::result::Result<(), ERPCError> {
+        self.task(name)?.trigger.notify_one();
+        Ok(())
+    }
+
+    /// The scheduled tasks, by name
+    pub fn list(&self) -> Vec<TaskInfo> {
+        let mut tasks: Vec<_> = self
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, task)| TaskInfo {
+                name: name.clone(),
+                interval: task.interval,
+                paused: task.paused.load(Ordering::Relaxed),
+                runs: task.runs(),
+            })
+            .collect();
+        tasks.sort_by(|a, b| a.name.cmp(&b.name));
+        tasks
+    }
+
+    /// Latest run of task `name`, `None` before the first one
+    pub fn latest(&self, name: &str) -> std::result::Result<Option<TaskRun>, ERPCError> {
+        Ok(self.task(name)?.latest.borrow().clone())
+    }
+
+    /// Receiver of every run of task `name`
+    pub fn subscribe(
+        &self,
+        name: &str,
+    ) -> std::result::Result<watch::Receiver<Option<TaskRun>>, ERPCError> {
+        Ok(self.task(name)?.latest.subscribe())
+    }
+
+    /// Wait for the next run of task `name`
+    pub async fn next_run(&self, name: &str) -> std::result::Result<TaskRun, ERPCError> {
+        let mut runs = self.subscribe(name)?;
+        loop {
+            runs.changed()
+                .await
+                .map_err(|_| ERPCError::InvalidArgument(format!("task {} was removed", name)))?;
+            if let Some(run) = runs.borrow_and_update().clone() {
+                return Ok(run);
+            }
+        }
+    }
+
+    /// Register the built-in scheduler methods on `registry`
+    pub async fn register_methods(
+        &self,
+        registry: &MethodRegistry,
+    ) -> std::result::Result<(), ERPCError> {
+        for (name, op, arg_spec, docstring) in [
+            (
+                SCHEDULE_LIST_METHOD,
+                ScheduleOp::List,
+                "()",
+                "List the scheduled tasks",
+            ),
+            (
+                SCHEDULE_RESULT_METHOD,
+                ScheduleOp::Result,
+                "name",
+                "Return the latest result of task NAME",
+            ),
+            (
+                SCHEDULE_WAIT_METHOD,
+                ScheduleOp::Wait,
+                "name",
+                "Wait for the next run of task NAME and return its result",
+            ),
+            (
+                SCHEDULE_PAUSE_METHOD,
+                ScheduleOp::Pause,
+                "name",
+                "Pause the periodic runs of task NAME",
+            ),
+            (
+                SCHEDULE_RESUME_METHOD,
+                ScheduleOp::Resume,
+                "name",
+                "Resume the periodic runs of task NAME",
+            ),
+            (
+                SCHEDULE_TRIGGER_METHOD,
+                ScheduleOp::Trigger,
+                "name",
+                "Run task NAME now",
+            ),
+        ] {
+            let method = ScheduleMethod {
+                scheduler: self.clone(),
+                op,
+                info: MethodInfo::new(name, Some(arg_spec), Some(docstring)),
+            };
+            registry.register_handler(name, Arc::new(method)).await;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ScheduleOp {
+    List,
+    Result,
+    Wait,
+    Pause,
+    Resume,
+    Trigger,
+}
+
+/// One of the built-in scheduler methods
+struct ScheduleMethod {
+    scheduler: Scheduler,
+    op: ScheduleOp,
+    info: MethodInfo,
+}
+
+/// The task name of `(NAME)` or a bare `NAME`
+fn task_name(args: &Value) -> std::result::Result<&str, ERPCError> {
+    let name = match args {
+        Value::Cons(cons) if cons.cdr().is_null() => cons.car(),
+        _ => args,
+    };
+    name.as_str()
+        .or_else(|| name.as_symbol())
+        .ok_or_else(|| ERPCError::InvalidArgument("expected a task name".to_string()))
+}
+
+#[async_trait::async_trait]
+impl MethodHandler for ScheduleMethod {
+    async fn call(&self, args: Value) -> std::result::Result<Value, ERPCError> {
+        let scheduler = &self.scheduler;
+        let name = || task_name(&args);
+        match self.op {
+            ScheduleOp::List => Ok(Value::list(
+                scheduler
+                    .list()
+                    .iter()
+                    .map(TaskInfo::to_value)
+                    .collect::<Vec<_>>(),
+            )),
+            ScheduleOp::Result => {
+                let name = name()?;
+                match scheduler.latest(name)? {
+                    Some(run) => run.into_result(name),
+                    None => Ok(Value::Nil),
+                }
+            }
+            ScheduleOp::Wait => {
+                let name = name()?;
+                scheduler.next_run(name).await?.into_result(name)
+            }
+            ScheduleOp::Pause => scheduler.pause(name()?).map(|()| Value::Nil),
+            ScheduleOp::Resume => scheduler.resume(name()?).map(|()| Value::Nil),
+            ScheduleOp::Trigger => scheduler.trigger(name()?).map(|()| Value::Nil),
+        }
+    }
+
+    fn info(&self) -> MethodInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_runs_and_trigger() {
+        let scheduler = Scheduler::new();
+        scheduler
+            .schedule("tick", Duration::from_secs(3600), || async {
+                Ok(Value::symbol("ok"))
+            })
+            .unwrap();
+        assert!(scheduler
+            .schedule("tick", Duration::from_secs(1), || async { Ok(Value::Nil) })
+            .is_err());
+
+        // The first run is immediate
+        let run = scheduler.next_run("tick").await.unwrap();
+        assert_eq!(run.count, 1);
+        assert_eq!(run.result, Ok(Value::symbol("ok")));
+
+        scheduler.pause("tick").unwrap();
+        assert_eq!(scheduler.list()[0].runs, 1);
+        assert!(scheduler.list()[0].paused);
+
+        // Triggered runs happen while paused
+        scheduler.trigger("tick").unwrap();
+        assert_eq!(scheduler.next_run("tick").await.unwrap().count, 2);
+
+        scheduler.unschedule("tick").unwrap();
+        assert!(scheduler.list().is_empty());
+    }
+}
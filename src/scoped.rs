@@ -0,0 +1,159 @@
+//! Methods registered for a single connection
+//!
+//! Every connection of a server has its own [`ConnectionMethods`], layered
+//! over the server's registry: a method registered there is only visible to
+//! that peer, shadows a global method of the same name, and goes away when
+//! the connection closes. Handlers reach the set of the connection they
+//! serve through [`ConnectionMethods::current`], e.g. to grant capabilities
+//! once a peer has logged in:
+//!
+//! ```
+//! use elrpc::scoped::ConnectionMethods;
+//! use lexpr::Value;
+//!
+//! fn login(password: String) -> elrpc::Result<bool> {
+//!     let granted = password == "hunter2";
+//!     if let (true, Some(methods)) = (granted, ConnectionMethods::current()) {
+//!         methods.register_value_method("secrets", |_| Ok(Value::Nil), None::<&str>, None::<&str>);
+//!     }
+//!     Ok(granted)
+//! }
+//! ```
+//!
+//! Like the [`CallContext`](crate::context::CallContext), the set is not
+//! visible to code a handler spawns onto other tasks; clone it first.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ERPCError;
+use crate::registry::{ArgsStyle, ClosureHandler, MethodHandler, MethodInfo, ValueHandler};
+
+tokio::task_local! {
+    static CURRENT: ConnectionMethods;
+}
+
+/// Methods visible to one connection only
+///
+/// Clones share the methods.
+#[derive(Clone, Default)]
+pub struct ConnectionMethods {
+    methods: Arc<RwLock<HashMap<String, Arc<dyn MethodHandler>>>>,
+    args_style: ArgsStyle,
+}
+
+impl ConnectionMethods {
+    /// Empty set whose typed methods receive their arguments in `style`
+    pub fn new(args_style: ArgsStyle) -> Self {
+        ConnectionMethods {
+            methods: Arc::default(),
+            args_style,
+        }
+    }
+
+    /// Methods of the connection the current task is serving, if any
+    pub fn current() -> Option<ConnectionMethods> {
+        CURRENT.try_with(ConnectionMethods::clone).ok()
+    }
+
+    /// Run `f` with this set as the current one
+    pub(crate) async fn scope<F: std::future::Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+
+    pub fn register_handler(&self, name: impl Into<String>, handler: Arc<dyn MethodHandler>) {
+        self.methods.write().unwrap().insert(name.into(), handler);
+    }
+
+    /// Register a typed method for this connection
+    pub fn register_method<F, Args, Ret>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) where
+        F: Fn(Args) -> std::result::Result<Ret, ERPCError> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+    {
+        let name = name.into();
+        let handler = ClosureHandler::typed_with_style(
+            func,
+            self.args_style,
+            name.clone(),
+            arg_spec,
+            docstring,
+        );
+        self.register_handler(name, Arc::new(handler));
+    }
+
+    /// Register a method taking and returning raw values for this connection
+    pub fn register_value_method<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) where
+        F: Fn(Value) -> std::result::Result<Value, ERPCError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let handler = ValueHandler::new(func, name.clone(), arg_spec, docstring);
+        self.register_handler(name, Arc::new(handler));
+    }
+
+    /// Remove a method, returning whether it was registered
+    pub fn unregister(&self, name: &str) -> bool {
+        self.methods.write().unwrap().remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn MethodHandler>> {
+        self.methods.read().unwrap().get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.methods.read().unwrap().contains_key(name)
+    }
+
+    /// Information about every method, for `methods` queries
+    pub fn infos(&self) -> Vec<MethodInfo> {
+        self.methods
+            .read()
+            .unwrap()
+            .values()
+            .map(|handler| handler.info())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_current_inside_scope_only() {
+        assert!(ConnectionMethods::current().is_none());
+
+        let methods = ConnectionMethods::default();
+        methods
+            .clone()
+            .scope(async {
+                ConnectionMethods::current().unwrap().register_value_method(
+                    "granted",
+                    Ok,
+                    None::<&str>,
+                    None::<&str>,
+                );
+            })
+            .await;
+
+        assert!(methods.contains("granted"));
+        assert_eq!(methods.infos()[0].name, "granted");
+        assert!(methods.unregister("granted"));
+        assert!(!methods.contains("granted"));
+    }
+}
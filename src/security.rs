@@ -0,0 +1,167 @@
+//! Hardened server defaults
+//!
+//! A [`SecurityProfile`] bundles the limits that keep one misbehaving or
+//! hostile peer from exhausting a server: frame size, nesting depth, call
+//! rate, idle time and who may connect at all. Setting
+//! [`ServerConfig::security`] applies them together:
+//!
+//! ```
+//! use elrpc::{SecurityProfile, ServerConfig};
+//!
+//! let config = ServerConfig {
+//!     security: Some(SecurityProfile::default()),
+//!     ..Default::default()
+//! };
+//! ```
+//!
+//! The profile only fills gaps: an explicit `quota` still decides the call
+//! rate, and an explicit `peer_filter` is kept, with loopback-only added.
+//!
+//! [`ServerConfig::security`]: crate::server::ServerConfig::security
+
+use std::time::Duration;
+
+use crate::peer_filter::PeerFilter;
+use crate::server::ServerConfig;
+use crate::stats::QuotaConfig;
+
+/// Limits applied to every connection of a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityProfile {
+    /// Largest frame a peer may send; a larger header closes the connection
+    pub max_frame_size: usize,
+    /// Deepest list or vector nesting accepted in a message
+    pub max_nesting_depth: usize,
+    /// Calls per second each connection may make (None is unlimited)
+    pub max_calls_per_second: Option<u64>,
+    /// Close connections that sent nothing and wait for no reply for this long
+    pub idle_timeout: Option<Duration>,
+    /// Reject peers that are not on a loopback address
+    pub loopback_only: bool,
+}
+
+impl Default for SecurityProfile {
+    fn default() -> Self {
+        SecurityProfile {
+            max_frame_size: 1024 * 1024,
+            max_nesting_depth: 128,
+            max_calls_per_second: Some(200),
+            idle_timeout: Some(Duration::from_secs(600)),
+            loopback_only: true,
+        }
+    }
+}
+
+impl SecurityProfile {
+    /// The default limits, for local editor integrations
+    pub fn new() -> Self {
+        SecurityProfile::default()
+    }
+
+    /// Set the largest accepted frame
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Set the deepest accepted nesting
+    pub fn max_nesting_depth(mut self, depth: usize) -> Self {
+        self.max_nesting_depth = depth;
+        self
+    }
+
+    /// Set the per-connection call rate
+    pub fn max_calls_per_second(mut self, calls: Option<u64>) -> Self {
+        self.max_calls_per_second = calls;
+        self
+    }
+
+    /// Set how long a connection may stay idle
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Accept peers on any address, for servers meant to be reached remotely
+    pub fn allow_remote(mut self) -> Self {
+        self.loopback_only = false;
+        self
+    }
+
+    /// Fold the connection-level limits into `config`
+    pub(crate) fn apply(&self, config: &mut ServerConfig) {
+        if self.loopback_only {
+            config
+                .peer_filter
+                .get_or_insert_with(PeerFilter::default)
+                .loopback_only = true;
+        }
+        if let (Some(calls), None) = (self.max_calls_per_second, &config.quota) {
+            config.quota = Some(QuotaConfig {
+                window: Duration::from_secs(1),
+                max_calls: Some(calls),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+/// Whether `text` nests lists or vectors deeper than `max`
+///
+/// Brackets inside strings and escaped characters do not count, so this
+/// agrees with the reader for anything EPC peers send.
+pub(crate) fn exceeds_depth(text: &str, max: usize) -> bool {
+    let mut depth = 0usize;
+    let mut bytes = text.bytes();
+    let mut in_string = false;
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => {
+                bytes.next();
+            }
+            b'"' => in_string = !in_string,
+            _ if in_string => {}
+            b'(' | b'[' => {
+                depth += 1;
+                if depth > max {
+                    return true;
+                }
+            }
+            b')' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nesting_depth() {
+        assert!(!exceeds_depth("(call 1 echo ((1 2) [3]))", 3));
+        assert!(exceeds_depth("(call 1 echo ((1 (2)) [3]))", 3));
+        // Brackets in strings and escaped characters are data
+        assert!(!exceeds_depth(r#"(call 1 echo ("((((" a\(b))"#, 2));
+        assert!(!exceeds_depth(r#"(call 1 echo ("\"(((("))"#, 2));
+    }
+
+    #[test]
+    fn test_apply_fills_gaps() {
+        let mut config = ServerConfig::default();
+        SecurityProfile::new().apply(&mut config);
+        assert!(config.peer_filter.unwrap().loopback_only);
+        let quota = config.quota.unwrap();
+        assert_eq!(quota.window, Duration::from_secs(1));
+        assert_eq!(quota.max_calls, Some(200));
+
+        let mut config = ServerConfig {
+            quota: Some(QuotaConfig::default()),
+            ..Default::default()
+        };
+        SecurityProfile::new().allow_remote().apply(&mut config);
+        assert!(config.peer_filter.is_none());
+        assert_eq!(config.quota.unwrap().max_calls, None);
+    }
+}
@@ -1,25 +1,137 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use bytes::BytesMut;
 use lexpr::Value;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
-use tracing::{debug, error, info, warn};
+use tokio::time::{Duration, Instant};
+#[cfg(feature = "logging")]
+use tracing::level_filters::LevelFilter;
 
-use crate::error::ERPCError;
-use crate::protocol::{Framer, Message};
-use crate::registry::MethodRegistry;
+use crate::abuse::{self, AbuseDetector, Offense};
+use crate::acl::{self, MethodAcl, MethodSet};
+use crate::announce::PortAnnouncer;
+use crate::arena::{ArenaValue, ValueArena};
+#[cfg(feature = "audit")]
+use crate::audit::AuditLog;
+use crate::auth::{Authenticator, Handshake, Identity, AUTH_METHOD};
+use crate::checksum::{self, FRAME_CHECKSUMS_METHOD};
+use crate::clock::{Clock, SystemClock};
+use crate::compat::{Compat, CompatSelector};
+use crate::context::{CallContext, Peer, PeerCredentials};
+use crate::error::{ERPCError, IntoEpcError};
+use crate::extract::{AppState, ExtractHandler, Handler};
+use crate::log::{debug, error, info, info_span, trace, warn, Instrument, Span};
+use crate::metadata::{self, CallMetadata, CALL_METADATA_METHOD};
+use crate::params::ParamsFn;
+use crate::peer_filter::PeerFilter;
+use crate::pool::{BufferPool, ReadSizer};
+use crate::protocol::{Framer, LengthPrefix, Message, Transport};
+use crate::registry::{
+    method_list, ArenaMethod, ArgsStyle, ClosureHandler, MethodHandler, MethodRegistry,
+    ValueHandler,
+};
+use crate::request_log::RequestLogConfig;
+use crate::scoped::ConnectionMethods;
+use crate::security::{self, SecurityProfile};
+use crate::stats::{ConnectionUsage, QuotaConfig, ServerStats, StatsSnapshot};
+use crate::tenant::{Tenants, SELECT_TENANT_METHOD};
+use crate::uid::{UidHalf, PARTITION_UIDS_METHOD};
+use crate::wiretap::{Direction, WireTap};
 
 /// Server configuration
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub bind_addr: String,
+    /// Connections served at once; further peers are disconnected right
+    /// after they connect
     pub max_connections: usize,
+    /// Time a call may take before the peer gets an `epc-error` for it, and
+    /// the time allowed for the authentication handshake
+    ///
+    /// Raw and arena methods run synchronously and are not interrupted.
     pub request_timeout: std::time::Duration,
+    /// Maximum number of idle frame buffers kept for reuse (0 disables pooling)
+    pub buffer_pool_size: usize,
+    /// Initial capacity of each connection's read buffer
+    pub read_buffer_size: usize,
+    /// Upper bound for the adaptively grown read-ahead capacity
+    pub max_read_buffer_size: usize,
+    /// When each connection writes its queued responses to the socket
+    pub flush_policy: FlushPolicy,
+    /// Chooses the flush policy of each connection instead of `flush_policy`
+    pub flush_selector: Option<FlushSelector>,
+    /// Queued responses are written as soon as they exceed this many bytes
+    pub max_write_batch: usize,
+    /// Capacity of each connection's outbound response queue
+    pub outbound_queue_size: usize,
+    /// What a handler does when its connection's response queue is full
+    pub overflow_policy: OverflowPolicy,
+    /// Maximum requests handled concurrently per connection before reading pauses
+    pub max_in_flight_per_connection: usize,
+    /// Maximum requests handled concurrently across all connections (None is unbounded)
+    pub max_worker_tasks: Option<usize>,
+    /// Run request handlers on a dedicated runtime with this many threads
+    /// instead of the caller's runtime
+    pub worker_threads: Option<usize>,
+    /// Per-connection usage limits (None disables quotas)
+    pub quota: Option<QuotaConfig>,
+    /// How [`Server::announce_port`] tells the parent process the port
+    pub port_announcer: PortAnnouncer,
+    /// Structured log of every call (None disables it)
+    pub request_log: Option<RequestLogConfig>,
+    /// Append-only record of every call for auditing (None disables it)
+    #[cfg(feature = "audit")]
+    pub audit_log: Option<AuditLog>,
+    /// Hook receiving every raw frame of every connection
+    pub wire_tap: Option<WireTap>,
+    /// Time the frames seen by `wire_tap` are stamped with
+    pub clock: Arc<dyn Clock>,
+    /// Quirks expected from peers, see the `compat` module
+    pub compat: Compat,
+    /// Chooses the profile of each connection instead of `compat`
+    pub compat_selector: Option<CompatSelector>,
+    /// How typed methods receive the argument list of a call
+    pub args_style: ArgsStyle,
+    /// Which peer addresses may connect (None accepts every peer)
+    pub peer_filter: Option<PeerFilter>,
+    /// Which methods each peer may call (None lets every peer call anything)
+    pub method_acl: Option<MethodAcl>,
+    /// Hardened limits applied on top of the settings above, see the
+    /// `security` module
+    pub security: Option<SecurityProfile>,
+    /// Permissions of the socket file created by [`Server::bind_unix`]
+    pub unix_socket_mode: u32,
+    /// Decides which peers are served, see the `auth` module
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Temporarily bans peers that keep misbehaving, see the `abuse` module
+    pub abuse: Option<AbuseDetector>,
+    /// Registries connections are bound to instead of the server's own, see
+    /// the `tenant` module
+    pub tenants: Option<Arc<Tenants>>,
+    /// Let clients reserve half of the uid space for their calls, see the
+    /// `uid` module
+    pub partition_uids: bool,
+    /// Let clients send metadata with their calls, see the `metadata` module
+    pub call_metadata: bool,
+    /// Let clients switch to checksummed frames, see the `checksum` module
+    pub frame_checksums: bool,
+    /// Skip whitespace peers such as epc.el send between frames
+    pub skip_frame_whitespace: bool,
+    /// Format of the length prefix of frames, six hex digits by default
+    pub length_prefix: LengthPrefix,
 }
 
 impl Default for ServerConfig {
@@ -28,6 +140,111 @@ impl Default for ServerConfig {
             bind_addr: "127.0.0.1:0".to_string(),
             max_connections: 100,
             request_timeout: std::time::Duration::from_secs(30),
+            buffer_pool_size: 64,
+            read_buffer_size: 1024,
+            max_read_buffer_size: 1024 * 1024,
+            flush_policy: FlushPolicy::Batched,
+            flush_selector: None,
+            max_write_batch: 64 * 1024,
+            outbound_queue_size: 128,
+            overflow_policy: OverflowPolicy::Block,
+            max_in_flight_per_connection: 64,
+            max_worker_tasks: None,
+            worker_threads: None,
+            quota: None,
+            port_announcer: PortAnnouncer::Stdout,
+            request_log: None,
+            #[cfg(feature = "audit")]
+            audit_log: None,
+            wire_tap: None,
+            clock: Arc::new(SystemClock),
+            compat: Compat::Standard,
+            compat_selector: None,
+            args_style: ArgsStyle::Single,
+            peer_filter: None,
+            method_acl: None,
+            security: None,
+            unix_socket_mode: 0o600,
+            authenticator: None,
+            abuse: None,
+            tenants: None,
+            partition_uids: false,
+            call_metadata: false,
+            frame_checksums: false,
+            skip_frame_whitespace: true,
+            length_prefix: LengthPrefix::EPC,
+        }
+    }
+}
+
+/// Policy for coalescing response frames into fewer socket writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Write every response as soon as it is produced
+    Immediate,
+    /// Write every response that is already queued in a single batch
+    Batched,
+    /// Hold responses for up to the given delay so later requests can share the write
+    Delay(Duration),
+}
+
+/// Callback choosing the flush policy of a new connection from its peer
+pub type FlushFn = dyn Fn(Peer) -> FlushPolicy + Send + Sync;
+
+/// Per-connection choice of [`FlushPolicy`] on a server
+///
+/// Lets latency-sensitive peers, such as an interactive Emacs, get
+/// `Immediate` writes while batch clients of the same server keep batching.
+#[derive(Clone)]
+pub struct FlushSelector(Arc<FlushFn>);
+
+impl std::fmt::Debug for FlushSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FlushSelector(..)")
+    }
+}
+
+impl FlushSelector {
+    /// Choose policies with `select`
+    pub fn new(select: impl Fn(Peer) -> FlushPolicy + Send + Sync + 'static) -> Self {
+        FlushSelector(Arc::new(select))
+    }
+
+    fn select(&self, peer: Peer) -> FlushPolicy {
+        (self.0)(peer)
+    }
+}
+
+/// Behaviour when a connection's outbound response queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for queue space, backpressuring handlers and then the reader
+    Block,
+    /// Drop the response and close the connection once the queued
+    /// responses are written, so the peer's outstanding calls fail at once
+    /// rather than waiting for replies that will never come
+    Shed,
+}
+
+/// Where request handlers run and how many may run at once
+#[derive(Clone)]
+struct Workers {
+    handle: Handle,
+    limit: Option<Arc<Semaphore>>,
+}
+
+impl Workers {
+    /// Wait for a worker slot if the server-wide limit is reached
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.limit {
+            Some(limit) => Some(
+                limit
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("worker semaphore is never closed"),
+            ),
+            None => None,
         }
     }
 }
@@ -36,7 +253,13 @@ impl Default for ServerConfig {
 pub struct Server {
     config: ServerConfig,
     registry: Arc<MethodRegistry>,
-    listener: Option<TcpListener>,
+    pool: Arc<BufferPool>,
+    stats: Arc<ServerStats>,
+    runtime: Option<Runtime>,
+    listener: Option<Listener>,
+    local_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    unix_path: Option<PathBuf>,
     shutdown_tx: Option<mpsc::Sender<()>>,
     handles: Vec<JoinHandle<std::result::Result<(), ERPCError>>>,
 }
@@ -47,12 +270,32 @@ impl Server {
         Server::with_config(ServerConfig::default())
     }
 
+    /// Start building a server fluently
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
     /// Create a new server with custom configuration
     pub fn with_config(config: ServerConfig) -> Self {
+        let registry = Arc::new(MethodRegistry::with_args_style(config.args_style));
+        Server::with_registry(config, registry)
+    }
+
+    /// Create a server whose methods come from an existing registry
+    pub fn with_registry(config: ServerConfig, registry: Arc<MethodRegistry>) -> Self {
         Server {
+            pool: Arc::new(BufferPool::with_capacity(
+                config.buffer_pool_size,
+                config.read_buffer_size,
+            )),
+            registry,
             config,
-            registry: Arc::new(MethodRegistry::new()),
+            stats: Arc::new(ServerStats::new()),
+            runtime: None,
             listener: None,
+            local_addr: None,
+            #[cfg(unix)]
+            unix_path: None,
             shutdown_tx: None,
             handles: Vec::new(),
         }
@@ -63,6 +306,26 @@ impl Server {
         &self.registry
     }
 
+    /// Share `state` with every handler through the [`State`](crate::extract::State)
+    /// extractor
+    ///
+    /// A server can hold one value of each type; a later value replaces an
+    /// earlier one of the same type.
+    pub fn with_state<S: Send + Sync + 'static>(self, state: S) -> Self {
+        self.registry.insert_state(state);
+        self
+    }
+
+    /// Get the buffer pool shared by this server's connections
+    pub fn buffer_pool(&self) -> &Arc<BufferPool> {
+        &self.pool
+    }
+
+    /// Snapshot of connection counts and per-connection usage
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
     /// Bind to a socket address
     pub async fn bind(
         &mut self,
@@ -76,19 +339,102 @@ impl Server {
 
         let socket_addr = listener.local_addr().map_err(|e| ERPCError::Io(e))?;
 
-        self.listener = Some(listener);
+        self.listener = Some(Listener::Tcp(listener));
+        self.local_addr = Some(socket_addr);
 
         info!("EPC server successfully bound to {}", socket_addr);
         debug!("Server ready to accept connections on {}", socket_addr);
         Ok(socket_addr)
     }
 
+    /// Listen on a socket inherited from the parent process
+    ///
+    /// The parent passes the descriptor number in `ELRPC_LISTEN_FD`, as
+    /// [`PortHandshake::InheritedSocket`](crate::process::PortHandshake) does.
+    #[cfg(unix)]
+    pub async fn bind_inherited(&mut self) -> std::result::Result<SocketAddr, ERPCError> {
+        use std::os::fd::FromRawFd;
+
+        let fd: i32 = std::env::var(crate::announce::LISTEN_FD_ENV)
+            .ok()
+            .and_then(|fd| fd.parse().ok())
+            .ok_or_else(|| {
+                ERPCError::ProtocolError(format!(
+                    "{} does not name a file descriptor",
+                    crate::announce::LISTEN_FD_ENV
+                ))
+            })?;
+
+        // SAFETY: the parent hands this descriptor to us for exclusive use
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let socket_addr = listener.local_addr()?;
+
+        self.listener = Some(Listener::Tcp(listener));
+        self.local_addr = Some(socket_addr);
+
+        info!("EPC server listening on inherited socket {}", socket_addr);
+        Ok(socket_addr)
+    }
+
+    /// Listen on a Unix socket at `path`
+    ///
+    /// The socket file gets the permissions in
+    /// [`ServerConfig::unix_socket_mode`], owner-only by default, and is
+    /// removed on shutdown. It is bound in a private directory and linked
+    /// into place once its mode is set, so no peer can connect while the
+    /// permissions are still looser. Unix peers have no address: handlers
+    /// find their credentials in [`CallContext`], logs show them as
+    /// `unix:uid=...`, and the peer filter and abuse detector skip them.
+    #[cfg(unix)]
+    pub async fn bind_unix(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> std::result::Result<(), ERPCError> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        let path = path.as_ref();
+        debug!("Binding server to Unix socket {}", path.display());
+        let name = path.file_name().ok_or_else(|| {
+            ERPCError::InvalidArgument(format!("{} is not a file path", path.display()))
+        })?;
+        let parent = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let mut staging_name = std::ffi::OsString::from(".");
+        staging_name.push(name);
+        staging_name.push(format!(".{}", std::process::id()));
+        let staging = parent.map_or_else(
+            || PathBuf::from(&staging_name),
+            |dir| dir.join(&staging_name),
+        );
+        std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+        let staged = staging.join("socket");
+        let mode = std::fs::Permissions::from_mode(self.config.unix_socket_mode);
+        let bound = UnixListener::bind(&staged).and_then(|listener| {
+            std::fs::set_permissions(&staged, mode)?;
+            // Unlike rename, linking fails rather than replace an existing file
+            std::fs::hard_link(&staged, path)?;
+            Ok(listener)
+        });
+        let _ = std::fs::remove_file(&staged);
+        let _ = std::fs::remove_dir(&staging);
+        let listener = bound?;
+
+        self.listener = Some(Listener::Unix(listener));
+        self.unix_path = Some(path.to_path_buf());
+        info!("EPC server listening on Unix socket {}", path.display());
+        Ok(())
+    }
+
     /// Get the port the server is bound to
     pub fn port(&self) -> Option<u16> {
-        self.listener
-            .as_ref()
-            .and_then(|l| l.local_addr().ok())
-            .map(|addr| addr.port())
+        self.local_addr.map(|addr| addr.port())
+    }
+
+    /// Get the address the server is bound to
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
     }
 
     /// Start serving in the background
@@ -98,33 +444,99 @@ impl Server {
             .take()
             .ok_or_else(|| ERPCError::ProtocolError("Server not bound".to_string()))?;
 
+        if let (Some(threads), None) = (self.config.worker_threads, &self.runtime) {
+            debug!("Starting dedicated worker runtime with {} threads", threads);
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(threads.max(1))
+                .thread_name("elrpc-worker")
+                .enable_all()
+                .build()
+                .map_err(ERPCError::Io)?;
+            self.runtime = Some(runtime);
+        }
+
         let registry = self.registry.clone();
-        let config = self.config.clone();
+        let pool = self.pool.clone();
+        let stats = self.stats.clone();
+        let mut config = self.config.clone();
+        if let Some(profile) = self.config.security.as_ref() {
+            profile.apply(&mut config);
+        }
+        let workers = Workers {
+            handle: match &self.runtime {
+                Some(runtime) => runtime.handle().clone(),
+                None => Handle::current(),
+            },
+            limit: self
+                .config
+                .max_worker_tasks
+                .map(|n| Arc::new(Semaphore::new(n.max(1)))),
+        };
+
+        let connections = Arc::new(Semaphore::new(self.config.max_connections.max(1)));
 
         let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
         self.shutdown_tx = Some(shutdown_tx);
 
-        info!("Starting server listener on {}", listener.local_addr()?);
+        #[cfg(unix)]
+        let name = self
+            .unix_path
+            .as_ref()
+            .map_or_else(|| listener.describe(), |path| path.display().to_string());
+        #[cfg(not(unix))]
+        let name = listener.describe();
+        info!("Starting server listener on {}", name);
 
         let handle = tokio::spawn(async move {
+            let spawn_connection = |stream: Box<dyn Transport>, peer: Peer| {
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    warn!("Rejected connection from {}: too many connections", peer);
+                    stats.record_rejected();
+                    return;
+                };
+                let registry = registry.clone();
+                let pool = pool.clone();
+                let config = config.clone();
+                let workers = workers.clone();
+                let stats = stats.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        handle_connection(stream, peer, registry, pool, workers, stats, config)
+                            .await
+                    {
+                        error!("Connection error from {}: {}", peer, e);
+                    }
+                    drop(permit);
+                });
+            };
             loop {
                 tokio::select! {
                     accept_result = listener.accept() => {
                         match accept_result {
-                            Ok((stream, addr)) => {
-                                info!("New connection accepted from {}", addr);
-                                debug!("Spawning handler for connection from {}", addr);
-                                let registry = registry.clone();
-                                let config = config.clone();
-
-                                tokio::spawn(async move {
-                                    debug!("Starting connection handler for {}", addr);
-                                    if let Err(e) = handle_connection(stream, addr, registry, config).await {
-                                        error!("Connection error from {}: {}", addr, e);
-                                    } else {
-                                        debug!("Connection handler completed for {}", addr);
+                            Ok(Accepted::Tcp(stream, addr)) => {
+                                if let Some(filter) = &config.peer_filter {
+                                    if !filter.permits(addr.ip()) {
+                                        warn!("Rejected connection from {} by peer filter", addr);
+                                        stats.record_rejected();
+                                        drop(stream);
+                                        continue;
                                     }
-                                });
+                                }
+                                if config.abuse.as_ref().is_some_and(|abuse| abuse.is_banned(addr.ip())) {
+                                    warn!("Rejected connection from banned peer {}", addr);
+                                    stats.record_rejected();
+                                    drop(stream);
+                                    continue;
+                                }
+                                info!("New connection accepted from {}", addr);
+                                spawn_connection(Box::new(stream), Peer::Tcp(addr));
+                            }
+                            #[cfg(unix)]
+                            Ok(Accepted::Unix(stream, credentials)) => {
+                                let peer = Peer::Unix(credentials);
+                                info!("New Unix socket connection accepted from {}", peer);
+                                spawn_connection(Box::new(stream), peer);
                             }
                             Err(e) => {
                                 error!("Failed to accept connection: {}", e);
@@ -146,6 +558,14 @@ impl Server {
         Ok(())
     }
 
+    /// Stop accepting connections without waiting, for use in `Drop`
+    #[cfg(any(test, feature = "test-util", feature = "conformance"))]
+    pub(crate) fn stop_listening(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.try_send(());
+        }
+    }
+
     /// Stop the server gracefully
     pub async fn shutdown(&mut self) -> std::result::Result<(), ERPCError> {
         if let Some(tx) = self.shutdown_tx.take() {
@@ -156,6 +576,16 @@ impl Server {
             let _ = handle.await;
         }
 
+        if let Some(runtime) = self.runtime.take() {
+            debug!("Stopping dedicated worker runtime");
+            runtime.shutdown_background();
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = self.unix_path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+
         info!("Server shutdown complete");
         Ok(())
     }
@@ -178,6 +608,187 @@ impl Server {
             .await
     }
 
+    /// Register a function taking one [parameter](crate::params) per
+    /// argument, with `&optional` and `&rest` tails
+    pub async fn register_params<F, M: 'static>(
+        &self,
+        name: impl Into<String>,
+        names: &[&str],
+        func: F,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: ParamsFn<M>,
+    {
+        self.registry
+            .register_params(name, names, func, docstring)
+            .await
+    }
+
+    /// Register an async function of [extractors](crate::extract)
+    pub async fn register_fn<H, M: 'static>(
+        &self,
+        name: impl Into<String>,
+        handler: H,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        H: Handler<M>,
+    {
+        self.registry
+            .register_fn(name, handler, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a typed method failing with its own error type, converted
+    /// with [`IntoEpcError`]
+    pub async fn register_fallible<F, Args, Ret, E>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(Args) -> std::result::Result<Ret, E> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+        E: IntoEpcError,
+    {
+        self.registry
+            .register_fallible(name, func, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a CPU-bound method (typed arguments) that runs on rayon's global pool
+    #[cfg(feature = "cpu-pool")]
+    pub async fn register_cpu_method<F, Args, Ret>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(Args) -> std::result::Result<Ret, ERPCError> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+    {
+        self.registry
+            .register_cpu_method(name, func, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a method receiving a large string uploaded in chunks
+    #[cfg(feature = "chunked")]
+    pub async fn register_chunked_sink<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(String) -> std::result::Result<Value, ERPCError> + Send + Sync + 'static,
+    {
+        self.registry
+            .register_chunked_sink(name, func, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a method receiving a large string uploaded in chunks, within
+    /// `limits`
+    #[cfg(feature = "chunked")]
+    pub async fn register_chunked_sink_with_limits<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        limits: crate::chunked::ChunkLimits,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(String) -> std::result::Result<Value, ERPCError> + Send + Sync + 'static,
+    {
+        self.registry
+            .register_chunked_sink_with_limits(name, func, limits, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a method whose large string result is downloaded in chunks
+    #[cfg(feature = "chunked")]
+    pub async fn register_chunked_source<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(Value) -> std::result::Result<String, ERPCError> + Send + Sync + 'static,
+    {
+        self.registry
+            .register_chunked_source(name, func, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a method whose large string result is downloaded in chunks,
+    /// holding downloads within `limits`
+    #[cfg(feature = "chunked")]
+    pub async fn register_chunked_source_with_limits<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        limits: crate::chunked::ChunkLimits,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(Value) -> std::result::Result<String, ERPCError> + Send + Sync + 'static,
+    {
+        self.registry
+            .register_chunked_source_with_limits(name, func, limits, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a method whose arguments are parsed into a per-request arena
+    pub async fn register_arena_method<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: for<'v> Fn(ArenaValue<'v>) -> std::result::Result<Value, ERPCError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.registry
+            .register_arena_method(name, func, arg_spec, docstring)
+            .await
+    }
+
+    /// Register a method working on the unparsed argument text
+    ///
+    /// See [`MethodRegistry::register_raw_method`].
+    pub async fn register_raw_method<F>(
+        &self,
+        name: impl Into<String>,
+        func: F,
+        arg_spec: Option<impl Into<String>>,
+        docstring: Option<impl Into<String>>,
+    ) -> std::result::Result<(), ERPCError>
+    where
+        F: Fn(&str) -> std::result::Result<String, ERPCError> + Send + Sync + 'static,
+    {
+        self.registry
+            .register_raw_method(name, func, arg_spec, docstring)
+            .await
+    }
+
     /// Register a method that accepts Value directly (for maximum flexibility)
     pub async fn register_value_method(
         &self,
@@ -191,274 +802,2640 @@ impl Server {
             .await
     }
 
+    /// Change the process-wide log level, returning the previous one
+    ///
+    /// Requires logging to be set up with [`init_logging`](crate::logging::init_logging).
+    #[cfg(feature = "logging")]
+    pub fn set_log_level(&self, level: &str) -> std::result::Result<LevelFilter, ERPCError> {
+        crate::logging::set_log_level(level)
+    }
+
+    /// Register the [`LOG_LEVEL_METHOD`](crate::logging::LOG_LEVEL_METHOD) admin method
+    ///
+    /// Called with `nil` it returns the current level; called with a level
+    /// name it switches to that level and returns the previous one.
+    #[cfg(feature = "logging")]
+    pub async fn register_log_level_method(&self) -> std::result::Result<(), ERPCError> {
+        self.registry
+            .register_value_method(
+                crate::logging::LOG_LEVEL_METHOD,
+                crate::logging::log_level_method,
+                Some("&optional level"),
+                Some("Return the log level, switching to LEVEL first if given"),
+            )
+            .await
+    }
+
+    /// Announce the bound port through the configured [`PortAnnouncer`]
+    pub fn announce_port(&self) -> std::result::Result<(), ERPCError> {
+        self.announce_port_with(&self.config.port_announcer)
+    }
+
     /// Print the port number to stdout (for Emacs compatibility)
     pub fn print_port(&self) -> std::result::Result<(), ERPCError> {
-        if let Some(port) = self.port() {
-            println!("{}", port);
-            Ok(())
-        } else {
-            Err(ERPCError::ProtocolError("Server not bound".to_string()))
+        self.announce_port_with(&PortAnnouncer::Stdout)
+    }
+
+    fn announce_port_with(&self, announcer: &PortAnnouncer) -> std::result::Result<(), ERPCError> {
+        let port = self
+            .port()
+            .ok_or_else(|| ERPCError::ProtocolError("Server not bound".to_string()))?;
+        announcer.announce(port)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which is not allowed inside async code
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
         }
     }
 }
 
-/// Handle a single client connection
-async fn handle_connection(
-    mut stream: TcpStream,
-    addr: std::net::SocketAddr,
-    registry: Arc<MethodRegistry>,
-    _config: ServerConfig,
-) -> std::result::Result<(), ERPCError> {
-    info!("Starting to handle connection from {}", addr);
-    debug!(
-        "Connection details: local_addr={}, peer_addr={}",
-        stream
-            .local_addr()
-            .unwrap_or_else(|_| "unknown".parse().unwrap()),
-        addr
-    );
+/// Fluent builder producing a bound and serving [`Server`]
+///
+/// ```no_run
+/// # async fn run() -> elrpc::Result<()> {
+/// let server = elrpc::Server::builder()
+///     .bind("127.0.0.1:0")
+///     .max_connections(10)
+///     .request_timeout(std::time::Duration::from_secs(5))
+///     .method("echo", |s: String| Ok(s))
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerBuilder {
+    config: ServerConfig,
+    handlers: Vec<(String, Arc<dyn MethodHandler>)>,
+    state: AppState,
+    announce: bool,
+}
 
-    let mut buffer = BytesMut::with_capacity(1024);
-    let mut message_count = 0;
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder {
+            config: ServerConfig::default(),
+            handlers: Vec::new(),
+            state: AppState::new(),
+            announce: false,
+        }
+    }
 
-    loop {
-        debug!("Waiting for data from client {}", addr);
-        // Read more data
-        let bytes_read = stream
-            .read_buf(&mut buffer)
-            .await
-            .map_err(|e| ERPCError::Io(e))?;
+    /// Replace the whole configuration; later setters adjust it further
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
 
-        debug!("Received {} bytes from client {}", bytes_read, addr);
+    /// Address to bind (default `127.0.0.1:0`)
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.config.bind_addr = addr.into();
+        self
+    }
 
-        if bytes_read == 0 {
-            info!("Client {} disconnected gracefully", addr);
-            break;
-        }
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = max_connections;
+        self
+    }
 
-        debug!(
-            "Total buffer size: {} bytes for client {}",
-            buffer.len(),
-            addr
-        );
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout = timeout;
+        self
+    }
 
-        // Process complete messages
-        while let Some(message_bytes) = Framer::extract_message(&mut buffer) {
-            message_count += 1;
-            debug!(
-                "Processing message #{} from client {} ({} bytes)",
-                message_count,
-                addr,
-                message_bytes.len()
-            );
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.config.worker_threads = Some(threads);
+        self
+    }
 
-            match process_message(message_bytes, &registry).await {
-                Ok(response) => {
-                    debug!(
-                        "Generated response for client {}: {} bytes",
-                        addr,
-                        response.len()
-                    );
-                    let framed = Framer::frame(response.as_bytes());
-                    debug!(
-                        "Sending framed response to client {}: {} bytes total",
-                        addr,
-                        framed.len()
-                    );
-                    stream
-                        .write_all(&framed)
-                        .await
-                        .map_err(|e| ERPCError::Io(e))?;
-                    debug!("Successfully sent response to client {}", addr);
-                }
-                Err(e) => {
-                    error!(
-                        "Error processing message #{} from {}: {}",
-                        message_count, addr, e
-                    );
-                    let error_msg = Message::new_epc_error(0, e.to_string())
-                        .to_sexp()
-                        .unwrap_or_else(|_| "(epc-error 0 \"Unknown error\")".to_string());
-                    debug!("Sending error response to client {}: {}", addr, error_msg);
-                    let framed = Framer::frame(error_msg.as_bytes());
-                    let _ = stream.write_all(&framed).await;
-                    break;
-                }
-            }
-        }
+    pub fn max_worker_tasks(mut self, tasks: usize) -> Self {
+        self.config.max_worker_tasks = Some(tasks);
+        self
+    }
 
-        debug!(
-            "Processed all complete messages for client {}, remaining buffer: {} bytes",
-            addr,
-            buffer.len()
-        );
+    pub fn quota(mut self, quota: QuotaConfig) -> Self {
+        self.config.quota = Some(quota);
+        self
     }
 
-    info!(
-        "Connection handler completed for client {}, processed {} messages",
-        addr, message_count
-    );
-    Ok(())
-}
+    /// Only accept connections from peers that `filter` permits
+    pub fn peer_filter(mut self, filter: PeerFilter) -> Self {
+        self.config.peer_filter = Some(filter);
+        self
+    }
 
-/// Process a single message
-async fn process_message(
+    /// Only let peers call the methods `acl` allows them
+    pub fn method_acl(mut self, acl: MethodAcl) -> Self {
+        self.config.method_acl = Some(acl);
+        self
+    }
+
+    /// Record every call in `log`
+    #[cfg(feature = "audit")]
+    pub fn audit_log(mut self, log: AuditLog) -> Self {
+        self.config.audit_log = Some(log);
+        self
+    }
+
+    /// Serve only peers `authenticator` accepts
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.config.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Ban peers that `detector` finds abusive
+    pub fn abuse_detector(mut self, detector: AbuseDetector) -> Self {
+        self.config.abuse = Some(detector);
+        self
+    }
+
+    /// Serve tenants from their own registries
+    pub fn tenants(mut self, tenants: Tenants) -> Self {
+        self.config.tenants = Some(Arc::new(tenants));
+        self
+    }
+
+    /// Let clients reserve half of the uid space for their calls
+    pub fn partition_uids(mut self, enabled: bool) -> Self {
+        self.config.partition_uids = enabled;
+        self
+    }
+
+    /// Let clients send metadata with their calls
+    pub fn call_metadata(mut self, enabled: bool) -> Self {
+        self.config.call_metadata = enabled;
+        self
+    }
+
+    /// Let clients switch to checksummed frames
+    pub fn frame_checksums(mut self, enabled: bool) -> Self {
+        self.config.frame_checksums = enabled;
+        self
+    }
+
+    /// Skip whitespace between frames (the default) or treat it as garbage
+    pub fn skip_frame_whitespace(mut self, skip: bool) -> Self {
+        self.config.skip_frame_whitespace = skip;
+        self
+    }
+
+    /// Frame messages with a nonstandard length prefix
+    pub fn length_prefix(mut self, prefix: LengthPrefix) -> Self {
+        self.config.length_prefix = prefix;
+        self
+    }
+
+    /// Set the permissions of a socket file created by [`Server::bind_unix`]
+    pub fn unix_socket_mode(mut self, mode: u32) -> Self {
+        self.config.unix_socket_mode = mode;
+        self
+    }
+
+    /// Apply the limits of `profile`
+    pub fn security(mut self, profile: SecurityProfile) -> Self {
+        self.config.security = Some(profile);
+        self
+    }
+
+    /// Expect the quirks of `compat` from every peer
+    pub fn compat(mut self, compat: Compat) -> Self {
+        self.config.compat = compat;
+        self
+    }
+
+    /// Announce the port once bound, through the given announcer
+    pub fn announce_port(mut self, announcer: PortAnnouncer) -> Self {
+        self.config.port_announcer = announcer;
+        self.announce = true;
+        self
+    }
+
+    /// Pass arguments to typed methods registered after this call in `style`
+    pub fn args_style(mut self, style: ArgsStyle) -> Self {
+        self.config.args_style = style;
+        self
+    }
+
+    /// Register a typed method
+    pub fn method<F, Args, Ret>(self, name: impl Into<String>, func: F) -> Self
+    where
+        F: Fn(Args) -> std::result::Result<Ret, ERPCError> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+    {
+        let name = name.into();
+        let handler = ClosureHandler::typed_with_style(
+            func,
+            self.config.args_style,
+            name.clone(),
+            None::<&str>,
+            None::<&str>,
+        );
+        self.handler(name, Arc::new(handler))
+    }
+
+    /// Register a typed method failing with its own error type, converted
+    /// with [`IntoEpcError`]
+    pub fn fallible_method<F, Args, Ret, E>(self, name: impl Into<String>, func: F) -> Self
+    where
+        F: Fn(Args) -> std::result::Result<Ret, E> + Send + Sync + 'static,
+        Args: for<'de> Deserialize<'de> + Send,
+        Ret: Serialize + Send,
+        E: IntoEpcError,
+    {
+        let name = name.into();
+        let handler = ClosureHandler::typed_with_style(
+            func,
+            self.config.args_style,
+            name.clone(),
+            None::<&str>,
+            None::<&str>,
+        );
+        self.handler(name, Arc::new(handler))
+    }
+
+    /// Share `state` with every handler, as [`Server::with_state`] does
+    pub fn state<S: Send + Sync + 'static>(mut self, state: S) -> Self {
+        self.state.insert(state);
+        self
+    }
+
+    /// Register an async function of [extractors](crate::extract)
+    pub fn fn_method<H, M: 'static>(self, name: impl Into<String>, handler: H) -> Self
+    where
+        H: Handler<M>,
+    {
+        let name = name.into();
+        let handler = ExtractHandler::new(
+            handler,
+            self.config.args_style,
+            name.clone(),
+            None::<&str>,
+            None::<&str>,
+        );
+        self.handler(name, Arc::new(handler))
+    }
+
+    /// Register a method working on raw S-expression values
+    pub fn value_method<F>(self, name: impl Into<String>, func: F) -> Self
+    where
+        F: Fn(Value) -> std::result::Result<Value, ERPCError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let handler = ValueHandler::new(func, name.clone(), None::<&str>, None::<&str>);
+        self.handler(name, Arc::new(handler))
+    }
+
+    /// Register any handler, e.g. one with an argument spec and docstring
+    pub fn handler(mut self, name: impl Into<String>, handler: Arc<dyn MethodHandler>) -> Self {
+        self.handlers.push((name.into(), handler));
+        self
+    }
+
+    /// Register the methods, bind, announce the port if requested and start serving
+    pub async fn build(self) -> std::result::Result<Server, ERPCError> {
+        let mut server = Server::with_config(self.config);
+        server.registry.set_state(self.state);
+        for (name, handler) in self.handlers {
+            server.registry.register_handler(name, handler).await;
+        }
+
+        let addr = server.config.bind_addr.clone();
+        server.bind(addr).await?;
+        if self.announce {
+            server.announce_port()?;
+        }
+        server.serve().await?;
+        Ok(server)
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+/// Socket a server accepts connections on
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A connection taken from a [`Listener`]
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream, Option<PeerCredentials>),
+}
+
+impl Listener {
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map_or_else(|_| "unknown address".to_string(), |addr| addr.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+                .unwrap_or_else(|| "unnamed Unix socket".to_string()),
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok(Accepted::Tcp(stream, addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                // A peer whose credentials cannot be read is still served,
+                // it just has none in its CallContext
+                let credentials = stream.peer_cred().ok().map(|cred| PeerCredentials {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                    pid: cred.pid(),
+                });
+                Ok(Accepted::Unix(stream, credentials))
+            }
+        }
+    }
+}
+
+/// Largest handshake frame read from a peer that is not authenticated yet
+const MAX_HANDSHAKE_FRAME: usize = 64 * 1024;
+
+/// Run the authenticator on a new connection
+///
+/// Reads the peer's handshake call first if the authenticator requires one
+/// and answers it. Bytes the peer sent after the handshake are left in
+/// `pending`.
+async fn authenticate_peer(
+    stream: &mut Box<dyn Transport>,
+    peer: &Peer,
+    authenticator: &dyn Authenticator,
+    config: &ServerConfig,
+    pending: &mut BytesMut,
+) -> std::result::Result<Identity, ERPCError> {
+    let mut handshake = Handshake {
+        peer: *peer,
+        credentials: peer.credentials(),
+        data: None,
+    };
+    if !authenticator.requires_handshake() {
+        return authenticator.authenticate(&handshake).await;
+    }
+
+    let compat = config
+        .compat_selector
+        .as_ref()
+        .map_or(config.compat, |selector| selector.select(*peer));
+    let prefix = config.length_prefix;
+    let read_call = async {
+        loop {
+            if prefix
+                .parse_length(pending)
+                .is_some_and(|len| len > MAX_HANDSHAKE_FRAME)
+            {
+                return Err(ERPCError::ProtocolError(
+                    "handshake frame too large".to_string(),
+                ));
+            }
+            if let Some(frame) = prefix.next_frame(pending, config.skip_frame_whitespace)? {
+                let text = std::str::from_utf8(&frame)
+                    .map_err(|e| ERPCError::InvalidMessageFormat(e.to_string()))?;
+                return compat.decode(text);
+            }
+            if stream.read_buf(pending).await? == 0 {
+                return Err(ERPCError::ConnectionClosed);
+            }
+        }
+    };
+    let message = tokio::time::timeout(config.request_timeout, read_call)
+        .await
+        .map_err(|_| ERPCError::Timeout)??;
+    let Message::Call { uid, method, args } = message else {
+        return Err(ERPCError::PermissionDenied(format!(
+            "expected a call to {} first",
+            AUTH_METHOD
+        )));
+    };
+
+    let result = if method == AUTH_METHOD {
+        handshake.data = Some(args);
+        authenticator.authenticate(&handshake).await
+    } else {
+        Err(ERPCError::PermissionDenied(format!(
+            "expected a call to {} first",
+            AUTH_METHOD
+        )))
+    };
+    let reply = match &result {
+        Ok(identity) => compat.encode(&Message::new_return(uid, Value::string(identity.name())))?,
+        Err(e) => compat.encode_error(uid, e)?,
+    };
+    stream.write_all(&prefix.frame(reply.as_bytes())?).await?;
+    result
+}
+
+/// Handle a single client connection
+///
+/// The reader dispatches each frame to a worker task, bounded by
+/// `max_in_flight_per_connection` and the server-wide worker limit.
+/// Responses go through a bounded queue to a writer task, so a peer that
+/// stops reading backpressures handler completion and eventually the reader.
+async fn handle_connection(
+    mut stream: Box<dyn Transport>,
+    peer: Peer,
+    registry: Arc<MethodRegistry>,
+    pool: Arc<BufferPool>,
+    workers: Workers,
+    stats: Arc<ServerStats>,
+    config: ServerConfig,
+) -> std::result::Result<(), ERPCError> {
+    info!("Starting to handle connection from {}", peer);
+    if let Some(credentials) = peer.credentials() {
+        debug!("Peer credentials: {:?}", credentials);
+    }
+
+    // Unix socket peers have no address to ban
+    let abuse = config.abuse.clone().filter(|_| !peer.is_unix());
+    let mut pending = BytesMut::new();
+    let identity = match &config.authenticator {
+        Some(authenticator) => {
+            let result = authenticate_peer(
+                &mut stream,
+                &peer,
+                authenticator.as_ref(),
+                &config,
+                &mut pending,
+            )
+            .await;
+            #[cfg(feature = "audit")]
+            if let Some(log) = &config.audit_log {
+                let outcome = result.as_ref().map(|_| ());
+                log.record(peer, 0, AUTH_METHOD, None, Duration::ZERO, outcome);
+            }
+            match result {
+                Ok(identity) => {
+                    info!("Peer {} authenticated as {}", peer, identity.name());
+                    Some(identity)
+                }
+                Err(e) => {
+                    warn!("Rejected connection from {}: {}", peer, e);
+                    stats.record_rejected();
+                    if let (Some(abuse), Some(ip)) = (&abuse, peer.ip()) {
+                        abuse.record(ip, Offense::AuthenticationFailure);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+
+    let tenant = match (&config.tenants, &identity) {
+        (Some(tenants), Some(identity)) => match tenants.for_identity(identity) {
+            Ok(tenant) => tenant,
+            Err(e) => {
+                warn!("Rejected connection from {}: {}", peer, e);
+                stats.record_rejected();
+                return Ok(());
+            }
+        },
+        _ => None,
+    };
+    if let Some((name, _)) = &tenant {
+        info!("Peer {} bound to tenant {}", peer, name);
+    }
+    let binding = match tenant {
+        Some((name, registry)) => Binding {
+            tenant: Some(name),
+            registry,
+        },
+        None => Binding {
+            tenant: None,
+            registry,
+        },
+    };
+
+    let window = config
+        .quota
+        .as_ref()
+        .map_or(QuotaConfig::default().window, |quota| quota.window);
+    let usage = Arc::new(ConnectionUsage::new(peer, window));
+    stats.register(usage.clone());
+    let connection_id = usage.id();
+    let allowed = config
+        .method_acl
+        .as_ref()
+        .map(|acl| acl.for_peer(&peer, identity.as_ref()));
+    let connection = Arc::new(ConnectionState {
+        addr: peer,
+        connection_id,
+        credentials: peer.credentials(),
+        identity,
+        abuse,
+        usage: usage.clone(),
+        quota: config.quota.clone(),
+        request_log: config.request_log.clone(),
+        #[cfg(feature = "audit")]
+        audit_log: config.audit_log.clone(),
+        compat: config
+            .compat_selector
+            .as_ref()
+            .map_or(config.compat, |selector| selector.select(peer)),
+        flush_policy: config
+            .flush_selector
+            .as_ref()
+            .map_or(config.flush_policy, |selector| selector.select(peer)),
+        allowed,
+        request_timeout: config.request_timeout,
+        max_nesting_depth: config
+            .security
+            .as_ref()
+            .map(|profile| profile.max_nesting_depth),
+        methods: ConnectionMethods::new(binding.registry.args_style()),
+        tenants: config.tenants.clone(),
+        binding: std::sync::RwLock::new(binding),
+        calls: std::sync::Mutex::new(HashSet::new()),
+        stats: stats.clone(),
+        partition_uids: config.partition_uids,
+        peer_half: std::sync::OnceLock::new(),
+        call_metadata: config.call_metadata,
+        sends_metadata: AtomicBool::new(false),
+        frame_checksums: config.frame_checksums,
+        checksums: AtomicBool::new(false),
+        shed: tokio::sync::Notify::new(),
+    });
+
+    let (reader, writer) = tokio::io::split(stream);
+    // Calls pipelined behind the handshake come first
+    let mut reader = std::io::Cursor::new(pending).chain(reader);
+    let (response_tx, response_rx) = mpsc::channel(config.outbound_queue_size.max(1));
+    let writer_handle = tokio::spawn(write_responses(
+        writer,
+        response_rx,
+        connection.clone(),
+        pool.clone(),
+        config.clone(),
+    ));
+
+    let max_in_flight = config.max_in_flight_per_connection.max(1);
+    let in_flight = Arc::new(Semaphore::new(max_in_flight));
+
+    let mut buffer = pool.get();
+    let mut message_count = 0;
+    let mut sizer = ReadSizer::new(config.read_buffer_size, config.max_read_buffer_size)
+        .length_prefix(config.length_prefix);
+
+    let max_frame_size = config
+        .security
+        .as_ref()
+        .map(|profile| profile.max_frame_size);
+    let idle_timeout = config
+        .security
+        .as_ref()
+        .and_then(|profile| profile.idle_timeout);
+
+    let read_result = 'read: loop {
+        sizer.prepare(&mut buffer);
+        let read = async {
+            tokio::select! {
+                read = reader.read_buf(&mut *buffer) => Some(read),
+                _ = connection.shed.notified() => None,
+            }
+        };
+        let read = match idle_timeout {
+            Some(idle) => match tokio::time::timeout(idle, read).await {
+                Ok(read) => read,
+                // Peers waiting for a reply are not idle
+                Err(_) if in_flight.available_permits() < max_in_flight => continue,
+                Err(_) => {
+                    info!("Closing connection from {} after {:?} idle", peer, idle);
+                    break Ok(());
+                }
+            },
+            None => read.await,
+        };
+        let bytes_read = match read {
+            Some(Ok(n)) => n,
+            Some(Err(e)) => break Err(ERPCError::Io(e)),
+            None => {
+                warn!(
+                    "Closing connection from {}: outbound queue overflowed",
+                    peer
+                );
+                break Err(ERPCError::QueueFull);
+            }
+        };
+
+        if bytes_read == 0 {
+            info!("Client {} disconnected gracefully", peer);
+            break Ok(());
+        }
+
+        trace!(
+            "Read {} bytes from client {}, {} buffered",
+            bytes_read,
+            peer,
+            buffer.len()
+        );
+
+        // Dispatch complete messages
+        loop {
+            if config.skip_frame_whitespace {
+                Framer::skip_whitespace(&mut buffer);
+            }
+            if let (Some(max), Some(len)) =
+                (max_frame_size, config.length_prefix.parse_length(&buffer))
+            {
+                if len > max {
+                    warn!(
+                        "Closing connection from {}: frame of {} bytes exceeds {}",
+                        peer, len, max
+                    );
+                    connection.offense(Offense::ProtocolViolation);
+                    break 'read Err(ERPCError::ProtocolError(format!(
+                        "frame of {} bytes exceeds the {} byte limit",
+                        len, max
+                    )));
+                }
+            }
+            let message_bytes = match config.length_prefix.extract_message(&mut buffer) {
+                Ok(Some(message_bytes)) => message_bytes,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Closing connection from {}: {}", peer, e);
+                    connection.offense(Offense::ProtocolViolation);
+                    break 'read Err(e);
+                }
+            };
+            message_count += 1;
+            sizer.observe(message_bytes.len());
+            connection
+                .usage
+                .record_bytes_in(config.length_prefix.width() + message_bytes.len());
+            if let Some(tap) = &config.wire_tap {
+                tap.record(
+                    Direction::Inbound,
+                    connection_id,
+                    peer,
+                    config.clock.now(),
+                    &message_bytes,
+                );
+            }
+
+            // Stop reading while too many requests are in flight
+            let permit = in_flight
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("in-flight semaphore is never closed");
+            let worker_permit = workers.acquire().await;
+            let registry = connection.registry();
+            let connection = connection.clone();
+            let response_tx = response_tx.clone();
+            let overflow_policy = config.overflow_policy;
+            // uid and method are filled in once the message is parsed
+            let span = info_span!(
+                "call",
+                peer = %peer,
+                uid = tracing::field::Empty,
+                method = tracing::field::Empty,
+                duration_us = tracing::field::Empty,
+            );
+
+            workers.handle.spawn(
+                async move {
+                    let response = match process_message(message_bytes, &registry, &connection)
+                        .await
+                    {
+                        Ok(response) => response,
+                        Err(e) => {
+                            error!("Error processing message from {}: {}", peer, e);
+                            connection.stats.record_uid_error(&e);
+                            if let Some(offense) = abuse::offense_of(&e) {
+                                connection.offense(offense);
+                            }
+                            connection
+                                .compat
+                                .encode(&Message::new_epc_error(0, e.to_string()))
+                                .unwrap_or_else(|_| "(epc-error 0 \"Unknown error\")".to_string())
+                        }
+                    };
+
+                    if response.is_empty() {
+                        // Extension messages need not be answered
+                    } else if let Err(e) =
+                        queue_response(&response_tx, response, overflow_policy).await
+                    {
+                        warn!("Dropping response for client {}: {}", peer, e);
+                        if matches!(e, ERPCError::QueueFull) {
+                            connection.shed.notify_one();
+                        }
+                    }
+                    drop(permit);
+                    drop(worker_permit);
+                }
+                .instrument(span),
+            );
+        }
+
+        sizer.shrink(&mut buffer);
+    };
+
+    // Let in-flight handlers finish and the writer drain the queue
+    drop(response_tx);
+    let write_result = writer_handle
+        .await
+        .map_err(|e| ERPCError::ProtocolError(e.to_string()));
+    stats.unregister_connection(connection_id);
+    let write_result = write_result?;
+
+    info!(
+        "Connection handler completed for client {}, processed {} messages",
+        peer, message_count
+    );
+    read_result.and(write_result)
+}
+
+/// Enqueue a response according to the overflow policy
+async fn queue_response(
+    response_tx: &mpsc::Sender<String>,
+    response: String,
+    policy: OverflowPolicy,
+) -> std::result::Result<(), ERPCError> {
+    match policy {
+        OverflowPolicy::Block => response_tx
+            .send(response)
+            .await
+            .map_err(|_| ERPCError::ConnectionClosed),
+        OverflowPolicy::Shed => response_tx.try_send(response).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => ERPCError::QueueFull,
+            mpsc::error::TrySendError::Closed(_) => ERPCError::ConnectionClosed,
+        }),
+    }
+}
+
+/// Write queued responses to the peer, coalescing them per the flush policy
+async fn write_responses<W>(
+    mut writer: W,
+    mut response_rx: mpsc::Receiver<String>,
+    connection: Arc<ConnectionState>,
+    pool: Arc<BufferPool>,
+    config: ServerConfig,
+) -> std::result::Result<(), ERPCError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let addr = connection.addr;
+    let mut out = pool.get();
+    // A response too large for the prefix cannot be sent without throwing
+    // the peer off, so it closes the connection
+    let frame_into = |out: &mut BytesMut, response: &str| {
+        if let Some(tap) = &config.wire_tap {
+            tap.record(
+                Direction::Outbound,
+                connection.connection_id,
+                addr,
+                config.clock.now(),
+                response.as_bytes(),
+            );
+        }
+        if connection.checksums.load(Ordering::Relaxed) {
+            config
+                .length_prefix
+                .frame_into(out, &checksum::seal(response.as_bytes()))
+        } else {
+            config.length_prefix.frame_into(out, response.as_bytes())
+        }
+    };
+
+    while let Some(response) = response_rx.recv().await {
+        frame_into(&mut out, &response)?;
+
+        // Gather further responses into the same write
+        match connection.flush_policy {
+            FlushPolicy::Immediate => {}
+            FlushPolicy::Batched => {
+                while out.len() < config.max_write_batch {
+                    match response_rx.try_recv() {
+                        Ok(response) => frame_into(&mut out, &response)?,
+                        Err(_) => break,
+                    }
+                }
+            }
+            FlushPolicy::Delay(delay) => {
+                let deadline = Instant::now() + delay;
+                while out.len() < config.max_write_batch {
+                    match tokio::time::timeout_at(deadline, response_rx.recv()).await {
+                        Ok(Some(response)) => frame_into(&mut out, &response)?,
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+            }
+        }
+
+        connection.usage.record_bytes_out(out.len());
+        flush_responses(&mut writer, &mut out, addr).await?;
+    }
+
+    let _ = writer.shutdown().await;
+    Ok(())
+}
+
+/// Write all queued response frames in one batch
+async fn flush_responses<W>(
+    writer: &mut W,
+    out: &mut BytesMut,
+    addr: Peer,
+) -> std::result::Result<(), ERPCError>
+where
+    W: AsyncWrite + Unpin,
+{
+    if out.is_empty() {
+        return Ok(());
+    }
+
+    trace!(
+        "Flushing {} bytes of responses to client {}",
+        out.len(),
+        addr
+    );
+    writer.write_all(out).await.map_err(ERPCError::Io)?;
+    out.clear();
+    Ok(())
+}
+
+/// State shared by all requests of one connection
+struct ConnectionState {
+    addr: Peer,
+    connection_id: u64,
+    credentials: Option<PeerCredentials>,
+    identity: Option<Identity>,
+    abuse: Option<AbuseDetector>,
+    usage: Arc<ConnectionUsage>,
+    quota: Option<QuotaConfig>,
+    request_log: Option<RequestLogConfig>,
+    #[cfg(feature = "audit")]
+    audit_log: Option<AuditLog>,
+    compat: Compat,
+    flush_policy: FlushPolicy,
+    /// Methods the peer may call, None when there is no ACL
+    allowed: Option<MethodSet>,
+    request_timeout: Duration,
+    max_nesting_depth: Option<usize>,
+    /// Methods registered for this connection only
+    methods: ConnectionMethods,
+    tenants: Option<Arc<Tenants>>,
+    binding: std::sync::RwLock<Binding>,
+    /// Uids of the peer's calls being handled
+    calls: std::sync::Mutex<HashSet<u64>>,
+    stats: Arc<ServerStats>,
+    partition_uids: bool,
+    /// Half of the uid space the peer took for its calls
+    peer_half: std::sync::OnceLock<UidHalf>,
+    call_metadata: bool,
+    /// Whether the peer agreed to send metadata with its calls
+    sends_metadata: AtomicBool,
+    frame_checksums: bool,
+    /// Whether frames carry checksums
+    checksums: AtomicBool,
+    /// Notified when a response was shed, to stop reading
+    shed: tokio::sync::Notify,
+}
+
+/// Holds a call's uid in [`ConnectionState::calls`] until the call is done
+struct CallUid<'a> {
+    connection: &'a ConnectionState,
+    uid: u64,
+}
+
+impl Drop for CallUid<'_> {
+    fn drop(&mut self) {
+        self.connection.calls.lock().unwrap().remove(&self.uid);
+    }
+}
+
+/// The tenant a connection is bound to and the registry serving it
+struct Binding {
+    tenant: Option<String>,
+    registry: Arc<MethodRegistry>,
+}
+
+impl ConnectionState {
+    /// Registry serving the connection's calls
+    fn registry(&self) -> Arc<MethodRegistry> {
+        self.binding.read().unwrap().registry.clone()
+    }
+
+    /// Bind the connection to the tenant named by `args`
+    fn select_tenant(&self, tenants: &Tenants, args: &Value) -> std::result::Result<(), ERPCError> {
+        let name = match args {
+            Value::Cons(cons) if cons.cdr().is_null() => cons.car(),
+            _ => args,
+        };
+        let name = name
+            .as_str()
+            .or_else(|| name.as_symbol())
+            .ok_or_else(|| ERPCError::InvalidArgument("expected a tenant name".to_string()))?;
+        let mut binding = self.binding.write().unwrap();
+        if let Some(tenant) = &binding.tenant {
+            return Err(ERPCError::PermissionDenied(format!(
+                "connection is bound to tenant {}",
+                tenant
+            )));
+        }
+        binding.registry = tenants.select(name)?;
+        binding.tenant = Some(name.to_string());
+        info!("Peer {} selected tenant {}", self.addr, name);
+        Ok(())
+    }
+
+    /// Reserve the half of the uid space named by `args` for the peer's
+    /// calls, returning the half left to the server
+    fn partition_uids(&self, args: &Value) -> std::result::Result<UidHalf, ERPCError> {
+        let name = match args {
+            Value::Cons(cons) if cons.cdr().is_null() => cons.car(),
+            _ => args,
+        };
+        let half = name
+            .as_symbol()
+            .or_else(|| name.as_str())
+            .and_then(UidHalf::from_name)
+            .ok_or_else(|| ERPCError::InvalidArgument("expected odd or even".to_string()))?;
+        self.peer_half
+            .set(half)
+            .map_err(|_| ERPCError::InvalidArgument("uids are partitioned already".to_string()))?;
+        info!("Peer {} takes {} uids", self.addr, half.name());
+        Ok(half.other())
+    }
+
+    /// Mark call `uid` in flight, failing if it already is
+    fn start_call(&self, uid: u64) -> std::result::Result<CallUid<'_>, ERPCError> {
+        if let Some(half) = self.peer_half.get().filter(|half| !half.contains(uid)) {
+            let e = ERPCError::ProtocolError(format!(
+                "uid {} is outside the peer's {} half",
+                uid,
+                half.name()
+            ));
+            self.offense(Offense::ProtocolViolation);
+            return Err(e);
+        }
+        if !self.calls.lock().unwrap().insert(uid) {
+            let e = ERPCError::UidReuse(uid);
+            self.stats.record_uid_error(&e);
+            self.offense(Offense::ProtocolViolation);
+            return Err(e);
+        }
+        Ok(CallUid {
+            connection: self,
+            uid,
+        })
+    }
+
+    /// Count an offense against the peer
+    fn offense(&self, offense: Offense) {
+        if let (Some(abuse), Some(ip)) = (&self.abuse, self.addr.ip()) {
+            abuse.record(ip, offense);
+        }
+    }
+
+    /// Context handlers see while serving call `uid`
+    fn call_context(&self, uid: u64, method: &str) -> CallContext {
+        CallContext {
+            peer: self.addr,
+            connection: self.connection_id,
+            uid,
+            method: method.to_string(),
+            credentials: self.credentials,
+            identity: self.identity.clone(),
+            metadata: None,
+        }
+    }
+
+    /// Whether the peer may call `method`
+    fn may_call(&self, method: &str) -> bool {
+        self.allowed
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(method))
+    }
+
+    /// Check a call against the method ACL, then the connection's quota
+    ///
+    /// Refused calls are audited here, since they never finish.
+    #[cfg_attr(not(feature = "audit"), allow(unused_variables))]
+    async fn admit(&self, uid: u64, method: &str) -> std::result::Result<(), ERPCError> {
+        let banned = self
+            .abuse
+            .as_ref()
+            .zip(self.addr.ip())
+            .is_some_and(|(abuse, ip)| abuse.is_banned(ip));
+        let admitted = if banned {
+            Err(abuse::banned())
+        } else if self.may_call(method) {
+            self.usage.admit_call(self.quota.as_ref()).await
+        } else {
+            Err(acl::denied(method))
+        };
+        if let Err(ERPCError::QuotaExceeded(_)) = admitted {
+            self.offense(Offense::RateLimit);
+        }
+        #[cfg(feature = "audit")]
+        if let (Err(e), Some(audit)) = (&admitted, &self.audit_log) {
+            audit.record(self.addr, uid, method, None, Duration::ZERO, Err(e));
+        }
+        admitted
+    }
+
+    /// Arguments of a call as the request and audit logs show them
+    fn logged_args(&self, method: &str, args: impl FnOnce() -> Value) -> LoggedArgs {
+        let request_log = self.request_log.as_ref().filter(|log| log.log_args);
+        #[cfg(feature = "audit")]
+        let audited = self.audit_log.is_some();
+        #[cfg(not(feature = "audit"))]
+        let audited = false;
+        if request_log.is_none() && !audited {
+            return LoggedArgs::default();
+        }
+        let args = args();
+        LoggedArgs {
+            request_log: request_log.map(|log| log.render_args(method, &args)),
+            #[cfg(feature = "audit")]
+            audit_log: self
+                .audit_log
+                .as_ref()
+                .and_then(|audit| audit.render_args(&args)),
+        }
+    }
+
+    /// Account for a finished call and add it to the request and audit logs
+    fn finish_call(
+        &self,
+        uid: u64,
+        method: &str,
+        args: LoggedArgs,
+        started: Instant,
+        result: &std::result::Result<impl Sized, ERPCError>,
+    ) {
+        let elapsed = started.elapsed();
+        self.usage.record_handler_time(elapsed);
+        Span::current().record("duration_us", elapsed.as_micros() as u64);
+        debug!("Call finished");
+        #[cfg(feature = "otel")]
+        crate::otel::record_call(crate::otel::Side::Server, method, elapsed, result.is_ok());
+        let outcome = result.as_ref().map(|_| ());
+        if let Some(log) = &self.request_log {
+            log.record(
+                self.addr,
+                uid,
+                method,
+                args.request_log.as_deref(),
+                elapsed,
+                outcome,
+            );
+        }
+        #[cfg(feature = "audit")]
+        if let Some(audit) = &self.audit_log {
+            audit.record(
+                self.addr,
+                uid,
+                method,
+                args.audit_log.as_deref(),
+                elapsed,
+                outcome,
+            );
+        }
+    }
+
+    /// Account for a call that ran past the request timeout and answer it
+    /// with an `epc-error`
+    fn timed_out(
+        &self,
+        uid: u64,
+        method: &str,
+        args: LoggedArgs,
+        started: Instant,
+    ) -> std::result::Result<String, ERPCError> {
+        warn!(
+            "Method '{}' timed out after {:?}",
+            method, self.request_timeout
+        );
+        self.finish_call(
+            uid,
+            method,
+            args,
+            started,
+            &Err::<(), _>(ERPCError::Timeout),
+        );
+        self.compat.encode(&Message::new_epc_error(
+            uid,
+            format!("call timed out after {:?}", self.request_timeout),
+        ))
+    }
+}
+
+/// Rendered arguments of one call, for each log that wants them
+#[derive(Default)]
+struct LoggedArgs {
+    request_log: Option<String>,
+    #[cfg(feature = "audit")]
+    audit_log: Option<String>,
+}
+
+/// Process a single message
+async fn process_message(
     message_bytes: bytes::Bytes,
     registry: &Arc<MethodRegistry>,
+    connection: &ConnectionState,
 ) -> std::result::Result<String, ERPCError> {
-    debug!("Processing message: {} bytes", message_bytes.len());
-
+    let message_bytes =
+        checksum::open(message_bytes, connection.checksums.load(Ordering::Relaxed))?;
     let message_str = std::str::from_utf8(&message_bytes)
         .map_err(|e| ERPCError::InvalidMessageFormat(e.to_string()))?;
+    if let Some(max) = connection.max_nesting_depth {
+        if security::exceeds_depth(message_str, max) {
+            return Err(ERPCError::InvalidMessageFormat(format!(
+                "message nested deeper than {} levels",
+                max
+            )));
+        }
+    }
+
+    // Raw and arena methods see the frame itself, so only standard peers can use them
+    if connection.compat == Compat::Standard && registry.has_raw_methods().await {
+        if let Some(response) = process_raw_call(message_str, registry, connection).await? {
+            return Ok(response);
+        }
+    }
+    if connection.compat == Compat::Standard && registry.has_arena_methods().await {
+        if let Some(response) = process_arena_call(message_str, registry, connection).await? {
+            return Ok(response);
+        }
+    }
+
+    let message = connection.compat.decode(message_str)?;
+
+    match message {
+        Message::Call { uid, method, args } => {
+            Span::current()
+                .record("uid", uid)
+                .record("method", method.as_str());
+            let _call = match connection.start_call(uid) {
+                Ok(call) => call,
+                Err(e) => {
+                    warn!(
+                        "Rejecting call '{}' from {}: {}",
+                        method, connection.addr, e
+                    );
+                    return connection.compat.encode_error(uid, &e);
+                }
+            };
+            if let Err(e) = connection.admit(uid, &method).await {
+                warn!(
+                    "Rejecting call '{}' from {}: {}",
+                    method, connection.addr, e
+                );
+                return connection.compat.encode_error(uid, &e);
+            }
+            if method == FRAME_CHECKSUMS_METHOD && connection.frame_checksums {
+                // The reply is the first checksummed frame
+                connection.checksums.store(true, Ordering::Relaxed);
+                debug!("Peer {} switched to checksummed frames", connection.addr);
+                return connection
+                    .compat
+                    .encode(&Message::new_return(uid, Value::Bool(true)));
+            }
+            if method == CALL_METADATA_METHOD && connection.call_metadata {
+                connection.sends_metadata.store(true, Ordering::Relaxed);
+                debug!("Peer {} sends call metadata", connection.addr);
+                return connection
+                    .compat
+                    .encode(&Message::new_return(uid, Value::Bool(true)));
+            }
+            let (args, metadata) = if connection.sends_metadata.load(Ordering::Relaxed) {
+                metadata::unwrap(args)
+            } else {
+                (args, None)
+            };
+            if method == PARTITION_UIDS_METHOD && connection.partition_uids {
+                return match connection.partition_uids(&args) {
+                    Ok(half) => connection
+                        .compat
+                        .encode(&Message::new_return(uid, Value::symbol(half.name()))),
+                    Err(e) => connection.compat.encode_error(uid, &e),
+                };
+            }
+            if let (SELECT_TENANT_METHOD, Some(tenants)) = (method.as_str(), &connection.tenants) {
+                return match connection.select_tenant(tenants, &args) {
+                    Ok(()) => connection
+                        .compat
+                        .encode(&Message::new_return(uid, Value::Bool(true))),
+                    Err(e) => connection.compat.encode_error(uid, &e),
+                };
+            }
+
+            #[cfg(feature = "otel")]
+            let (args, handler_span) = crate::otel::handler_span(&method, args);
+            #[cfg(not(feature = "otel"))]
+            let handler_span = Span::none();
+
+            let logged_args = connection.logged_args(&method, || args.clone());
+            let started = Instant::now();
+            let local = connection.methods.get(&method);
+            let call = async {
+                match local {
+                    Some(handler) => {
+                        #[cfg(any(test, feature = "test-util"))]
+                        registry.inject_faults(&method).await?;
+                        registry.call_handler(handler.as_ref(), args).await
+                    }
+                    None => registry.call_method(&method, args).await,
+                }
+            };
+            let mut context = connection.call_context(uid, &method);
+            context.metadata = metadata;
+            let call = context.scope(
+                connection
+                    .methods
+                    .clone()
+                    .scope(call.instrument(handler_span)),
+            );
+            let Ok(result) = tokio::time::timeout(connection.request_timeout, call).await else {
+                return connection.timed_out(uid, &method, logged_args, started);
+            };
+            connection.finish_call(uid, &method, logged_args, started, &result);
+
+            match result {
+                Ok(result) => connection.compat.encode(&Message::new_return(uid, result)),
+                Err(e) => {
+                    error!("Method '{}' failed: {}", method, e);
+                    connection.compat.encode_error(uid, &e)
+                }
+            }
+        }
+        Message::Methods { uid } => {
+            Span::current().record("uid", uid);
+            let mut methods = registry.query_methods().await?;
+            // Connection methods shadow global ones of the same name
+            methods.retain(|info| !connection.methods.contains(&info.name));
+            methods.extend(connection.methods.infos());
+            methods.retain(|info| connection.may_call(&info.name));
+            debug!("Returning {} methods", methods.len());
+
+            connection
+                .compat
+                .encode(&Message::new_return(uid, method_list(methods)))
+        }
+        message @ Message::Extension { .. } => {
+            Span::current().record("uid", message.uid());
+            // An empty response sends nothing back
+            match registry.handle_extension(message).await? {
+                Some(reply) => connection.compat.encode(&reply),
+                None => Ok(String::new()),
+            }
+        }
+        Message::Return { uid, .. }
+        | Message::ReturnError { uid, .. }
+        | Message::EPCError { uid, .. } => {
+            // The server makes no calls, so no reply can be expected
+            warn!(
+                "Received reply to unknown call:\n{}",
+                crate::pretty::for_log(&message.to_value())
+            );
+            Err(ERPCError::UnknownUid(uid))
+        }
+    }
+}
+
+/// Dispatch a call to an arena method, if the message targets one
+///
+/// Returns `None` when the message is not a call to an arena method, so the
+/// caller falls back to the regular `lexpr` parse. The method name is read
+/// off the frame text first, so only calls to arena methods are parsed into
+/// an arena.
+///
+/// Arena methods run synchronously and cannot be interrupted: one running
+/// past the request timeout is answered as timed out once it returns.
+async fn process_arena_call(
+    message_str: &str,
+    registry: &Arc<MethodRegistry>,
+    connection: &ConnectionState,
+) -> std::result::Result<Option<String>, ERPCError> {
+    let Some((uid, method_name, args)) = split_raw_call(message_str) else {
+        return Ok(None);
+    };
+    if connection.methods.contains(method_name) {
+        return Ok(None);
+    }
+    let Some(method) = registry.arena_method(method_name).await else {
+        return Ok(None);
+    };
+
+    Span::current()
+        .record("uid", uid)
+        .record("method", method_name);
+    let _call = match connection.start_call(uid) {
+        Ok(call) => call,
+        Err(e) => {
+            warn!(
+                "Rejecting call '{}' from {}: {}",
+                method_name, connection.addr, e
+            );
+            return Ok(Some(connection.compat.encode_error(uid, &e)?));
+        }
+    };
+    if let Err(e) = connection.admit(uid, method_name).await {
+        warn!(
+            "Rejecting call '{}' from {}: {}",
+            method_name, connection.addr, e
+        );
+        return Ok(Some(connection.compat.encode_error(uid, &e)?));
+    }
+    let (args, metadata) = unwrap_metadata(connection, args);
+    let arena = match ValueArena::parse(&args) {
+        Ok(arena) => arena,
+        Err(e) => return Ok(Some(connection.compat.encode_error(uid, &e)?)),
+    };
+    debug!("Dispatching to arena method, {} nodes", arena.node_count());
+
+    let args = arena.root();
+    let logged_args = connection.logged_args(method_name, || args.to_value());
+    let started = Instant::now();
+    let Some(faults) = injected_faults(connection, registry, method_name).await else {
+        return connection
+            .timed_out(uid, method_name, logged_args, started)
+            .map(Some);
+    };
+    let mut context = connection.call_context(uid, method_name);
+    context.metadata = metadata;
+    let result =
+        faults.and_then(|()| context.sync_scope(|| call_arena(&method, method_name, args)));
+    if started.elapsed() > connection.request_timeout {
+        return connection
+            .timed_out(uid, method_name, logged_args, started)
+            .map(Some);
+    }
+    connection.finish_call(uid, method_name, logged_args, started, &result);
+
+    let response = match result {
+        Ok(result) => connection.compat.encode(&Message::new_return(uid, result)),
+        Err(e) => {
+            error!("Method '{}' failed: {}", method_name, e);
+            connection.compat.encode_error(uid, &e)
+        }
+    };
+    response.map(Some)
+}
+
+/// Call arena method `method`, continuing a propagated trace if the
+/// arguments carry one
+fn call_arena(
+    method: &ArenaMethod,
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))] method_name: &str,
+    args: ArenaValue<'_>,
+) -> std::result::Result<Value, ERPCError> {
+    #[cfg(feature = "otel")]
+    if args.get(0).and_then(|v| v.as_symbol()) == Some(crate::otel::TRACE_CONTEXT_MARKER) {
+        // Propagated calls are rare enough to re-parse the arguments without the envelope
+        let (args, handler_span) = crate::otel::handler_span(method_name, args.to_value());
+        let text =
+            lexpr::to_string(&args).map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+        let inner = ValueArena::parse(&text)?;
+        return handler_span.in_scope(|| method.call(inner.root()));
+    }
+    method.call(args)
+}
+
+/// Cut the metadata envelope off the argument text of a raw or arena call,
+/// if the peer agreed to send one
+fn unwrap_metadata<'a>(
+    connection: &ConnectionState,
+    args: &'a str,
+) -> (Cow<'a, str>, Option<CallMetadata>) {
+    if connection.sends_metadata.load(Ordering::Relaxed) {
+        metadata::unwrap_text(args)
+    } else {
+        (Cow::Borrowed(args), None)
+    }
+}
+
+/// Split a `(call UID METHOD ARGS)` frame into its parts, leaving the
+/// argument text unparsed
+///
+/// Only plain decimal uids and method names without escapes are recognized;
+/// anything else is left to the regular parse.
+fn split_raw_call(text: &str) -> Option<(u64, &str, &str)> {
+    let rest = text.trim().strip_prefix('(')?.trim_start();
+    let rest = rest.strip_prefix("call")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let uid_end = rest.find(|c: char| !c.is_ascii_digit())?;
+    let uid = rest[..uid_end].parse().ok()?;
+    let rest = &rest[uid_end..];
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim_start();
+
+    let (method, rest) = if let Some(quoted) = rest.strip_prefix('"') {
+        let end = quoted.find('"')?;
+        let method = &quoted[..end];
+        if method.contains('\\') {
+            return None;
+        }
+        (method, &quoted[end + 1..])
+    } else {
+        let end = rest.find(|c: char| c.is_whitespace() || "()\"';`\\".contains(c))?;
+        (&rest[..end], &rest[end..])
+    };
+    if method.is_empty() {
+        return None;
+    }
+
+    let args = rest.trim_end().strip_suffix(')')?.trim();
+    if args.is_empty() {
+        return None;
+    }
+    Some((uid, method, args))
+}
+
+/// Dispatch a call to a raw method, if the message targets one
+///
+/// Returns `None` when the message is not a call to a raw method, so the
+/// caller falls back to the regular `lexpr` parse. Like arena methods, raw
+/// methods are answered as timed out if they return after the timeout.
+async fn process_raw_call(
+    message_str: &str,
+    registry: &Arc<MethodRegistry>,
+    connection: &ConnectionState,
+) -> std::result::Result<Option<String>, ERPCError> {
+    let Some((uid, method_name, args)) = split_raw_call(message_str) else {
+        return Ok(None);
+    };
+    if connection.methods.contains(method_name) {
+        return Ok(None);
+    }
+    let Some(method) = registry.raw_method(method_name).await else {
+        return Ok(None);
+    };
+
+    Span::current()
+        .record("uid", uid)
+        .record("method", method_name);
+    debug!(
+        "Dispatching to raw method, {} bytes of arguments",
+        args.len()
+    );
+    let _call = match connection.start_call(uid) {
+        Ok(call) => call,
+        Err(e) => {
+            warn!(
+                "Rejecting call '{}' from {}: {}",
+                method_name, connection.addr, e
+            );
+            return Ok(Some(connection.compat.encode_error(uid, &e)?));
+        }
+    };
+    if let Err(e) = connection.admit(uid, method_name).await {
+        warn!(
+            "Rejecting call '{}' from {}: {}",
+            method_name, connection.addr, e
+        );
+        return Ok(Some(connection.compat.encode_error(uid, &e)?));
+    }
+
+    let (args, metadata) = unwrap_metadata(connection, args);
+    let args = args.as_ref();
+    // Only parsed when a log wants the arguments
+    let logged_args = connection.logged_args(method_name, || {
+        lexpr::from_str(args).unwrap_or_else(|_| Value::string(args))
+    });
+    let started = Instant::now();
+    let Some(faults) = injected_faults(connection, registry, method_name).await else {
+        return connection
+            .timed_out(uid, method_name, logged_args, started)
+            .map(Some);
+    };
+    let mut context = connection.call_context(uid, method_name);
+    context.metadata = metadata;
+    let result = faults
+        .and_then(|()| context.sync_scope(|| method.call(args)))
+        .and_then(|result| checked_raw_result(method_name, result));
+    if started.elapsed() > connection.request_timeout {
+        return connection
+            .timed_out(uid, method_name, logged_args, started)
+            .map(Some);
+    }
+    connection.finish_call(uid, method_name, logged_args, started, &result);
+
+    match result {
+        Ok(result) => Ok(Some(format!("(return {} {})", uid, result))),
+        Err(e) => {
+            error!("Method '{}' failed: {}", method_name, e);
+            Ok(Some(connection.compat.encode_error(uid, &e)?))
+        }
+    }
+}
+
+/// Apply the faults tests injected into `method`, or None if they outlast
+/// the request timeout
+#[cfg(any(test, feature = "test-util"))]
+async fn injected_faults(
+    connection: &ConnectionState,
+    registry: &MethodRegistry,
+    method: &str,
+) -> Option<std::result::Result<(), ERPCError>> {
+    let faults = registry.inject_faults(method);
+    tokio::time::timeout(connection.request_timeout, faults)
+        .await
+        .ok()
+}
+
+/// Production builds have no injected faults
+#[cfg(not(any(test, feature = "test-util")))]
+async fn injected_faults(
+    _connection: &ConnectionState,
+    _registry: &MethodRegistry,
+    _method: &str,
+) -> Option<std::result::Result<(), ERPCError>> {
+    Some(Ok(()))
+}
+
+/// The text a raw method returned, if it is a single expression
+///
+/// Text that is not would desynchronize the peer reading the reply. The
+/// check is only made in debug builds; release builds trust the method.
+fn checked_raw_result(method: &str, result: String) -> std::result::Result<String, ERPCError> {
+    if cfg!(debug_assertions) {
+        let text = result.trim();
+        if crate::protocol::datum_len(text) != Some(text.len()) {
+            return Err(ERPCError::SerializationError(format!(
+                "raw method {} returned text that is not one expression",
+                method
+            )));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_raw_result() {
+        for good in ["nil", " (1 \"two)\" [3]) ", "\"a\\\"b\""] {
+            assert!(checked_raw_result("m", good.to_string()).is_ok());
+        }
+        if cfg!(debug_assertions) {
+            for bad in ["(1 2", "1 2", ")", "", "\"open"] {
+                assert!(checked_raw_result("m", bad.to_string()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_raw_call() {
+        assert_eq!(
+            split_raw_call("(call 12 echo (1 \"two\" #s(x)))"),
+            Some((12, "echo", "(1 \"two\" #s(x))"))
+        );
+        assert_eq!(
+            split_raw_call(" ( call 3 \"dash-name\" nil ) "),
+            Some((3, "dash-name", "nil"))
+        );
+        assert_eq!(split_raw_call("(return 1 nil)"), None);
+        assert_eq!(split_raw_call("(call x echo nil)"), None);
+        assert_eq!(split_raw_call("(call 1 echo)"), None);
+    }
+
+    #[tokio::test]
+    async fn test_server_bind() {
+        let mut server = Server::new();
+        let addr = server.bind("127.0.0.1:0").await.unwrap();
+        assert!(addr.port() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_builder_serves_registered_methods() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .max_connections(4)
+            .request_timeout(Duration::from_secs(5))
+            .method("echo", |s: String| Ok(s))
+            .value_method("identity", Ok)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(server.config.max_connections, 4);
+
+        let port = server.port().unwrap();
+        let client = crate::client::Client::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let reply: String = client.call_sync("echo", "hi").await.unwrap();
+        assert_eq!(reply, "hi");
+        let methods = client.query_methods().await.unwrap();
+        assert_eq!(methods.len(), 2);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_turns_away_extra_peers() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .max_connections(1)
+            .method("echo", |s: String| Ok(s))
+            .build()
+            .await
+            .unwrap();
+        let addr = format!("127.0.0.1:{}", server.port().unwrap());
+
+        let first = crate::client::Client::connect(addr.as_str()).await.unwrap();
+        let reply: String = first.call_sync("echo", "hi").await.unwrap();
+        assert_eq!(reply, "hi");
+
+        // The second peer is disconnected without being served
+        let mut second = TcpStream::connect(addr.as_str()).await.unwrap();
+        let mut buffer = BytesMut::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), second.read_buf(&mut buffer))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)), "{:?}", read);
+        assert_eq!(server.stats().rejected_connections, 1);
+
+        // Its slot is free again once the first peer leaves
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let third = crate::client::Client::connect(addr.as_str()).await.unwrap();
+        let reply: String = third.call_sync("echo", "again").await.unwrap();
+        assert_eq!(reply, "again");
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout_answers_with_epc_error() {
+        use crate::extract::RawValue;
+
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .request_timeout(Duration::from_millis(100))
+            .fn_method("slow", |RawValue(_): RawValue| async {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                Ok::<_, ERPCError>(true)
+            })
+            .build()
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        stream
+            .write_all(&Framer::frame(b"(call 5 slow nil)"))
+            .await
+            .unwrap();
+        let mut buffer = BytesMut::new();
+        let reply = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(frame) = Framer::extract_message(&mut buffer) {
+                    return String::from_utf8(frame.to_vec()).unwrap();
+                }
+                assert!(stream.read_buf(&mut buffer).await.unwrap() > 0);
+            }
+        })
+        .await
+        .unwrap();
+        assert!(reply.starts_with("(epc-error 5"), "{}", reply);
+        assert!(reply.contains("timed out"), "{}", reply);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_echo_method() {
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+
+        server
+            .register_method(
+                "echo",
+                |args: String| Ok(args),
+                Some("args"),
+                Some("Echo back arguments"),
+            )
+            .await
+            .unwrap();
+
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        // Test via TCP connection
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+
+        let message = Message::new_call(1, "echo", Value::from("hello"));
+        let message_str = message.to_sexp().unwrap();
+        let framed = Framer::frame(message_str.as_bytes());
+
+        stream.write_all(&framed).await.unwrap();
+
+        let mut buffer = BytesMut::new();
+        let bytes_read = stream.read_buf(&mut buffer).await.unwrap();
+        assert!(bytes_read > 0);
+
+        // Cleanup
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_methods_query() {
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+
+        server
+            .register_method(
+                "add",
+                |(a, b): (i64, i64)| Ok(a + b),
+                Some("a b"),
+                Some("Add two numbers"),
+            )
+            .await
+            .unwrap();
+
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        // Test methods query
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+
+        let message = Message::new_methods(1);
+        let message_str = message.to_sexp().unwrap();
+        let framed = Framer::frame(message_str.as_bytes());
+
+        stream.write_all(&framed).await.unwrap();
+
+        let mut buffer = BytesMut::new();
+        let bytes_read = stream.read_buf(&mut buffer).await.unwrap();
+        assert!(bytes_read > 0);
+
+        // Cleanup
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_responses_coalesced() {
+        let mut server = Server::with_config(ServerConfig {
+            flush_policy: FlushPolicy::Delay(Duration::from_millis(5)),
+            ..Default::default()
+        });
+        server.bind("127.0.0.1:0").await.unwrap();
+
+        server
+            .register_method("echo", |args: String| Ok(args), Some("args"), None::<&str>)
+            .await
+            .unwrap();
+
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+
+        // Send two calls in a single write
+        let mut request = BytesMut::new();
+        for uid in 1..=2 {
+            let message = Message::new_call(uid, "echo", Value::from("hello"));
+            Framer::frame_into(&mut request, message.to_sexp().unwrap().as_bytes());
+        }
+        stream.write_all(&request).await.unwrap();
+
+        let mut buffer = BytesMut::new();
+        let mut uids = Vec::new();
+        while uids.len() < 2 {
+            assert!(stream.read_buf(&mut buffer).await.unwrap() > 0);
+            while let Some(frame) = Framer::extract_message(&mut buffer) {
+                let message = Message::from_sexp(std::str::from_utf8(&frame).unwrap()).unwrap();
+                uids.push(message.uid());
+            }
+        }
+        // Calls are handled concurrently, so responses may arrive in any order
+        uids.sort();
+        assert_eq!(uids, vec![1, 2]);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_selector_overrides_policy() {
+        let mut server = Server::with_config(ServerConfig {
+            flush_policy: FlushPolicy::Delay(Duration::from_secs(60)),
+            flush_selector: Some(FlushSelector::new(|_| FlushPolicy::Immediate)),
+            ..Default::default()
+        });
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_method("echo", |args: String| Ok(args), Some("args"), None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let mut request = BytesMut::new();
+        let message = Message::new_call(1, "echo", Value::from("hello"));
+        Framer::frame_into(&mut request, message.to_sexp().unwrap().as_bytes());
+        stream.write_all(&request).await.unwrap();
+
+        // The server-wide delay would hold the reply for a minute
+        let mut buffer = BytesMut::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_buf(&mut buffer));
+        assert!(read.await.unwrap().unwrap() > 0);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue_response_overflow() {
+        let (tx, mut rx) = mpsc::channel(1);
+
+        queue_response(&tx, "first".to_string(), OverflowPolicy::Shed)
+            .await
+            .unwrap();
+        let shed = queue_response(&tx, "second".to_string(), OverflowPolicy::Shed).await;
+        assert!(matches!(shed, Err(ERPCError::QueueFull)));
+
+        // Blocking waits for the writer to make room
+        let blocked = tokio::spawn({
+            let tx = tx.clone();
+            async move { queue_response(&tx, "third".to_string(), OverflowPolicy::Block).await }
+        });
+        assert_eq!(rx.recv().await.unwrap(), "first");
+        blocked.await.unwrap().unwrap();
+        assert_eq!(rx.recv().await.unwrap(), "third");
+    }
+
+    #[tokio::test]
+    async fn test_shed_response_closes_connection() {
+        let mut server = Server::with_config(ServerConfig {
+            overflow_policy: OverflowPolicy::Shed,
+            outbound_queue_size: 1,
+            ..Default::default()
+        });
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_value_method(
+                "big",
+                |_| Ok(Value::string("x".repeat(8 << 20))),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        // Not reading stalls the writer, filling the queue
+        let calls = 6;
+        let mut request = BytesMut::new();
+        for uid in 1..=calls {
+            let message = Message::new_call(uid, "big", Value::Nil);
+            Framer::frame_into(&mut request, message.to_sexp().unwrap().as_bytes());
+        }
+        stream.write_all(&request).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // The peer sees the replies that were queued, then the connection
+        // closing instead of waiting on the shed ones
+        let mut buffer = BytesMut::new();
+        let mut replies = 0;
+        let closed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                while Framer::extract_message(&mut buffer).is_some() {
+                    replies += 1;
+                }
+                if stream.read_buf(&mut buffer).await.unwrap() == 0 {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(closed.is_ok(), "connection was not closed");
+        assert!(replies < calls);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dedicated_worker_runtime() {
+        let mut server = Server::with_config(ServerConfig {
+            worker_threads: Some(2),
+            max_worker_tasks: Some(4),
+            ..Default::default()
+        });
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_value_method(
+                "thread",
+                |_| {
+                    let name = std::thread::current().name().unwrap_or("").to_string();
+                    Ok(Value::from(name))
+                },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
 
-    debug!("Received message string: {}", message_str);
+        let client = crate::client::Client::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let name = client.call_value("thread", Value::Null).await.unwrap();
+        assert_eq!(name, Value::from("elrpc-worker"));
 
-    let message = Message::from_sexp(message_str)?;
+        client.close().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
 
-    debug!("Parsed message: {:?}", message);
+    #[tokio::test]
+    async fn test_partitioned_uids_refuse_the_other_half() {
+        let mut server = Server::with_config(ServerConfig {
+            partition_uids: true,
+            ..Default::default()
+        });
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_value_method("echo", Ok, Some("args"), None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
 
-    match message {
-        Message::Call { uid, method, args } => {
-            debug!(
-                "Processing CALL uid={}, method={}, args={:?}",
-                uid, method, args
-            );
-            match registry.call_method(&method, args).await {
-                Ok(result) => {
-                    debug!(
-                        "Method '{}' executed successfully, result: {:?}",
-                        method, result
-                    );
-                    let response = Message::new_return(uid, result);
-                    let sexp = response.to_sexp()?;
-                    debug!("Returning response: {}", sexp);
-                    Ok(sexp)
-                }
-                Err(e) => {
-                    error!("Method '{}' failed: {}", method, e);
-                    let response = Message::new_return_error(uid, e.to_string());
-                    let sexp = response.to_sexp()?;
-                    debug!("Returning error response: {}", sexp);
-                    Ok(sexp)
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let mut buffer = BytesMut::new();
+        async fn exchange(stream: &mut TcpStream, buffer: &mut BytesMut, request: &str) -> String {
+            stream
+                .write_all(&Framer::frame(request.as_bytes()))
+                .await
+                .unwrap();
+            loop {
+                if let Some(frame) = Framer::extract_message(buffer) {
+                    return String::from_utf8(frame.to_vec()).unwrap();
                 }
+                assert!(stream.read_buf(buffer).await.unwrap() > 0);
             }
         }
-        Message::Methods { uid } => {
-            debug!("Processing METHODS query uid={}", uid);
-            let methods = registry.query_methods().await?;
-            debug!("Found {} methods to return", methods.len());
-
-            // Create the expected format for methods response: list of [name, arg_spec, docstring]
-            let method_list = Value::list(
-                methods
-                    .into_iter()
-                    .map(|info| {
-                        Value::list(vec![
-                            Value::string(info.name),
-                            info.arg_spec.map(Value::string).unwrap_or(Value::Null),
-                            info.docstring.map(Value::string).unwrap_or(Value::Null),
-                        ])
-                    })
-                    .collect::<Vec<Value>>(),
-            );
 
-            let response = Message::new_return(uid, method_list);
-            let sexp = response.to_sexp()?;
-            debug!("Returning methods response: {}", sexp);
-            Ok(sexp)
-        }
-        _ => {
-            warn!("Received unexpected message type: {:?}", message);
-            Err(ERPCError::InvalidMessageFormat(format!(
-                "Unexpected message type: {:?}",
-                message
-            )))
-        }
-    }
-}
+        let reply = exchange(
+            &mut stream,
+            &mut buffer,
+            "(call 1 elrpc-partition-uids (odd))",
+        )
+        .await;
+        assert!(reply.contains("even"), "{}", reply);
+        let reply = exchange(&mut stream, &mut buffer, "(call 3 echo nil)").await;
+        assert!(reply.starts_with("(return 3"), "{}", reply);
+        let reply = exchange(&mut stream, &mut buffer, "(call 4 echo nil)").await;
+        assert!(reply.contains("outside"), "{}", reply);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        server.shutdown().await.unwrap();
+    }
 
     #[tokio::test]
-    async fn test_server_bind() {
-        let mut server = Server::new();
-        let addr = server.bind("127.0.0.1:0").await.unwrap();
-        assert!(addr.port() > 0);
+    async fn test_partitioned_uids_cover_raw_methods() {
+        let mut server = Server::with_config(ServerConfig {
+            partition_uids: true,
+            ..Default::default()
+        });
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_raw_method(
+                "raw-echo",
+                |args| Ok(args.to_string()),
+                Some("args"),
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let mut buffer = BytesMut::new();
+        async fn exchange(stream: &mut TcpStream, buffer: &mut BytesMut, request: &str) -> String {
+            stream
+                .write_all(&Framer::frame(request.as_bytes()))
+                .await
+                .unwrap();
+            loop {
+                if let Some(frame) = Framer::extract_message(buffer) {
+                    return String::from_utf8(frame.to_vec()).unwrap();
+                }
+                assert!(stream.read_buf(buffer).await.unwrap() > 0);
+            }
+        }
+
+        let reply = exchange(
+            &mut stream,
+            &mut buffer,
+            "(call 1 elrpc-partition-uids (odd))",
+        )
+        .await;
+        assert!(reply.contains("even"), "{}", reply);
+        let reply = exchange(&mut stream, &mut buffer, "(call 3 raw-echo (1 2))").await;
+        assert!(reply.starts_with("(return 3"), "{}", reply);
+        let reply = exchange(&mut stream, &mut buffer, "(call 4 raw-echo (1 2))").await;
+        assert!(reply.contains("outside"), "{}", reply);
+
+        server.shutdown().await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_echo_method() {
-        let mut server = Server::new();
+    async fn test_raw_methods_get_arguments_without_metadata() {
+        let mut server = Server::with_config(ServerConfig {
+            call_metadata: true,
+            ..Default::default()
+        });
         server.bind("127.0.0.1:0").await.unwrap();
-
         server
-            .register_method(
-                "echo",
-                |args: String| Ok(args),
+            .register_raw_method(
+                "raw-trace",
+                |args| {
+                    let metadata = CallContext::current().unwrap().metadata.unwrap();
+                    Ok(format!("(\"{}\" {})", metadata.trace_id().unwrap(), args))
+                },
                 Some("args"),
-                Some("Echo back arguments"),
+                None::<&str>,
             )
             .await
             .unwrap();
-
         let port = server.port().unwrap();
         server.serve().await.unwrap();
 
-        // Test via TCP connection
         let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
             .await
             .unwrap();
+        let mut buffer = BytesMut::new();
+        let mut replies = Vec::new();
+        for request in [
+            "(call 1 elrpc-call-metadata nil)",
+            "(call 2 raw-trace (elrpc-metadata (:trace-id \"4bf9\") 1 2))",
+        ] {
+            stream
+                .write_all(&Framer::frame(request.as_bytes()))
+                .await
+                .unwrap();
+            loop {
+                if let Some(frame) = Framer::extract_message(&mut buffer) {
+                    replies.push(String::from_utf8(frame.to_vec()).unwrap());
+                    break;
+                }
+                assert!(stream.read_buf(&mut buffer).await.unwrap() > 0);
+            }
+        }
+        assert_eq!(replies[1], "(return 2 (\"4bf9\" (1 2)))");
 
-        let message = Message::new_call(1, "echo", Value::from("hello"));
-        let message_str = message.to_sexp().unwrap();
-        let framed = Framer::frame(message_str.as_bytes());
+        server.shutdown().await.unwrap();
+    }
 
-        stream.write_all(&framed).await.unwrap();
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_raw_methods_refuse_reused_uids() {
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_raw_method(
+                "slow",
+                |args| {
+                    std::thread::sleep(Duration::from_millis(300));
+                    Ok(args.to_string())
+                },
+                Some("args"),
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
 
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            stream
+                .write_all(&Framer::frame(b"(call 7 slow nil)"))
+                .await
+                .unwrap();
+        }
         let mut buffer = BytesMut::new();
-        let bytes_read = stream.read_buf(&mut buffer).await.unwrap();
-        assert!(bytes_read > 0);
+        let mut replies = Vec::new();
+        while replies.len() < 2 {
+            if let Some(frame) = Framer::extract_message(&mut buffer) {
+                replies.push(String::from_utf8(frame.to_vec()).unwrap());
+                continue;
+            }
+            assert!(stream.read_buf(&mut buffer).await.unwrap() > 0);
+        }
+        assert!(replies[0].contains("reused"), "{:?}", replies);
+        assert!(replies[1].starts_with("(return 7"), "{:?}", replies);
+        assert_eq!(server.stats().uid_errors.uid_reuses, 1);
 
-        // Cleanup
         server.shutdown().await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_methods_query() {
-        let mut server = Server::new();
+    async fn test_call_quota_and_stats() {
+        let mut server = Server::with_config(ServerConfig {
+            quota: Some(QuotaConfig {
+                max_calls: Some(2),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
         server.bind("127.0.0.1:0").await.unwrap();
-
         server
-            .register_method(
-                "add",
-                |(a, b): (i64, i64)| Ok(a + b),
-                Some("a b"),
-                Some("Add two numbers"),
+            .register_value_method("echo", Ok, Some("args"), None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let client = crate::client::Client::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        client.call_value("echo", Value::from(1)).await.unwrap();
+        client.call_value("echo", Value::from(2)).await.unwrap();
+        let result = client.call_value("echo", Value::from(3)).await;
+        assert!(matches!(result, Err(ERPCError::ApplicationError { .. })));
+
+        let stats = server.stats();
+        assert_eq!(stats.total_connections, 1);
+        assert_eq!(stats.connections[0].total.calls, 2);
+        assert!(stats.connections[0].total.bytes_in > 0);
+        assert!(stats.connections[0].total.bytes_out > 0);
+
+        client.close().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_method_acl_checked_before_dispatch() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .method_acl(
+                MethodAcl::new()
+                    .allow_everyone(["get-*"])
+                    .allow("192.0.2.0/24".parse().unwrap(), ["*"]),
             )
+            .method("get-name", |s: String| Ok(s))
+            .method("set-name", |s: String| Ok(s))
+            .build()
+            .await
+            .unwrap();
+
+        let port = server.port().unwrap();
+        let client = crate::client::Client::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let name: String = client.call_sync("get-name", "a").await.unwrap();
+        assert_eq!(name, "a");
+        let result: std::result::Result<String, _> = client.call_sync("set-name", "b").await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("permission denied"));
+
+        let methods = client.query_methods().await.unwrap();
+        assert_eq!(methods.len(), 1);
+        assert_eq!(methods[0].name, "get-name");
+
+        client.close().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "audit")]
+    async fn test_audit_log_records_calls() {
+        let records = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .audit_log(AuditLog::new(move |record| {
+                sink.lock()
+                    .unwrap()
+                    .push(record.render(crate::audit::AuditFormat::Sexp));
+            }))
+            .method_acl(MethodAcl::new().allow_everyone(["echo"]))
+            .method("echo", |s: String| Ok(s))
+            .method("drop-table", |s: String| Ok(s))
+            .build()
             .await
             .unwrap();
 
         let port = server.port().unwrap();
+        let client = crate::client::Client::connect(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let _: String = client.call_sync("echo", "hi").await.unwrap();
+        let denied: std::result::Result<String, _> = client.call_sync("drop-table", "t").await;
+        assert!(denied.is_err());
+
+        let records = records.lock().unwrap().clone();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].contains(":method \"echo\" :outcome ok"));
+        assert!(records[0].contains(":args \"sha256:"));
+        assert!(records[1].contains(":method \"drop-table\" :outcome error"));
+
+        client.close().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_security_profile_limits() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .security(
+                SecurityProfile::new()
+                    .max_frame_size(16)
+                    .idle_timeout(Some(Duration::from_millis(100))),
+            )
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut buf = [0u8; 64];
+
+        // An oversized frame closes the connection before its body arrives
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"0000ff(call").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        // So does saying nothing
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let started = Instant::now();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix_stages_in_private_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("elrpc.sock");
+        let mut server = Server::new();
+        server.bind_unix(&path).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let entries: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec![std::ffi::OsString::from("elrpc.sock")]);
+
+        // An existing file is left alone rather than replaced
+        let mut second = Server::new();
+        assert!(second.bind_unix(&path).await.is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_mode_and_credentials() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("elrpc.sock");
+        let mut server = Server::new();
+        server
+            .register_value_method(
+                "whoami",
+                |_| {
+                    let cx = CallContext::current().unwrap();
+                    Ok(Value::from(
+                        cx.credentials.map_or(-1, |creds| creds.uid as i64),
+                    ))
+                },
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        server.bind_unix(&path).await.unwrap();
         server.serve().await.unwrap();
 
-        // Test methods query
-        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(&Framer::frame(b"(call 1 whoami nil)"))
             .await
             .unwrap();
+        let mut buf = BytesMut::new();
+        let response = loop {
+            if let Some(message) = Framer::extract_message(&mut buf) {
+                break message;
+            }
+            assert!(stream.read_buf(&mut buf).await.unwrap() > 0);
+        };
+        assert_eq!(
+            std::str::from_utf8(&response).unwrap(),
+            format!("(return 1 {})", metadata.uid())
+        );
+        assert_eq!(server.stats().total_connections, 1);
 
-        let message = Message::new_methods(1);
-        let message_str = message.to_sexp().unwrap();
-        let framed = Framer::frame(message_str.as_bytes());
+        drop(stream);
+        server.shutdown().await.unwrap();
+        assert!(!path.exists());
+    }
 
-        stream.write_all(&framed).await.unwrap();
+    struct Token(&'static str);
 
-        let mut buffer = BytesMut::new();
-        let bytes_read = stream.read_buf(&mut buffer).await.unwrap();
-        assert!(bytes_read > 0);
+    #[async_trait::async_trait]
+    impl Authenticator for Token {
+        fn requires_handshake(&self) -> bool {
+            true
+        }
 
-        // Cleanup
+        async fn authenticate(
+            &self,
+            handshake: &Handshake,
+        ) -> std::result::Result<Identity, ERPCError> {
+            match handshake.data.as_ref().and_then(|data| data.as_str()) {
+                Some(token) if token == self.0 => Ok(Identity::new("holder").with_data(7u32)),
+                _ => Err(ERPCError::PermissionDenied("bad token".to_string())),
+            }
+        }
+    }
+
+    /// Refuses every peer without a handshake
+    struct Nobody;
+
+    #[async_trait::async_trait]
+    impl Authenticator for Nobody {
+        async fn authenticate(
+            &self,
+            handshake: &Handshake,
+        ) -> std::result::Result<Identity, ERPCError> {
+            assert!(handshake.data.is_none());
+            Err(ERPCError::PermissionDenied(handshake.peer.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authenticator_refuses_peers() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .authenticator(Nobody)
+            .build()
+            .await
+            .unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        let stats = server.stats();
+        assert_eq!(stats.rejected_connections, 1);
+        assert_eq!(stats.total_connections, 0);
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_handshake_identity() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .authenticator(Token("secret"))
+            .value_method("whoami", |_| {
+                let identity = CallContext::current().unwrap().identity.unwrap();
+                assert_eq!(identity.data::<u32>(), Some(&7));
+                Ok(Value::string(identity.name()))
+            })
+            .build()
+            .await
+            .unwrap();
+        let addr = format!("127.0.0.1:{}", server.port().unwrap());
+
+        let config = |token: &str| crate::client::ClientConfig {
+            auth_data: Some(Value::string(token)),
+            ..Default::default()
+        };
+        let client = crate::client::Client::connect_with_config(&addr, config("secret"))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.call_value("whoami", Value::Null).await.unwrap(),
+            Value::string("holder")
+        );
+        client.close().await.unwrap();
+
+        let refused = crate::client::Client::connect_with_config(&addr, config("guess")).await;
+        assert!(refused.is_err());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_method_acl_matches_identity() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .authenticator(Token("secret"))
+            .method_acl(
+                MethodAcl::new()
+                    .allow_everyone(["get-*"])
+                    .allow_identity("holder", ["set-*"])
+                    .allow_identity("someone-else", ["*"]),
+            )
+            .method("get-name", |s: String| Ok(s))
+            .method("set-name", |s: String| Ok(s))
+            .method("drop-table", |s: String| Ok(s))
+            .build()
+            .await
+            .unwrap();
+        let config = crate::client::ClientConfig {
+            auth_data: Some(Value::string("secret")),
+            ..Default::default()
+        };
+        let client = crate::client::Client::connect_with_config(
+            format!("127.0.0.1:{}", server.port().unwrap()),
+            config,
+        )
+        .await
+        .unwrap();
+
+        let name: String = client.call_sync("set-name", "b").await.unwrap();
+        assert_eq!(name, "b");
+        let denied: std::result::Result<String, _> = client.call_sync("drop-table", "t").await;
+        assert!(denied
+            .unwrap_err()
+            .to_string()
+            .contains("permission denied"));
+
+        client.close().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_abusive_peer_is_banned() {
+        let bans = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = bans.clone();
+        let detector = AbuseDetector::new()
+            .threshold(2)
+            .on_event(move |event| seen.lock().unwrap().push(event.clone()));
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .security(SecurityProfile::new().max_frame_size(16))
+            .abuse_detector(detector.clone())
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut buf = [0u8; 64];
+
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"0000ff(call").await.unwrap();
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        }
+        assert!(detector.is_banned(addr.ip()));
+        assert_eq!(bans.lock().unwrap().len(), 1);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        let stats = server.stats();
+        assert_eq!(stats.rejected_connections, 1);
+        assert_eq!(stats.total_connections, 2);
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peer_filter_rejects_at_accept() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .peer_filter(PeerFilter::new().deny("127.0.0.0/8".parse().unwrap()))
+            .build()
+            .await
+            .unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        let stats = server.stats();
+        assert_eq!(stats.rejected_connections, 1);
+        assert_eq!(stats.total_connections, 0);
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wire_tap_sees_both_directions() {
+        let frames = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = frames.clone();
+        let tap = WireTap::new(move |frame| {
+            seen.lock().unwrap().push((
+                frame.direction,
+                String::from_utf8_lossy(frame.payload).into_owned(),
+            ));
+        });
+
+        let mut server = Server::builder()
+            .config(ServerConfig {
+                wire_tap: Some(tap),
+                ..Default::default()
+            })
+            .value_method("echo", Ok)
+            .build()
+            .await
+            .unwrap();
+        let client =
+            crate::client::Client::connect(format!("127.0.0.1:{}", server.port().unwrap()))
+                .await
+                .unwrap();
+        client.call_value("echo", Value::from(42)).await.unwrap();
+        client.close().await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let frames = frames.lock().unwrap();
+        assert_eq!(
+            *frames,
+            vec![
+                (Direction::Inbound, "(call 1 echo 42)".to_string()),
+                (Direction::Outbound, "(return 1 42)".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_python_epc_compat() {
+        let mut server = Server::builder()
+            .compat(Compat::PythonEpc)
+            .value_method("echo", Ok)
+            .value_method("fail", |_| {
+                Err(ERPCError::ApplicationError {
+                    class: "ValueError".to_string(),
+                    message: "bad value".to_string(),
+                    backtrace: vec!["handler".to_string()],
+                })
+            })
+            .build()
+            .await
+            .unwrap();
+        let config = crate::client::ClientConfig {
+            compat: Compat::PythonEpc,
+            ..Default::default()
+        };
+        let client = crate::client::Client::connect_with_config(
+            format!("127.0.0.1:{}", server.port().unwrap()),
+            config,
+        )
+        .await
+        .unwrap();
+
+        let args = Value::list(vec![Value::from(1), Value::from("two")]);
+        assert_eq!(client.call_value("echo", args.clone()).await.unwrap(), args);
+        let error = client.call_value("fail", Value::Null).await.unwrap_err();
+        assert!(matches!(
+            error,
+            ERPCError::ApplicationError { class, backtrace, .. }
+                if class == "ValueError" && backtrace == ["handler"]
+        ));
+
+        client.close().await.unwrap();
         server.shutdown().await.unwrap();
     }
 }
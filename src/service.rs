@@ -0,0 +1,201 @@
+//! `tower::Service` adapters
+//!
+//! With the `tower` feature, EPC calls can go through tower middleware such
+//! as retries, timeouts, load shedding and rate limits. Both sides speak
+//! [`Call`]:
+//!
+//! - [`ClientService`] makes each call on a [`Client`], so a client can be
+//!   wrapped in layers like any other service.
+//! - [`MethodRegistry::mount_service`] dispatches every call the registry
+//!   has no method for to a service, so a server can be built from one.
+//!
+//! ```ignore
+//! let client = ClientService::new(Client::connect(addr).await?);
+//! let mut client = tower::ServiceBuilder::new()
+//!     .timeout(Duration::from_secs(5))
+//!     .service(client);
+//! let sum = client.ready().await?.call(Call::new("add", args)).await?;
+//! ```
+//!
+//! Errors from middleware are turned into `ERPCError::ApplicationError` with
+//! class `ServiceError`, unless they already are an [`ERPCError`].
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use lexpr::Value;
+use tower_service::Service;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+
+#[cfg(doc)]
+use crate::registry::MethodRegistry;
+
+/// Boxed future of an EPC call
+pub type CallFuture =
+    Pin<Box<dyn Future<Output = std::result::Result<Value, ERPCError>> + Send + 'static>>;
+
+/// Error type of tower middleware
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A call as a service request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub method: String,
+    pub args: Value,
+}
+
+impl Call {
+    pub fn new(method: impl Into<String>, args: Value) -> Self {
+        Call {
+            method: method.into(),
+            args,
+        }
+    }
+}
+
+/// The [`ERPCError`] a service error stands for
+pub fn service_error(error: impl Into<BoxError>) -> ERPCError {
+    match error.into().downcast::<ERPCError>() {
+        Ok(error) => *error,
+        Err(error) => ERPCError::ApplicationError {
+            class: "ServiceError".to_string(),
+            message: error.to_string(),
+            backtrace: vec![],
+        },
+    }
+}
+
+/// A [`Client`] as a service
+///
+/// Clones share the connection. The service is always ready; the client's
+/// own in-flight limit applies to calls.
+#[derive(Clone)]
+pub struct ClientService {
+    client: Arc<Client>,
+}
+
+impl ClientService {
+    pub fn new(client: Client) -> Self {
+        ClientService {
+            client: Arc::new(client),
+        }
+    }
+
+    /// The client calls go through
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl From<Arc<Client>> for ClientService {
+    fn from(client: Arc<Client>) -> Self {
+        ClientService { client }
+    }
+}
+
+impl Service<Call> for ClientService {
+    type Response = Value;
+    type Error = ERPCError;
+    type Future = CallFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::result::Result<(), ERPCError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, call: Call) -> Self::Future {
+        let client = self.client.clone();
+        Box::pin(async move { client.call_value(&call.method, call.args).await })
+    }
+}
+
+/// Calls dispatched to a mounted service
+pub(crate) type Dispatcher = dyn Fn(Call) -> CallFuture + Send + Sync;
+
+/// Turn `service` into a dispatcher, waiting for readiness on each call
+pub(crate) fn dispatcher<S>(service: S) -> Arc<Dispatcher>
+where
+    S: Service<Call, Response = Value> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    Arc::new(move |call| {
+        let mut service = service.clone();
+        Box::pin(async move {
+            std::future::poll_fn(|cx| service.poll_ready(cx))
+                .await
+                .map_err(service_error)?;
+            service.call(call).await.map_err(service_error)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    /// Answers every call with its method name
+    #[derive(Clone)]
+    struct MethodName;
+
+    impl Service<Call> for MethodName {
+        type Response = Value;
+        type Error = BoxError;
+        type Future = Pin<Box<dyn Future<Output = std::result::Result<Value, BoxError>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::result::Result<(), BoxError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, call: Call) -> Self::Future {
+            Box::pin(async move {
+                if call.method == "fail" {
+                    return Err("refused".into());
+                }
+                Ok(Value::string(call.method))
+            })
+        }
+    }
+
+    #[test]
+    fn test_service_error() {
+        assert!(matches!(
+            service_error(ERPCError::Timeout),
+            ERPCError::Timeout
+        ));
+        let error = service_error("overloaded");
+        assert_eq!(
+            error.to_string(),
+            "application error: ServiceError: overloaded"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_and_server_services() {
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .value_method("echo", Ok)
+            .build()
+            .await
+            .unwrap();
+        server.registry().mount_service(MethodName).await;
+
+        let client = Client::connect(format!("127.0.0.1:{}", server.port().unwrap()))
+            .await
+            .unwrap();
+        let mut service = ClientService::new(client);
+        // Registered methods come first
+        let echoed = service.call(Call::new("echo", Value::from(1))).await;
+        assert_eq!(echoed.unwrap(), Value::from(1));
+        let named = service.call(Call::new("anything", Value::Nil)).await;
+        assert_eq!(named.unwrap(), Value::string("anything"));
+        assert!(service.call(Call::new("fail", Value::Nil)).await.is_err());
+
+        service.client().close().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+}
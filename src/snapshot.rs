@@ -0,0 +1,220 @@
+//! Wire-format snapshots
+//!
+//! Deployed Emacs packages parse exactly what this crate writes, so a change
+//! to how a message or value is printed can break them even when every
+//! Rust-side test passes. [`WireSnapshots`] pins the wire form: each snapshot
+//! is a file holding one frame, length header included, and a check fails
+//! when the frame produced now differs from the stored one.
+//!
+//! ```no_run
+//! use elrpc::snapshot::WireSnapshots;
+//! use elrpc::Message;
+//! use lexpr::Value;
+//!
+//! let snapshots = WireSnapshots::new("tests/snapshots");
+//! snapshots.assert_message("return-string", &Message::new_return(1, Value::string("hi")));
+//! ```
+//!
+//! Snapshots are written, never compared, when [`UPDATE_ENV`] is set, e.g.
+//! `ELRPC_UPDATE_SNAPSHOTS=1 cargo test`. A failed check shows both frames
+//! and a line diff of their indented renderings.
+
+use std::path::PathBuf;
+
+use lexpr::Value;
+
+use crate::error::ERPCError;
+use crate::pretty::PrettyConfig;
+use crate::protocol::{Framer, Message};
+
+/// Environment variable that makes checks rewrite their snapshots
+pub const UPDATE_ENV: &str = "ELRPC_UPDATE_SNAPSHOTS";
+
+/// Directory of stored wire snapshots
+#[derive(Debug, Clone)]
+pub struct WireSnapshots {
+    dir: PathBuf,
+    update: bool,
+}
+
+impl WireSnapshots {
+    /// Snapshots stored in `dir`, updated if [`UPDATE_ENV`] is set
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        WireSnapshots {
+            dir: dir.into(),
+            update: std::env::var_os(UPDATE_ENV).is_some_and(|value| value != "0"),
+        }
+    }
+
+    /// Write snapshots instead of comparing them
+    pub fn update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    /// File snapshot `name` is stored in
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.epc", name))
+    }
+
+    /// Compare the frame of `message` with snapshot `name`
+    pub fn check_message(
+        &self,
+        name: &str,
+        message: &Message,
+    ) -> std::result::Result<(), ERPCError> {
+        self.check_payload(name, &message.to_sexp()?)
+    }
+
+    /// Compare the printed form of `value` with snapshot `name`
+    pub fn check_value(&self, name: &str, value: &Value) -> std::result::Result<(), ERPCError> {
+        let payload =
+            lexpr::to_string(value).map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+        self.check_payload(name, &payload)
+    }
+
+    /// Compare the frame of `payload` with snapshot `name`
+    pub fn check_payload(&self, name: &str, payload: &str) -> std::result::Result<(), ERPCError> {
+        let actual = String::from_utf8_lossy(&Framer::frame(payload.as_bytes())).into_owned();
+        let path = self.path(name);
+        if self.update {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, format!("{}\n", actual))?;
+            return Ok(());
+        }
+
+        let expected = match std::fs::read_to_string(&path) {
+            Ok(stored) => stored.trim_end_matches(['\r', '\n']).to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ERPCError::ProtocolError(format!(
+                    "no wire snapshot {}; run with {}=1 to write it",
+                    path.display(),
+                    UPDATE_ENV
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if expected == actual {
+            return Ok(());
+        }
+        Err(ERPCError::ProtocolError(format!(
+            "wire snapshot {} changed\nstored: {}\nnow:    {}\n{}",
+            name,
+            expected,
+            actual,
+            diff(&render(&expected), &render(&actual))
+        )))
+    }
+
+    /// Panic unless the frame of `message` matches snapshot `name`
+    pub fn assert_message(&self, name: &str, message: &Message) {
+        if let Err(e) = self.check_message(name, message) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Panic unless the printed form of `value` matches snapshot `name`
+    pub fn assert_value(&self, name: &str, value: &Value) {
+        if let Err(e) = self.check_value(name, value) {
+            panic!("{}", e);
+        }
+    }
+}
+
+/// Indented rendering of a frame, or the frame itself if it does not parse
+fn render(frame: &str) -> String {
+    let payload = frame.get(6..).unwrap_or(frame);
+    let untruncated = PrettyConfig {
+        width: 60,
+        max_depth: usize::MAX,
+        max_items: usize::MAX,
+        max_string_len: usize::MAX,
+    };
+    match lexpr::from_str(payload) {
+        Ok(value) => untruncated.render(&value),
+        Err(_) => payload.to_string(),
+    }
+}
+
+/// Line diff of `expected` and `actual`, with `-` marking lines only in the
+/// first and `+` lines only in the second
+pub fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+
+    // Longest common subsequence lengths of the suffixes
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            out.push_str(&format!("  {}\n", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            out.push_str(&format!("- {}\n", old[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!("+ {}\n", new[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_diff_marks_changed_lines() {
+        assert_eq!(
+            diff("(return 1\n        nil)", "(return 1\n        t)"),
+            "  (return 1\n-         nil)\n+         t)\n"
+        );
+        assert_eq!(diff("same", "same"), "  same\n");
+    }
+
+    #[test]
+    fn test_check_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = WireSnapshots::new(dir.path()).update(false);
+        assert!(snapshots.check_payload("methods", "(methods 1)").is_err());
+
+        snapshots
+            .clone()
+            .update(true)
+            .check_payload("methods", "(methods 1)")
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(snapshots.path("methods")).unwrap(),
+            "00000b(methods 1)\n"
+        );
+        snapshots.check_payload("methods", "(methods 1)").unwrap();
+    }
+
+    #[test]
+    fn test_message_wire_format() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/snapshots");
+        let snapshots = WireSnapshots::new(dir);
+        let hello = Value::list(vec![Value::string("hello")]);
+        snapshots.assert_message("call", &Message::new_call(1, "echo", hello));
+        snapshots.assert_message("return", &Message::new_return(1, Value::string("hi")));
+        snapshots.assert_message("return-error", &Message::new_return_error(2, "boom"));
+        snapshots.assert_message("epc-error", &Message::new_epc_error(3, "no such method"));
+        snapshots.assert_message("methods", &Message::new_methods(4));
+    }
+}
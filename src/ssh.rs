@@ -0,0 +1,272 @@
+//! Client connections tunneled through SSH
+//!
+//! EPC servers usually listen on localhost only, so reaching one on another
+//! machine takes a tunnel. [`Client::connect_ssh`](crate::Client::connect_ssh)
+//! runs `ssh -W` to forward one connection and speaks EPC over the
+//! command's stdin and stdout, without a local port or a tunnel to tear
+//! down afterwards:
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use elrpc::ssh::SshConfig;
+//! use elrpc::{Client, ClientConfig};
+//!
+//! let client = Client::connect_ssh("alice@build-box", 12345).await?;
+//!
+//! let ssh = SshConfig::new("build-box", 12345)
+//!     .arg("-i")
+//!     .arg("/home/alice/.ssh/epc_key");
+//! let client = Client::connect_ssh_with_config(ssh, ClientConfig::default()).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `ssh` runs in batch mode: it cannot prompt for passwords or host keys,
+//! as its stdin carries the connection, so keys must be loaded or agent
+//! based and the host known. When `ssh` fails, the connection closes and
+//! its error output is logged as warnings. The `ssh` process is killed
+//! when the client is dropped.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use crate::error::ERPCError;
+use crate::log::warn;
+
+/// How to reach a server through `ssh`
+#[derive(Debug, Clone)]
+pub struct SshConfig {
+    destination: String,
+    remote_host: String,
+    remote_port: u16,
+    program: String,
+    args: Vec<String>,
+}
+
+impl SshConfig {
+    /// Connect to `remote_port` on localhost of `destination`
+    pub fn new(destination: impl Into<String>, remote_port: u16) -> Self {
+        SshConfig {
+            destination: destination.into(),
+            remote_host: "localhost".to_string(),
+            remote_port,
+            program: "ssh".to_string(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Host the server runs on as seen from `destination`, for servers
+    /// behind a jump host
+    pub fn remote_host(mut self, host: impl Into<String>) -> Self {
+        self.remote_host = host.into();
+        self
+    }
+
+    /// Run `program` instead of `ssh`
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Pass `arg` to `ssh`, e.g. `-p`, `-i` or `-J` and their values
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Arguments `ssh` is run with
+    ///
+    /// `--` ends the options, so a destination starting with `-` cannot be
+    /// taken for one.
+    fn command_args(&self) -> Vec<String> {
+        let mut args = self.args.clone();
+        args.extend([
+            "-T".to_string(),
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-W".to_string(),
+            self.forward_target(),
+            "--".to_string(),
+            self.destination.clone(),
+        ]);
+        args
+    }
+
+    /// `host:port` argument of `-W`, with IPv6 addresses in brackets
+    fn forward_target(&self) -> String {
+        let host = self.remote_host.as_str();
+        if host.contains(':') && !host.starts_with('[') {
+            format!("[{}]:{}", host, self.remote_port)
+        } else {
+            format!("{}:{}", host, self.remote_port)
+        }
+    }
+
+    /// Start `ssh` forwarding a connection to the server
+    pub(crate) fn open(&self) -> std::result::Result<SshStream, ERPCError> {
+        let mut child = Command::new(&self.program)
+            .args(self.command_args())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| ERPCError::ProcessError(format!("cannot run {}: {}", self.program, e)))?;
+
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(ERPCError::ProcessError(format!(
+                "{} has no stdio",
+                self.program
+            )));
+        };
+        if let Some(stderr) = child.stderr.take() {
+            let tunnel = self.to_string();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    warn!("ssh to {}: {}", tunnel, line);
+                }
+            });
+        }
+        Ok(SshStream {
+            _child: child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+impl fmt::Display for SshConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.destination, self.remote_host, self.remote_port
+        )
+    }
+}
+
+/// The stdio of a running `ssh -W`, as one byte stream
+pub(crate) struct SshStream {
+    /// Killed on drop
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl AsyncRead for SshStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_args() {
+        let ssh = SshConfig::new("alice@build-box", 12345)
+            .remote_host("10.0.0.5")
+            .arg("-p")
+            .arg("2222");
+        assert_eq!(
+            ssh.command_args(),
+            vec![
+                "-p",
+                "2222",
+                "-T",
+                "-o",
+                "BatchMode=yes",
+                "-W",
+                "10.0.0.5:12345",
+                "--",
+                "alice@build-box"
+            ]
+        );
+        assert_eq!(ssh.to_string(), "alice@build-box:10.0.0.5:12345");
+    }
+
+    #[test]
+    fn test_command_args_quote_destination_and_ipv6() {
+        let args = SshConfig::new("-oProxyCommand=touch /tmp/pwned", 7000)
+            .remote_host("fd00::5")
+            .command_args();
+        assert_eq!(
+            &args[args.len() - 4..],
+            [
+                "-W",
+                "[fd00::5]:7000",
+                "--",
+                "-oProxyCommand=touch /tmp/pwned"
+            ]
+        );
+
+        let args = SshConfig::new("box", 7000)
+            .remote_host("[::1]")
+            .command_args();
+        assert!(args.contains(&"[::1]:7000".to_string()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_client_over_stdio_tunnel() {
+        use crate::{Client, ClientConfig, Server};
+
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_method("echo", |s: String| Ok(s), None::<&str>, None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        // bash forwards stdio to the port the way `ssh -W` would; the ssh
+        // arguments end up as its positional parameters
+        let script = format!(
+            "exec 3<>/dev/tcp/127.0.0.1/{}; cat <&3 & exec cat >&3",
+            port
+        );
+        let ssh = SshConfig::new("unused", port)
+            .program("bash")
+            .arg("-c")
+            .arg(script);
+        let client = Client::connect_ssh_with_config(ssh, ClientConfig::default())
+            .await
+            .unwrap();
+        let reply: String = client.call_sync("echo", "tunneled").await.unwrap();
+        assert_eq!(reply, "tunneled");
+
+        drop(client);
+        server.shutdown().await.unwrap();
+    }
+}
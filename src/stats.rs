@@ -0,0 +1,418 @@
+//! Per-connection usage accounting and quotas
+//!
+//! Every connection owns a [`ConnectionUsage`] counting calls, bytes and
+//! handler time, both in total and for the current quota window. The server
+//! keeps them in [`ServerStats`] so operators can inspect usage at runtime,
+//! along with counts of [uid correlation errors](UidErrors).
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::context::Peer;
+use crate::error::ERPCError;
+use crate::wiretap::next_connection_id;
+
+/// What happens to calls from a client that exceeded its quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Answer with a `QuotaExceeded` error
+    Reject,
+    /// Delay the call until the quota window resets
+    Throttle,
+}
+
+/// Per-connection limits applied over a fixed window
+#[derive(Debug, Clone)]
+pub struct QuotaConfig {
+    /// Length of the accounting window
+    pub window: Duration,
+    /// Maximum calls per window
+    pub max_calls: Option<u64>,
+    /// Maximum request plus response bytes per window
+    pub max_bytes: Option<u64>,
+    /// Maximum total handler execution time per window
+    pub max_handler_time: Option<Duration>,
+    pub action: QuotaAction,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            window: Duration::from_secs(60),
+            max_calls: None,
+            max_bytes: None,
+            max_handler_time: None,
+            action: QuotaAction::Reject,
+        }
+    }
+}
+
+/// Usage counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub calls: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub handler_time: Duration,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.calls += other.calls;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.handler_time += other.handler_time;
+    }
+
+    /// Name of the first limit this usage has reached, if any
+    fn exceeded(&self, quota: &QuotaConfig) -> Option<&'static str> {
+        if quota.max_calls.is_some_and(|max| self.calls >= max) {
+            Some("calls")
+        } else if quota
+            .max_bytes
+            .is_some_and(|max| self.bytes_in + self.bytes_out >= max)
+        {
+            Some("bytes")
+        } else if quota
+            .max_handler_time
+            .is_some_and(|max| self.handler_time >= max)
+        {
+            Some("handler time")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UsageState {
+    total: Usage,
+    window: Usage,
+    window_start: Instant,
+}
+
+/// Usage of a single connection
+#[derive(Debug)]
+pub struct ConnectionUsage {
+    id: u64,
+    addr: Peer,
+    window: Duration,
+    state: Mutex<UsageState>,
+}
+
+impl ConnectionUsage {
+    pub fn new(addr: Peer, window: Duration) -> Self {
+        ConnectionUsage {
+            id: next_connection_id(),
+            addr,
+            window,
+            state: Mutex::new(UsageState {
+                total: Usage::default(),
+                window: Usage::default(),
+                window_start: Instant::now(),
+            }),
+        }
+    }
+
+    /// Process-wide unique id of the connection, as wire taps see it
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Peer address of the connection
+    pub fn addr(&self) -> Peer {
+        self.addr
+    }
+
+    fn record(&self, delta: Usage) {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state);
+        state.total.add(&delta);
+        state.window.add(&delta);
+    }
+
+    fn roll(&self, state: &mut UsageState) {
+        if state.window_start.elapsed() >= self.window {
+            state.window = Usage::default();
+            state.window_start = Instant::now();
+        }
+    }
+
+    /// Record an incoming frame
+    pub fn record_bytes_in(&self, bytes: usize) {
+        self.record(Usage {
+            bytes_in: bytes as u64,
+            ..Default::default()
+        });
+    }
+
+    /// Record an outgoing frame
+    pub fn record_bytes_out(&self, bytes: usize) {
+        self.record(Usage {
+            bytes_out: bytes as u64,
+            ..Default::default()
+        });
+    }
+
+    /// Record time spent in a handler
+    pub fn record_handler_time(&self, elapsed: Duration) {
+        self.record(Usage {
+            handler_time: elapsed,
+            ..Default::default()
+        });
+    }
+
+    /// Admit a call against the quota, counting it if admitted
+    ///
+    /// Over-quota calls fail with `ERPCError::QuotaExceeded` under
+    /// [`QuotaAction::Reject`], or wait for the next window under
+    /// [`QuotaAction::Throttle`].
+    pub async fn admit_call(
+        &self,
+        quota: Option<&QuotaConfig>,
+    ) -> std::result::Result<(), ERPCError> {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                self.roll(&mut state);
+
+                match quota.and_then(|quota| state.window.exceeded(quota)) {
+                    None => {
+                        state.total.calls += 1;
+                        state.window.calls += 1;
+                        return Ok(());
+                    }
+                    Some(limit) => match quota.map(|quota| quota.action) {
+                        Some(QuotaAction::Throttle) => {
+                            self.window.saturating_sub(state.window_start.elapsed())
+                        }
+                        _ => {
+                            return Err(ERPCError::QuotaExceeded(format!(
+                                "{} quota exceeded for {}",
+                                limit, self.addr
+                            )))
+                        }
+                    },
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Lifetime usage
+    pub fn total(&self) -> Usage {
+        self.state.lock().unwrap().total
+    }
+
+    /// Usage in the current quota window
+    pub fn current_window(&self) -> Usage {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state);
+        state.window
+    }
+}
+
+/// Usage report for one connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStats {
+    pub id: u64,
+    pub addr: Peer,
+    pub total: Usage,
+    pub window: Usage,
+}
+
+/// Uid correlation errors seen, by kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UidErrors {
+    /// Replies to calls that had been answered already
+    pub duplicate_returns: u64,
+    /// Replies to calls that were never made
+    pub unknown_uids: u64,
+    /// Calls reusing the uid of a call still in flight
+    pub uid_reuses: u64,
+}
+
+/// Live counters behind [`UidErrors`]
+#[derive(Debug, Default)]
+pub struct UidErrorCounters {
+    duplicate_returns: AtomicU64,
+    unknown_uids: AtomicU64,
+    uid_reuses: AtomicU64,
+}
+
+impl UidErrorCounters {
+    /// Count `error` if it is a uid correlation error
+    pub fn record(&self, error: &ERPCError) {
+        let counter = match error {
+            ERPCError::DuplicateReturn(_) => &self.duplicate_returns,
+            ERPCError::UnknownUid(_) => &self.unknown_uids,
+            ERPCError::UidReuse(_) => &self.uid_reuses,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> UidErrors {
+        UidErrors {
+            duplicate_returns: self.duplicate_returns.load(Ordering::Relaxed),
+            unknown_uids: self.unknown_uids.load(Ordering::Relaxed),
+            uid_reuses: self.uid_reuses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time view of server statistics
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsSnapshot {
+    /// Connections accepted since the server started
+    pub total_connections: u64,
+    /// Connections closed at accept time by the peer filter
+    pub rejected_connections: u64,
+    /// Uid correlation errors of peers since the server started
+    pub uid_errors: UidErrors,
+    /// Currently open connections
+    pub connections: Vec<ConnectionStats>,
+}
+
+/// Live statistics shared by a server's connections
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    total_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    uid_errors: UidErrorCounters,
+    connections: Mutex<HashMap<u64, Arc<ConnectionUsage>>>,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        ServerStats::default()
+    }
+
+    /// Start tracking a connection
+    pub fn register(&self, usage: Arc<ConnectionUsage>) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(usage.id(), usage);
+    }
+
+    /// Count a connection refused at accept time
+    pub fn record_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `error` if it is a uid correlation error
+    pub fn record_uid_error(&self, error: &ERPCError) {
+        self.uid_errors.record(error);
+    }
+
+    /// Stop tracking the closed connection `id`
+    pub fn unregister_connection(&self, id: u64) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    /// Usage of the open connection `id`
+    pub fn connection(&self, id: u64) -> Option<Arc<ConnectionUsage>> {
+        self.connections.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let connections = self.connections.lock().unwrap();
+        StatsSnapshot {
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+            uid_errors: self.uid_errors.snapshot(),
+            connections: connections
+                .values()
+                .map(|usage| ConnectionStats {
+                    id: usage.id(),
+                    addr: usage.addr(),
+                    total: usage.total(),
+                    window: usage.current_window(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> Peer {
+        Peer::Tcp("127.0.0.1:4000".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_call_quota_rejects() {
+        let quota = QuotaConfig {
+            max_calls: Some(2),
+            ..Default::default()
+        };
+        let usage = ConnectionUsage::new(addr(), quota.window);
+
+        usage.admit_call(Some(&quota)).await.unwrap();
+        usage.admit_call(Some(&quota)).await.unwrap();
+        let result = usage.admit_call(Some(&quota)).await;
+        assert!(matches!(result, Err(ERPCError::QuotaExceeded(_))));
+        assert_eq!(usage.total().calls, 2);
+    }
+
+    #[tokio::test]
+    async fn test_throttle_waits_for_next_window() {
+        let quota = QuotaConfig {
+            window: Duration::from_millis(50),
+            max_bytes: Some(100),
+            action: QuotaAction::Throttle,
+            ..Default::default()
+        };
+        let usage = ConnectionUsage::new(addr(), quota.window);
+
+        usage.record_bytes_in(150);
+        let start = Instant::now();
+        usage.admit_call(Some(&quota)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(usage.current_window().bytes_in, 0);
+        assert_eq!(usage.total().bytes_in, 150);
+    }
+
+    #[test]
+    fn test_snapshot_tracks_open_connections() {
+        let stats = ServerStats::new();
+        let usage = Arc::new(ConnectionUsage::new(addr(), Duration::from_secs(60)));
+        stats.register(usage.clone());
+        usage.record_handler_time(Duration::from_millis(5));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total_connections, 1);
+        assert_eq!(
+            snapshot.connections[0].total.handler_time,
+            Duration::from_millis(5)
+        );
+
+        assert!(Arc::ptr_eq(&stats.connection(usage.id()).unwrap(), &usage));
+        stats.unregister_connection(usage.id());
+        assert!(stats.snapshot().connections.is_empty());
+        assert!(stats.connection(usage.id()).is_none());
+    }
+
+    #[test]
+    fn test_uid_errors_counted_by_kind() {
+        let stats = ServerStats::new();
+        stats.record_uid_error(&ERPCError::UnknownUid(3));
+        stats.record_uid_error(&ERPCError::UnknownUid(4));
+        stats.record_uid_error(&ERPCError::UidReuse(5));
+        stats.record_uid_error(&ERPCError::Timeout);
+
+        assert_eq!(
+            stats.snapshot().uid_errors,
+            UidErrors {
+                duplicate_returns: 0,
+                unknown_uids: 2,
+                uid_reuses: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Stress and soak harness for EPC endpoints
+//!
+//! Spawns several clients that each issue concurrent calls against any EPC
+//! server and reports throughput, latency percentiles and error counts.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lexpr::Value;
+use tokio::task::JoinSet;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::log::{debug, warn};
+
+/// Stress run configuration
+#[derive(Debug, Clone)]
+pub struct StressConfig {
+    /// Address of the EPC server under test
+    pub addr: String,
+    /// Method to call; it must accept a single string argument
+    pub method: String,
+    /// Number of client connections
+    pub clients: usize,
+    /// Concurrent in-flight calls per client
+    pub concurrency: usize,
+    /// Total calls issued by each client
+    pub calls_per_client: usize,
+    /// Size in bytes of the string payload sent with each call
+    pub payload_size: usize,
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        StressConfig {
+            addr: "127.0.0.1:12345".to_string(),
+            method: "echo".to_string(),
+            clients: 4,
+            concurrency: 8,
+            calls_per_client: 1000,
+            payload_size: 64,
+        }
+    }
+}
+
+/// Latency distribution of successful calls
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencySummary {
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencySummary {
+    /// Summarize a set of samples (sorts them in place)
+    pub fn from_samples(samples: &mut [Duration]) -> Self {
+        if samples.is_empty() {
+            return LatencySummary::default();
+        }
+        samples.sort_unstable();
+
+        let percentile = |p: f64| {
+            let index = ((p / 100.0) * (samples.len() - 1) as f64).round() as usize;
+            samples[index]
+        };
+        let total: Duration = samples.iter().sum();
+
+        LatencySummary {
+            min: samples[0],
+            mean: total / samples.len() as u32,
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// Result of a stress run
+#[derive(Debug, Clone, Default)]
+pub struct StressReport {
+    /// Calls that completed successfully
+    pub calls: u64,
+    /// Calls that returned an error
+    pub errors: u64,
+    /// Wall-clock time of the whole run
+    pub elapsed: Duration,
+    /// Latency of successful calls
+    pub latency: LatencySummary,
+}
+
+impl StressReport {
+    /// Successful calls per second
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.calls as f64 / secs
+        }
+    }
+}
+
+impl fmt::Display for StressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "calls: {} ok, {} errors in {:.2?} ({:.0} calls/s)",
+            self.calls,
+            self.errors,
+            self.elapsed,
+            self.throughput()
+        )?;
+        write!(
+            f,
+            "latency: min {:.2?} mean {:.2?} p50 {:.2?} p90 {:.2?} p99 {:.2?} max {:.2?}",
+            self.latency.min,
+            self.latency.mean,
+            self.latency.p50,
+            self.latency.p90,
+            self.latency.p99,
+            self.latency.max
+        )
+    }
+}
+
+/// Outcome of one worker task
+#[derive(Default)]
+struct WorkerResult {
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+/// Run a stress test against the configured endpoint
+pub async fn run(config: &StressConfig) -> std::result::Result<StressReport, ERPCError> {
+    if config.clients == 0 || config.concurrency == 0 {
+        return Err(ERPCError::InvalidArgument(
+            "clients and concurrency must be at least 1".to_string(),
+        ));
+    }
+
+    let payload = Arc::new("x".repeat(config.payload_size));
+    let mut clients = Vec::with_capacity(config.clients);
+    for _ in 0..config.clients {
+        clients.push(Arc::new(Client::connect(config.addr.clone()).await?));
+    }
+    debug!(
+        "Stress run: {} clients x {} concurrent calls against {}",
+        config.clients, config.concurrency, config.addr
+    );
+
+    let start = Instant::now();
+    let mut workers = JoinSet::new();
+    for client in &clients {
+        for worker in 0..config.concurrency {
+            // Spread the client's calls evenly over its workers
+            let calls = config.calls_per_client / config.concurrency
+                + usize::from(worker < config.calls_per_client % config.concurrency);
+            let client = Arc::clone(client);
+            let method = config.method.clone();
+            let payload = Arc::clone(&payload);
+
+            workers.spawn(async move {
+                let mut result = WorkerResult {
+                    latencies: Vec::with_capacity(calls),
+                    errors: 0,
+                };
+                for _ in 0..calls {
+                    let call_start = Instant::now();
+                    match client
+                        .call_value(&method, Value::string(payload.as_str()))
+                        .await
+                    {
+                        Ok(_) => result.latencies.push(call_start.elapsed()),
+                        Err(e) => {
+                            warn!("Stress call to '{}' failed: {}", method, e);
+                            result.errors += 1;
+                        }
+                    }
+                }
+                result
+            });
+        }
+    }
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    while let Some(result) = workers.join_next().await {
+        let result = result.map_err(|e| ERPCError::ProcessError(e.to_string()))?;
+        latencies.extend(result.latencies);
+        errors += result.errors;
+    }
+    let elapsed = start.elapsed();
+
+    for client in clients {
+        let _ = client.close().await;
+    }
+
+    Ok(StressReport {
+        calls: latencies.len() as u64,
+        errors,
+        elapsed,
+        latency: LatencySummary::from_samples(&mut latencies),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    #[test]
+    fn test_latency_summary() {
+        let mut samples: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let summary = LatencySummary::from_samples(&mut samples);
+
+        assert_eq!(summary.min, Duration::from_millis(1));
+        assert_eq!(summary.max, Duration::from_millis(100));
+        assert_eq!(summary.p50, Duration::from_millis(51));
+        assert_eq!(summary.p99, Duration::from_millis(99));
+    }
+
+    #[tokio::test]
+    async fn test_stress_against_echo_server() {
+        let mut server = Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        server
+            .register_value_method("echo", Ok, Some("args"), None::<&str>)
+            .await
+            .unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let report = run(&StressConfig {
+            addr: format!("127.0.0.1:{}", port),
+            clients: 2,
+            concurrency: 3,
+            calls_per_client: 10,
+            payload_size: 16,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(report.calls, 20);
+        assert_eq!(report.errors, 0);
+
+        server.shutdown().await.unwrap();
+    }
+}
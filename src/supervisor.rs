@@ -0,0 +1,296 @@
+//! Supervision of a child EPC process with automatic restarts
+//!
+//! A [`Supervisor`] owns a [`Process`], notices when the child exits on its
+//! own, and restarts it with exponential backoff until the restart budget of
+//! its [`RestartPolicy`] is spent. Callers always reach the current child
+//! through [`Supervisor::client`].
+
+use std::collections::VecDeque;
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::client::Client;
+use crate::error::ERPCError;
+use crate::log::{debug, error, info, warn};
+use crate::process::Process;
+
+/// When and how often a crashed child is restarted
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Maximum restarts allowed within `window` before giving up
+    pub max_restarts: u32,
+    /// Sliding window for counting restarts
+    pub window: Duration,
+    /// Delay before the first restart
+    pub initial_backoff: Duration,
+    /// Upper bound for the doubling restart delay
+    pub max_backoff: Duration,
+    /// How often the child is checked for exit
+    pub poll_interval: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Delay before the `attempt`-th restart within the window (1-based)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Lifecycle notifications delivered to the supervisor callback
+#[derive(Debug, Clone)]
+pub enum SupervisorEvent {
+    /// The child exited without being asked to
+    Exited { status: Option<ExitStatus> },
+    /// A restart is scheduled after `delay`
+    Restarting { attempt: u32, delay: Duration },
+    /// The child is running again and the client is reconnected
+    Restarted { pid: Option<u32>, port: Option<u16> },
+    /// A restart attempt failed
+    RestartFailed { error: String },
+    /// The restart budget is spent; the supervisor stopped
+    GaveUp { restarts: u32 },
+}
+
+/// Callback receiving supervisor events
+pub type EventCallback = dyn Fn(&SupervisorEvent) + Send + Sync;
+
+/// Keeps a child EPC process running
+pub struct Supervisor {
+    process: Arc<Mutex<Process>>,
+    stop_tx: watch::Sender<bool>,
+    monitor: Option<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    /// Start `process` and supervise it
+    pub async fn start(
+        mut process: Process,
+        policy: RestartPolicy,
+        on_event: Option<Arc<EventCallback>>,
+    ) -> std::result::Result<Self, ERPCError> {
+        process.start().await?;
+        info!("Supervising process with pid {:?}", process.pid());
+
+        let process = Arc::new(Mutex::new(process));
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let monitor = tokio::spawn(monitor(process.clone(), policy, on_event, stop_rx));
+
+        Ok(Supervisor {
+            process,
+            stop_tx,
+            monitor: Some(monitor),
+        })
+    }
+
+    /// Client connected to the current child, if it is up
+    pub async fn client(&self) -> Option<Arc<Client>> {
+        self.process.lock().await.client_handle()
+    }
+
+    /// OS process id of the current child
+    pub async fn pid(&self) -> Option<u32> {
+        self.process.lock().await.pid()
+    }
+
+    /// Whether the supervisor is still monitoring (it stops after giving up)
+    pub fn is_active(&self) -> bool {
+        self.monitor
+            .as_ref()
+            .is_some_and(|monitor| !monitor.is_finished())
+    }
+
+    /// Stop supervising and shut the child down
+    pub async fn stop(mut self) -> std::result::Result<(), ERPCError> {
+        let _ = self.stop_tx.send(true);
+        if let Some(monitor) = self.monitor.take() {
+            let _ = monitor.await;
+        }
+        self.process.lock().await.stop().await?;
+        Ok(())
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        let _ = self.stop_tx.send(true);
+    }
+}
+
+/// Watch the child and restart it according to `policy`
+async fn monitor(
+    process: Arc<Mutex<Process>>,
+    policy: RestartPolicy,
+    on_event: Option<Arc<EventCallback>>,
+    mut stop_rx: watch::Receiver<bool>,
+) {
+    let notify = |event: SupervisorEvent| {
+        debug!("Supervisor event: {:?}", event);
+        if let Some(callback) = &on_event {
+            callback(&event);
+        }
+    };
+    let mut restarts: VecDeque<Instant> = VecDeque::new();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(policy.poll_interval) => {}
+            _ = stop_rx.changed() => return,
+        }
+
+        let status = {
+            let mut process = process.lock().await;
+            if process.is_running() {
+                continue;
+            }
+            process.exit_status()
+        };
+        warn!("Supervised process exited with {:?}", status);
+        notify(SupervisorEvent::Exited { status });
+
+        // Restart until one attempt succeeds or the budget is spent
+        loop {
+            let now = Instant::now();
+            while restarts
+                .front()
+                .is_some_and(|at| now.duration_since(*at) > policy.window)
+            {
+                restarts.pop_front();
+            }
+            if restarts.len() as u32 >= policy.max_restarts {
+                error!(
+                    "Supervised process restarted {} times within {:?}, giving up",
+                    restarts.len(),
+                    policy.window
+                );
+                notify(SupervisorEvent::GaveUp {
+                    restarts: restarts.len() as u32,
+                });
+                return;
+            }
+
+            restarts.push_back(now);
+            let attempt = restarts.len() as u32;
+            let delay = policy.backoff(attempt);
+            notify(SupervisorEvent::Restarting { attempt, delay });
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = stop_rx.changed() => return,
+            }
+
+            let mut process = process.lock().await;
+            match process.start().await {
+                Ok(()) => {
+                    info!("Restarted supervised process, pid {:?}", process.pid());
+                    notify(SupervisorEvent::Restarted {
+                        pid: process.pid(),
+                        port: process.port(),
+                    });
+                    break;
+                }
+                Err(e) => {
+                    warn!("Failed to restart supervised process: {}", e);
+                    notify(SupervisorEvent::RestartFailed {
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(350));
+        assert_eq!(policy.backoff(40), Duration::from_millis(350));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_restarts_crashing_child_until_budget_spent() {
+        let mut server = crate::server::Server::new();
+        server.bind("127.0.0.1:0").await.unwrap();
+        let port = server.port().unwrap();
+        server.serve().await.unwrap();
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let process = Process::new(
+            "sh",
+            vec![
+                "-c".to_string(),
+                format!("echo {}; sleep 0.1; exit 3", port),
+            ],
+        );
+        let policy = RestartPolicy {
+            max_restarts: 2,
+            initial_backoff: Duration::from_millis(10),
+            poll_interval: Duration::from_millis(20),
+            ..Default::default()
+        };
+
+        let supervisor = Supervisor::start(
+            process,
+            policy,
+            Some(Arc::new(move |event: &SupervisorEvent| {
+                seen.lock().unwrap().push(event.clone());
+            })),
+        )
+        .await
+        .unwrap();
+        assert!(supervisor.client().await.is_some());
+
+        for _ in 0..100 {
+            if !supervisor.is_active() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(!supervisor.is_active());
+
+        let events = events.lock().unwrap().clone();
+        let restarted = events
+            .iter()
+            .filter(|event| matches!(event, SupervisorEvent::Restarted { .. }))
+            .count();
+        assert_eq!(restarted, 2);
+        assert!(matches!(
+            events.last(),
+            Some(SupervisorEvent::GaveUp { restarts: 2 })
+        ));
+
+        supervisor.stop().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+}
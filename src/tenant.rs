@@ -0,0 +1,187 @@
+//! Isolated method sets behind one listener
+//!
+//! [`Tenants`] maps names to registries of their own, so one server can host
+//! several tenants or plugins whose methods and [application
+//! state](crate::extract::State) never mix. A connection is bound to a
+//! tenant in one of two ways:
+//!
+//! - by its [`Identity`]: once the server's authenticator accepted the peer,
+//!   the route picks the tenant, by default the one named like the identity,
+//! - by selection: with [`Tenants::selectable`], a peer not bound yet calls
+//!   [`SELECT_TENANT_METHOD`] with a tenant name, and every later call goes
+//!   to that tenant.
+//!
+//! Connections bound to no tenant are served by the server's own registry.
+//! A binding is final; a bound peer cannot select another tenant.
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use elrpc::{MethodRegistry, Server, Tenants};
+//!
+//! let tenants = Tenants::new().selectable(true);
+//! let notes = tenants.insert("notes", MethodRegistry::new());
+//! notes
+//!     .register_closure("count", |(): ()| Ok(3), None::<&str>, None::<&str>)
+//!     .await?;
+//!
+//! let server = Server::builder()
+//!     .bind("127.0.0.1:0")
+//!     .tenants(tenants)
+//!     .build()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use crate::auth::Identity;
+use crate::error::ERPCError;
+use crate::registry::MethodRegistry;
+
+/// Method a peer calls to bind its connection to a tenant
+pub const SELECT_TENANT_METHOD: &str = "elrpc-select-tenant";
+
+type Route = dyn Fn(&Identity) -> Option<String> + Send + Sync;
+
+/// Registries by tenant name, set on
+/// [`ServerConfig::tenants`](crate::server::ServerConfig)
+pub struct Tenants {
+    registries: RwLock<HashMap<String, Arc<MethodRegistry>>>,
+    route: Box<Route>,
+    selectable: bool,
+}
+
+impl Default for Tenants {
+    fn default() -> Self {
+        Tenants::new()
+    }
+}
+
+impl fmt::Debug for Tenants {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tenants")
+            .field("tenants", &self.names())
+            .field("selectable", &self.selectable)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tenants {
+    /// No tenants yet, routing identities by name, without selection
+    pub fn new() -> Self {
+        Tenants {
+            registries: RwLock::new(HashMap::new()),
+            route: Box::new(|identity| Some(identity.name().to_string())),
+            selectable: false,
+        }
+    }
+
+    /// Pick the tenant of authenticated peers with `route`
+    ///
+    /// Peers it returns `None` for stay unbound.
+    pub fn route<F>(mut self, route: F) -> Self
+    where
+        F: Fn(&Identity) -> Option<String> + Send + Sync + 'static,
+    {
+        self.route = Box::new(route);
+        self
+    }
+
+    /// Let unbound peers pick a tenant with [`SELECT_TENANT_METHOD`]
+    pub fn selectable(mut self, selectable: bool) -> Self {
+        self.selectable = selectable;
+        self
+    }
+
+    /// Add tenant `name`, replacing an earlier one, and return its registry
+    pub fn insert(&self, name: impl Into<String>, registry: MethodRegistry) -> Arc<MethodRegistry> {
+        let registry = Arc::new(registry);
+        self.insert_shared(name, registry.clone());
+        registry
+    }
+
+    /// Add tenant `name` served by a registry shared with other code
+    pub fn insert_shared(&self, name: impl Into<String>, registry: Arc<MethodRegistry>) {
+        self.registries
+            .write()
+            .unwrap()
+            .insert(name.into(), registry);
+    }
+
+    /// Remove tenant `name`; connections already bound keep its registry
+    pub fn remove(&self, name: &str) -> Option<Arc<MethodRegistry>> {
+        self.registries.write().unwrap().remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<MethodRegistry>> {
+        self.registries.read().unwrap().get(name).cloned()
+    }
+
+    /// Tenant names, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.registries.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The tenant an authenticated peer is bound to, if any
+    ///
+    /// Fails when the route names a tenant that does not exist.
+    pub(crate) fn for_identity(
+        &self,
+        identity: &Identity,
+    ) -> std::result::Result<Option<(String, Arc<MethodRegistry>)>, ERPCError> {
+        let Some(name) = (self.route)(identity) else {
+            return Ok(None);
+        };
+        let registry = self.get(&name).ok_or_else(|| unknown(&name))?;
+        Ok(Some((name, registry)))
+    }
+
+    /// The registry of the tenant a peer selects
+    pub(crate) fn select(&self, name: &str) -> std::result::Result<Arc<MethodRegistry>, ERPCError> {
+        if !self.selectable {
+            return Err(ERPCError::PermissionDenied(
+                "tenants cannot be selected".to_string(),
+            ));
+        }
+        self.get(name).ok_or_else(|| unknown(name))
+    }
+}
+
+fn unknown(name: &str) -> ERPCError {
+    ERPCError::PermissionDenied(format!("no tenant {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_and_selection() {
+        let tenants = Tenants::new();
+        tenants.insert("alice", MethodRegistry::new());
+
+        let (name, _) = tenants
+            .for_identity(&Identity::new("alice"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(name, "alice");
+        assert!(tenants.for_identity(&Identity::new("mallory")).is_err());
+        assert!(tenants.select("alice").is_err());
+
+        let tenants = tenants
+            .route(|identity| identity.name().strip_prefix("team:").map(String::from))
+            .selectable(true);
+        assert!(tenants
+            .for_identity(&Identity::new("mallory"))
+            .unwrap()
+            .is_none());
+        assert!(tenants.select("alice").is_ok());
+        assert!(tenants.select("bob").is_err());
+        assert_eq!(tenants.names(), ["alice"]);
+    }
+}
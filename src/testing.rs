@@ -0,0 +1,582 @@
+//! Test doubles for code talking EPC
+//!
+//! [`MockServer`] listens on a local port and answers calls from a list of
+//! expectations, for testing clients without a real service behind them:
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use elrpc::testing::MockServer;
+//! use lexpr::Value;
+//!
+//! let mock = MockServer::start().await?;
+//! mock.expect_call("add")
+//!     .with(Value::list(vec![Value::from(5), Value::from(3)]))
+//!     .returning(Value::from(8))
+//!     .times(1);
+//!
+//! let client = elrpc::Client::connect(mock.addr().to_string()).await?;
+//! let sum: i64 = client.call_sync("add", (5, 3)).await?;
+//! assert_eq!(sum, 8);
+//! mock.verify();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`MockClient`] is the other side: it sends whatever messages or raw bytes
+//! a server test needs and hands back the server's replies undecoded, so
+//! tests need no framing loops of their own.
+//!
+//! Tests of a real registry use [`pair`], which serves it on a free port and
+//! connects a client:
+//!
+//! ```no_run
+//! # async fn run() -> elrpc::Result<()> {
+//! use elrpc::MethodRegistry;
+//!
+//! let registry = MethodRegistry::new();
+//! registry
+//!     .register_closure("echo", |s: String| Ok(s), None::<&str>, None::<&str>)
+//!     .await?;
+//! let pair = elrpc::testing::pair(registry).await?;
+//! let reply: String = pair.client.call_sync("echo", "hi").await?;
+//! assert_eq!(reply, "hi");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Timeouts, retry delays and expiries inside the crate all run on tokio's
+//! clock, so tests can pause it with `#[tokio::test(start_paused = true)]`
+//! and see a ten minute timeout fire instantly. [`timed`] measures a future
+//! on that clock, and with the `test-util` feature [`advance_in_steps`]
+//! moves it forward while letting woken tasks run in between. Paused time
+//! jumps ahead whenever the runtime has nothing to do, waiting on sockets
+//! included, so such tests are best kept off real connections.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use lexpr::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::client::{Client, ClientConfig};
+use crate::error::ERPCError;
+use crate::log::{debug, warn};
+use crate::protocol::{Framer, Message};
+use crate::registry::{method_list, MethodInfo, MethodRegistry};
+use crate::server::{Server, ServerConfig};
+
+/// How long a [`MockClient`] waits for a reply by default
+const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reply a [`MockServer`] sends to a matching call
+#[derive(Debug, Clone)]
+enum Reply {
+    Return(Value),
+    Error(String),
+    EpcError(String),
+}
+
+#[derive(Debug)]
+struct ExpectationState {
+    method: String,
+    args: Option<Value>,
+    reply: Reply,
+    delay: Option<Duration>,
+    times: Option<usize>,
+    calls: usize,
+}
+
+impl ExpectationState {
+    fn matches(&self, method: &str, args: &Value) -> bool {
+        self.method == method
+            && self.args.as_ref().is_none_or(|expected| expected == args)
+            && self.times.is_none_or(|times| self.calls < times)
+    }
+
+    fn is_met(&self) -> bool {
+        match self.times {
+            Some(times) => self.calls == times,
+            None => self.calls > 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    expectations: Vec<ExpectationState>,
+    received: Vec<(String, Value)>,
+    unexpected: Vec<(String, Value)>,
+}
+
+impl MockState {
+    /// Reply to a call, recording it
+    fn answer(&mut self, method: &str, args: &Value) -> (Reply, Option<Duration>) {
+        self.received.push((method.to_string(), args.clone()));
+        let expectation = self
+            .expectations
+            .iter_mut()
+            .find(|expectation| expectation.matches(method, args));
+        match expectation {
+            Some(expectation) => {
+                expectation.calls += 1;
+                (expectation.reply.clone(), expectation.delay)
+            }
+            None => {
+                warn!("Mock server got an unexpected call to {}", method);
+                self.unexpected.push((method.to_string(), args.clone()));
+                let error = format!("unexpected call to {}", method);
+                (Reply::EpcError(error), None)
+            }
+        }
+    }
+}
+
+/// EPC server answering calls from expectations
+///
+/// Expectations are tried in the order they were set; the first one for the
+/// method whose arguments match and that is not used up answers. Other calls
+/// get an `epc-error` and are reported by [`MockServer::verify`]. The server
+/// stops when dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl MockServer {
+    /// Listen on a free local port
+    pub async fn start() -> std::result::Result<Self, ERPCError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let shared = state.clone();
+        let task = tokio::spawn(async move {
+            // Connections are aborted along with the listener
+            let mut connections = JoinSet::new();
+            while let Ok((stream, peer)) = listener.accept().await {
+                debug!("Mock server accepted {}", peer);
+                connections.spawn(serve_mock(stream, shared.clone()));
+            }
+        });
+
+        Ok(MockServer { addr, state, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Expect calls to `method`, answered with nil unless configured
+    /// otherwise
+    pub fn expect_call(&self, method: impl Into<String>) -> Expectation {
+        let mut state = self.state.lock().unwrap();
+        state.expectations.push(ExpectationState {
+            method: method.into(),
+            args: None,
+            reply: Reply::Return(Value::Nil),
+            delay: None,
+            times: None,
+            calls: 0,
+        });
+        Expectation {
+            state: self.state.clone(),
+            index: state.expectations.len() - 1,
+        }
+    }
+
+    /// Every call received so far, with its arguments
+    pub fn received(&self) -> Vec<(String, Value)> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Panic unless every expectation was met and no unexpected call came
+    pub fn verify(&self) {
+        let state = self.state.lock().unwrap();
+        let mut problems = Vec::new();
+        for expectation in state.expectations.iter().filter(|e| !e.is_met()) {
+            problems.push(format!(
+                "{} called {} times, expected {}",
+                expectation.method,
+                expectation.calls,
+                expectation
+                    .times
+                    .map_or_else(|| "at least once".to_string(), |n| n.to_string())
+            ));
+        }
+        for (method, args) in &state.unexpected {
+            problems.push(format!("unexpected call {} {}", method, args));
+        }
+        assert!(
+            problems.is_empty(),
+            "mock server expectations failed:\n  {}",
+            problems.join("\n  ")
+        );
+    }
+}
+
+/// Handle configuring one expectation of a [`MockServer`]
+#[derive(Debug, Clone)]
+pub struct Expectation {
+    state: Arc<Mutex<MockState>>,
+    index: usize,
+}
+
+impl Expectation {
+    fn update(self, f: impl FnOnce(&mut ExpectationState)) -> Self {
+        f(&mut self.state.lock().unwrap().expectations[self.index]);
+        self
+    }
+
+    /// Only match calls with exactly these arguments, as the list the client
+    /// sends
+    pub fn with(self, args: impl Into<Value>) -> Self {
+        let args = args.into();
+        self.update(|expectation| expectation.args = Some(args))
+    }
+
+    /// Answer with `value`
+    pub fn returning(self, value: impl Into<Value>) -> Self {
+        let value = value.into();
+        self.update(|expectation| expectation.reply = Reply::Return(value))
+    }
+
+    /// Answer with an application error
+    pub fn returning_error(self, message: impl Into<String>) -> Self {
+        let message = message.into();
+        self.update(|expectation| expectation.reply = Reply::Error(message))
+    }
+
+    /// Answer with a protocol error
+    pub fn returning_epc_error(self, message: impl Into<String>) -> Self {
+        let message = message.into();
+        self.update(|expectation| expectation.reply = Reply::EpcError(message))
+    }
+
+    /// Wait `delay` before answering, e.g. to exercise client timeouts
+    pub fn delayed(self, delay: Duration) -> Self {
+        self.update(|expectation| expectation.delay = Some(delay))
+    }
+
+    /// Answer `times` calls, no more; [`MockServer::verify`] then requires
+    /// exactly that many
+    pub fn times(self, times: usize) -> Self {
+        self.update(|expectation| expectation.times = Some(times))
+    }
+
+    /// Calls answered so far
+    pub fn calls(&self) -> usize {
+        self.state.lock().unwrap().expectations[self.index].calls
+    }
+}
+
+/// Answer the calls of one connection to a [`MockServer`]
+async fn serve_mock(mut stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let mut buffer = BytesMut::new();
+    loop {
+        while let Some(frame) = Framer::next_frame(&mut buffer, true) {
+            let Some(reply) = mock_reply(&frame, &state).await else {
+                continue;
+            };
+            let text = match reply.to_sexp() {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("Mock server cannot encode its reply: {}", e);
+                    continue;
+                }
+            };
+            if stream
+                .write_all(&Framer::frame(text.as_bytes()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        match stream.read_buf(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+async fn mock_reply(frame: &[u8], state: &Mutex<MockState>) -> Option<Message> {
+    let message = std::str::from_utf8(frame)
+        .map_err(|e| ERPCError::InvalidMessageFormat(e.to_string()))
+        .and_then(Message::from_sexp);
+    let message = match message {
+        Ok(message) => message,
+        Err(e) => return Some(Message::new_epc_error(0, e.to_string())),
+    };
+    match message {
+        Message::Call { uid, method, args } => {
+            let (reply, delay) = state.lock().unwrap().answer(&method, &args);
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            Some(match reply {
+                Reply::Return(value) => Message::new_return(uid, value),
+                Reply::Error(message) => Message::new_return_error(uid, message),
+                Reply::EpcError(message) => Message::new_epc_error(uid, message),
+            })
+        }
+        Message::Methods { uid } => {
+            let mut names: Vec<_> = state
+                .lock()
+                .unwrap()
+                .expectations
+                .iter()
+                .map(|expectation| expectation.method.clone())
+                .collect();
+            names.sort();
+            names.dedup();
+            let infos = names
+                .into_iter()
+                .map(|name| MethodInfo::new(name, None::<&str>, None::<&str>))
+                .collect();
+            Some(Message::new_return(uid, method_list(infos)))
+        }
+        _ => None,
+    }
+}
+
+/// Scripted EPC client for server tests
+///
+/// Unlike [`Client`](crate::client::Client) it does not interpret replies:
+/// errors come back as `ReturnError` and `EPCError` messages, and anything
+/// may be sent, malformed frames included.
+#[derive(Debug)]
+pub struct MockClient {
+    stream: TcpStream,
+    buffer: BytesMut,
+    next_uid: u64,
+    timeout: Duration,
+}
+
+impl MockClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> std::result::Result<Self, ERPCError> {
+        Ok(MockClient {
+            stream: TcpStream::connect(addr).await?,
+            buffer: BytesMut::new(),
+            next_uid: 1,
+            timeout: DEFAULT_REPLY_TIMEOUT,
+        })
+    }
+
+    /// Fail [`MockClient::receive`] after waiting `timeout`, five seconds by
+    /// default
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Send `message` as is
+    pub async fn send(&mut self, message: &Message) -> std::result::Result<(), ERPCError> {
+        self.send_payload(message.to_sexp()?.as_bytes()).await
+    }
+
+    /// Frame and send `payload`, which need not be a valid message
+    pub async fn send_payload(&mut self, payload: &[u8]) -> std::result::Result<(), ERPCError> {
+        self.send_bytes(&Framer::frame(payload)).await
+    }
+
+    /// Send `bytes` without framing them
+    pub async fn send_bytes(&mut self, bytes: &[u8]) -> std::result::Result<(), ERPCError> {
+        self.stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Next message from the server
+    pub async fn receive(&mut self) -> std::result::Result<Message, ERPCError> {
+        let read = async {
+            loop {
+                if let Some(frame) = Framer::next_frame(&mut self.buffer, true) {
+                    let text = std::str::from_utf8(&frame)
+                        .map_err(|e| ERPCError::InvalidMessageFormat(e.to_string()))?;
+                    return Message::from_sexp(text);
+                }
+                if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                    return Err(ERPCError::ConnectionClosed);
+                }
+            }
+        };
+        tokio::time::timeout(self.timeout, read)
+            .await
+            .map_err(|_| ERPCError::Timeout)?
+    }
+
+    /// Call `method` under a fresh uid and return the next message, which
+    /// is the reply unless the server sent something else first
+    pub async fn call(
+        &mut self,
+        method: impl Into<String>,
+        args: Value,
+    ) -> std::result::Result<Message, ERPCError> {
+        let uid = self.next_uid;
+        self.next_uid += 1;
+        self.send(&Message::new_call(uid, method, args)).await?;
+        self.receive().await
+    }
+}
+
+/// Server and a client connected to it, made by [`pair`]
+///
+/// The server stops accepting connections when the pair is dropped.
+pub struct TestPair {
+    pub server: Server,
+    pub client: Client,
+}
+
+impl Drop for TestPair {
+    fn drop(&mut self) {
+        self.server.stop_listening();
+    }
+}
+
+/// Serve `registry` on a free local port and connect a client to it
+pub async fn pair(
+    registry: impl Into<Arc<MethodRegistry>>,
+) -> std::result::Result<TestPair, ERPCError> {
+    pair_with_config(registry, ServerConfig::default(), ClientConfig::default()).await
+}
+
+/// [`pair`] with custom configurations; the server's bind address is
+/// ignored
+pub async fn pair_with_config(
+    registry: impl Into<Arc<MethodRegistry>>,
+    server_config: ServerConfig,
+    client_config: ClientConfig,
+) -> std::result::Result<TestPair, ERPCError> {
+    let mut server = Server::with_registry(server_config, registry.into());
+    let addr = server.bind("127.0.0.1:0").await?;
+    server.serve().await?;
+    // The listener is bound, so the connection is queued until accepted
+    let client = Client::connect_with_config(addr.to_string(), client_config).await?;
+    Ok(TestPair { server, client })
+}
+
+/// Run `future`, returning its output and how long it took on tokio's
+/// clock, which is virtual time when the clock is paused
+pub async fn timed<F: Future>(future: F) -> (F::Output, Duration) {
+    let started = tokio::time::Instant::now();
+    let output = future.await;
+    (output, started.elapsed())
+}
+
+/// Move paused time forward by `by`, at most `step` at a time, yielding to
+/// woken tasks after each step
+///
+/// A single `tokio::time::advance` fires every timer due within the jump at
+/// once; stepping lets a task woken by one timer set the next before time
+/// passes it. Panics if the clock is not paused or `step` is zero.
+#[cfg(feature = "test-util")]
+pub async fn advance_in_steps(by: Duration, step: Duration) {
+    assert!(!step.is_zero(), "advance_in_steps needs a non-zero step");
+    let deadline = tokio::time::Instant::now() + by;
+    loop {
+        let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        tokio::time::advance(left.min(step)).await;
+        tokio::task::yield_now().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expectations_match_in_order() {
+        let mut state = MockState::default();
+        state.expectations.push(ExpectationState {
+            method: "add".to_string(),
+            args: Some(Value::symbol("first")),
+            reply: Reply::Return(Value::symbol("one")),
+            delay: None,
+            times: Some(1),
+            calls: 0,
+        });
+        state.expectations.push(ExpectationState {
+            method: "add".to_string(),
+            args: None,
+            reply: Reply::Error("boom".to_string()),
+            delay: None,
+            times: None,
+            calls: 0,
+        });
+
+        let first = Value::symbol("first");
+        assert!(matches!(state.answer("add", &first).0, Reply::Return(_)));
+        assert!(matches!(state.answer("add", &first).0, Reply::Error(_)));
+        assert!(matches!(state.answer("sub", &first).0, Reply::EpcError(_)));
+        assert!(state.expectations.iter().all(ExpectationState::is_met));
+        assert_eq!(state.received.len(), 3);
+        assert_eq!(state.unexpected.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pair_serves_registry() {
+        let registry = MethodRegistry::new();
+        registry
+            .register_value_method(
+                "ping",
+                |_| Ok(Value::symbol("pong")),
+                None::<&str>,
+                None::<&str>,
+            )
+            .await
+            .unwrap();
+        let pair = pair(registry).await.unwrap();
+        let methods = pair.client.query_methods().await.unwrap();
+        assert_eq!(methods[0].name, "ping");
+        assert_eq!(pair.server.stats().total_connections, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeouts_run_on_paused_clock() {
+        let registry = MethodRegistry::new();
+        registry
+            .register_value_method("slow", Ok, None::<&str>, None::<&str>)
+            .await
+            .unwrap();
+        registry.set_latency("slow", Duration::from_secs(600));
+
+        let call = registry.call_method("slow", Value::Null);
+        let (result, elapsed) = timed(tokio::time::timeout(Duration::from_secs(60), call)).await;
+        assert!(result.is_err());
+        assert_eq!(elapsed, Duration::from_secs(60));
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test(start_paused = true)]
+    async fn test_advance_in_steps() {
+        let ticks = Arc::new(Mutex::new(Vec::new()));
+        let recorded = ticks.clone();
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                recorded.lock().unwrap().push(tokio::time::Instant::now());
+            }
+        });
+        tokio::task::yield_now().await;
+
+        let started = tokio::time::Instant::now();
+        advance_in_steps(Duration::from_secs(30), Duration::from_secs(1)).await;
+        let ticks = ticks.lock().unwrap();
+        assert_eq!(ticks.len(), 3);
+        assert_eq!(ticks[2] - started, Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,405 @@
+//! TLS for client connections, with certificate pinning
+//!
+//! Set [`ClientConfig::tls`](crate::client::ClientConfig) to talk to a server
+//! behind a TLS terminator, such as an `stunnel` in front of a remote Emacs.
+//! The server is authenticated in one of two ways:
+//!
+//! - with [pins](CertPin): the server is accepted if its certificate, or the
+//!   public key in it, has a pinned SHA-256 hash. No CA is involved, so a
+//!   self-signed certificate is enough, and neither its names nor its
+//!   validity period are checked; rotate the pin to replace it;
+//! - otherwise against [`TlsConfig::root_certificate`] trust anchors, with
+//!   the usual chain, name and expiry checks.
+//!
+//! ```no_run
+//! # async fn connect() -> elrpc::Result<()> {
+//! use elrpc::tls::{CertPin, TlsConfig};
+//! use elrpc::{Client, ClientConfig};
+//!
+//! let pin = "spki-sha256:1f2c3b...".parse()?;
+//! let config = ClientConfig {
+//!     tls: Some(TlsConfig::new().pin(pin)),
+//!     ..Default::default()
+//! };
+//! let client = Client::connect_with_config("devbox:7000", config).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `openssl x509 -pubkey -noout -in cert.pem | openssl pkey -pubin -outform der
+//! | sha256sum` prints the hash for an `spki-sha256:` pin.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{self, ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    self, CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::error::ERPCError;
+
+/// SHA-256 hash the server certificate must match
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CertPin {
+    /// Hash of the whole DER certificate
+    Certificate([u8; 32]),
+    /// Hash of the DER `SubjectPublicKeyInfo`, which survives re-issuing the
+    /// certificate for the same key
+    Spki([u8; 32]),
+}
+
+impl CertPin {
+    /// Pin exactly the DER certificate `der`
+    pub fn certificate(der: &[u8]) -> Self {
+        CertPin::Certificate(Sha256::digest(der).into())
+    }
+
+    /// Pin the public key of the DER certificate `der`
+    pub fn spki(der: &[u8]) -> std::result::Result<Self, ERPCError> {
+        let spki = spki_of(der)
+            .ok_or_else(|| ERPCError::InvalidArgument("malformed certificate".to_string()))?;
+        Ok(CertPin::Spki(Sha256::digest(spki).into()))
+    }
+
+    /// Whether the DER certificate `der` matches this pin
+    pub fn matches(&self, der: &[u8]) -> bool {
+        match self {
+            CertPin::Certificate(hash) => Sha256::digest(der)[..] == hash[..],
+            CertPin::Spki(hash) => {
+                spki_of(der).is_some_and(|spki| Sha256::digest(spki)[..] == hash[..])
+            }
+        }
+    }
+}
+
+impl FromStr for CertPin {
+    type Err = ERPCError;
+
+    /// Parse `cert-sha256:HEX` or `spki-sha256:HEX`; colons in HEX are ignored
+    fn from_str(s: &str) -> std::result::Result<Self, ERPCError> {
+        let invalid = || ERPCError::InvalidArgument(format!("invalid certificate pin: {}", s));
+        let (kind, hex) = s.split_once(':').ok_or_else(invalid)?;
+        let digits: Vec<u8> = hex.bytes().filter(|&b| b != b':').collect();
+        if digits.len() != 64 {
+            return Err(invalid());
+        }
+        let mut hash = [0u8; 32];
+        for (byte, pair) in hash.iter_mut().zip(digits.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        match kind {
+            "cert-sha256" => Ok(CertPin::Certificate(hash)),
+            "spki-sha256" => Ok(CertPin::Spki(hash)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for CertPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, hash) = match self {
+            CertPin::Certificate(hash) => ("cert-sha256", hash),
+            CertPin::Spki(hash) => ("spki-sha256", hash),
+        };
+        write!(f, "{}:", kind)?;
+        hash.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::Debug for CertPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CertPin({})", self)
+    }
+}
+
+/// One DER element and the input following it
+struct DerElement<'a> {
+    tag: u8,
+    /// The element including its tag and length
+    whole: &'a [u8],
+    contents: &'a [u8],
+    rest: &'a [u8],
+}
+
+/// Split off the DER element at the start of `input`
+fn der_element(input: &[u8]) -> Option<DerElement<'_>> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = input.len() - rest.len();
+    Some(DerElement {
+        tag,
+        whole: &input[..header + len],
+        contents: &rest[..len],
+        rest: &rest[len..],
+    })
+}
+
+/// The DER `SubjectPublicKeyInfo` of an X.509 certificate
+fn spki_of(cert: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    let sequence = |input| der_element(input).filter(|element| element.tag == SEQUENCE);
+    let cert = sequence(cert)?.contents;
+    let tbs = sequence(cert)?.contents;
+    // version, serialNumber, signature, issuer, validity, subject, then the key
+    let mut fields = tbs;
+    let first = der_element(fields)?;
+    if first.tag == VERSION {
+        fields = first.rest;
+    }
+    for _ in 0..5 {
+        fields = der_element(fields)?.rest;
+    }
+    Some(sequence(fields)?.whole)
+}
+
+/// Client-side TLS settings
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Name to send as SNI and, without pins, to check the certificate
+    /// against; defaults to the host connected to
+    pub server_name: Option<String>,
+    /// DER certificates of the CAs to trust when there are no pins
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Accept the server if any of these matches its certificate
+    pub pins: Vec<CertPin>,
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        TlsConfig::default()
+    }
+
+    /// Use `name` instead of the host connected to
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    /// Trust the CA with DER certificate `der`
+    pub fn root_certificate(mut self, der: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates.push(der.into());
+        self
+    }
+
+    /// Accept servers whose certificate matches `pin`
+    pub fn pin(mut self, pin: CertPin) -> Self {
+        self.pins.push(pin);
+        self
+    }
+
+    fn connector(&self) -> std::result::Result<TlsConnector, ERPCError> {
+        let invalid = |e: rustls::Error| ERPCError::InvalidArgument(e.to_string());
+        let provider = Arc::new(ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?;
+
+        let config = if self.pins.is_empty() {
+            let mut roots = RootCertStore::empty();
+            for der in &self.root_certificates {
+                roots
+                    .add(CertificateDer::from(der.clone()))
+                    .map_err(invalid)?;
+            }
+            let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| ERPCError::InvalidArgument(e.to_string()))?;
+            builder.with_webpki_verifier(verifier).with_no_client_auth()
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pins: self.pins.clone(),
+                    provider,
+                }))
+                .with_no_client_auth()
+        };
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+
+    /// Run the TLS handshake over `stream`, connected to `addr`
+    pub(crate) async fn connect(
+        &self,
+        addr: &str,
+        stream: TcpStream,
+    ) -> std::result::Result<TlsStream<TcpStream>, ERPCError> {
+        let host = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => addr
+                .rsplit_once(':')
+                .map_or(addr, |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']'),
+        };
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| ERPCError::InvalidArgument(format!("{}: {}", host, e)))?;
+        self.connector()?
+            .connect(name, stream)
+            .await
+            .map_err(ERPCError::Io)
+    }
+}
+
+/// Accepts exactly the certificates matching a pin
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<CertPin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if self.pins.iter().any(|pin| pin.matches(end_entity)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+    use tokio_rustls::TlsAcceptor;
+
+    #[test]
+    fn test_pins() {
+        let key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = key.cert.der();
+
+        let spki = CertPin::spki(der).unwrap();
+        assert_eq!(
+            spki,
+            CertPin::Spki(Sha256::digest(key.key_pair.public_key_der()).into())
+        );
+        assert!(spki.matches(der));
+        assert!(CertPin::certificate(der).matches(der));
+
+        let text = spki.to_string();
+        assert!(text.starts_with("spki-sha256:"));
+        assert_eq!(text.parse::<CertPin>().unwrap(), spki);
+        assert!("spki-sha256:abcd".parse::<CertPin>().is_err());
+        assert!("md5:00".parse::<CertPin>().is_err());
+
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        assert!(!spki.matches(other.cert.der()));
+    }
+
+    #[tokio::test]
+    async fn test_pinned_handshake() {
+        let key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![key.cert.der().clone()],
+                    PrivateKeyDer::Pkcs8(key.key_pair.serialize_der().into()),
+                )
+                .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut tls) = acceptor.accept(stream).await {
+                    let _ = tls.write_all(b"hello").await;
+                    let _ = tls.shutdown().await;
+                }
+            }
+        });
+
+        let pinned = TlsConfig::new().pin(CertPin::spki(key.cert.der()).unwrap());
+        let stream = TcpStream::connect(&addr).await.unwrap();
+        let mut tls = pinned.connect(&addr, stream).await.unwrap();
+        let mut greeting = String::new();
+        tls.read_to_string(&mut greeting).await.unwrap();
+        assert_eq!(greeting, "hello");
+
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let wrong = TlsConfig::new().pin(CertPin::certificate(other.cert.der()));
+        let stream = TcpStream::connect(&addr).await.unwrap();
+        assert!(wrong.connect(&addr, stream).await.is_err());
+
+        // Without pins the certificate has to chain to a trusted root
+        let untrusted = TlsConfig::new().root_certificate(other.cert.der().to_vec());
+        let stream = TcpStream::connect(&addr).await.unwrap();
+        assert!(untrusted.connect(&addr, stream).await.is_err());
+    }
+}
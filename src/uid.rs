@@ -1,4 +1,53 @@
+//! Where call uids come from
+//!
+//! Uids only have to be unique among the calls a peer has outstanding, and
+//! counting up from 1 does that. A long-lived peer however cannot tell a
+//! restarted client's call 3 from the call 3 of the session before, whose
+//! reply it may still be about to send. A [`UidStrategy`] other than the
+//! sequential [`UidGenerator`] avoids that:
+//!
+//! - [`RandomUids`] draws every uid at random,
+//! - [`SessionUids`] counts up from a random per-session base, so uids stay
+//!   ordered within a session while sessions do not overlap,
+//! - [`PersistentUids`] keeps counting where the previous process stopped,
+//!   by saving a high-water mark in a file.
+//!
+//! Tests that must produce the same frames on every run use a
+//! [`UidGenerator`] or [`ScriptedUids`], which hands out the uids of a
+//! recorded trace.
+//!
+//! Uids stay below 2^61, inside the fixnum range of Emacs.
+//!
+//! Both sides of a connection may make calls, each numbering its own. With
+//! [`PartitionedUids`] they keep to different halves of the uid space
+//! instead, odd uids for one direction and even ones for the other, so a
+//! uid alone tells which side started an exchange. Clients with
+//! `ClientConfig::partition_uids` ask the server for this by calling
+//! [`PARTITION_UIDS_METHOD`] with the half they take; servers with
+//! `ServerConfig::partition_uids` agree and then refuse calls from the
+//! other half.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::log::{debug, warn};
+
+/// Largest uid a strategy hands out
+pub const MAX_UID: u64 = (1 << 61) - 1;
+
+/// Method a client calls with `odd` or `even` to take that half of the uid
+/// space for its calls
+pub const PARTITION_UIDS_METHOD: &str = "elrpc-partition-uids";
+
+/// Source of the uids a peer gives its calls
+pub trait UidStrategy: Send + Sync + fmt::Debug {
+    fn next_uid(&self) -> u64;
+}
 
 /// Thread-safe UID generator using atomic operations
 #[derive(Debug)]
@@ -43,6 +92,414 @@ impl Default for UidGenerator {
     }
 }
 
+impl UidStrategy for UidGenerator {
+    fn next_uid(&self) -> u64 {
+        self.next()
+    }
+}
+
+/// A random nonzero uid at most [`MAX_UID`]
+fn random_uid() -> u64 {
+    let bits = uuid::Uuid::new_v4().as_u64_pair().0 & MAX_UID;
+    bits.max(1)
+}
+
+/// Uids drawn at random for every call
+///
+/// Two uids collide with a chance of about n²/2^62 among n outstanding calls.
+#[derive(Debug, Default)]
+pub struct RandomUids;
+
+impl UidStrategy for RandomUids {
+    fn next_uid(&self) -> u64 {
+        random_uid()
+    }
+}
+
+/// Uids from a fixed list, e.g. those of a recorded trace being replayed
+///
+/// Once the list is used up it counts on from its last uid.
+#[derive(Debug)]
+pub struct ScriptedUids {
+    state: Mutex<(VecDeque<u64>, u64)>,
+}
+
+impl ScriptedUids {
+    pub fn new(uids: impl IntoIterator<Item = u64>) -> Self {
+        ScriptedUids {
+            state: Mutex::new((uids.into_iter().collect(), 1)),
+        }
+    }
+}
+
+impl UidStrategy for ScriptedUids {
+    fn next_uid(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let (script, next) = &mut *state;
+        let uid = script.pop_front().unwrap_or(*next);
+        *next = uid.wrapping_add(1).clamp(1, MAX_UID);
+        uid
+    }
+}
+
+/// Uids counting up from a random base picked per session
+///
+/// The base is picked from a UUID with its low 32 bits clear, so a session
+/// has 2^32 uids before it picks a new base.
+#[derive(Debug)]
+pub struct SessionUids {
+    next: Mutex<u64>,
+}
+
+impl SessionUids {
+    pub fn new() -> Self {
+        SessionUids {
+            next: Mutex::new(SessionUids::base()),
+        }
+    }
+
+    fn base() -> u64 {
+        (random_uid() & !0xffff_ffff).max(1 << 32)
+    }
+}
+
+impl Default for SessionUids {
+    fn default() -> Self {
+        SessionUids::new()
+    }
+}
+
+impl UidStrategy for SessionUids {
+    fn next_uid(&self) -> u64 {
+        let mut next = self.next.lock().unwrap();
+        let uid = *next;
+        *next = if (uid + 1) & 0xffff_ffff == 0 {
+            SessionUids::base()
+        } else {
+            uid + 1
+        };
+        uid
+    }
+}
+
+/// Half of the uid space, reserved for the calls of one side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UidHalf {
+    Odd,
+    Even,
+}
+
+impl UidHalf {
+    pub fn contains(self, uid: u64) -> bool {
+        (uid % 2 == 1) == (self == UidHalf::Odd)
+    }
+
+    /// The half left to the other side
+    pub fn other(self) -> UidHalf {
+        match self {
+            UidHalf::Odd => UidHalf::Even,
+            UidHalf::Even => UidHalf::Odd,
+        }
+    }
+
+    /// Name of the half in [`PARTITION_UIDS_METHOD`] calls
+    pub fn name(self) -> &'static str {
+        match self {
+            UidHalf::Odd => "odd",
+            UidHalf::Even => "even",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<UidHalf> {
+        match name {
+            "odd" => Some(UidHalf::Odd),
+            "even" => Some(UidHalf::Even),
+            _ => None,
+        }
+    }
+}
+
+/// Uids of another strategy, moved into one half of the uid space
+///
+/// The inner strategy's uid `n` becomes `2n - 1` in the odd half and `2n`
+/// in the even one, so sequential uids stay sequential within the half.
+#[derive(Debug)]
+pub struct PartitionedUids {
+    inner: std::sync::Arc<dyn UidStrategy>,
+    half: UidHalf,
+}
+
+impl PartitionedUids {
+    pub fn new(inner: std::sync::Arc<dyn UidStrategy>, half: UidHalf) -> Self {
+        PartitionedUids { inner, half }
+    }
+
+    pub fn half(&self) -> UidHalf {
+        self.half
+    }
+}
+
+impl UidStrategy for PartitionedUids {
+    fn next_uid(&self) -> u64 {
+        loop {
+            // Below 2^60, so doubling stays within MAX_UID
+            let n = self.inner.next_uid() & (MAX_UID >> 1);
+            if n != 0 {
+                return match self.half {
+                    UidHalf::Odd => 2 * n - 1,
+                    UidHalf::Even => 2 * n,
+                };
+            }
+        }
+    }
+}
+
+/// Uids [`PersistentUids`] reserves with each write of its file by default
+const DEFAULT_BLOCK: u64 = 1024;
+
+/// Where [`PersistentUids`] continues once it has handed out [`MAX_UID`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Wraparound {
+    /// Start over at 1
+    #[default]
+    Restart,
+    /// Continue from a random uid, away from what peers may have seen lately
+    Random,
+}
+
+/// How long [`PersistentUids`] waits before retrying a failed save
+const SAVE_RETRY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Reservation {
+    next: u64,
+    /// The uid saved in the file; uids below it are handed out in order
+    reserved: u64,
+    /// Counts wraparounds, so a save for the previous run of uids is not
+    /// taken for one of the current run
+    epoch: u64,
+    /// A later reservation is being saved in the background
+    saving: bool,
+    /// No save is started before this, after one failed
+    retry_at: Option<Instant>,
+}
+
+/// The file of a [`PersistentUids`] and the reservation it holds
+#[derive(Debug)]
+struct SeedFile {
+    path: PathBuf,
+    state: Mutex<Reservation>,
+    saved: Condvar,
+}
+
+impl SeedFile {
+    /// Save `seed` as the first uid of the next process, durably
+    fn save(&self, seed: u64) -> std::io::Result<()> {
+        let temp = self.path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temp)?;
+        writeln!(file, "{}", seed)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &self.path)?;
+        // The rename is only durable once the directory is synced; not
+        // every platform lets a directory be opened for that
+        let dir = self.path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(Ok(dir)) = dir.map(std::fs::File::open) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+
+    /// Save `seed` off the caller's thread, extending the reservation of
+    /// run `epoch` once it is written
+    fn save_in_background(self: &Arc<Self>, seed: u64, epoch: u64) -> std::io::Result<()> {
+        let file = self.clone();
+        std::thread::Builder::new()
+            .name("elrpc-uid-seed".to_string())
+            .spawn(move || {
+                let result = file.save(seed);
+                let mut state = file.state.lock().unwrap();
+                state.saving = false;
+                match result {
+                    Ok(()) if state.epoch == epoch => {
+                        state.reserved = seed;
+                        state.retry_at = None;
+                    }
+                    Ok(()) => {}
+                    Err(e) => {
+                        warn!("Cannot save uid seed to {}: {}", file.path.display(), e);
+                        state.retry_at = Some(Instant::now() + SAVE_RETRY);
+                    }
+                }
+                file.saved.notify_all();
+            })
+            .map(drop)
+    }
+}
+
+/// Sequential uids that survive restarts
+///
+/// The file holds the first uid the next process may use. Uids are reserved
+/// in blocks, so the file is written once per block rather than per call; a
+/// process that exits skips the rest of its block. The file is only read
+/// when opened, so two processes must not share it.
+///
+/// Opening and configuring write the file on the caller's thread. After
+/// that, the next block is reserved by a background thread once half of the
+/// current one is used, and a uid is only handed out in order once the file
+/// covers it. Until then, for instance while the file cannot be written,
+/// uids are drawn at random as [`RandomUids`] does, so the next process
+/// cannot repeat them.
+#[derive(Debug)]
+pub struct PersistentUids {
+    file: Arc<SeedFile>,
+    /// The uid the file held when it was opened
+    seed: u64,
+    block: u64,
+    wraparound: Wraparound,
+}
+
+impl PersistentUids {
+    /// Continue from the uid saved in `path`, or from 1 if it does not exist
+    ///
+    /// The first block is reserved before this returns.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let seed = match std::fs::read_to_string(&path) {
+            Ok(text) => text.trim().parse::<u64>().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("bad uid seed in {}: {}", path.display(), e),
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e),
+        };
+        debug!("Continuing uids at {} from {}", seed, path.display());
+        let uids = PersistentUids {
+            file: Arc::new(SeedFile {
+                path,
+                state: Mutex::new(Reservation {
+                    next: 1,
+                    reserved: 1,
+                    epoch: 0,
+                    saving: false,
+                    retry_at: None,
+                }),
+                saved: Condvar::new(),
+            }),
+            seed,
+            block: DEFAULT_BLOCK,
+            wraparound: Wraparound::default(),
+        };
+        uids.reserve_first()?;
+        Ok(uids)
+    }
+
+    /// Reserve `block` uids per write of the file
+    pub fn block(mut self, block: u64) -> Self {
+        self.block = block.max(1);
+        self.reconfigure();
+        self
+    }
+
+    /// Where to continue after [`MAX_UID`]
+    pub fn wraparound(mut self, wraparound: Wraparound) -> Self {
+        self.wraparound = wraparound;
+        self.reconfigure();
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    /// Where a new run of uids starts
+    fn wrap_start(&self) -> u64 {
+        match self.wraparound {
+            Wraparound::Restart => 1,
+            Wraparound::Random => random_uid().min(MAX_UID - 1),
+        }
+    }
+
+    /// Reserve the first block from the seed the file held
+    fn reserve_first(&self) -> std::io::Result<()> {
+        let next = if self.seed >= MAX_UID {
+            self.wrap_start()
+        } else {
+            self.seed.max(1)
+        };
+        let reserved = next.saturating_add(self.block).min(MAX_UID);
+        self.file.save(reserved)?;
+        let mut state = self.file.state.lock().unwrap();
+        state.next = next;
+        state.reserved = reserved;
+        Ok(())
+    }
+
+    /// Reserve the first block again with new settings, keeping the old
+    /// reservation if the file cannot be written
+    fn reconfigure(&self) {
+        if let Err(e) = self.reserve_first() {
+            warn!(
+                "Cannot save uid seed to {}: {}",
+                self.file.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Start saving the next block once half of the current one is used
+    fn reserve_ahead(&self, state: &mut Reservation) {
+        if state.saving
+            || state.reserved - state.next > self.block / 2
+            || state.retry_at.is_some_and(|at| Instant::now() < at)
+        {
+            return;
+        }
+        let seed = state.reserved.saturating_add(self.block).min(MAX_UID);
+        if seed == state.reserved {
+            // The run ends at MAX_UID; the next one is reserved as it starts
+            return;
+        }
+        state.saving = true;
+        if let Err(e) = self.file.save_in_background(seed, state.epoch) {
+            warn!("Cannot start saving uid seed: {}", e);
+            state.saving = false;
+            state.retry_at = Some(Instant::now() + SAVE_RETRY);
+        }
+    }
+
+    /// Wait for a background save to finish
+    #[cfg(test)]
+    fn settle(&self) {
+        let state = self.file.state.lock().unwrap();
+        drop(
+            self.file
+                .saved
+                .wait_while(state, |state| state.saving)
+                .unwrap(),
+        );
+    }
+}
+
+impl UidStrategy for PersistentUids {
+    fn next_uid(&self) -> u64 {
+        let mut state = self.file.state.lock().unwrap();
+        let uid = (state.next < state.reserved).then(|| {
+            state.next += 1;
+            state.next - 1
+        });
+        if state.next >= MAX_UID {
+            state.next = self.wrap_start();
+            state.reserved = state.next;
+            state.epoch += 1;
+        }
+        self.reserve_ahead(&mut state);
+        drop(state);
+        uid.unwrap_or_else(random_uid)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,6 +514,15 @@ mod tests {
         assert_eq!(gen.next(), 3);
     }
 
+    #[test]
+    fn test_scripted_uids() {
+        let uids = ScriptedUids::new([7, 3]);
+        assert_eq!(uids.next_uid(), 7);
+        assert_eq!(uids.next_uid(), 3);
+        assert_eq!(uids.next_uid(), 4);
+        assert_eq!(ScriptedUids::new([]).next_uid(), 1);
+    }
+
     #[test]
     fn test_uid_from_custom_start() {
         let gen = UidGenerator::from(100);
@@ -97,6 +563,78 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_random_strategies_stay_in_fixnum_range() {
+        let random = RandomUids;
+        let uids: std::collections::HashSet<_> = (0..1000).map(|_| random.next_uid()).collect();
+        assert_eq!(uids.len(), 1000);
+        assert!(uids.iter().all(|&uid| uid > 0 && uid <= MAX_UID));
+
+        let session = SessionUids::new();
+        let first = session.next_uid();
+        assert!(first > 0 && first <= MAX_UID);
+        assert_eq!(first & 0xffff_ffff, 0);
+        assert_eq!(session.next_uid(), first + 1);
+
+        *session.next.lock().unwrap() = first | 0xffff_ffff;
+        assert_eq!(session.next_uid(), first | 0xffff_ffff);
+        assert_eq!(session.next_uid() & 0xffff_ffff, 0);
+    }
+
+    #[test]
+    fn test_persistent_uids_continue_after_restart() {
+        let path = std::env::temp_dir().join(format!("elrpc-uids-{}", uuid::Uuid::new_v4()));
+        let uids = PersistentUids::open(&path).unwrap().block(10);
+        assert_eq!(uids.next_uid(), 1);
+        assert_eq!(uids.next_uid(), 2);
+        drop(uids);
+
+        let uids = PersistentUids::open(&path).unwrap();
+        assert_eq!(uids.next_uid(), 11);
+
+        std::fs::write(&path, (MAX_UID - 1).to_string()).unwrap();
+        let uids = PersistentUids::open(&path).unwrap();
+        assert_eq!(uids.next_uid(), MAX_UID - 1);
+        uids.settle();
+        assert_eq!(uids.next_uid(), 1);
+        drop(uids);
+        assert_eq!(
+            PersistentUids::open(&path).unwrap().next_uid(),
+            1 + DEFAULT_BLOCK
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_persistent_uids_stop_at_the_saved_reservation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uids");
+        let uids = PersistentUids::open(&path).unwrap().block(4);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "5\n");
+
+        // Saving fails from here on
+        drop(dir);
+        let first: Vec<u64> = (0..4).map(|_| uids.next_uid()).collect();
+        assert_eq!(first, [1, 2, 3, 4]);
+        uids.settle();
+        assert!((0..100).all(|_| !(5..=8).contains(&uids.next_uid())));
+    }
+
+    #[test]
+    fn test_partitioned_uids_keep_to_their_half() {
+        let odd = PartitionedUids::new(std::sync::Arc::new(UidGenerator::new()), UidHalf::Odd);
+        assert_eq!([odd.next_uid(), odd.next_uid(), odd.next_uid()], [1, 3, 5]);
+        let even = PartitionedUids::new(std::sync::Arc::new(UidGenerator::new()), UidHalf::Even);
+        assert_eq!([even.next_uid(), even.next_uid()], [2, 4]);
+
+        let random = PartitionedUids::new(std::sync::Arc::new(RandomUids), UidHalf::Odd);
+        assert!((0..100)
+            .map(|_| random.next_uid())
+            .all(|uid| UidHalf::Odd.contains(uid) && uid <= MAX_UID));
+        assert!(!UidHalf::Odd.other().contains(7));
+        assert_eq!(UidHalf::from_name("even"), Some(UidHalf::Even));
+    }
+
     #[test]
     fn test_current_and_reset() {
         let gen = UidGenerator::new();
@@ -0,0 +1,208 @@
+//! Finding where two values differ
+//!
+//! `assert_eq!` on two large argument trees prints both as `Debug` dumps and
+//! leaves the reader to find the difference. [`first_mismatch`] walks both
+//! values instead and reports the first place they differ, by its path from
+//! the top, and [`assert_epc_eq!`](crate::assert_epc_eq) panics with that:
+//!
+//! ```should_panic
+//! use lexpr::Value;
+//!
+//! let expected = lexpr::from_str(r#"(:user (:name "ada" :langs ("lisp")))"#).unwrap();
+//! let actual = lexpr::from_str(r#"(:user (:name "ada" :langs ("rust")))"#).unwrap();
+//! // panics with: values differ at [1][3][0]: expected "lisp", got "rust"
+//! elrpc::assert_epc_eq!(actual, expected);
+//! ```
+//!
+//! Paths index lists and vectors from zero: `[1][3][0]` is the first
+//! element of the fourth element of the second. `[2..]` stands for the tail
+//! of a list after two elements, where one list is dotted and the other is
+//! not or their dotted tails differ.
+
+use std::fmt;
+
+use lexpr::Value;
+
+use crate::pretty::pretty;
+
+/// The first place two values differ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    /// Path from the top, empty when the values differ as a whole
+    pub path: String,
+    /// What the expected value holds there, `None` if it is shorter
+    pub expected: Option<Value>,
+    /// What the actual value holds there, `None` if it is shorter
+    pub actual: Option<Value>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<Value>| match value {
+            Some(value) => pretty(value),
+            None => "nothing".to_string(),
+        };
+        if self.path.is_empty() {
+            write!(f, "values differ")?;
+        } else {
+            write!(f, "values differ at {}", self.path)?;
+        }
+        write!(
+            f,
+            ": expected {}, got {}",
+            show(&self.expected),
+            show(&self.actual)
+        )
+    }
+}
+
+/// Where `actual` first differs from `expected`, in depth-first order
+pub fn first_mismatch(expected: &Value, actual: &Value) -> Option<Mismatch> {
+    let mut path = String::new();
+    walk(&mut path, expected, actual)
+}
+
+fn walk(path: &mut String, expected: &Value, actual: &Value) -> Option<Mismatch> {
+    match (expected, actual) {
+        (Value::Cons(_), Value::Cons(_)) => {
+            let (mut expected, mut actual) = (expected, actual);
+            let mut index = 0;
+            while let (Value::Cons(left), Value::Cons(right)) = (expected, actual) {
+                let mismatch = at(path, &format!("[{}]", index), |path| {
+                    walk(path, left.car(), right.car())
+                });
+                if mismatch.is_some() {
+                    return mismatch;
+                }
+                (expected, actual) = (left.cdr(), right.cdr());
+                index += 1;
+            }
+            match (expected, actual) {
+                _ if expected == actual => None,
+                (Value::Cons(extra), Value::Null) => Some(Mismatch {
+                    path: format!("{}[{}]", path, index),
+                    expected: Some(extra.car().clone()),
+                    actual: None,
+                }),
+                (Value::Null, Value::Cons(extra)) => Some(Mismatch {
+                    path: format!("{}[{}]", path, index),
+                    expected: None,
+                    actual: Some(extra.car().clone()),
+                }),
+                _ => Some(Mismatch {
+                    path: format!("{}[{}..]", path, index),
+                    expected: Some(expected.clone()),
+                    actual: Some(actual.clone()),
+                }),
+            }
+        }
+        (Value::Vector(left), Value::Vector(right)) => {
+            for (index, (left, right)) in left.iter().zip(right.iter()).enumerate() {
+                let mismatch = at(path, &format!("[{}]", index), |path| {
+                    walk(path, left, right)
+                });
+                if mismatch.is_some() {
+                    return mismatch;
+                }
+            }
+            let index = left.len().min(right.len());
+            (left.len() != right.len()).then(|| Mismatch {
+                path: format!("{}[{}]", path, index),
+                expected: left.get(index).cloned(),
+                actual: right.get(index).cloned(),
+            })
+        }
+        _ if expected == actual => None,
+        _ => Some(Mismatch {
+            path: path.clone(),
+            expected: Some(expected.clone()),
+            actual: Some(actual.clone()),
+        }),
+    }
+}
+
+/// Run `f` with `step` appended to `path`
+fn at<T>(path: &mut String, step: &str, f: impl FnOnce(&mut String) -> T) -> T {
+    let len = path.len();
+    path.push_str(step);
+    let result = f(path);
+    path.truncate(len);
+    result
+}
+
+/// Panic with the first mismatch unless `actual` equals `expected`
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_values_eq(actual: &Value, expected: &Value, context: Option<fmt::Arguments<'_>>) {
+    if let Some(mismatch) = first_mismatch(expected, actual) {
+        match context {
+            Some(context) => panic!("{}: {}", context, mismatch),
+            None => panic!("{}", mismatch),
+        }
+    }
+}
+
+/// Assert that two EPC values are equal, reporting the path of the first
+/// difference
+///
+/// Takes `Value`s or anything dereferencing to one, such as `EpcValue`, in
+/// the order of `assert_eq!`: the actual value first. A format string and
+/// arguments may follow.
+#[macro_export]
+macro_rules! assert_epc_eq {
+    ($actual:expr, $expected:expr $(,)?) => {
+        $crate::value_diff::assert_values_eq(&$actual, &$expected, ::std::option::Option::None)
+    };
+    ($actual:expr, $expected:expr, $($context:tt)+) => {
+        $crate::value_diff::assert_values_eq(
+            &$actual,
+            &$expected,
+            ::std::option::Option::Some(::std::format_args!($($context)+)),
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(car: Value, cdr: Value) -> Value {
+        Value::cons(car, cdr)
+    }
+
+    #[test]
+    fn test_first_mismatch_paths() {
+        // ((a "x") b) against ((a "y") b)
+        let inner = |s: &str| pair(Value::symbol("a"), pair(Value::string(s), Value::Null));
+        let outer = |s: &str| pair(inner(s), pair(Value::symbol("b"), Value::Null));
+        assert_eq!(first_mismatch(&outer("x"), &outer("x")), None);
+        let mismatch = first_mismatch(&outer("x"), &outer("y")).unwrap();
+        assert_eq!(mismatch.path, "[0][1]");
+        assert_eq!(mismatch.actual, Some(Value::string("y")));
+
+        // ((a "x") b) and (a b) against (a)
+        let short = pair(Value::symbol("a"), Value::Null);
+        let mismatch = first_mismatch(&outer("x"), &short).unwrap();
+        assert_eq!(mismatch.path, "[0]");
+        let long = pair(Value::symbol("a"), pair(Value::symbol("b"), Value::Null));
+        let mismatch = first_mismatch(&long, &short).unwrap();
+        assert_eq!((mismatch.path.as_str(), mismatch.actual), ("[1]", None));
+
+        // (a . b) against (a . c)
+        let dotted = |s: &str| pair(Value::symbol("a"), Value::symbol(s));
+        let mismatch = first_mismatch(&dotted("b"), &dotted("c")).unwrap();
+        assert_eq!(mismatch.path, "[1..]");
+
+        let vector = |s: &str| Value::Vector(vec![Value::symbol("a"), Value::symbol(s)].into());
+        assert_eq!(
+            first_mismatch(&vector("b"), &vector("c")).unwrap().path,
+            "[1]"
+        );
+        assert_eq!(
+            first_mismatch(&Value::symbol("a"), &vector("c"))
+                .unwrap()
+                .path,
+            ""
+        );
+    }
+}
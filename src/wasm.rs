@@ -0,0 +1,224 @@
+//! Browser client over WebSocket
+//!
+//! On `wasm32` targets with the `wasm` feature, [`Client`] talks to an EPC
+//! server through a browser `WebSocket`, with the same calls as the TCP
+//! client:
+//!
+//! ```ignore
+//! let client = elrpc::wasm::Client::connect("wss://example.org/epc").await?;
+//! let sum: i64 = client.call_sync("add", (1, 2)).await?;
+//! ```
+//!
+//! Each WebSocket message carries EPC frames exactly as they appear on TCP,
+//! length prefix included, so a server can feed them to the same [`Framer`].
+//! Frames split across or packed into messages are reassembled. Both binary
+//! and text messages are accepted; the client sends binary ones.
+//!
+//! The client is not `Send`: browser objects live on the thread that made
+//! them.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use bytes::BytesMut;
+use js_sys::{ArrayBuffer, Uint8Array};
+use lexpr::Value;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+use crate::error::ERPCError;
+use crate::log::{debug, warn};
+use crate::protocol::{Framer, Message};
+use crate::registry::MethodInfo;
+
+type Reply = std::result::Result<Message, ERPCError>;
+
+/// Calls waiting for their reply, by uid
+type Pending = Rc<RefCell<HashMap<u64, oneshot::Sender<Reply>>>>;
+
+fn js_error(value: JsValue) -> ERPCError {
+    ERPCError::ProtocolError(
+        value
+            .as_string()
+            .unwrap_or_else(|| format!("WebSocket error: {:?}", value)),
+    )
+}
+
+/// EPC client for the browser
+pub struct Client {
+    socket: WebSocket,
+    pending: Pending,
+    next_uid: Cell<u64>,
+    // The socket only holds references; these keep the handlers alive
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl Client {
+    /// Open a WebSocket to `url` and wait until it is connected
+    pub async fn connect(url: &str) -> std::result::Result<Self, ERPCError> {
+        let socket = WebSocket::new(url).map_err(js_error)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let opened_tx = Rc::new(RefCell::new(Some(opened_tx)));
+        let on_open = {
+            let opened_tx = opened_tx.clone();
+            Closure::<dyn FnMut(Event)>::new(move |_| {
+                if let Some(tx) = opened_tx.borrow_mut().take() {
+                    let _ = tx.send(Ok(()));
+                }
+            })
+        };
+        let on_error = Closure::<dyn FnMut(Event)>::new(move |_| {
+            if let Some(tx) = opened_tx.borrow_mut().take() {
+                let _ = tx.send(Err(ERPCError::ConnectionClosed));
+            }
+        });
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        let opened = opened_rx.await.map_err(|_| ERPCError::ConnectionClosed)?;
+        socket.set_onopen(None);
+        socket.set_onerror(None);
+        opened?;
+        debug!("Connected to EPC server at {}", url);
+
+        let pending = Pending::default();
+        let on_message = {
+            let pending = pending.clone();
+            let buffer = RefCell::new(BytesMut::new());
+            Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                let data = event.data();
+                let bytes = match data.dyn_ref::<ArrayBuffer>() {
+                    Some(array) => Uint8Array::new(array).to_vec(),
+                    None => match data.as_string() {
+                        Some(text) => text.into_bytes(),
+                        None => return,
+                    },
+                };
+                let mut buffer = buffer.borrow_mut();
+                buffer.extend_from_slice(&bytes);
+                while let Some(frame) = Framer::next_frame(&mut buffer, true) {
+                    let reply = std::str::from_utf8(&frame)
+                        .map_err(ERPCError::from)
+                        .and_then(Message::from_sexp);
+                    match reply {
+                        Ok(reply) => {
+                            if let Some(tx) = pending.borrow_mut().remove(&reply.uid()) {
+                                let _ = tx.send(Ok(reply));
+                            }
+                        }
+                        Err(e) => warn!("Dropping malformed frame: {}", e),
+                    }
+                }
+            })
+        };
+        let on_close = {
+            let pending = pending.clone();
+            Closure::<dyn FnMut(CloseEvent)>::new(move |_| {
+                for (_, tx) in pending.borrow_mut().drain() {
+                    let _ = tx.send(Err(ERPCError::ConnectionClosed));
+                }
+            })
+        };
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        Ok(Client {
+            socket,
+            pending,
+            next_uid: Cell::new(1),
+            _on_message: on_message,
+            _on_close: on_close,
+        })
+    }
+
+    fn next_uid(&self) -> u64 {
+        let uid = self.next_uid.get();
+        self.next_uid.set(uid + 1);
+        uid
+    }
+
+    /// Send a message and wait for its reply
+    async fn send_message(&self, message: Message) -> Reply {
+        let text = message.to_sexp()?;
+        let (tx, rx) = oneshot::channel();
+        self.pending.borrow_mut().insert(message.uid(), tx);
+        if let Err(e) = self
+            .socket
+            .send_with_u8_array(&Framer::frame(text.as_bytes()))
+        {
+            self.pending.borrow_mut().remove(&message.uid());
+            return Err(js_error(e));
+        }
+        rx.await.map_err(|_| ERPCError::ConnectionClosed)?
+    }
+
+    /// Call a method with typed arguments and result
+    pub async fn call_sync<Args, Ret>(
+        &self,
+        method: &str,
+        args: Args,
+    ) -> std::result::Result<Ret, ERPCError>
+    where
+        Args: Serialize,
+        Ret: for<'de> Deserialize<'de>,
+    {
+        let args = serde_lexpr::to_value(&args)
+            .map_err(|e| ERPCError::SerializationError(e.to_string()))?;
+        let result = self.call_value(method, args).await?;
+        serde_lexpr::from_value(&result).map_err(|e| ERPCError::SerializationError(e.to_string()))
+    }
+
+    /// Call a method with raw S-expression arguments, returning the raw result
+    pub async fn call_value(
+        &self,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Value, ERPCError> {
+        let message = Message::new_call(self.next_uid(), method, args);
+        match self.send_message(message).await? {
+            Message::Return { result, .. } => Ok(result),
+            Message::ReturnError { error, .. } => Err(ERPCError::ApplicationError {
+                class: "RuntimeError".to_string(),
+                message: error,
+                backtrace: vec![],
+            }),
+            Message::EPCError { error, .. } => Err(ERPCError::ProtocolError(error)),
+            _ => Err(ERPCError::InvalidMessageFormat(
+                "Unexpected response type".to_string(),
+            )),
+        }
+    }
+
+    /// Query available methods from the server
+    pub async fn query_methods(&self) -> std::result::Result<Vec<MethodInfo>, ERPCError> {
+        match self
+            .send_message(Message::new_methods(self.next_uid()))
+            .await?
+        {
+            Message::Return { result, .. } => serde_lexpr::from_value(&result)
+                .map_err(|e| ERPCError::SerializationError(e.to_string())),
+            _ => Err(ERPCError::InvalidMessageFormat(
+                "Expected methods response".to_string(),
+            )),
+        }
+    }
+
+    /// Close the connection, failing calls still waiting
+    pub fn close(&self) -> std::result::Result<(), ERPCError> {
+        self.socket.close().map_err(js_error)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
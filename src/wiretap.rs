@@ -0,0 +1,163 @@
+//! Observing the raw frames exchanged with a peer
+//!
+//! A [`WireTap`] set on [`ServerConfig`](crate::server::ServerConfig) or
+//! [`ClientConfig`](crate::client::ClientConfig) sees every frame exactly as
+//! it crosses the socket: inbound frames before they are parsed, outbound
+//! frames right after serialization. This is the tool for interop problems
+//! with `epc.el`, where the question is usually what was actually sent.
+
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::context::Peer;
+use crate::protocol::Framer;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Process-wide unique id for a new connection
+pub(crate) fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Which way a frame travels, seen from this process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// One frame as seen on the wire
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    pub direction: Direction,
+    /// Connection the frame belongs to, unique within the process
+    pub connection: u64,
+    pub peer: Peer,
+    pub timestamp: SystemTime,
+    /// The S-expression payload; on the wire it is preceded by its length
+    /// as six hex digits
+    pub payload: &'a [u8],
+}
+
+impl Frame<'_> {
+    /// The frame including its length header, byte for byte as on the wire
+    pub fn to_wire(&self) -> Vec<u8> {
+        Framer::frame(self.payload).to_vec()
+    }
+}
+
+/// Callback receiving raw frames
+pub type WireTapFn = dyn Fn(&Frame<'_>) + Send + Sync;
+
+/// Hook receiving every frame of a connection
+///
+/// The callback runs inline on the connection's reader and writer, so it
+/// should be quick.
+#[derive(Clone)]
+pub struct WireTap(Arc<WireTapFn>);
+
+impl fmt::Debug for WireTap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WireTap(..)")
+    }
+}
+
+impl WireTap {
+    /// Tap frames with `callback`
+    pub fn new(callback: impl Fn(&Frame<'_>) + Send + Sync + 'static) -> Self {
+        WireTap(Arc::new(callback))
+    }
+
+    /// Write one line per frame to `writer`
+    ///
+    /// Lines look like `1718000000.123456 #3 127.0.0.1:50000 <- 00000e(methods 1)`,
+    /// with `<-` for inbound and `->` for outbound frames.
+    pub fn dump(writer: impl Write + Send + 'static) -> Self {
+        let writer = Mutex::new(writer);
+        WireTap::new(move |frame| {
+            let since_epoch = frame
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let arrow = match frame.direction {
+                Direction::Inbound => "<-",
+                Direction::Outbound => "->",
+            };
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            // A tap must never break the connection, so write errors are dropped
+            let _ = writeln!(
+                writer,
+                "{}.{:06} #{} {} {} {}",
+                since_epoch.as_secs(),
+                since_epoch.subsec_micros(),
+                frame.connection,
+                frame.peer,
+                arrow,
+                String::from_utf8_lossy(&frame.to_wire())
+            );
+            let _ = writer.flush();
+        })
+    }
+
+    /// Hand one frame to the callback
+    pub(crate) fn record(
+        &self,
+        direction: Direction,
+        connection: u64,
+        peer: Peer,
+        timestamp: SystemTime,
+        payload: &[u8],
+    ) {
+        (self.0)(&Frame {
+            direction,
+            connection,
+            peer,
+            timestamp,
+            payload,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dump_writes_wire_bytes() {
+        let out = Shared::default();
+        let tap = WireTap::dump(out.clone());
+        let peer = Peer::Tcp("127.0.0.1:4242".parse().unwrap());
+        let at = UNIX_EPOCH + std::time::Duration::from_micros(1_718_000_000_123_456);
+
+        tap.record(Direction::Inbound, 7, peer, at, b"(methods 1)");
+        tap.record(Direction::Outbound, 7, peer, at, b"(return 1 nil)");
+
+        let dumped = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = dumped.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "1718000000.123456 #7 127.0.0.1:4242 <- 00000b(methods 1)"
+        );
+        assert_eq!(
+            lines[1],
+            "1718000000.123456 #7 127.0.0.1:4242 -> 00000e(return 1 nil)"
+        );
+    }
+}
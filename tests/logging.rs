@@ -0,0 +1,35 @@
+//! Log level control
+//!
+//! `init_logging` installs the process-wide subscriber, so this test runs
+//! in its own binary instead of alongside the library's unit tests.
+
+use elrpc::logging::{init_logging, log_level, set_log_level, LOG_LEVEL_METHOD};
+use elrpc::Server;
+use lexpr::Value;
+use tracing::level_filters::LevelFilter;
+
+#[tokio::test]
+async fn test_reload_level() {
+    init_logging(LevelFilter::INFO).unwrap();
+    assert!(init_logging(LevelFilter::INFO).is_err());
+
+    assert_eq!(set_log_level("debug").unwrap(), LevelFilter::INFO);
+    assert_eq!(log_level().unwrap(), LevelFilter::DEBUG);
+    assert!(set_log_level("chatty").is_err());
+
+    let server = Server::new();
+    server.register_log_level_method().await.unwrap();
+    let registry = server.registry();
+    let previous = registry
+        .call_method(LOG_LEVEL_METHOD, Value::list(vec![Value::symbol("warn")]))
+        .await
+        .unwrap();
+    assert_eq!(previous, Value::string("debug"));
+    assert_eq!(
+        registry
+            .call_method(LOG_LEVEL_METHOD, Value::Null)
+            .await
+            .unwrap(),
+        Value::string("warn")
+    );
+}